@@ -0,0 +1,34 @@
+/* Helpers shared by the benchmarks, for putting a runner back to the same state before every
+timed run. */
+use bevy::prelude::Entity;
+use juice_box::simulation::sim_runner::SimRunner;
+use juice_box::simulation::{SimConstraints, SimGrid, SimParticle};
+
+/// Copy a runner's particles, in the order restore() expects them.
+pub fn snapshot(runner: &mut SimRunner) -> Vec<(Entity, SimParticle)> {
+    runner.run(|_, _, _, particles, _, _, _, _| {
+        particles
+            .iter()
+            .map(|(particle_id, particle)| (particle_id, particle.clone()))
+            .collect()
+    })
+}
+
+/// Put a runner's constraints, grid, and particles back to copies taken earlier.
+pub fn restore(
+    runner: &mut SimRunner,
+    saved_constraints: &SimConstraints,
+    saved_grid: &SimGrid,
+    saved_particles: &[(Entity, SimParticle)],
+) {
+    runner.run(|_, constraints, grid, particles, _, _, _, _| {
+        *constraints = saved_constraints.clone();
+        *grid = saved_grid.clone();
+        for ((particle_id, mut particle), (saved_id, saved_particle)) in
+            particles.iter_mut().zip(saved_particles)
+        {
+            debug_assert_eq!(*saved_id, particle_id);
+            *particle = saved_particle.clone();
+        }
+    });
+}
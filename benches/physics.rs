@@ -0,0 +1,217 @@
+/* Benchmarks for each stage of step_simulation_once().  Run with `cargo bench --bench physics`;
+every stage is timed separately on discs of 1k, 10k, and 50k particles resting on the floor of
+grids of several sizes, so a slowdown in one stage can't hide behind the rest of the step.  Nothing
+here opens a window, so the benchmarks run headless. */
+mod common;
+
+use std::time::{Duration, Instant};
+
+use bevy::prelude::{Entity, Vec2};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use juice_box::simulation::sim_physics_engine::{
+    extrapolate_values, grid_to_particles, handle_particle_grid_collisions,
+    make_grid_velocities_incompressible, particles_to_grid, push_particles_apart,
+    update_particle_lookup, update_particles,
+};
+use juice_box::simulation::sim_runner::SimRunner;
+use juice_box::simulation::{step_simulation_once, SimConstraints, SimGrid, SimParticle};
+
+use common::{restore, snapshot};
+
+const PARTICLE_COUNTS: [usize; 3] = [1_000, 10_000, 50_000];
+const GRID_SIZES: [usize; 3] = [64, 128, 256];
+
+/* Particles per unit radius given to add_particles_in_radius().  Its rings are 5 / density apart,
+with particles 2 * PI / density apart around each ring, so a disc of radius r holds about
+(r * density)^2 / 10 particles; at 3.0 that is about seven particles per cell. */
+const PARTICLE_DENSITY: f32 = 3.0;
+
+/// Every stage of step_simulation_once() that touches particles or the pressure solve.
+#[derive(Clone, Copy)]
+enum Stage {
+    UpdateParticles,
+    PushParticlesApart,
+    HandleParticleGridCollisions,
+    LabelCells,
+    ParticlesToGrid,
+    ExtrapolateValues,
+    MakeGridVelocitiesIncompressible,
+    GridToParticles,
+    WholeStep,
+}
+
+impl Stage {
+    const ALL: [Stage; 9] = [
+        Stage::UpdateParticles,
+        Stage::PushParticlesApart,
+        Stage::HandleParticleGridCollisions,
+        Stage::LabelCells,
+        Stage::ParticlesToGrid,
+        Stage::ExtrapolateValues,
+        Stage::MakeGridVelocitiesIncompressible,
+        Stage::GridToParticles,
+        Stage::WholeStep,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Stage::UpdateParticles => "update_particles",
+            Stage::PushParticlesApart => "push_particles_apart",
+            Stage::HandleParticleGridCollisions => "handle_particle_grid_collisions",
+            Stage::LabelCells => "label_cells",
+            Stage::ParticlesToGrid => "particles_to_grid",
+            Stage::ExtrapolateValues => "extrapolate_values",
+            Stage::MakeGridVelocitiesIncompressible => "make_grid_velocities_incompressible",
+            Stage::GridToParticles => "grid_to_particles",
+            Stage::WholeStep => "step_simulation_once",
+        }
+    }
+
+    /** Run this stage once.  grid_to_particles() is handed `change_grid` as the change in the
+    grid over the step; how long it takes doesn't depend on the values in it. */
+    fn run(self, runner: &mut SimRunner, change_grid: &SimGrid) {
+        runner.run(
+            |commands, constraints, grid, particles, _, _, obstacles, rigid_bodies| {
+                let timestep: f32 = constraints.timestep;
+                match self {
+                    Stage::UpdateParticles => {
+                        update_particles(constraints, particles, grid, &[], timestep)
+                    }
+                    Stage::PushParticlesApart => {
+                        push_particles_apart(constraints, grid, particles, &[])
+                    }
+                    Stage::HandleParticleGridCollisions => {
+                        handle_particle_grid_collisions(constraints, grid, particles)
+                    }
+                    Stage::LabelCells => grid.label_cells(),
+                    Stage::ParticlesToGrid => {
+                        particles_to_grid(grid, particles, constraints);
+                    }
+                    Stage::ExtrapolateValues => extrapolate_values(grid, 1),
+                    Stage::MakeGridVelocitiesIncompressible => {
+                        make_grid_velocities_incompressible(grid, constraints, timestep)
+                    }
+                    Stage::GridToParticles => {
+                        grid_to_particles(grid, change_grid, particles, constraints, timestep)
+                    }
+                    Stage::WholeStep => step_simulation_once(
+                        commands,
+                        constraints,
+                        grid,
+                        particles,
+                        obstacles,
+                        rigid_bodies,
+                        timestep,
+                    ),
+                }
+            },
+        );
+    }
+}
+
+/** A simulation stopped partway through a step, right before its grid velocities are made
+incompressible, along with a copy of its state so that every stage can start from the same spot. */
+struct Scene {
+    grid_size: usize,
+    particle_count: usize,
+    runner: SimRunner,
+    constraints: SimConstraints,
+    grid: SimGrid,
+    particles: Vec<(Entity, SimParticle)>,
+}
+
+impl Scene {
+    /** Drop a disc of about `particle_count` particles onto the floor of a walled-in grid
+    `grid_size` cells wide; returns None if the disc doesn't fit. */
+    fn new(grid_size: usize, particle_count: usize) -> Option<Scene> {
+        let grid: SimGrid = SimGrid::new(grid_size, grid_size);
+        let cell_size: f32 = grid.cell_size as f32;
+        let width: f32 = grid_size as f32 * cell_size;
+        let radius: f32 = f32::sqrt(10.0 * particle_count as f32) / PARTICLE_DENSITY;
+        if 2.0 * radius + 4.0 * cell_size > width {
+            return None;
+        }
+
+        // Reseeding would spawn and despawn particles, so leave it off to keep the scene fixed.
+        let constraints: SimConstraints = SimConstraints {
+            min_particles_per_cell: 0,
+            max_particles_per_cell: 0,
+            ..SimConstraints::default()
+        };
+
+        let mut runner: SimRunner = SimRunner::new(constraints, grid);
+        runner.grid_mut().force_edge_solids();
+        runner.add_particles_in_radius(
+            PARTICLE_DENSITY,
+            radius,
+            Vec2::new(width / 2.0, 2.0 * cell_size + radius),
+            Vec2::new(10.0, -5.0),
+            0,
+        );
+
+        /* Step once so every grid field is filled in, then run the next step up to the point
+        where its grid velocities are made incompressible. */
+        runner.step();
+        runner.run(|_, constraints, grid, particles, _, _, _, _| {
+            let timestep: f32 = constraints.timestep;
+            update_particles(constraints, particles, grid, &[], timestep);
+            push_particles_apart(constraints, grid, particles, &[]);
+            handle_particle_grid_collisions(constraints, grid, particles);
+            update_particle_lookup(grid, particles);
+            grid.label_cells();
+            particles_to_grid(grid, particles, constraints);
+            extrapolate_values(grid, 1);
+        });
+
+        Some(Scene {
+            grid_size,
+            particle_count,
+            constraints: runner.constraints().clone(),
+            grid: runner.grid().clone(),
+            particles: snapshot(&mut runner),
+            runner,
+        })
+    }
+}
+
+fn bench_step_stages(c: &mut Criterion) {
+    let mut scenes: Vec<Scene> = GRID_SIZES
+        .iter()
+        .flat_map(|&grid_size| {
+            PARTICLE_COUNTS
+                .iter()
+                .filter_map(move |&particle_count| Scene::new(grid_size, particle_count))
+        })
+        .collect();
+
+    for stage in Stage::ALL {
+        let mut group = c.benchmark_group(stage.name());
+        group.sample_size(10);
+        for scene in scenes.iter_mut() {
+            let id = BenchmarkId::new(format!("{0}x{0}", scene.grid_size), scene.particle_count);
+
+            // Only the stage itself is timed, not putting the scene back together before it.
+            group.bench_function(id, |b| {
+                b.iter_custom(|iterations| {
+                    let mut elapsed: Duration = Duration::ZERO;
+                    for _ in 0..iterations {
+                        restore(
+                            &mut scene.runner,
+                            &scene.constraints,
+                            &scene.grid,
+                            &scene.particles,
+                        );
+                        let start: Instant = Instant::now();
+                        stage.run(&mut scene.runner, &scene.grid);
+                        elapsed += start.elapsed();
+                    }
+                    elapsed
+                })
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_step_stages);
+criterion_main!(benches);
@@ -0,0 +1,93 @@
+/* Benchmarks for the particle <-> grid velocity transfers.  Run with `cargo bench --bench transfer`;
+each transfer is timed at several particle counts, so its cost should grow linearly with the
+number of particles. */
+mod common;
+
+use std::time::{Duration, Instant};
+
+use bevy::prelude::{Entity, Vec2};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use juice_box::simulation::sim_physics_engine::{grid_to_particles, particles_to_grid};
+use juice_box::simulation::sim_runner::SimRunner;
+use juice_box::simulation::{SimConstraints, SimGrid, SimParticle};
+
+use common::{restore, snapshot};
+
+const PARTICLE_COUNTS: [usize; 3] = [1_000, 4_000, 10_000];
+
+/// Build a walled-in runner with roughly `particle_count` particles packed into the bottom of the grid.
+fn create_runner(particle_count: usize) -> SimRunner {
+    let mut runner: SimRunner = SimRunner::default();
+    runner.grid_mut().force_edge_solids();
+
+    // Lay the particles out row by row inside the walls, from the floor upwards.
+    let cell_size: f32 = runner.grid().cell_size as f32;
+    let inner_width: f32 = (runner.grid().dimensions.1 as f32 - 2.0) * cell_size;
+    let spacing: f32 = f32::sqrt(inner_width * inner_width * 0.9 / particle_count as f32);
+    let per_row: usize = (inner_width / spacing) as usize;
+    for i in 0..particle_count {
+        let position: Vec2 = Vec2::new(
+            cell_size + spacing * (0.5 + (i % per_row) as f32),
+            cell_size + spacing * (0.5 + (i / per_row) as f32),
+        );
+        runner
+            .add_particle(position, Vec2::new(10.0, -5.0), 0)
+            .unwrap();
+    }
+
+    // Step once so cell labels, the spatial lookup, and grid velocities are all filled in.
+    runner.step();
+    runner
+}
+
+fn bench_particles_to_grid(c: &mut Criterion) {
+    let mut group = c.benchmark_group("particles_to_grid");
+    for particle_count in PARTICLE_COUNTS {
+        let mut runner: SimRunner = create_runner(particle_count);
+        group.bench_function(BenchmarkId::from_parameter(particle_count), |b| {
+            b.iter(|| {
+                runner.run(|_, constraints, grid, particles, _, _, _, _| {
+                    particles_to_grid(grid, particles, constraints);
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_grid_to_particles(c: &mut Criterion) {
+    let mut group = c.benchmark_group("grid_to_particles");
+    for particle_count in PARTICLE_COUNTS {
+        let mut runner: SimRunner = create_runner(particle_count);
+        let saved_constraints: SimConstraints = runner.constraints().clone();
+        let change_grid: SimGrid = runner.grid().clone();
+        let saved_particles: Vec<(Entity, SimParticle)> = snapshot(&mut runner);
+
+        /* Transferring velocities changes the particles, so put them back before every run; only
+        the transfer itself is timed. */
+        group.bench_function(BenchmarkId::from_parameter(particle_count), |b| {
+            b.iter_custom(|iterations| {
+                let mut elapsed: Duration = Duration::ZERO;
+                for _ in 0..iterations {
+                    restore(
+                        &mut runner,
+                        &saved_constraints,
+                        &change_grid,
+                        &saved_particles,
+                    );
+                    let start: Instant = Instant::now();
+                    runner.run(|_, constraints, grid, particles, _, _, _, _| {
+                        let timestep: f32 = constraints.timestep;
+                        grid_to_particles(grid, &change_grid, particles, constraints, timestep);
+                    });
+                    elapsed += start.elapsed();
+                }
+                elapsed
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_particles_to_grid, bench_grid_to_particles);
+criterion_main!(benches);
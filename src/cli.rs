@@ -0,0 +1,98 @@
+use crate::error::Error;
+use crate::file_system::{init_world_for_files, load_scene, save_scene};
+use crate::simulation::sim_runner::SimRunner;
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+pub const USAGE: &str = "usage: juice_box run <scene.juice> --steps <N> --out <final.juice>";
+
+/// Arguments for running a scene headlessly, parsed from `juice_box run ...`.
+#[derive(Debug, PartialEq)]
+pub struct RunArgs {
+    pub scene: String, // Key of the scene to load; like every bevy_save key it has no ".juice" extension.
+    pub steps: usize,
+    pub out: String, // Key of the file the final state is saved to.
+}
+
+/** Parse the arguments following the "run" subcommand, e.g. `scene.juice --steps 600 --out
+final.juice`.  The scene and output paths may be given with or without the .juice extension. */
+pub fn parse_run_args(args: &[String]) -> Result<RunArgs> {
+    let mut scene: Option<String> = None;
+    let mut steps: Option<usize> = None;
+    let mut out: Option<String> = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--steps" => {
+                let Some(value) = args.next() else {
+                    return Err(Error::InvalidArguments("--steps needs a value"));
+                };
+                match value.parse::<usize>() {
+                    Ok(value) => steps = Some(value),
+                    Err(_e) => {
+                        return Err(Error::InvalidArguments(
+                            "--steps must be a non-negative whole number",
+                        ))
+                    }
+                }
+            }
+            "--out" => {
+                let Some(value) = args.next() else {
+                    return Err(Error::InvalidArguments("--out needs a value"));
+                };
+                out = Some(strip_juice_extension(value));
+            }
+            _ => {
+                if arg.starts_with("--") || scene.is_some() {
+                    return Err(Error::InvalidArguments("unexpected argument"));
+                }
+                scene = Some(strip_juice_extension(arg));
+            }
+        }
+    }
+
+    let Some(scene) = scene else {
+        return Err(Error::InvalidArguments("missing scene file"));
+    };
+    let Some(steps) = steps else {
+        return Err(Error::InvalidArguments("missing --steps"));
+    };
+    let Some(out) = out else {
+        return Err(Error::InvalidArguments("missing --out"));
+    };
+
+    Ok(RunArgs { scene, steps, out })
+}
+
+/// Remove a trailing .juice from a path, since bevy_save adds the extension itself.
+fn strip_juice_extension(path: &str) -> String {
+    match path.strip_suffix(".juice") {
+        Some(key) => key.to_string(),
+        None => path.to_string(),
+    }
+}
+
+/** Load a scene into a headless runner, step it with the scene's own timestep, and save the final
+state.  No window, renderer, or UI is created. */
+pub fn run_scene(args: &RunArgs) -> Result<()> {
+    let mut runner: SimRunner = SimRunner::default();
+    init_world_for_files(runner.world_mut());
+
+    load_scene(args.scene.clone(), runner.world_mut())?;
+    runner.step_many(args.steps);
+    save_scene(args.out.clone(), runner.world_mut())?;
+
+    let constraints = runner.constraints();
+    println!(
+        "Ran {} for {} steps with {} particles (last pressure solve: {} iterations, residual {}); saved to {}.juice",
+        args.scene,
+        args.steps,
+        constraints.particle_count,
+        constraints.pressure_iterations,
+        constraints.pressure_residual,
+        args.out
+    );
+
+    Ok(())
+}
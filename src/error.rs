@@ -18,9 +18,18 @@ pub enum Error {
     #[error("Invalid entity ID: `{0}`")]
     InvalidEntityID(&'static str),
 
+    #[error("Invalid fluid material: `{0}`")]
+    InvalidMaterial(&'static str),
+
     #[error("Issue computing interpolated value: `{0}`")]
     Interpolation(&'static str),
 
     #[error("Cannot connect to file explorer: `{0}`")]
     FileExplorer(&'static str),
+
+    #[error("Could not read or write file: `{0}`")]
+    FileIO(&'static str),
+
+    #[error("Invalid command line arguments: `{0}`")]
+    InvalidArguments(&'static str),
 }
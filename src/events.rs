@@ -91,6 +91,8 @@ impl ModifyVisualizationEvent {
         let fluid_color_variable: FluidColorRenderType = match ui_state.fluid_color_variable {
             0 => FluidColorRenderType::Velocity,
             1 => FluidColorRenderType::Density,
+            2 => FluidColorRenderType::Material,
+            3 => FluidColorRenderType::Temperature,
             _ => FluidColorRenderType::Arbitrary,
         };
 
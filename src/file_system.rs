@@ -1,56 +1,36 @@
-// TODO: Allow the loading to be called directly with a key instead of automatically opening a file dialog every time.
 // TODO: Record the current filepath for regular saving, only Save As works currently.
 // TODO: The app crashes when the user closes a file dialog or tries to select a wrong file. Fix this.
 
 use bevy::ecs::query::*;
 use bevy::prelude::*;
+use bevy::reflect::TypeRegistry;
 use bevy_save::*;
 use std;
+#[cfg(feature = "gui")]
 use std::path::PathBuf;
 
 use crate::error::Error;
 use crate::simulation::{
-    SimConstraints, SimDrain, SimFaucet, SimGrid, SimGridCellType, SimParticle, SimSurfaceDirection,
+    SimBoundaries, SimBoundary, SimColor, SimConstraints, SimDrain, SimFaucet, SimFluidMaterial,
+    SimFluidMode, SimGrid, SimGridArray, SimGridCellType, SimHeatSource, SimObstacle, SimParticle,
+    SimRigidBody, SimShape, SimSolid, SimSpatialHash, SimSurfaceDirection, SimTransferScheme,
 };
+#[cfg(feature = "gui")]
 use crate::ui::UIStateManager;
 
 use std::io::{Read, Write};
 
 use serde::{de::DeserializeSeed, Serialize};
 
+#[cfg(feature = "gui")]
 pub struct FileSystem;
+#[cfg(feature = "gui")]
 impl Plugin for FileSystem {
     fn build(&self, app: &mut App) {
         app.insert_resource(CurrentFile::default());
 
         // Setting up the type registry so the data can be accessed
-
-        // Registering SimParticle and it's associated types
-        app.register_type::<SimParticle>();
-        app.register_type::<Option<Vec2>>(); // Needed for loading position, velocity, and any other Vec2 types
-
-        // Registering SimConstraints
-        // All associated types are f32, usize, u8, and Vec2. All already registered
-        app.register_type::<SimConstraints>();
-        app.register_type::<(Entity, Vec2)>();
-        app.register_type::<Vec<(Entity, Vec2)>>();
-
-        // Registering SimGrid and it's associated types
-        app.register_type::<SimGrid>();
-        app.register_type::<(u16, u16)>(); // Needed for loading dimensions
-        app.register_type::<SimGridCellType>();
-        app.register_type::<Vec<SimGridCellType>>();
-        app.register_type::<Vec<Vec<SimGridCellType>>>(); // Needed for loading the cell_type
-        app.register_type::<Vec<f32>>();
-        app.register_type::<Vec<Vec<f32>>>(); // Needed for loading cell_center, velocity_u, velocity_v, and density
-        app.register_type::<Vec<Entity>>();
-        app.register_type::<Vec<Vec<Entity>>>(); // Needed for loading spatial_lookup
-        app.register_type::<Option<Rect>>(); // Pretty sure needed for loading any <Vec<Vec<T>>>()
-
-        // Registering SimFaucet, SimDrain, and their associated types
-        app.register_type::<SimFaucet>();
-        app.register_type::<SimDrain>();
-        app.register_type::<SimSurfaceDirection>();
+        register_juice_types(&mut app.world.resource::<AppTypeRegistry>().write());
 
         // Loading and saving funcitonality is called using Bevy's state transitions
         // Since they have direct world and file access, they freeze all other processes. This is to prevent them being scheduled in Update.
@@ -64,11 +44,79 @@ impl Plugin for FileSystem {
     }
 }
 
+/// Registers every type that is saved to or loaded from a .juice file.
+pub fn register_juice_types(registry: &mut TypeRegistry) {
+    // Registering SimParticle and it's associated types
+    registry.register::<SimParticle>();
+    registry.register::<Option<Vec2>>(); // Needed for loading position, velocity, and any other Vec2 types
+    registry.register::<Mat2>(); // Needed for loading the APIC affine matrix
+
+    // Registering SimConstraints
+    // All associated types are f32, usize, u8, and Vec2. All already registered, except SimTransferScheme
+    // and the fluid materials
+    registry.register::<SimConstraints>();
+    registry.register::<SimTransferScheme>();
+    registry.register::<SimFluidMode>();
+    registry.register::<SimFluidMaterial>();
+    registry.register::<Vec<SimFluidMaterial>>(); // Needed for loading fluid_materials
+    registry.register::<SimColor>(); // Needed for loading each material's color
+    registry.register::<(Entity, Vec2)>();
+    registry.register::<Vec<(Entity, Vec2)>>();
+
+    // Registering SimGrid and it's associated types
+    registry.register::<SimGrid>();
+    registry.register::<(u16, u16)>(); // Needed for loading dimensions
+    registry.register::<SimGridCellType>();
+    registry.register::<Vec<SimGridCellType>>();
+    registry.register::<SimGridArray<SimGridCellType>>(); // Needed for loading the cell_type
+    registry.register::<SimHeatSource>();
+    registry.register::<Vec<SimHeatSource>>();
+    registry.register::<SimGridArray<SimHeatSource>>(); // Needed for loading the heat_sources
+    registry.register::<Vec<f32>>(); // Needed for loading solid_distance
+    registry.register::<SimGridArray<f32>>(); // Needed for loading cell_center, velocity_u, velocity_v, and the per-cell fluid values
+    registry.register::<Vec<Entity>>();
+    registry.register::<SimSpatialHash>(); // Not saved, but reflected with spatial_lookup
+    registry.register::<Vec<usize>>(); // Needed for loading obstacle_cells
+    registry.register::<SimSolid>();
+    registry.register::<Vec<SimSolid>>(); // Needed for loading solids
+    registry.register::<Vec<Vec2>>(); // Needed for loading polygon solids
+    registry.register::<SimBoundaries>();
+    registry.register::<SimBoundary>();
+    registry.register::<Option<Rect>>(); // Pretty sure needed for loading any <Vec<Vec<T>>>()
+
+    // Registering SimFaucet, SimDrain, and their associated types
+    registry.register::<SimFaucet>();
+    registry.register::<SimDrain>();
+    registry.register::<SimSurfaceDirection>();
+
+    // Registering SimObstacle, SimRigidBody, and their shape
+    registry.register::<SimObstacle>();
+    registry.register::<SimRigidBody>();
+    registry.register::<SimShape>();
+}
+
+/** Give a world without an App (e.g. a headless SimRunner's world) everything bevy_save needs to
+load and save .juice files; the FileSystem and bevy_save plugins do this for the windowed app. */
+pub fn init_world_for_files(world: &mut World) {
+    let type_registry: AppTypeRegistry = AppTypeRegistry::default();
+    {
+        let mut registry = type_registry.write();
+        registry.register::<Vec2>();
+        registry.register::<Entity>();
+        register_juice_types(&mut registry);
+    }
+    world.insert_resource(type_registry);
+    world.insert_resource(<JuicePipeline as Pipeline>::Backend::default());
+    world.init_resource::<RollbackRegistry>();
+}
+
+#[cfg(feature = "gui")]
 #[derive(Resource)]
 pub struct CurrentFile {
     filepath: String,
 }
 
+#[cfg(feature = "gui")]
 impl Default for CurrentFile {
     fn default() -> CurrentFile {
         Self {
@@ -77,14 +125,16 @@ impl Default for CurrentFile {
     }
 }
 
+#[cfg(feature = "gui")]
 impl CurrentFile {
     fn _new(filepath: String) -> Self {
         Self { filepath: filepath }
     }
 }
 
-#[derive(States, Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(States, Debug, Clone, PartialEq, Eq, Hash, Default)]
 pub enum JuiceStates {
+    #[default]
     Running,
     New,
     Loading,
@@ -93,12 +143,6 @@ pub enum JuiceStates {
     SavingAs,
 }
 
-impl Default for JuiceStates {
-    fn default() -> JuiceStates {
-        JuiceStates::Running
-    }
-}
-
 /// Custom file format. Extension is set to .juice, but under the hood it's really just json.
 /// Connects to bevy_save's JSONFormat implementation and uses that.
 pub struct JUICEFormat;
@@ -114,23 +158,115 @@ impl Format for JUICEFormat {
         JSONFormat::serialize(writer, value)
     }
 
-    // Setting bevy_save's JSON deserializer to JUICEFormat's deserializer. This creates the resources/entities from the file.
+    /// Reads the file as JSON, upgrades it with migrate_juice_save() in case it was saved by an older
+    /// version of JuiceBox, and then creates the resources/entities from it.
     fn deserialize<R: Read, S: for<'de> DeserializeSeed<'de, Value = T>, T>(
         reader: R,
         seed: S,
     ) -> Result<T, bevy_save::Error> {
-        JSONFormat::deserialize(reader, seed)
+        let mut save: serde_json::Value =
+            serde_json::from_reader(reader).map_err(bevy_save::Error::loading)?;
+        migrate_juice_save(&mut save).map_err(bevy_save::Error::custom)?;
+        seed.deserialize(save).map_err(bevy_save::Error::loading)
     }
 }
 
+/// Fields of SimGrid that older saves stored as a list of rows, rather than as a SimGridArray.
+const SIM_GRID_ARRAY_FIELDS: [&str; 5] = [
+    "cell_type",
+    "cell_center",
+    "velocity_u",
+    "velocity_v",
+    "heat_sources",
+];
+
+/// Fields of SimGrid that older saves stored as one flat list of cells, rather than as a SimGridArray.
+const SIM_GRID_CELL_FIELDS: [&str; 8] = [
+    "density",
+    "fluid_density",
+    "fluid_viscosity",
+    "fluid_flow_index",
+    "fluid_yield_stress",
+    "fluid_friction",
+    "temperature",
+    "smoke_density",
+];
+
+/// Upgrade a .juice save, as parsed JSON, from an older layout to the current one. Saves that are
+/// already up to date are left alone.
+pub fn migrate_juice_save(save: &mut serde_json::Value) -> Result<(), String> {
+    let grid = match save
+        .get_mut("resources")
+        .and_then(|resources| resources.get_mut(SimGrid::type_path()))
+        .and_then(|grid| grid.as_object_mut())
+    {
+        Some(grid) => grid,
+        None => return Ok(()),
+    };
+
+    // Grids used to be lists of rows; flatten them into { rows, cols, data }.
+    for field in SIM_GRID_ARRAY_FIELDS {
+        let rows: Vec<serde_json::Value> = match grid.get_mut(field) {
+            Some(serde_json::Value::Array(rows)) => std::mem::take(rows),
+            _ => continue,
+        };
+        let row_count: usize = rows.len();
+        let mut col_count: usize = 0;
+        let mut data: Vec<serde_json::Value> = Vec::new();
+        for (row_index, row) in rows.into_iter().enumerate() {
+            let serde_json::Value::Array(row) = row else {
+                return Err(format!("SimGrid's {field} is not a list of rows!"));
+            };
+            if row_index == 0 {
+                col_count = row.len();
+            } else if row.len() != col_count {
+                return Err(format!("SimGrid's {field} has rows of different lengths!"));
+            }
+            data.extend(row);
+        }
+        grid.insert(
+            field.to_string(),
+            serde_json::json!({ "rows": row_count, "cols": col_count, "data": data }),
+        );
+    }
+
+    /* Per-cell values used to be flat lists; give them the grid's shape.  Lists that don't match
+    the grid are dropped, leaving the loaded grid's own values in place. */
+    let dimensions: Option<(u64, u64)> = grid
+        .get("dimensions")
+        .and_then(|dimensions| Some((dimensions.get(0)?.as_u64()?, dimensions.get(1)?.as_u64()?)));
+    for field in SIM_GRID_CELL_FIELDS {
+        let cells: Vec<serde_json::Value> = match grid.get_mut(field) {
+            Some(serde_json::Value::Array(cells)) => std::mem::take(cells),
+            _ => continue,
+        };
+        match dimensions {
+            Some((rows, cols)) if cells.len() as u64 == rows * cols => {
+                grid.insert(
+                    field.to_string(),
+                    serde_json::json!({ "rows": rows, "cols": cols, "data": cells }),
+                );
+            }
+            _ => {
+                grid.remove(field);
+            }
+        }
+    }
+
+    // The spatial lookup is rebuilt from the particles after loading, so it is no longer saved.
+    grid.remove("spatial_lookup");
+
+    Ok(())
+}
+
 /// Pipeline for saving and loading files. Contains current key (filepath) and an implementation of bevy_save's Pipeline
-struct JuicePipeline {
+pub struct JuicePipeline {
     key: String, // The full filepath for the location of the file.
 }
 
 impl JuicePipeline {
     pub fn new(key: String) -> Self {
-        Self { key: key }
+        Self { key }
     }
 }
 
@@ -141,11 +277,11 @@ impl Pipeline for JuicePipeline {
     type Key<'a> = &'a str;
 
     fn key(&self) -> Self::Key<'_> {
-        return &self.key;
+        &self.key
     }
 
     /// Generates a snapshot of bevy's world, the current SimGrid, SimConstraints, all SimParticles,
-    /// all SimDrains, and all SimFaucets.
+    /// all SimObstacles, all SimRigidBodies, all SimDrains, and all SimFaucets.
     ///
     /// This is the Pipeline's way to save files. Most of the implementation is in bevy_save.
     fn capture(builder: SnapshotBuilder) -> Snapshot {
@@ -154,28 +290,39 @@ impl Pipeline for JuicePipeline {
             .allow::<SimGrid>()
             .allow::<SimConstraints>()
             .allow::<SimParticle>()
+            .allow::<SimObstacle>()
+            .allow::<SimRigidBody>()
             // .allow::<SimFaucet>()
             // .allow::<SimDrain>()
             .extract_resource::<SimGrid>()
             .extract_resource::<SimConstraints>()
             .extract_entities_matching(|e| e.contains::<SimParticle>())
+            .extract_entities_matching(|e| e.contains::<SimObstacle>())
+            .extract_entities_matching(|e| e.contains::<SimRigidBody>())
             // .extract_entities_matching(|e| e.contains::<SimFaucet>())
             // .extract_entities_matching(|e| e.contains::<SimDrain>())
             .build()
     }
 
-    /// Despawns all SimParticles, SimDrains, and SimFaucets in the current world,
-    /// then loads a snapshot generated from a file.
+    /// Despawns all SimParticles, SimObstacles, SimRigidBodies, SimDrains, and SimFaucets in the
+    /// current world, then loads a snapshot generated from a file.
     ///
     /// This is the Pipeline's way to load files. Most of the implementation is in bevy_save.
     fn apply(world: &mut World, snapshot: &Snapshot) -> Result<(), bevy_save::Error> {
         snapshot
             .applier(world)
-            .despawn::<Or<(With<SimParticle>, With<SimFaucet>, With<SimDrain>)>>() // Despawning all entities.
+            .despawn::<Or<(
+                With<SimParticle>,
+                With<SimObstacle>,
+                With<SimRigidBody>,
+                With<SimFaucet>,
+                With<SimDrain>,
+            )>>() // Despawning all entities.
             .apply()
     }
 }
 
+#[cfg(feature = "gui")]
 fn handle_new_scene(world: &mut World) {
     // Creates new file dialog asking the user to create new file.
     let key: String = match create_new_file() {
@@ -191,10 +338,16 @@ fn handle_new_scene(world: &mut World) {
         current_file.filepath = key.clone();
     };
 
-    load_scene(String::from("metadata/default-file"), world);
-    save_scene(key, world);
+    if let Err(e) = load_scene(String::from("metadata/default-file"), world) {
+        println!("{}", e);
+        return;
+    }
+    if let Err(e) = save_scene(key, world) {
+        println!("{}", e);
+    }
 }
 
+#[cfg(feature = "gui")]
 /// Runs file dialog asking user for filepath, loads the file into the world. Function runs when state = JuiceStates::Loading.
 fn handle_loading(world: &mut World) {
     // Creates new file dialog asking the user to select an existing file.
@@ -211,18 +364,24 @@ fn handle_loading(world: &mut World) {
         current_file.filepath = key.clone();
     };
 
-    load_scene(key, world);
+    if let Err(e) = load_scene(key, world) {
+        println!("{}", e);
+    }
 }
 
+#[cfg(feature = "gui")]
 fn handle_reloading(world: &mut World) {
     let key: String = match world.get_resource::<CurrentFile>() {
         Some(current_file) => current_file.filepath.clone(),
         None => return (), /*world.get_resource::<CurrentFile>().unwrap().filepath.clone()*/ // TODO run save as here
     };
 
-    load_scene(key, world);
+    if let Err(e) = load_scene(key, world) {
+        println!("{}", e);
+    }
 }
 
+#[cfg(feature = "gui")]
 /// Triggers a file dialog asking user for filepath, saves the data into the file. Function runs when state = JuiceStates::Saving.
 /// Does nothing if user doesn't select a file.
 fn handle_saving(world: &mut World) {
@@ -231,9 +390,12 @@ fn handle_saving(world: &mut World) {
         None => return (), /*world.get_resource::<CurrentFile>().unwrap().filepath.clone()*/ // TODO run save as here
     };
 
-    save_scene(key, world);
+    if let Err(e) = save_scene(key, world) {
+        println!("{}", e);
+    }
 }
 
+#[cfg(feature = "gui")]
 fn handle_saving_as(world: &mut World) {
     // Creates new file dialog asking the user to create new file.
     let key: String = match create_new_file() {
@@ -249,9 +411,12 @@ fn handle_saving_as(world: &mut World) {
         current_file.filepath = key.clone();
     };
 
-    save_scene(key, world);
+    if let Err(e) = save_scene(key, world) {
+        println!("{}", e);
+    }
 }
 
+#[cfg(feature = "gui")]
 /// Sets state back to JuiceStates::Running.
 fn reset_file_state(
     mut file_state: ResMut<NextState<JuiceStates>>,
@@ -262,6 +427,7 @@ fn reset_file_state(
     ui_state_manager.file_state = JuiceStates::default();
 }
 
+#[cfg(feature = "gui")]
 /// Triggers a file dialog asking user to select an existing .juice file. Returns the path to it as an Option<String>.
 fn get_file() -> Result<String, Error> {
     let start_path = match std::env::current_dir() {
@@ -298,6 +464,7 @@ fn get_file() -> Result<String, Error> {
     Ok(key.to_string()) // Removing mutability
 }
 
+#[cfg(feature = "gui")]
 /// Runs a file dialog asking user to create a new .juice file. Returns the path to it as an Option<String>.
 ///
 /// Does not actually create a file, just passes a String to where one should be created.
@@ -337,28 +504,19 @@ fn create_new_file() -> Result<String, Error> {
 }
 
 /// Initiate new pipeline and load scene to key.
-fn load_scene(key: String, world: &mut World) {
+pub fn load_scene(key: String, world: &mut World) -> Result<(), Error> {
     match world.load(JuicePipeline::new(key)) {
         Ok(_ok) => {}
         Err(_e) => {
-            println!(
-                "{}",
-                Error::FileExplorer(
-                    "Did not load correctly, perhaps filepath was incorrect or file was corrupted?"
-                )
-            );
-            return ();
+            return Err(Error::FileIO(
+                "Did not load correctly, perhaps filepath was incorrect or file was corrupted?",
+            ));
         }
     }
 
-    // Erase the spatial lookup table, this will cause "ghost particles" otherwise.
+    // Fields missing from the save were kept from the old grid, so fit them to the loaded one.
     if let Some(mut grid) = world.get_resource_mut::<SimGrid>() {
-        grid.spatial_lookup = vec![
-            vec![Entity::PLACEHOLDER; 0];
-            grid.dimensions.0 as usize * grid.dimensions.1 as usize
-        ];
-    } else {
-        println!("Grid not constructed in time; please reset simulation before continuing!");
+        grid.resize_cell_fields();
     }
 
     // Pause the simulation once we have loaded in!
@@ -367,18 +525,20 @@ fn load_scene(key: String, world: &mut World) {
     } else {
         println!("Constraints not constructed in time; cannot pause!");
     }
+
+    Ok(())
 }
 
 /// Initiate new pipeline and save scene to key.
-fn save_scene(key: String, world: &mut World) {
+pub fn save_scene(key: String, world: &mut World) -> Result<(), Error> {
     match world.save(JuicePipeline::new(key)) {
         Ok(_ok) => {}
         Err(_e) => {
-            println!(
-                "{}",
-                Error::FileExplorer("Did not save correctly, perhaps filepath was incorrect?")
-            );
-            return ();
+            return Err(Error::FileIO(
+                "Did not save correctly, perhaps filepath was incorrect?",
+            ));
         }
     }
+
+    Ok(())
 }
@@ -2,14 +2,21 @@ use std::f32::consts::PI;
 
 use crate::{
     events::ModifyVisualizationEvent,
-    simulation::{SimConstraints, SimDrain, SimFaucet, SimGrid, SimGridCellType, SimParticle},
+    simulation::{
+        SimConstraints, SimDrain, SimFaucet, SimFluidMode, SimGrid, SimGridCellType, SimHeatSource,
+        SimObstacle, SimParticle, SimRigidBody, SimShape, SimSolid,
+    },
     ui::{SimTool, UIStateManager},
     util::{
         self, cartesian_to_polar, degrees_to_radians, get_cursor_position, JUICE_BLUE, JUICE_GREEN,
         JUICE_SKY_BLUE,
     },
 };
-use bevy::{core_pipeline::prelude::ClearColor, prelude::*};
+use bevy::{
+    core_pipeline::prelude::ClearColor,
+    prelude::*,
+    render::render_resource::{Extent3d, TextureDimension, TextureFormat},
+};
 
 pub struct JuiceRenderer;
 impl Plugin for JuiceRenderer {
@@ -25,10 +32,13 @@ impl Plugin for JuiceRenderer {
         app.add_systems(Update, update_particle_position);
         app.add_systems(Update, update_particle_color);
         app.add_systems(Update, update_particle_size);
+        app.add_systems(Update, update_smoke_texture);
 
         app.add_systems(Update, draw_grid_vectors);
         app.add_systems(Update, draw_grid_cells);
         app.add_systems(Update, draw_grid_solids);
+        app.add_systems(Update, draw_obstacles);
+        app.add_systems(Update, draw_sub_cell_solids);
 
         app.add_systems(PostUpdate, validate_entity_sprites);
         app.add_systems(PostUpdate, draw_gravity_arrow);
@@ -41,6 +51,8 @@ pub enum FluidColorRenderType {
     Arbitrary,
     Velocity,
     Density,
+    Material,
+    Temperature,
     GridCell,
     Spume,
 }
@@ -79,6 +91,8 @@ struct GridRenderData {
     draw_grid: bool,
     grid_color: Color,
     solid_cell_color: Color,
+    obstacle_color: Color,
+    rigid_body_color: Color,
 
     draw_vectors: bool,
     vector_color: Color,
@@ -93,6 +107,8 @@ impl Default for GridRenderData {
             draw_grid: false,
             grid_color: Color::DARK_GRAY,
             solid_cell_color: Color::GOLD,
+            obstacle_color: Color::ORANGE_RED,
+            rigid_body_color: Color::ANTIQUE_WHITE,
 
             draw_vectors: false,
             vector_color: Color::WHITE,
@@ -103,6 +119,10 @@ impl Default for GridRenderData {
     }
 }
 
+/// Marks the sprite that shows a smoke scene's density, stretched over the whole grid.
+#[derive(Component)]
+struct SmokeTexture;
+
 /// Handle events sent to the renderer.
 fn handle_events(
     mut ev_viz: EventReader<ModifyVisualizationEvent>,
@@ -123,7 +143,7 @@ fn handle_events(
 }
 
 /// Custom rendering pipeline initialization.
-fn setup_renderer(mut commands: Commands, grid: Res<SimGrid>) {
+fn setup_renderer(mut commands: Commands, grid: Res<SimGrid>, mut images: ResMut<Assets<Image>>) {
     // Spawn a camera to view our simulation world!
     commands.spawn(Camera2dBundle {
         transform: Transform {
@@ -137,6 +157,78 @@ fn setup_renderer(mut commands: Commands, grid: Res<SimGrid>) {
         },
         ..default()
     });
+
+    // Spawn a hidden texture for drawing smoke into; it only shows up in smoke scenes.
+    commands.spawn((
+        SpriteBundle {
+            texture: images.add(create_smoke_image(grid.as_ref())),
+            visibility: Visibility::Hidden,
+            ..default()
+        },
+        SmokeTexture,
+    ));
+}
+
+/// Create a transparent texture with one pixel per grid cell.
+fn create_smoke_image(grid: &SimGrid) -> Image {
+    Image::new_fill(
+        Extent3d {
+            width: grid.dimensions.1 as u32,
+            height: grid.dimensions.0 as u32,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0, 0, 0, 0],
+        TextureFormat::Rgba8UnormSrgb,
+    )
+}
+
+/** Draw each cell's smoke density into the smoke texture, from clear to white, and only show the
+texture while the simulation is in smoke mode. */
+fn update_smoke_texture(
+    grid: Res<SimGrid>,
+    constraints: Res<SimConstraints>,
+    mut images: ResMut<Assets<Image>>,
+    mut smoke: Query<
+        (&Handle<Image>, &mut Sprite, &mut Transform, &mut Visibility),
+        With<SmokeTexture>,
+    >,
+) {
+    for (image_handle, mut sprite, mut transform, mut visibility) in smoke.iter_mut() {
+        if constraints.fluid_mode != SimFluidMode::Smoke {
+            *visibility = Visibility::Hidden;
+            continue;
+        }
+        *visibility = Visibility::Visible;
+
+        let Some(image) = images.get_mut(image_handle) else {
+            continue;
+        };
+
+        // Make a new texture if the grid has changed size.
+        if image.texture_descriptor.size.width != grid.dimensions.1 as u32
+            || image.texture_descriptor.size.height != grid.dimensions.0 as u32
+        {
+            *image = create_smoke_image(grid.as_ref());
+        }
+
+        // Texture rows run top to bottom just like grid rows, so cells map straight to pixels.
+        for (index, pixel) in image.data.chunks_exact_mut(4).enumerate() {
+            let density: f32 = match grid.smoke_density.as_slice().get(index) {
+                Some(density) => density.clamp(0.0, 1.0),
+                None => 0.0,
+            };
+            pixel.copy_from_slice(&[235, 235, 235, (density * 255.0) as u8]);
+        }
+
+        // Stretch the texture over the grid, behind everything else.
+        let grid_size: Vec2 = Vec2::new(
+            (grid.dimensions.1 * grid.cell_size) as f32,
+            (grid.dimensions.0 * grid.cell_size) as f32,
+        );
+        sprite.custom_size = Some(grid_size);
+        transform.translation = (grid_size / 2.0).extend(-1.0);
+    }
 }
 
 /** Creates and links a new sprite to the specified particle; **Must be called each time a new
@@ -311,6 +403,12 @@ fn update_particle_color(
                 util::JUICE_BLUE,
             ],
         ),
+        FluidColorRenderType::Material => {
+            color_particles_by_material(particles, constraints.as_ref())
+        }
+        FluidColorRenderType::Temperature => {
+            color_particles_by_temperature(particles, constraints.as_ref())
+        }
         FluidColorRenderType::Arbitrary => {
             color_particles(particles, particle_render_data.fluid_colors[0])
         }
@@ -352,6 +450,41 @@ fn color_particles_by_density(
     }
 }
 
+/// Color all particles in the simulation by the color of the fluid they are made of.
+fn color_particles_by_material(
+    mut particles: Query<(&SimParticle, &mut Sprite)>,
+    constraints: &SimConstraints,
+) {
+    for (particle, mut sprite) in particles.iter_mut() {
+        sprite.color = match constraints.fluid_materials.get(particle.material) {
+            Some(material) => material.color.into(),
+            None => JUICE_BLUE,
+        };
+    }
+}
+
+/** Color all particles in the simulation by their temperature, from blue at the cooler temperature
+to red at the heater temperature. */
+fn color_particles_by_temperature(
+    mut particles: Query<(&SimParticle, &mut Sprite)>,
+    constraints: &SimConstraints,
+) {
+    let temperature_range: f32 = constraints.heater_temperature - constraints.cooler_temperature;
+    for (particle, mut sprite) in particles.iter_mut() {
+        let warmth: f32 =
+            (particle.temperature - constraints.cooler_temperature) / temperature_range;
+        sprite.color = util::generate_color_from_gradient(
+            &vec![
+                JUICE_BLUE,
+                util::JUICE_SKY_BLUE,
+                util::JUICE_YELLOW,
+                util::JUICE_RED,
+            ],
+            warmth,
+        );
+    }
+}
+
 /// Color all particles in the simulation as anything you want!
 fn color_particles(mut particles: Query<(&SimParticle, &mut Sprite)>, color: Color) {
     for (_, mut sprite) in particles.iter_mut() {
@@ -385,7 +518,7 @@ fn draw_grid_solids(grid: Res<SimGrid>, grid_render_data: Res<GridRenderData>, m
     for row in 0..grid.dimensions.0 {
         for col in 0..grid.dimensions.1 {
             // Uncomment to visualize all non-air cells within the simulation!
-            // if grid.cell_type[row as usize][col as usize] != SimGridCellType::Air {
+            // if grid.cell_type[(row as usize, col as usize)] != SimGridCellType::Air {
             // 	draw_solid_cell(		// Draw something if solid.
             // 		grid.as_ref(),
             // 		Vec2 { x: row as f32, y: col as f32 },
@@ -403,17 +536,21 @@ fn draw_grid_solids(grid: Res<SimGrid>, grid_render_data: Res<GridRenderData>, m
             // 	&mut gizmos
             // );
 
-            match grid.cell_type[row as usize][col as usize] {
+            match grid.cell_type[(row as usize, col as usize)] {
                 SimGridCellType::Fluid => continue, // Do nothing if fluid.
                 SimGridCellType::Air => continue,   // Do nothing if air.
                 SimGridCellType::Solid => draw_solid_cell(
-                    // Draw something if solid.
+                    // Draw something if solid, colored by whether it heats or cools.
                     grid.as_ref(),
                     Vec2 {
                         x: row as f32,
                         y: col as f32,
                     },
-                    grid_render_data.solid_cell_color,
+                    match grid.get_heat_source(row as usize, col as usize) {
+                        SimHeatSource::None => grid_render_data.solid_cell_color,
+                        SimHeatSource::Heater => util::JUICE_RED,
+                        SimHeatSource::Cooler => util::JUICE_SKY_BLUE,
+                    },
                     &mut gizmos,
                 ),
             }
@@ -435,6 +572,74 @@ fn draw_solid_cell(grid: &SimGrid, cell_coordinates: Vec2, color: Color, gizmos:
     gizmos.rect_2d(position, 0.0, Vec2::splat(grid.cell_size as f32), color);
 }
 
+/// Outline the solid geometry that cuts through grid cells.
+fn draw_sub_cell_solids(
+    grid: Res<SimGrid>,
+    grid_render_data: Res<GridRenderData>,
+    mut gizmos: Gizmos,
+) {
+    let color: Color = grid_render_data.solid_cell_color;
+    for solid in grid.solids.iter() {
+        match solid {
+            SimSolid::Circle(center, radius) => {
+                gizmos.circle_2d(*center, *radius, color);
+            }
+            SimSolid::Segment(start, end, half_thickness) => {
+                let side: Vec2 = (*end - *start).perp().normalize_or_zero() * *half_thickness;
+                gizmos.line_2d(*start + side, *end + side, color);
+                gizmos.line_2d(*start - side, *end - side, color);
+                gizmos.circle_2d(*start, *half_thickness, color);
+                gizmos.circle_2d(*end, *half_thickness, color);
+            }
+            SimSolid::Polygon(corners) => {
+                gizmos.linestrip_2d(corners.iter().chain(corners.first()).copied(), color);
+            }
+        }
+    }
+}
+
+/** Outline each obstacle's and rigid body's true shape on top of the cells it has made solid. */
+fn draw_obstacles(
+    obstacles: Query<&SimObstacle>,
+    rigid_bodies: Query<&SimRigidBody>,
+    grid_render_data: Res<GridRenderData>,
+    mut gizmos: Gizmos,
+) {
+    for obstacle in obstacles.iter() {
+        draw_shape(
+            &mut gizmos,
+            obstacle.shape,
+            obstacle.position,
+            obstacle.rotation,
+            grid_render_data.obstacle_color,
+        );
+    }
+    for rigid_body in rigid_bodies.iter() {
+        draw_shape(
+            &mut gizmos,
+            rigid_body.shape,
+            rigid_body.position,
+            rigid_body.rotation,
+            grid_render_data.rigid_body_color,
+        );
+    }
+}
+
+/// Outline a shape, with a line from its center so that spinning is visible.
+fn draw_shape(gizmos: &mut Gizmos, shape: SimShape, position: Vec2, rotation: f32, color: Color) {
+    let heading: Vec2 = Vec2::from_angle(rotation);
+    match shape {
+        SimShape::Circle(radius) => {
+            gizmos.circle_2d(position, radius, color);
+            gizmos.line_2d(position, position + heading * radius, color);
+        }
+        SimShape::Rectangle(half_size) => {
+            gizmos.rect_2d(position, rotation, half_size * 2.0, color);
+            gizmos.line_2d(position, position + heading * half_size.x, color);
+        }
+    }
+}
+
 /// Draw grid cells based on SimGrid using Bevy's Gizmos!
 fn draw_grid_cells(grid: Res<SimGrid>, grid_render_data: Res<GridRenderData>, mut gizmos: Gizmos) {
     let grid_width: f32 = (grid.dimensions.1 * grid.cell_size) as f32;
@@ -525,13 +730,13 @@ fn draw_grid_vectors(
 
             // Horizontal velocity components.
             let velocities_u: [f32; 2] = [
-                grid.velocity_u[row_u][column_u0],
-                grid.velocity_u[row_u][column_u1],
+                grid.velocity_u[(row_u, column_u0)],
+                grid.velocity_u[(row_u, column_u1)],
             ];
             // Vertical velocity components.
             let velocities_v: [f32; 2] = [
-                grid.velocity_v[row_v0][column_v],
-                grid.velocity_v[row_v1][column_v],
+                grid.velocity_v[(row_v0, column_v)],
+                grid.velocity_v[(row_v1, column_v)],
             ];
 
             // Calculate magnitude of velocity within the call.
@@ -0,0 +1,20 @@
+/* JuiceBox's simulation library.  Everything needed to build and step a fluid simulation lives
+here; the windowed app (UI, renderer, and file dialogs) is only compiled in with the "gui"
+feature.  See simulation::sim_runner::SimRunner for driving the solver without a window. */
+pub mod cli;
+pub mod error;
+pub mod file_system;
+pub mod simulation;
+pub mod util;
+
+#[cfg(feature = "gui")]
+pub mod events;
+#[cfg(feature = "gui")]
+pub mod juice_renderer;
+#[cfg(feature = "gui")]
+pub mod ui;
+
+// The tests spell boolean checks as assert_eq!(true, ...).
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
+mod test;
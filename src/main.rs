@@ -1,17 +1,42 @@
+#[cfg(feature = "gui")]
 use bevy::prelude::*;
+#[cfg(feature = "gui")]
 use bevy_egui::EguiPlugin;
+#[cfg(feature = "gui")]
 use bevy_save::SavePlugin;
-pub mod error;
-pub mod file_system;
-pub mod juice_renderer;
-pub mod simulation;
-pub mod util;
-
-pub mod events;
-pub mod test;
-pub mod ui;
+use juice_box::cli;
+#[cfg(feature = "gui")]
+use juice_box::{file_system, juice_renderer, simulation, ui, util};
 
 fn main() {
+    // `juice_box run <scene.juice> --steps N --out <final.juice>` runs a scene without a window.
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("run") {
+        let result = cli::parse_run_args(&args[2..]).and_then(|run_args| cli::run_scene(&run_args));
+        if let Err(e) = result {
+            eprintln!("{}\n{}", e, cli::USAGE);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Without the "gui" feature there is no window to open; batch mode is all this build can do.
+    #[cfg(not(feature = "gui"))]
+    {
+        eprintln!(
+            "juice_box was built without the \"gui\" feature.\n{}",
+            cli::USAGE
+        );
+        std::process::exit(1);
+    }
+
+    #[cfg(feature = "gui")]
+    run_app();
+}
+
+/// Open the JuiceBox window.
+#[cfg(feature = "gui")]
+fn run_app() {
     let mut juicebox: App = App::new();
 
     juicebox.add_systems(Startup, util::set_window_icon);
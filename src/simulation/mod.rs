@@ -1,17 +1,24 @@
 pub mod sim_physics_engine;
+pub mod sim_runner;
 pub mod sim_state_manager;
 pub mod util;
 
 use bevy::prelude::*;
 //use bevy::prelude::init_state;
 use self::sim_state_manager::{
-    activate_components, add_drain, add_faucet, add_particles_in_radius, delete_all_drains,
-    delete_all_faucets, delete_all_particles, delete_drain, delete_faucet, delete_particle,
-    delete_particles_in_radius, select_particles,
+    activate_components, add_particles_in_radius, delete_all_drains, delete_all_faucets,
+    delete_all_obstacles, delete_all_particles, delete_all_rigid_bodies, delete_particle,
+    delete_particles_in_radius, feed_inflow_edges, reseed_particles,
+};
+#[cfg(feature = "gui")]
+use self::sim_state_manager::{
+    add_drain, add_faucet, add_smoke_in_radius, construct_new_simulation, delete_drain,
+    delete_faucet, delete_smoke_in_radius, select_particles,
 };
 use crate::error::Error;
+#[cfg(feature = "gui")]
 use crate::events::{ClearEvent, PlayPauseStepEvent, ResetEvent, UseToolEvent};
-use crate::test::test_state_manager::construct_new_simulation;
+#[cfg(feature = "gui")]
 use crate::ui::{SimTool, UIStateManager};
 use crate::util::{cartesian_to_polar, degrees_to_radians, polar_to_cartesian};
 use bevy::math::Vec2;
@@ -19,7 +26,16 @@ use sim_physics_engine::*;
 
 pub type Result<T> = core::result::Result<T, Error>;
 
+/// Default ambient temperature of the simulation, and of any fluid added to it.
+pub const ROOM_TEMPERATURE: f32 = 20.0;
+
+/// Thickest that a non-Newtonian fluid can get when it is barely being sheared, in units^2 / second.
+pub const MAX_EFFECTIVE_VISCOSITY: f32 = 5000.0;
+
+/// Bevy plugin for the windowed app; headless users should use sim_runner::SimRunner instead.
+#[cfg(feature = "gui")]
 pub struct Simulation;
+#[cfg(feature = "gui")]
 impl Plugin for Simulation {
     fn build(&self, app: &mut App) {
         app.insert_resource(SimConstraints::default());
@@ -31,6 +47,7 @@ impl Plugin for Simulation {
 }
 
 /// Simulation state manager initialization.
+#[cfg(feature = "gui")]
 fn setup(mut ev_reset: EventWriter<ResetEvent>) {
     // construct_test_simulation_layout(constraints.as_mut(), grid.as_mut(), &mut commands, &asset_server);
     // construct_simulation_bias_test(constraints.as_mut(), grid.as_mut(), &mut commands, &asset_server);
@@ -39,12 +56,15 @@ fn setup(mut ev_reset: EventWriter<ResetEvent>) {
 }
 
 /// Simulation state manager update; handles user interactions with the simulation.
+#[cfg(feature = "gui")]
 fn update(
     mut constraints: ResMut<SimConstraints>,
     mut grid: ResMut<SimGrid>,
     mut particles: Query<(Entity, &mut SimParticle)>,
     faucets: Query<(Entity, &mut SimFaucet)>,
     drains: Query<(Entity, &mut SimDrain)>,
+    mut obstacles: Query<(Entity, &mut SimObstacle)>,
+    mut rigid_bodies: Query<(Entity, &mut SimRigidBody)>,
 
     mut commands: Commands,
     ui_state: Res<UIStateManager>,
@@ -57,29 +77,28 @@ fn update(
     this does mean that a lower framerate slows everything down, but it does prevent the
     whole thing from blowing up spectacularly.  For a dynamic timestep using the same scale of
     milliseconds, you would use the following code:
-    let dynamic_timestep: f32 = time.delta().as_millis() as f32 * 0.001; */
+    let dynamic_timestep: f32 = time.delta().as_millis() as f32 * 0.001;
+    With adaptive_timestep on, the frame is still fixed, but gets split into substeps. */
     let fixed_timestep: f32 = constraints.timestep;
 
     // If the simulation is not paused, run the simulation!
     if !constraints.is_paused {
-        step_simulation_once(
+        step_simulation_frame(
             &mut commands,
             constraints.as_mut(),
             grid.as_mut(),
             &mut particles,
             &faucets,
             &drains,
+            &mut obstacles,
+            &mut rigid_bodies,
             fixed_timestep,
         );
     }
 
-    /* Handle all simulation events received through our EventReader<> objects.  IMPORTANT: This
-    *must* happen after we step through the simulation.  If we handle events first, then in the
-    case of a reset event, Bevy will not go through its despawn() schedule in time.  The
-    simulation will then incorrectly label cells as fluid BEFORE the command to despawn the
-    particles has executed.  Because the particles will be despawned before the next update
-    schedule runs, there will never be a change in lookup index for these "ghost" particles, so
-    they will not be removed from the simulation until the next reset event. */
+    /* Handle all simulation events received through our EventReader<> objects.  Particles that
+    events despawn are gone by the next step, and the spatial lookup is rebuilt from the particles
+    that are left at the start of every step, so they never linger as "ghost" particles. */
     handle_events(
         ev_reset,
         ev_clear,
@@ -91,12 +110,15 @@ fn update(
         &mut particles,
         &faucets,
         &drains,
+        &mut obstacles,
+        &mut rigid_bodies,
         &ui_state,
         fixed_timestep,
     );
 }
 
 /// Handles incoming events from the UI
+#[cfg(feature = "gui")]
 fn handle_events(
     mut ev_reset: EventReader<ResetEvent>,
     mut ev_clear: EventReader<ClearEvent>,
@@ -108,12 +130,23 @@ fn handle_events(
     particles: &mut Query<(Entity, &mut SimParticle)>,
     faucets: &Query<(Entity, &mut SimFaucet)>,
     drains: &Query<(Entity, &mut SimDrain)>,
+    obstacles: &mut Query<(Entity, &mut SimObstacle)>,
+    rigid_bodies: &mut Query<(Entity, &mut SimRigidBody)>,
     ui_state: &UIStateManager,
     timestep: f32,
 ) {
     // If there is a reset event sent, we reset the simulation.
     for _ in ev_reset.read() {
-        reset_simulation_to_default(&mut commands, constraints, grid, particles, faucets, drains);
+        reset_simulation_to_default(
+            commands,
+            constraints,
+            grid,
+            particles,
+            faucets,
+            drains,
+            obstacles,
+            rigid_bodies,
+        );
         construct_new_simulation(constraints, grid, &mut commands);
         return;
     }
@@ -122,6 +155,8 @@ fn handle_events(
         delete_all_particles(commands, constraints, grid, particles);
         delete_all_drains(commands, drains);
         delete_all_faucets(commands, faucets);
+        delete_all_obstacles(commands, obstacles);
+        delete_all_rigid_bodies(commands, rigid_bodies);
         return;
     }
 
@@ -144,13 +179,15 @@ fn handle_events(
             if !constraints.is_paused {
                 constraints.is_paused = true;
             }
-            step_simulation_once(
+            step_simulation_frame(
                 commands,
                 constraints,
                 grid,
                 particles,
                 faucets,
                 drains,
+                obstacles,
+                rigid_bodies,
                 timestep,
             );
         }
@@ -222,6 +259,19 @@ fn handle_events(
                 }
             }
             SimTool::AddFluid => {
+                // Smoke scenes get a puff of hot smoke instead of particles.
+                if constraints.fluid_mode == SimFluidMode::Smoke {
+                    add_smoke_in_radius(
+                        constraints,
+                        grid,
+                        ui_state.add_fluid_density,
+                        ui_state.add_remove_fluid_radius,
+                        tool_use.pos,
+                    )
+                    .ok();
+                    continue;
+                }
+
                 // Add particles with the given slider info from the UI.
                 add_particles_in_radius(
                     &mut commands,
@@ -231,6 +281,7 @@ fn handle_events(
                     ui_state.add_remove_fluid_radius,
                     tool_use.pos,
                     Vec2::ZERO,
+                    ui_state.fluid_material,
                 );
             }
             SimTool::RemoveFluid => {
@@ -239,9 +290,21 @@ fn handle_events(
                     continue;
                 }
 
+                // Smoke scenes have their smoke cleared away instead.
+                if constraints.fluid_mode == SimFluidMode::Smoke {
+                    delete_smoke_in_radius(
+                        constraints,
+                        grid,
+                        tool_use.pos,
+                        ui_state.add_remove_fluid_radius,
+                    );
+                    continue;
+                }
+
                 // Remove particles with the given slider info from the UI.
                 delete_particles_in_radius(
                     &mut commands,
+                    constraints,
                     grid,
                     particles,
                     tool_use.pos,
@@ -259,12 +322,17 @@ fn handle_events(
 
                 // For each selected cell, change it to solid and delete all particles inside of it.
                 for i in 0..grid_cells.len() {
-                    // Change cell to solid.
+                    // Change cell to solid, heating or cooling its surroundings if asked to.
                     let _ = grid.set_grid_cell_type(
                         grid_cells[i].x as usize,
                         grid_cells[i].y as usize,
                         SimGridCellType::Solid,
                     );
+                    let _ = grid.set_heat_source(
+                        grid_cells[i].x as usize,
+                        grid_cells[i].y as usize,
+                        ui_state.wall_heat_source,
+                    );
 
                     // Delete particles inside of this cell.
                     let lookup_index: usize = grid.get_lookup_index(grid_cells[i]);
@@ -287,6 +355,11 @@ fn handle_events(
                         grid_cells[i].y as usize,
                         SimGridCellType::Air,
                     );
+                    let _ = grid.set_heat_source(
+                        grid_cells[i].x as usize,
+                        grid_cells[i].y as usize,
+                        SimHeatSource::None,
+                    );
                 }
             }
             SimTool::AddDrain => {
@@ -346,6 +419,7 @@ fn handle_events(
                     None,
                     ui_state.faucet_radius,
                     faucet_direciton,
+                    ui_state.fluid_material,
                 )
                 .ok();
             }
@@ -374,8 +448,8 @@ pub fn change_gravity(
 ) {
     // Convert existing gravity to polar coordinates.
     let mut polar_gravity: Vec2 = cartesian_to_polar(constraints.gravity);
-    polar_gravity.x += 200.0 * magnitude_change as f32 * constraints.timestep;
-    polar_gravity.y += 4.0 * direction_change as f32 * constraints.timestep;
+    polar_gravity.x += 200.0 * magnitude_change * constraints.timestep;
+    polar_gravity.y += 4.0 * direction_change * constraints.timestep;
 
     /* Limit the magnitude of the vector to prevent ugly behavior near 0.0.  ADDITIONALLY: I (Kade)
     found a bug where if a polar vector has magnitude 0 the direction will automatically become
@@ -384,43 +458,188 @@ pub fn change_gravity(
     constraints.gravity = polar_to_cartesian(polar_gravity);
 }
 
-/// Step the fluid simulation one time!
-pub fn step_simulation_once(
+/** Step the fluid simulation forward by one frame of `timestep`.  With adaptive_timestep on, the
+frame is split into as many equal substeps as the CFL condition asks for (see
+cfl_substep_count()); otherwise this is a single step_simulation_once().  Drains, faucets, and
+inflow edges run once at the end of the frame. */
+#[allow(clippy::too_many_arguments)]
+pub fn step_simulation_frame(
     commands: &mut Commands,
     constraints: &mut SimConstraints,
     grid: &mut SimGrid,
     particles: &mut Query<(Entity, &mut SimParticle)>,
     faucets: &Query<(Entity, &mut SimFaucet)>,
     drains: &Query<(Entity, &mut SimDrain)>,
+    obstacles: &mut Query<(Entity, &mut SimObstacle)>,
+    rigid_bodies: &mut Query<(Entity, &mut SimRigidBody)>,
     timestep: f32,
 ) {
+    let substeps: usize = if constraints.adaptive_timestep {
+        cfl_substep_count(constraints, grid, particles, timestep)
+    } else {
+        1
+    };
+    constraints.substeps = substeps;
+
+    let substep: f32 = timestep / substeps as f32;
+    for _ in 0..substeps {
+        step_simulation_once(
+            commands,
+            constraints,
+            grid,
+            particles,
+            obstacles,
+            rigid_bodies,
+            substep,
+        );
+    }
+
+    /* Reseeding, drains, faucets, and inflow edges add and remove whole particles, so they run
+    once per frame no matter how many substeps it took; smoke has no particles for them to work
+    on.  Reseeding goes first, while the grid still matches the particles from the last substep. */
+    if constraints.fluid_mode != SimFluidMode::Smoke {
+        reseed_particles(commands, constraints, grid, particles);
+        activate_components(commands, constraints, particles, faucets, drains, grid).ok();
+        feed_inflow_edges(commands, constraints, grid);
+    }
+}
+
+/** Number of substeps needed to advance `timestep` without any particle or grid velocity moving
+more than `cfl_number` cells per substep, or surface tension ripples outrunning a substep, capped
+at `max_substeps`. */
+pub fn cfl_substep_count(
+    constraints: &SimConstraints,
+    grid: &SimGrid,
+    particles: &Query<(Entity, &mut SimParticle)>,
+    timestep: f32,
+) -> usize {
+    let max_substeps: usize = usize::max(1, constraints.max_substeps as usize);
+    let max_distance: f32 = constraints.cfl_number * grid.cell_size as f32;
+    let distance: f32 = find_max_velocity(grid, particles) * timestep;
+
+    // Runaway velocities would ask for infinitely many substeps; use as many as we're allowed.
+    if !distance.is_finite() || max_distance <= 0.0 {
+        return max_substeps;
+    }
+
+    /* Surface tension's ripples get faster the shorter they are, so the shortest ones the grid can
+    hold limit each substep to sqrt(density * cell_size^3 / (2 * PI * surface_tension)), using the
+    lightest fluid, or water, since it ripples fastest. */
+    let mut substeps: f32 = distance / max_distance;
+    if constraints.surface_tension > 0.0 {
+        let density: f32 = constraints
+            .fluid_materials
+            .iter()
+            .map(|material| material.density)
+            .fold(1.0, f32::min);
+        let cell_size: f32 = grid.cell_size as f32;
+        let max_substep: f32 = f32::sqrt(
+            density * cell_size.powi(3) / (std::f32::consts::TAU * constraints.surface_tension),
+        );
+        substeps = f32::max(substeps, timestep / max_substep);
+    }
+
+    if !substeps.is_finite() {
+        return max_substeps;
+    }
+    (f32::ceil(substeps) as usize).clamp(1, max_substeps)
+}
+
+/** Step the fluid simulation one time!  Drains, faucets, and inflow edges only run once per frame,
+in step_simulation_frame(). */
+pub fn step_simulation_once(
+    commands: &mut Commands,
+    constraints: &mut SimConstraints,
+    grid: &mut SimGrid,
+    particles: &mut Query<(Entity, &mut SimParticle)>,
+    obstacles: &mut Query<(Entity, &mut SimObstacle)>,
+    rigid_bodies: &mut Query<(Entity, &mut SimRigidBody)>,
+    timestep: f32,
+) {
+    /* Move obstacles and rigid bodies, then carve them out of the grid so every stage below sees
+    them as solids.  Rigid bodies act just like obstacles until the fluid pushes back on them. */
+    move_obstacles(obstacles, timestep);
+    move_rigid_bodies(constraints, grid, rigid_bodies, timestep);
+    let obstacles: Vec<SimObstacle> = obstacles
+        .iter()
+        .map(|(_, obstacle)| obstacle.clone())
+        .chain(rigid_bodies.iter().map(|(_, body)| body.as_obstacle()))
+        .collect();
+    rasterize_obstacles(grid, &obstacles);
+
+    // Smoke lives entirely on the grid, so it skips every particle stage below.
+    if constraints.fluid_mode == SimFluidMode::Smoke {
+        step_smoke_once(constraints, grid, &obstacles, timestep);
+        apply_fluid_forces(grid, rigid_bodies, timestep);
+        return;
+    }
+
     /* Integrate particles, update their lookup indices, update grid density values, and process
     collisions. */
-    update_particles(constraints, particles, grid, timestep);
-    push_particles_apart(constraints, grid, particles);
+    update_particles(constraints, particles, grid, &obstacles, timestep);
+    push_particles_apart(constraints, grid, particles, &obstacles);
     handle_particle_grid_collisions(constraints, grid, particles);
 
+    /* Pushing particles apart, collisions, and wrapping around periodic edges all move particles
+    after update_particles() sorted them, so sort them again before the cells are labeled; escaped
+    particles are taken back out of the lookup as they're deleted. */
+    update_particle_lookup(grid, particles);
+
+    // Particles that flowed out through an open edge of the grid are gone for good.
+    let escaped_particles: Vec<Entity> = particles
+        .iter()
+        .filter(|(_, particle)| !grid.is_position_within_grid(&particle.position))
+        .map(|(particle_id, _)| particle_id)
+        .collect();
+    for particle_id in escaped_particles {
+        let _ = delete_particle(commands, constraints, particles, grid, particle_id);
+    }
+
     /* Label grid cells, transfer particle velocities to the grid, project/diffuse/advect them,
     then transfer velocities back.  Finally, extrapolate velocities to smooth out the
     fluid-air boundary. */
     grid.label_cells();
-    particles_to_grid(grid, particles);
+    update_fluid_properties(constraints, grid, particles);
+    particles_to_grid(grid, particles, constraints);
     extrapolate_values(grid, 1);
 
     // Store a copy of the grid from the previous simulation step for "change grid" creation.
     let old_grid = grid.clone();
 
-    /* Make fluid incompressible, find the difference in grid from before incompressibility,
-    interpolate grid velocities back to each particle, and finally extrapolate velocity values
-    one final time! */
-    make_grid_velocities_incompressible(grid, constraints);
-    let change_grid = create_change_grid(&old_grid, &grid);
-    grid_to_particles(grid, &change_grid, particles, constraints);
+    /* Diffuse velocities by the fluid's viscosity, pull its surface together, strengthen its
+    swirls, make fluid incompressible, find the difference in grid from before these forces,
+    interpolate grid velocities back to each particle, and finally extrapolate velocity values one
+    final time! */
+    apply_viscosity(grid, constraints, timestep);
+    apply_surface_tension(grid, constraints, timestep);
+    apply_vorticity_confinement(grid, constraints, timestep);
+
+    // Spread heat through the fluid, and let warm fluid rise and cool fluid sink.
+    diffuse_temperature(grid, constraints, timestep);
+    apply_buoyancy(grid, constraints, timestep);
+
+    /* Open edges of the grid let fluid in and out, and moving obstacles push fluid out of their way
+    and drag it along with them. */
+    apply_boundary_velocities(grid);
+    apply_obstacle_velocities(grid, &obstacles);
+
+    /* Friction needs the pressure, so sand rubs after the fluid is made incompressible; slowing
+    some faces down and not others squeezes the fluid though, so project it once more afterwards.
+    The second pressure only corrects the first, so add them up for the rigid bodies. */
+    make_grid_velocities_incompressible(grid, constraints, timestep);
+    if apply_granular_friction(grid, constraints, timestep) {
+        let pressure: SimGridArray<f32> = grid.cell_center.clone();
+        make_grid_velocities_incompressible(grid, constraints, timestep);
+        for (total, first) in grid.cell_center.iter_mut().zip(pressure.iter()) {
+            *total += first;
+        }
+    }
+    apply_fluid_forces(grid, rigid_bodies, timestep);
+    let change_grid = create_change_grid(&old_grid, grid);
+    grid_to_particles(grid, &change_grid, particles, constraints, timestep);
+    temperatures_to_particles(&old_grid, grid, particles);
     extrapolate_values(grid, 1);
 
-    // Run drains and faucets, panics if something weird/bad happens
-    activate_components(commands, constraints, particles, faucets, drains, grid).ok();
-
     // If a particle freaks out, get rid of it!
     for particle in particles.iter() {
         if particle.1.position.x.is_nan() || particle.1.position.y.is_nan() {
@@ -429,7 +648,43 @@ pub fn step_simulation_once(
     }
 }
 
+/** Step a smoke simulation once.  The grid itself carries the smoke's density and temperature, so
+every open cell is fluid: carry everything along with the flow, apply forces, and make the flow
+incompressible.  Obstacles must already be carved out of the grid (see rasterize_obstacles()). */
+pub fn step_smoke_once(
+    constraints: &mut SimConstraints,
+    grid: &mut SimGrid,
+    obstacles: &[SimObstacle],
+    timestep: f32,
+) {
+    for cell_type in grid.cell_type.iter_mut() {
+        if *cell_type != SimGridCellType::Solid {
+            *cell_type = SimGridCellType::Fluid;
+        }
+    }
+
+    // Smoke is one plain material; clear out whatever a liquid left in these.
+    grid.fluid_density.fill(1.0);
+    grid.fluid_viscosity.fill(0.0);
+    grid.fluid_flow_index.fill(1.0);
+    grid.fluid_yield_stress.fill(0.0);
+
+    close_solid_faces(grid);
+    advect_smoke(grid, timestep);
+    close_solid_faces(grid);
+
+    diffuse_temperature(grid, constraints, timestep);
+    apply_buoyancy(grid, constraints, timestep);
+    apply_viscosity(grid, constraints, timestep);
+    apply_vorticity_confinement(grid, constraints, timestep);
+    close_solid_faces(grid);
+    apply_obstacle_velocities(grid, obstacles);
+
+    make_grid_velocities_incompressible(grid, constraints, timestep);
+}
+
 /// Reset simulation components to their default state and delete all particles.
+#[allow(clippy::too_many_arguments)]
 pub fn reset_simulation_to_default(
     commands: &mut Commands,
     constraints: &mut SimConstraints,
@@ -437,53 +692,93 @@ pub fn reset_simulation_to_default(
     particles: &Query<(Entity, &mut SimParticle)>,
     faucets: &Query<(Entity, &mut SimFaucet)>,
     drains: &Query<(Entity, &mut SimDrain)>,
+    obstacles: &Query<(Entity, &mut SimObstacle)>,
+    rigid_bodies: &Query<(Entity, &mut SimRigidBody)>,
 ) {
     println!("Resetting simulation to default...");
 
-    // Reset all particles, faucets, and drains!
+    // Reset all particles, faucets, drains, obstacles, and rigid bodies!
     delete_all_particles(commands, constraints, grid, particles);
     delete_all_faucets(commands, faucets);
     delete_all_drains(commands, drains);
+    delete_all_obstacles(commands, obstacles);
+    delete_all_rigid_bodies(commands, rigid_bodies);
 
-    // Reset the grid by creating a new default grid and copying its values.
-    let reset_grid: SimGrid = SimGrid::default();
-    let row_count: usize = reset_grid.dimensions.0 as usize;
-    let col_count: usize = reset_grid.dimensions.1 as usize;
-    grid.dimensions = reset_grid.dimensions;
-    grid.cell_size = reset_grid.cell_size;
-    grid.cell_type = vec![vec![SimGridCellType::Air; col_count]; row_count];
-    grid.cell_center = vec![vec![0.0; col_count]; row_count];
-    grid.velocity_u = vec![vec![f32::MIN; col_count + 1]; row_count];
-    grid.velocity_v = vec![vec![f32::MIN; col_count]; row_count + 1];
-    grid.spatial_lookup = vec![vec![Entity::PLACEHOLDER; 0]; row_count * col_count];
-    grid.density = vec![0.0; row_count * col_count];
+    // Reset the grid by replacing it with a new default grid.
+    *grid = SimGrid::default();
 
     // Reset constraints by creating a default constraints and copying its values.
     let reset_constraints: SimConstraints = SimConstraints::default();
+    constraints.fluid_mode = reset_constraints.fluid_mode;
     constraints.grid_particle_ratio = reset_constraints.grid_particle_ratio;
+    constraints.viscosity = reset_constraints.viscosity;
+    constraints.surface_tension = reset_constraints.surface_tension;
+    constraints.vorticity_confinement = reset_constraints.vorticity_confinement;
+    constraints.ambient_temperature = reset_constraints.ambient_temperature;
+    constraints.heater_temperature = reset_constraints.heater_temperature;
+    constraints.cooler_temperature = reset_constraints.cooler_temperature;
+    constraints.thermal_diffusivity = reset_constraints.thermal_diffusivity;
+    constraints.thermal_expansion = reset_constraints.thermal_expansion;
+    constraints.transfer_scheme = reset_constraints.transfer_scheme;
     constraints.timestep = reset_constraints.timestep;
     constraints.incomp_iters_per_frame = reset_constraints.incomp_iters_per_frame;
+    constraints.pressure_tolerance = reset_constraints.pressure_tolerance;
+    constraints.pressure_residual = reset_constraints.pressure_residual;
+    constraints.pressure_iterations = reset_constraints.pressure_iterations;
     constraints.collision_iters_per_frame = reset_constraints.collision_iters_per_frame;
+    constraints.deterministic = reset_constraints.deterministic;
+    constraints.adaptive_timestep = reset_constraints.adaptive_timestep;
+    constraints.cfl_number = reset_constraints.cfl_number;
+    constraints.max_substeps = reset_constraints.max_substeps;
+    constraints.substeps = reset_constraints.substeps;
     constraints.gravity = reset_constraints.gravity;
     constraints.particle_radius = reset_constraints.particle_radius;
     constraints.particle_count = reset_constraints.particle_count;
     constraints.particle_rest_density = reset_constraints.particle_rest_density;
+    constraints.min_particles_per_cell = reset_constraints.min_particles_per_cell;
+    constraints.max_particles_per_cell = reset_constraints.max_particles_per_cell;
+    constraints.fluid_materials = reset_constraints.fluid_materials;
 }
 
 #[derive(Resource, Reflect, Clone)]
 #[reflect(Resource)]
 pub struct SimConstraints {
-    pub is_paused: bool, // Is the simulation currently paused?
-    pub timestep: f32,   // Timestep for simulation updates.
-    pub gravity: Vec2,   // Cartesian gravity vector.
+    pub is_paused: bool,          // Is the simulation currently paused?
+    pub timestep: f32,            // Timestep for simulation updates.
+    pub gravity: Vec2,            // Cartesian gravity vector.
+    pub fluid_mode: SimFluidMode, // Simulate a liquid made of particles, or smoke on the grid?
 
     pub grid_particle_ratio: f32, // PIC/FLIP simulation ratio (0.0 = FLIP, 1.0 = PIC).
-    pub incomp_iters_per_frame: u8, // Simulation incompressibility iterations per frame.
+    pub viscosity: f32, // Kinematic viscosity of all fluid, on top of each material's own.
+    pub surface_tension: f32, // Surface tension coefficient at the fluid/air boundary.
+    pub vorticity_confinement: f32, // Strength of the force that keeps swirls going (0.0 = off).
+    pub transfer_scheme: SimTransferScheme, // Particle <-> grid velocity transfer method.
+    pub incomp_iters_per_frame: u8, // Max. pressure solver iterations per frame.
+    pub pressure_tolerance: f32, // Pressure solver stops at this residual, relative to divergence.
+    pub pressure_residual: f32, // Largest divergence left over after the last pressure solve.
+    pub pressure_iterations: usize, // Iterations the last pressure solve took.
     pub collision_iters_per_frame: u8, // Collision iterations per frame.
+    pub deterministic: bool, // Give bit-identical results no matter how many threads are used?
+
+    pub adaptive_timestep: bool, // Split each frame into substeps that satisfy the CFL condition?
+    pub cfl_number: f32,         // Max. cells anything may travel in one substep.
+    pub max_substeps: u8,        // Upper limit on substeps per frame.
+    pub substeps: usize,         // Substeps taken in the last frame.
 
-    pub particle_radius: f32,       // Particle collision radii.
-    pub particle_count: usize,      // Number of particles in the simulation.
-    pub particle_rest_density: f32, // Rest density of particles in simulation.
+    pub ambient_temperature: f32, // Temperature at which fluid is neither buoyant nor heavy.
+    pub heater_temperature: f32,  // Temperature heater cells hold the fluid around them at.
+    pub cooler_temperature: f32,  // Temperature cooler cells hold the fluid around them at.
+    pub thermal_diffusivity: f32, // How quickly heat spreads through fluid, in units^2 / second.
+    pub thermal_expansion: f32,   // Buoyancy per degree away from the ambient temperature.
+
+    pub particle_radius: f32,          // Particle collision radii.
+    pub particle_count: usize,         // Number of particles in the simulation.
+    pub particle_rest_density: f32,    // Rest density of particles in simulation.
+    pub min_particles_per_cell: usize, // Particles given to each empty cell inside the fluid (at most 5, 0 = never).
+    pub max_particles_per_cell: usize, // Crowded cells are thinned out to this many (0 = never).
+
+    // Every kind of fluid particles can be made of; SimParticle::material indexes into this.
+    pub fluid_materials: Vec<SimFluidMaterial>,
 
     // A list of currently selected particles along with their position offsets from the mouse cursor!
     pub selected_particles: Vec<(Entity, Vec2)>,
@@ -496,14 +791,38 @@ impl Default for SimConstraints {
             timestep: 1.0 / 120.0,
             // (9.81 * 2) ^ 2 = ~385 (Bevy caps FPS at 60, we run sim at 120).
             gravity: Vec2 { x: 0.0, y: -385.0 },
+            fluid_mode: SimFluidMode::Liquid,
 
             grid_particle_ratio: 0.3, // 0.0 = inviscid (FLIP), 1.0 = viscous (PIC).
+            viscosity: 0.0,
+            surface_tension: 0.0,
+            vorticity_confinement: 0.0,
+            transfer_scheme: SimTransferScheme::PicFlip,
             incomp_iters_per_frame: 100,
+            pressure_tolerance: 0.0001,
+            pressure_residual: 0.0,
+            pressure_iterations: 0,
             collision_iters_per_frame: 2,
+            deterministic: false,
+
+            adaptive_timestep: false,
+            cfl_number: 1.0,
+            max_substeps: 8,
+            substeps: 0,
+
+            ambient_temperature: ROOM_TEMPERATURE,
+            heater_temperature: 100.0,
+            cooler_temperature: 0.0,
+            thermal_diffusivity: 20.0,
+            thermal_expansion: 0.005,
 
             particle_radius: 2.0,
             particle_count: 0,
             particle_rest_density: 0.0,
+            min_particles_per_cell: 0,
+            max_particles_per_cell: 0,
+
+            fluid_materials: SimFluidMaterial::default_materials(),
 
             selected_particles: Vec::new(),
         }
@@ -511,6 +830,46 @@ impl Default for SimConstraints {
 }
 
 impl SimConstraints {
+    /// Density of a fluid material relative to water; unknown materials are treated as water.
+    pub fn material_density(&self, material: usize) -> f32 {
+        match self.fluid_materials.get(material) {
+            Some(fluid_material) => fluid_material.density,
+            None => 1.0,
+        }
+    }
+
+    /// Kinematic viscosity of a fluid material; unknown materials have none.
+    pub fn material_viscosity(&self, material: usize) -> f32 {
+        match self.fluid_materials.get(material) {
+            Some(fluid_material) => fluid_material.viscosity,
+            None => 0.0,
+        }
+    }
+
+    /// Power-law flow index of a fluid material; unknown materials are Newtonian, with 1.
+    pub fn material_flow_index(&self, material: usize) -> f32 {
+        match self.fluid_materials.get(material) {
+            Some(fluid_material) => fluid_material.flow_index,
+            None => 1.0,
+        }
+    }
+
+    /// Yield stress of a fluid material; unknown materials have none.
+    pub fn material_yield_stress(&self, material: usize) -> f32 {
+        match self.fluid_materials.get(material) {
+            Some(fluid_material) => fluid_material.yield_stress,
+            None => 0.0,
+        }
+    }
+
+    /// Friction between the grains of a fluid material; liquids and unknown materials have none.
+    pub fn material_friction(&self, material: usize) -> f32 {
+        match self.fluid_materials.get(material) {
+            Some(fluid_material) => fluid_material.friction,
+            None => 0.0,
+        }
+    }
+
     /// Change the gravity direction and strength constraints within the simulation.
     fn _change_gravity(sim: &mut SimConstraints, gravity: Vec2) {
         sim.gravity = gravity;
@@ -537,6 +896,131 @@ impl SimConstraints {
     }
 }
 
+/** A color, with each channel from 0.0 to 1.0.  Bevy's Color is part of its renderer, which
+headless builds leave out, so the simulation keeps its colors in this instead. */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Reflect)]
+pub struct SimColor {
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+    pub a: f32,
+}
+
+impl SimColor {
+    /// An opaque color.
+    pub const fn rgb(r: f32, g: f32, b: f32) -> Self {
+        Self { r, g, b, a: 1.0 }
+    }
+}
+
+#[cfg(feature = "gui")]
+impl From<SimColor> for Color {
+    fn from(color: SimColor) -> Color {
+        Color::rgba(color.r, color.g, color.b, color.a)
+    }
+}
+
+/// A kind of fluid (water, oil, ...) that particles can be made of.
+#[derive(Clone, Debug, PartialEq, Reflect)]
+pub struct SimFluidMaterial {
+    pub name: String,
+    pub density: f32, // Density relative to water; lighter fluids float on heavier ones.
+    pub viscosity: f32, // Kinematic viscosity (or consistency), in units^2 / second.
+    pub flow_index: f32, // Power-law exponent; below 1 thins when sheared, above 1 thickens.
+    pub yield_stress: f32, // Stress below which the fluid holds still, in units^2 / second^2.
+    pub friction: f32, // Friction between grains (tan of the angle of repose); 0 for liquids.
+    pub color: SimColor, // Color particles of this fluid are drawn with.
+}
+
+impl SimFluidMaterial {
+    pub fn new(name: &str, density: f32, viscosity: f32, color: SimColor) -> Self {
+        Self {
+            name: name.to_string(),
+            density,
+            viscosity,
+            flow_index: 1.0,
+            yield_stress: 0.0,
+            friction: 0.0,
+            color,
+        }
+    }
+
+    /** A shear-thinning (flow_index < 1) or shear-thickening (flow_index > 1) fluid, whose
+    viscosity is `consistency * strain_rate^(flow_index - 1)`. */
+    pub fn new_power_law(
+        name: &str,
+        density: f32,
+        consistency: f32,
+        flow_index: f32,
+        color: SimColor,
+    ) -> Self {
+        Self {
+            flow_index,
+            ..Self::new(name, density, consistency, color)
+        }
+    }
+
+    /** A Bingham plastic, which holds still like a soft solid until it is pushed harder than its
+    yield stress, then flows with its plastic viscosity. */
+    pub fn new_bingham(
+        name: &str,
+        density: f32,
+        plastic_viscosity: f32,
+        yield_stress: f32,
+        color: SimColor,
+    ) -> Self {
+        Self {
+            yield_stress,
+            ..Self::new(name, density, plastic_viscosity, color)
+        }
+    }
+
+    /** A granular material like sand, whose grains hold still until they are pushed hard enough
+    to slide past each other; piles of it settle at an angle of repose of atan(friction). */
+    pub fn new_granular(name: &str, density: f32, friction: f32, color: SimColor) -> Self {
+        Self {
+            friction,
+            ..Self::new(name, density, 0.0, color)
+        }
+    }
+
+    /// Is this material made of grains rather than a liquid?
+    pub fn is_granular(&self) -> bool {
+        self.friction > 0.0
+    }
+
+    /// Does this material's viscosity change with how hard it is sheared?
+    pub fn is_non_newtonian(&self) -> bool {
+        self.flow_index != 1.0 || self.yield_stress > 0.0
+    }
+
+    /** The fluids every new simulation starts with; water comes first so it is the default.
+    Particles refer to their material by its index here, so new materials only go on the end. */
+    pub fn default_materials() -> Vec<SimFluidMaterial> {
+        vec![
+            SimFluidMaterial::new("Water", 1.0, 0.0, SimColor::rgb(0.0, 0.25, 1.0)),
+            SimFluidMaterial::new("Oil", 0.8, 10.0, SimColor::rgb(1.0, 0.73, 0.17)),
+            SimFluidMaterial::new("Honey", 1.4, 200.0, SimColor::rgb(0.85, 0.45, 0.05)),
+            SimFluidMaterial::new("Syrup", 1.3, 60.0, SimColor::rgb(0.55, 0.2, 0.05)),
+            SimFluidMaterial::new_granular("Sand", 1.6, 0.7, SimColor::rgb(0.86, 0.72, 0.45)),
+            SimFluidMaterial::new_bingham(
+                "Ketchup",
+                1.1,
+                20.0,
+                1500.0,
+                SimColor::rgb(0.7, 0.05, 0.05),
+            ),
+            SimFluidMaterial::new_power_law(
+                "Oobleck",
+                1.5,
+                10.0,
+                2.0,
+                SimColor::rgb(0.93, 0.93, 0.85),
+            ),
+        ]
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Reflect)]
 pub enum SimGridCellType {
     Solid,
@@ -544,6 +1028,31 @@ pub enum SimGridCellType {
     Air,
 }
 
+/// What kind of fluid a scene simulates.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Reflect)]
+pub enum SimFluidMode {
+    #[default]
+    Liquid, // Particles carry the fluid, and the grid only solves for its velocity.
+    Smoke, // The grid carries smoke density and temperature itself; there are no particles.
+}
+
+/// Whether a solid cell heats or cools the fluid next to it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Reflect)]
+pub enum SimHeatSource {
+    #[default]
+    None,
+    Heater, // Holds neighboring fluid at SimConstraints::heater_temperature.
+    Cooler, // Holds neighboring fluid at SimConstraints::cooler_temperature.
+}
+
+/// How velocities are carried between particles and the grid.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Reflect)]
+pub enum SimTransferScheme {
+    #[default]
+    PicFlip, // Blend of PIC and FLIP, set by SimConstraints::grid_particle_ratio.
+    Apic, // Affine PIC; each particle also carries its local velocity gradient.
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Reflect)]
 pub enum SimSurfaceDirection {
     North,
@@ -552,35 +1061,392 @@ pub enum SimSurfaceDirection {
     West,
 }
 
+/// What happens to fluid that reaches an edge of the grid.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Reflect)]
+pub enum SimBoundary {
+    #[default]
+    Solid, // A wall that nothing gets through.
+    Outflow,      // Fluid leaves freely; particles that cross the edge are deleted.
+    Inflow(Vec2), // Fluid is pushed in through the edge with this velocity.
+    Periodic,     // Fluid that leaves comes back in through the opposite edge.
+}
+
+/** The boundary condition on each edge of the grid.  Change them with SimGrid::set_boundary(),
+which keeps periodic edges in pairs. */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Reflect)]
+pub struct SimBoundaries {
+    north: SimBoundary,
+    south: SimBoundary,
+    east: SimBoundary,
+    west: SimBoundary,
+}
+
+/** A 2D array of per-cell (or per-face) values, stored flat in row-major order so that a whole
+grid is one allocation.  Index with `[(row, col)]`, or with a plain lookup index (row * cols + col)
+like the spatial lookup uses. */
+#[derive(Clone, Debug, PartialEq, Reflect)]
+pub struct SimGridArray<T> {
+    rows: usize,
+    cols: usize,
+    data: Vec<T>,
+}
+
+impl<T> Default for SimGridArray<T> {
+    fn default() -> SimGridArray<T> {
+        SimGridArray {
+            rows: 0,
+            cols: 0,
+            data: Vec::new(),
+        }
+    }
+}
+
+impl<T: Clone> SimGridArray<T> {
+    /// Make a `rows` x `cols` array with every entry set to `value`.
+    pub fn new(rows: usize, cols: usize, value: T) -> SimGridArray<T> {
+        SimGridArray {
+            rows,
+            cols,
+            data: vec![value; rows * cols],
+        }
+    }
+
+    /// Set every entry to `value` without reallocating.
+    pub fn fill(&mut self, value: T) {
+        self.data.fill(value);
+    }
+
+    /// Make this a `rows` x `cols` array of `value`, only reallocating if its size changed.
+    pub fn reset(&mut self, rows: usize, cols: usize, value: T) {
+        if rows == self.rows && cols == self.cols {
+            self.fill(value);
+        } else {
+            *self = SimGridArray::new(rows, cols, value);
+        }
+    }
+
+    /// Resize to `rows` x `cols`, keeping entries that are still inside and setting new ones to
+    /// `value`.
+    pub fn resize(&mut self, rows: usize, cols: usize, value: T) {
+        if rows == self.rows && cols == self.cols {
+            return;
+        }
+        let mut resized: SimGridArray<T> = SimGridArray::new(rows, cols, value);
+        for row in 0..usize::min(rows, self.rows) {
+            for col in 0..usize::min(cols, self.cols) {
+                resized[(row, col)] = self[(row, col)].clone();
+            }
+        }
+        *self = resized;
+    }
+}
+
+impl<T> SimGridArray<T> {
+    /// Build an array out of a list of equally long rows.
+    pub fn from_rows(rows: Vec<Vec<T>>) -> SimGridArray<T> {
+        let row_count: usize = rows.len();
+        let col_count: usize = rows.first().map_or(0, |row| row.len());
+        let data: Vec<T> = rows.into_iter().flatten().collect();
+        assert_eq!(
+            data.len(),
+            row_count * col_count,
+            "Grid rows must all be the same length!"
+        );
+
+        SimGridArray {
+            rows: row_count,
+            cols: col_count,
+            data,
+        }
+    }
+
+    pub fn row_count(&self) -> usize {
+        self.rows
+    }
+
+    pub fn col_count(&self) -> usize {
+        self.cols
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Get the entry at (row, col), or None if that is outside of the array.
+    pub fn get(&self, row: usize, col: usize) -> Option<&T> {
+        if row >= self.rows || col >= self.cols {
+            return None;
+        }
+        self.data.get(row * self.cols + col)
+    }
+
+    /// Get the entry at (row, col) mutably, or None if that is outside of the array.
+    pub fn get_mut(&mut self, row: usize, col: usize) -> Option<&mut T> {
+        if row >= self.rows || col >= self.cols {
+            return None;
+        }
+        self.data.get_mut(row * self.cols + col)
+    }
+
+    /// Get a single row as a slice.
+    pub fn row(&self, row: usize) -> &[T] {
+        &self.data[row * self.cols..(row + 1) * self.cols]
+    }
+
+    /// Get a single row as a mutable slice.
+    pub fn row_mut(&mut self, row: usize) -> &mut [T] {
+        &mut self.data[row * self.cols..(row + 1) * self.cols]
+    }
+
+    /// Iterate over the rows, top to bottom, as slices.
+    pub fn iter_rows(&self) -> std::slice::ChunksExact<'_, T> {
+        self.data.chunks_exact(usize::max(self.cols, 1))
+    }
+
+    /// Iterate over the rows, top to bottom, as mutable slices.
+    pub fn iter_rows_mut(&mut self) -> std::slice::ChunksExactMut<'_, T> {
+        self.data.chunks_exact_mut(usize::max(self.cols, 1))
+    }
+
+    /// Iterate over every entry in row-major order.
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
+        self.data.iter()
+    }
+
+    /// Iterate mutably over every entry in row-major order.
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, T> {
+        self.data.iter_mut()
+    }
+
+    /// All entries in row-major order; entry (row, col) is at row * col_count() + col.
+    pub fn as_slice(&self) -> &[T] {
+        &self.data
+    }
+
+    /// All entries in row-major order, mutably.
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        &mut self.data
+    }
+
+    /// Make a new array of the same shape by applying `f` to every entry.
+    pub fn map<U>(&self, f: impl FnMut(&T) -> U) -> SimGridArray<U> {
+        SimGridArray {
+            rows: self.rows,
+            cols: self.cols,
+            data: self.data.iter().map(f).collect(),
+        }
+    }
+}
+
+impl<T> std::ops::Index<(usize, usize)> for SimGridArray<T> {
+    type Output = T;
+
+    fn index(&self, (row, col): (usize, usize)) -> &T {
+        debug_assert!(
+            row < self.rows && col < self.cols,
+            "Grid index out of bounds!"
+        );
+        &self.data[row * self.cols + col]
+    }
+}
+
+impl<T> std::ops::IndexMut<(usize, usize)> for SimGridArray<T> {
+    fn index_mut(&mut self, (row, col): (usize, usize)) -> &mut T {
+        debug_assert!(
+            row < self.rows && col < self.cols,
+            "Grid index out of bounds!"
+        );
+        &mut self.data[row * self.cols + col]
+    }
+}
+
+impl<T> std::ops::Index<usize> for SimGridArray<T> {
+    type Output = T;
+
+    fn index(&self, lookup_index: usize) -> &T {
+        &self.data[lookup_index]
+    }
+}
+
+impl<T> std::ops::IndexMut<usize> for SimGridArray<T> {
+    fn index_mut(&mut self, lookup_index: usize) -> &mut T {
+        &mut self.data[lookup_index]
+    }
+}
+
+/** Which particles are inside of each grid cell.  Rather than keeping a list per cell up to date as
+particles move, the whole table is rebuilt every step with a counting sort: the particles are kept
+sorted by cell in one array, and the particles of the cell at `lookup_index` are
+`particles[cell_start[lookup_index]..cell_end[lookup_index]]`. */
+#[derive(Clone, Debug, Default, PartialEq, Reflect)]
+pub struct SimSpatialHash {
+    cell_start: Vec<usize>, // Index into `particles` of each cell's first particle.
+    cell_end: Vec<usize>,   // Index into `particles` just past each cell's last particle.
+    particles: Vec<Entity>, // Every particle, sorted by lookup index.
+}
+
+impl SimSpatialHash {
+    /** Sort `particles`, given as (particle, lookup index) pairs, into `cell_count` cells.  Any
+    particle with a lookup index outside of the grid is left out.  Reuses the table's memory. */
+    pub fn rebuild(&mut self, cell_count: usize, particles: &[(Entity, usize)]) {
+        // Count the particles in each cell.
+        self.cell_end.clear();
+        self.cell_end.resize(cell_count, 0);
+        for &(_, lookup_index) in particles {
+            if lookup_index < cell_count {
+                self.cell_end[lookup_index] += 1;
+            }
+        }
+
+        // Each cell starts where the cells before it end.
+        self.cell_start.clear();
+        let mut particle_total: usize = 0;
+        for count in self.cell_end.iter_mut() {
+            self.cell_start.push(particle_total);
+            particle_total += *count;
+            *count = self.cell_start[self.cell_start.len() - 1];
+        }
+
+        // Drop each particle into the next free slot of its cell.
+        self.particles.clear();
+        self.particles.resize(particle_total, Entity::PLACEHOLDER);
+        for &(particle_id, lookup_index) in particles {
+            if lookup_index < cell_count {
+                self.particles[self.cell_end[lookup_index]] = particle_id;
+                self.cell_end[lookup_index] += 1;
+            }
+        }
+    }
+
+    /// Get the particles inside of the cell at `lookup_index`; empty if it is out of bounds.
+    pub fn cell(&self, lookup_index: usize) -> &[Entity] {
+        match (
+            self.cell_start.get(lookup_index),
+            self.cell_end.get(lookup_index),
+        ) {
+            (Some(&start), Some(&end)) => &self.particles[start..end],
+            _ => &[],
+        }
+    }
+
+    /** Take a particle out of the cell at `lookup_index`, so that it isn't found again before the
+    next rebuild; returns false if it wasn't there.  Only that one cell is searched. */
+    pub fn remove(&mut self, particle_id: Entity, lookup_index: usize) -> bool {
+        let Some(slot) = self
+            .cell(lookup_index)
+            .iter()
+            .position(|id| *id == particle_id)
+        else {
+            return false;
+        };
+
+        // Swap it to the end of its cell, then shrink the cell by one.
+        let start: usize = self.cell_start[lookup_index];
+        let end: usize = self.cell_end[lookup_index];
+        self.particles.swap(start + slot, end - 1);
+        self.cell_end[lookup_index] -= 1;
+
+        true
+    }
+
+    /// Empty the cell at `lookup_index` until the next rebuild.
+    pub fn clear_cell(&mut self, lookup_index: usize) {
+        if let (Some(&start), Some(end)) = (
+            self.cell_start.get(lookup_index),
+            self.cell_end.get_mut(lookup_index),
+        ) {
+            *end = start;
+        }
+    }
+
+    /// Empty every cell.
+    pub fn clear(&mut self) {
+        self.cell_start.clear();
+        self.cell_end.clear();
+        self.particles.clear();
+    }
+}
+
 #[derive(Resource, Clone, Reflect)]
 #[reflect(Resource)]
 pub struct SimGrid {
     pub dimensions: (u16, u16), // # of Hor. and Vert. cells in the simulation.
     pub cell_size: u16,
-    pub cell_type: Vec<Vec<SimGridCellType>>,
-    pub cell_center: Vec<Vec<f32>>, // Magnitude of pressure at center of cell.
-    pub velocity_u: Vec<Vec<f32>>,  // Hor. magnitude as row<column<>>; left -> right.
-    pub velocity_v: Vec<Vec<f32>>,  // Vert. magnitude as row<column<>>; up -> down.
-    pub spatial_lookup: Vec<Vec<Entity>>, // [cell_hash_value[list_of_entities_within_cell]].
-    pub density: Vec<f32>,          // Density for each grid cell.
+    pub cell_type: SimGridArray<SimGridCellType>,
+    pub cell_center: SimGridArray<f32>, // Magnitude of pressure at center of cell.
+    pub velocity_u: SimGridArray<f32>,  // Hor. magnitude, rows x (cols + 1); left -> right.
+    pub velocity_v: SimGridArray<f32>,  // Vert. magnitude, (rows + 1) x cols; up -> down.
+    #[reflect(skip_serializing)] // Rebuilt from the particles every step, so it isn't saved.
+    pub spatial_lookup: SimSpatialHash, // Particles within each cell, by lookup index.
+    pub density: SimGridArray<f32>,     // Density for each grid cell.
+    pub fluid_density: SimGridArray<f32>, // Average material density of the fluid in each cell.
+    pub fluid_viscosity: SimGridArray<f32>, // Average material viscosity of the fluid in each cell.
+    pub fluid_flow_index: SimGridArray<f32>, // Average power-law flow index of the fluid in each cell.
+    pub fluid_yield_stress: SimGridArray<f32>, // Average yield stress of the fluid in each cell.
+    pub fluid_friction: SimGridArray<f32>,   // Average grain friction of the fluid in each cell.
+    pub temperature: SimGridArray<f32>,      // Average temperature of the fluid in each cell.
+    pub heat_sources: SimGridArray<SimHeatSource>, // Which solid cells are heaters or coolers.
+    pub smoke_density: SimGridArray<f32>,    // How much smoke is in each cell, in smoke mode.
+    pub obstacle_cells: Vec<usize>, // Lookup indices of the cells obstacles have made solid.
+    pub solids: Vec<SimSolid>,      // Solid geometry that doesn't line up with the cells.
+    pub solid_distance: Vec<f32>,   // Distance from each cell corner to the nearest of `solids`.
+    pub boundaries: SimBoundaries,  // What happens to fluid at each edge of the grid.
 }
 
 impl Default for SimGrid {
     fn default() -> SimGrid {
+        SimGrid::new(50, 50)
+    }
+}
+
+impl SimGrid {
+    /** Make an empty grid of `rows` x `cols` cells of still air at room temperature, with solid
+    boundaries on every edge. */
+    pub fn new(rows: usize, cols: usize) -> SimGrid {
         SimGrid {
-            dimensions: (50, 50),
+            dimensions: (rows as u16, cols as u16),
             cell_size: 5,
-            cell_type: vec![vec![SimGridCellType::Air; 50]; 50],
-            cell_center: vec![vec![0.0; 50]; 50],
-            velocity_u: vec![vec![0.0; 51]; 50],
-            velocity_v: vec![vec![0.0; 50]; 51],
-            spatial_lookup: vec![vec![Entity::PLACEHOLDER; 0]; 5000],
-            density: vec![0.0; 5000],
+            cell_type: SimGridArray::new(rows, cols, SimGridCellType::Air),
+            cell_center: SimGridArray::new(rows, cols, 0.0),
+            velocity_u: SimGridArray::new(rows, cols + 1, 0.0),
+            velocity_v: SimGridArray::new(rows + 1, cols, 0.0),
+            spatial_lookup: SimSpatialHash::default(),
+            density: SimGridArray::new(rows, cols, 0.0),
+            fluid_density: SimGridArray::new(rows, cols, 1.0),
+            fluid_viscosity: SimGridArray::new(rows, cols, 0.0),
+            fluid_flow_index: SimGridArray::new(rows, cols, 1.0),
+            fluid_yield_stress: SimGridArray::new(rows, cols, 0.0),
+            fluid_friction: SimGridArray::new(rows, cols, 0.0),
+            temperature: SimGridArray::new(rows, cols, ROOM_TEMPERATURE),
+            heat_sources: SimGridArray::new(rows, cols, SimHeatSource::None),
+            smoke_density: SimGridArray::new(rows, cols, 0.0),
+            obstacle_cells: Vec::new(),
+            solids: Vec::new(),
+            solid_distance: Vec::new(),
+            boundaries: SimBoundaries::default(),
         }
     }
-}
 
-impl SimGrid {
+    /** Give every per-cell field the grid's own shape, keeping the values that still fit.  Scenes
+    saved as liquids (or before smoke existed) may not have all of them, or may have them sized for
+    whatever grid was loaded before. */
+    pub fn resize_cell_fields(&mut self) {
+        let (rows, cols) = (self.dimensions.0 as usize, self.dimensions.1 as usize);
+        self.density.resize(rows, cols, 0.0);
+        self.fluid_density.resize(rows, cols, 1.0);
+        self.fluid_viscosity.resize(rows, cols, 0.0);
+        self.fluid_flow_index.resize(rows, cols, 1.0);
+        self.fluid_yield_stress.resize(rows, cols, 0.0);
+        self.fluid_friction.resize(rows, cols, 0.0);
+        self.temperature.resize(rows, cols, ROOM_TEMPERATURE);
+        self.heat_sources.resize(rows, cols, SimHeatSource::None);
+        self.smoke_density.resize(rows, cols, 0.0);
+    }
+
     /// Set simulation grid cell type.
     pub fn set_grid_cell_type(
         &mut self,
@@ -595,14 +1461,51 @@ impl SimGrid {
             return Err(Error::OutOfGridBounds("Y-coord. is out of bounds!"));
         }
 
-        self.cell_type[row][col] = cell_type;
+        self.cell_type[(row, col)] = cell_type;
+
+        Ok(())
+    }
+
+    /// Make a cell heat or cool the fluid next to it; only matters while the cell is solid.
+    pub fn set_heat_source(
+        &mut self,
+        row: usize,
+        col: usize,
+        heat_source: SimHeatSource,
+    ) -> Result<()> {
+        if row >= self.dimensions.0 as usize {
+            return Err(Error::OutOfGridBounds("X-coord. is out of bounds!"));
+        }
+        if col >= self.dimensions.1 as usize {
+            return Err(Error::OutOfGridBounds("Y-coord. is out of bounds!"));
+        }
+
+        // Scenes saved before heat sources existed load without any, so make room for them.
+        self.heat_sources.resize(
+            self.dimensions.0 as usize,
+            self.dimensions.1 as usize,
+            SimHeatSource::None,
+        );
+        self.heat_sources[(row, col)] = heat_source;
 
         Ok(())
     }
 
+    /// Get whether a solid cell heats or cools its surroundings; out of bounds cells do neither.
+    pub fn get_heat_source(&self, row: usize, col: usize) -> SimHeatSource {
+        if self.get_cell_type_value(row, col) != 0 {
+            return SimHeatSource::None;
+        }
+        match self.heat_sources.get(row, col) {
+            Some(heat_source) => *heat_source,
+            None => SimHeatSource::None,
+        }
+    }
+
     /// Set simulation grid dimensions.
     pub fn set_grid_dimensions(&mut self, width: u16, height: u16) -> Result<()> {
         self.dimensions = (height, width);
+        self.update_solid_distance();
 
         Ok(())
     }
@@ -610,6 +1513,7 @@ impl SimGrid {
     // Set simulation grid cell size.
     pub fn set_grid_cell_size(&mut self, cell_size: u16) -> Result<()> {
         self.cell_size = cell_size;
+        self.update_solid_distance();
 
         Ok(())
     }
@@ -638,12 +1542,153 @@ impl SimGrid {
             let pos_x = col_index as f32 * self.cell_size as f32;
             let pos_y = grid_height as f32 - (row_index as f32 * self.cell_size as f32 + offset);
 
-            return Vec2::new(pos_x, pos_y);
+            Vec2::new(pos_x, pos_y)
         } else {
             let pos_x = col_index as f32 * self.cell_size as f32 + offset;
             let pos_y = grid_height as f32 - (row_index as f32 * self.cell_size as f32);
 
-            return Vec2::new(pos_x, pos_y);
+            Vec2::new(pos_x, pos_y)
+        }
+    }
+
+    /** Set what happens to fluid at one edge of the grid.  Periodic edges come in pairs, so making
+    an edge periodic makes the opposite edge periodic too, and replacing a periodic edge with
+    anything else turns the opposite edge into a wall. */
+    pub fn set_boundary(&mut self, edge: SimSurfaceDirection, boundary: SimBoundary) {
+        let (this, opposite) = match edge {
+            SimSurfaceDirection::North => (&mut self.boundaries.north, &mut self.boundaries.south),
+            SimSurfaceDirection::South => (&mut self.boundaries.south, &mut self.boundaries.north),
+            SimSurfaceDirection::East => (&mut self.boundaries.east, &mut self.boundaries.west),
+            SimSurfaceDirection::West => (&mut self.boundaries.west, &mut self.boundaries.east),
+        };
+
+        if boundary == SimBoundary::Periodic {
+            *opposite = SimBoundary::Periodic;
+        } else if *this == SimBoundary::Periodic {
+            *opposite = SimBoundary::Solid;
+        }
+        *this = boundary;
+    }
+
+    /// Get what happens to fluid at one edge of the grid.
+    pub fn get_boundary(&self, edge: SimSurfaceDirection) -> SimBoundary {
+        match edge {
+            SimSurfaceDirection::North => self.boundaries.north,
+            SimSurfaceDirection::South => self.boundaries.south,
+            SimSurfaceDirection::East => self.boundaries.east,
+            SimSurfaceDirection::West => self.boundaries.west,
+        }
+    }
+
+    /** Find which edge of the grid a position has crossed; returns None for positions inside of
+    the grid.  Positions past a corner count as crossing the east or west edge. */
+    pub fn get_crossed_edge(&self, position: Vec2) -> Option<SimSurfaceDirection> {
+        let grid_width: f32 = (self.dimensions.1 * self.cell_size) as f32;
+        let grid_height: f32 = (self.dimensions.0 * self.cell_size) as f32;
+
+        if position.x < 0.0 {
+            Some(SimSurfaceDirection::West)
+        } else if position.x > grid_width {
+            Some(SimSurfaceDirection::East)
+        } else if position.y < 0.0 {
+            Some(SimSurfaceDirection::South)
+        } else if position.y > grid_height {
+            Some(SimSurfaceDirection::North)
+        } else {
+            None
+        }
+    }
+
+    /// Bring a position that has left the grid through a periodic edge in through the opposite edge.
+    pub fn wrap_position(&self, position: Vec2) -> Vec2 {
+        let grid_width: f32 = (self.dimensions.1 * self.cell_size) as f32;
+        let grid_height: f32 = (self.dimensions.0 * self.cell_size) as f32;
+
+        let mut wrapped: Vec2 = position;
+        if self.boundaries.east == SimBoundary::Periodic {
+            wrapped.x = wrapped.x.rem_euclid(grid_width);
+        }
+        if self.boundaries.north == SimBoundary::Periodic {
+            wrapped.y = wrapped.y.rem_euclid(grid_height);
+        }
+        wrapped
+    }
+
+    /// Add solid geometry that cuts through cells instead of filling them.
+    pub fn add_solid(&mut self, solid: SimSolid) {
+        self.solids.push(solid);
+        self.update_solid_distance();
+    }
+
+    /// Remove all of the solid geometry added with add_solid().
+    pub fn clear_solids(&mut self) {
+        self.solids.clear();
+        self.update_solid_distance();
+    }
+
+    /** Recalculate the distance from every cell corner to the nearest solid in `solids`; corners
+    are stored row by row, with row 0 along the top of the grid. */
+    pub fn update_solid_distance(&mut self) {
+        if self.solids.is_empty() {
+            self.solid_distance = Vec::new();
+            return;
+        }
+
+        let (rows, cols) = (self.dimensions.0 as usize, self.dimensions.1 as usize);
+        let cell_size: f32 = self.cell_size as f32;
+        let grid_height: f32 = rows as f32 * cell_size;
+        let mut solid_distance: Vec<f32> = Vec::with_capacity((rows + 1) * (cols + 1));
+        for row in 0..=rows {
+            for col in 0..=cols {
+                let corner: Vec2 =
+                    Vec2::new(col as f32 * cell_size, grid_height - row as f32 * cell_size);
+                solid_distance.push(self.get_solid_distance(corner));
+            }
+        }
+        self.solid_distance = solid_distance;
+    }
+
+    /** Distance from a position to the surface of the nearest solid in `solids`; negative inside
+    of a solid, and f32::MAX if there are none. */
+    pub fn get_solid_distance(&self, position: Vec2) -> f32 {
+        self.solids.iter().fold(f32::MAX, |distance, solid| {
+            f32::min(distance, solid.signed_distance(position))
+        })
+    }
+
+    /// Direction pointing out of the surface of the solid in `solids` nearest to a position.
+    pub fn get_solid_normal(&self, position: Vec2) -> Vec2 {
+        util::surface_normal(position, |point| self.get_solid_distance(point))
+    }
+
+    /** Fraction of a cell face that is not covered by `solids`, from 0 (blocked) to 1 (open).
+    Like get_velocity_point_pos(), `horizontal` picks a face from `velocity_u` rather than
+    `velocity_v`.  Whole solid cells are not taken into account here. */
+    pub fn get_face_open_fraction(&self, row: usize, col: usize, horizontal: bool) -> f32 {
+        let corners: usize = self.dimensions.1 as usize + 1;
+        let corner_distance = |row: usize, col: usize| -> f32 {
+            match self.solid_distance.get(row * corners + col) {
+                Some(distance) => *distance,
+                None => f32::MAX,
+            }
+        };
+
+        // Horizontal velocities sit on the left side of a cell, and vertical ones on the top.
+        let first: f32 = corner_distance(row, col);
+        let second: f32 = match horizontal {
+            true => corner_distance(row + 1, col),
+            false => corner_distance(row, col + 1),
+        };
+
+        // The surface crosses the face where the distance between its two corners reaches zero.
+        if first < 0.0 && second < 0.0 {
+            0.0
+        } else if first < 0.0 {
+            1.0 - first / (first - second)
+        } else if second < 0.0 {
+            1.0 - second / (second - first)
+        } else {
+            1.0
         }
     }
 
@@ -658,7 +1703,7 @@ impl SimGrid {
         /* When modifying flow out of a cell, we need to modify said flow by 0 if the
         cell the flow is going into is solid.  If the cell is not solid, we leave flow
         unmodified. */
-        match self.cell_type[cell_row][cell_col] {
+        match self.cell_type[(cell_row, cell_col)] {
             SimGridCellType::Solid => 0,
             SimGridCellType::Fluid => 1,
             SimGridCellType::Air => 1,
@@ -897,7 +1942,7 @@ impl SimGrid {
         // For each nearby cell, add its density weighted based on position to final density value.
         for cell in nearby_cells {
             // If one of our cell is solid, use the center cell's density instead.
-            // if self.cell_type[cell.x as usize][cell.y as usize] == SimGridCellType::Solid {
+            // if self.cell_type[(cell.x as usize, cell.y as usize)] == SimGridCellType::Solid {
             // 	cell = &center_cell;
             // }
 
@@ -916,51 +1961,26 @@ impl SimGrid {
         ((cell_coordinates[0] as u16 * self.dimensions.1) + cell_coordinates[1] as u16) as usize
     }
 
-    /// Add a new particle into our spatial lookup table.
-    pub fn add_particle_to_lookup(&mut self, particle_id: Entity, lookup_index: usize) {
-        if lookup_index > self.spatial_lookup.len() {
-            eprintln!("Particle lookup index is out-of-bounds; cannot add particle to table!");
-            return;
-        }
-        self.spatial_lookup[lookup_index].push(particle_id);
+    /** Sort particles, given as (particle, lookup index) pairs, into our spatial lookup table,
+    replacing whatever was in it. */
+    pub fn rebuild_spatial_lookup(&mut self, particles: &[(Entity, usize)]) {
+        let cell_count: usize = self.dimensions.0 as usize * self.dimensions.1 as usize;
+        self.spatial_lookup.rebuild(cell_count, particles);
     }
 
-    /// Remove a particle from our spatial lookup table; does nothing if the particle isn't found.
-    pub fn remove_particle_from_lookup(&mut self, particle_id: Entity, lookup_index: usize) {
-        if lookup_index > self.spatial_lookup.len() {
-            eprintln!("Particle lookup index is out-of-bounds; cannot remove particle from table!");
-            return;
-        }
-
-        // Search through our spatial lookup at the specified location.
-        for particle_index in 0..self.spatial_lookup[lookup_index].len() {
-            // If we found it, remove it.
-            if self.spatial_lookup[lookup_index][particle_index] == particle_id {
-                self.spatial_lookup[lookup_index].swap_remove(particle_index);
-                break;
-            }
-        }
+    /** Remove a particle from our spatial lookup table; returns false (and does nothing) if the
+    particle isn't found. */
+    pub fn remove_particle_from_lookup(
+        &mut self,
+        particle_id: Entity,
+        lookup_index: usize,
+    ) -> bool {
+        self.spatial_lookup.remove(particle_id, lookup_index)
     }
 
     /// Get a Vec<Entity> of the particles currently inside of the cell at lookup_index.
     pub fn get_particles_in_lookup(&self, lookup_index: usize) -> Vec<Entity> {
-        // Return an empty vector if we are out of bounds.
-        if lookup_index >= (self.dimensions.0 * self.dimensions.1) as usize {
-            return Vec::new();
-        }
-
-        let mut lookup_vector: Vec<Entity> = Vec::new();
-
-        for particle_id in self.spatial_lookup[lookup_index].clone() {
-            // TODO: Don't use placeholder!  Bad kitty!!!
-            if particle_id == Entity::PLACEHOLDER {
-                continue;
-            }
-
-            lookup_vector.push(particle_id);
-        }
-
-        lookup_vector
+        self.spatial_lookup.cell(lookup_index).to_vec()
     }
 
     /// Delete all particles within a cell, given that cell's lookup index.
@@ -971,12 +1991,12 @@ impl SimGrid {
         particles: &Query<(Entity, &mut SimParticle)>,
         lookup_index: usize,
     ) {
-        for particle_id in self.spatial_lookup[lookup_index].iter_mut() {
+        for particle_id in self.spatial_lookup.cell(lookup_index) {
             // Look for the particle in our particles query.
             if let Ok(_particle) = particles.get(*particle_id) {
-                /* Despawn particle; since we are already mutably borrowing the lookup table, we
-                can't remove any particles from the lookup table until we are done iterating
-                through the table. */
+                /* Despawn particle; since we are already borrowing the lookup table, we can't
+                remove any particles from the lookup table until we are done iterating through
+                the table. */
                 commands.entity(*particle_id).despawn();
 
                 /* BUG: This overflowed once while testing, and I'm betting it's because I misuse
@@ -988,7 +2008,7 @@ impl SimGrid {
         }
 
         // Clear the spatial lookup table at the current index.
-        self.spatial_lookup[lookup_index].clear();
+        self.spatial_lookup.clear_cell(lookup_index);
     }
 
     /// Get velocity of the cell
@@ -1001,132 +2021,169 @@ impl SimGrid {
             return Vec2::ZERO;
         }
 
-        let left_u = self.velocity_u[row][column];
-        let right_u = self.velocity_u[row][column + 1];
-        let top_v = self.velocity_v[row][column];
-        let down_v = self.velocity_v[row + 1][column];
+        let left_u = self.velocity_u[(row, column)];
+        let right_u = self.velocity_u[(row, column + 1)];
+        let top_v = self.velocity_v[(row, column)];
+        let down_v = self.velocity_v[(row + 1, column)];
 
         let u_avg = (left_u + right_u) / 2.0;
         let v_avg = (top_v + down_v) / 2.0;
 
-        let velocity = Vec2::new(u_avg, v_avg);
-
-        velocity
+        Vec2::new(u_avg, v_avg)
     }
 
-    /// Get the particles in all 9 cells surrounding a point.
-    fn get_nearby_particles(&self, lookup_index: usize) -> Vec<Entity> {
-        let mut nearby_particles: Vec<Entity> = Vec::new();
-        let mut cells_to_check: Vec<usize> = Vec::new();
-        let col_count: usize = self.dimensions.1 as usize;
+    /**
+        Goes through the entire grid and labels the cells with their respective type
+    **/
+    pub fn label_cells(&mut self) {
+        let (rows, cols) = (self.dimensions.0 as usize, self.dimensions.1 as usize);
 
-        let is_cell_on_right_border: bool = lookup_index % (col_count - 1) == 0;
-        let is_cell_on_left_border: bool = lookup_index % col_count == 0;
+        // Relabel in place, rather than building a new label array every frame.
+        self.cell_type.resize(rows, cols, SimGridCellType::Air);
 
-        /* Make sure the current row's cells-to-check are valid.  If they are, search for particles
-        within them. */
-        cells_to_check.push(lookup_index);
-        if lookup_index > 0 && !is_cell_on_left_border {
-            cells_to_check.push(lookup_index - 1);
-        }
-        if lookup_index < self.spatial_lookup.len() && !is_cell_on_right_border {
-            cells_to_check.push(lookup_index + 1);
+        for lookup_index in 0..rows * cols {
+            // Solid cells stay solid.
+            if self.cell_type[lookup_index] == SimGridCellType::Solid {
+                continue;
+            }
+
+            // Determine if non-solid cell is Air or fluid, based on the particles inside of it.
+            let has_particles: bool = !self.spatial_lookup.cell(lookup_index).is_empty();
+            self.cell_type[lookup_index] = if has_particles {
+                SimGridCellType::Fluid
+            } else {
+                SimGridCellType::Air
+            };
         }
+    }
+
+    /** Generate walls around simulation bounds, on every edge whose boundary is a solid wall, and
+    open up the walls left on edges that aren't solid anymore. */
+    pub fn force_edge_solids(&mut self) {
+        let last_row: usize = (self.dimensions.0 - 1) as usize;
+        let last_col: usize = (self.dimensions.1 - 1) as usize;
 
-        // Previous row's cell check:
-        if lookup_index >= col_count {
-            cells_to_check.push(lookup_index - col_count);
-            if !is_cell_on_left_border {
-                cells_to_check.push(lookup_index - col_count - 1);
+        /* Clear the open edges first, so that a corner shared with a solid edge ends up solid;
+        label_cells() will mark the cleared cells as fluid again if there are particles in them. */
+        for i in 0..(self.dimensions.0 as usize) {
+            if self.boundaries.west != SimBoundary::Solid {
+                let _ = self.set_grid_cell_type(i, 0, SimGridCellType::Air);
             }
-            if !is_cell_on_right_border {
-                cells_to_check.push(lookup_index - col_count + 1);
+            if self.boundaries.east != SimBoundary::Solid {
+                let _ = self.set_grid_cell_type(i, last_col, SimGridCellType::Air);
             }
         }
-
-        // Next row's cell check:
-        if lookup_index <= self.spatial_lookup.len() - col_count {
-            cells_to_check.push(lookup_index + col_count);
-            if !is_cell_on_left_border {
-                cells_to_check.push(lookup_index + col_count - 1);
+        for i in 0..(self.dimensions.1 as usize) {
+            if self.boundaries.south != SimBoundary::Solid {
+                let _ = self.set_grid_cell_type(last_row, i, SimGridCellType::Air);
             }
-            if lookup_index < self.spatial_lookup.len() - col_count && !is_cell_on_right_border {
-                cells_to_check.push(lookup_index + col_count + 1);
+            if self.boundaries.north != SimBoundary::Solid {
+                let _ = self.set_grid_cell_type(0, i, SimGridCellType::Air);
             }
         }
 
-        for i in 0..cells_to_check.len() {
-            nearby_particles.append(&mut self.get_particles_in_lookup(cells_to_check[i]));
+        // Set rows.
+        for i in 0..(self.dimensions.0 as usize) {
+            if self.boundaries.west == SimBoundary::Solid {
+                let _ = self.set_grid_cell_type(i, 0, SimGridCellType::Solid);
+            }
+            if self.boundaries.east == SimBoundary::Solid {
+                let _ = self.set_grid_cell_type(i, last_col, SimGridCellType::Solid);
+            }
         }
 
-        nearby_particles
+        // Set columns.
+        for i in 0..(self.dimensions.1 as usize) {
+            if self.boundaries.south == SimBoundary::Solid {
+                let _ = self.set_grid_cell_type(last_row, i, SimGridCellType::Solid);
+            }
+            if self.boundaries.north == SimBoundary::Solid {
+                let _ = self.set_grid_cell_type(0, i, SimGridCellType::Solid);
+            }
+        }
     }
+}
 
-    /**
-        Goes through the entire grid and labels the cells with their respective type
-    **/
-    pub fn label_cells(&mut self) {
-        let (rows, cols) = self.dimensions;
-
-        // Create a new label array
-        let mut cell_types = vec![vec![SimGridCellType::Air; cols as usize]; rows as usize];
+/** Solid geometry that doesn't have to line up with the grid, like a ramp or a round bowl.  Cells
+that are only partly inside of it stay open, and fluid can flow through the uncovered part of
+their faces. */
+#[derive(Clone, Debug, PartialEq, Reflect)]
+pub enum SimSolid {
+    Circle(Vec2, f32),        // Center and radius.
+    Segment(Vec2, Vec2, f32), // Start, end, and half of the thickness.
+    Polygon(Vec<Vec2>),       // Corners, in order around the outline.
+}
 
-        for row in 0..rows as usize {
-            for col in 0..cols as usize {
-                // Check if cell is solid
-                if self.cell_type[row][col] == SimGridCellType::Solid {
-                    cell_types[row][col] = SimGridCellType::Solid;
-                    continue;
+impl SimSolid {
+    /// Distance from a point to the solid's surface; negative inside of the solid.
+    pub fn signed_distance(&self, point: Vec2) -> f32 {
+        match self {
+            SimSolid::Circle(center, radius) => point.distance(*center) - radius,
+            SimSolid::Segment(start, end, half_thickness) => {
+                let along: Vec2 = *end - *start;
+                let t: f32 = match along.length_squared() > 0.0 {
+                    true => ((point - *start).dot(along) / along.length_squared()).clamp(0.0, 1.0),
+                    false => 0.0,
+                };
+                point.distance(*start + along * t) - half_thickness
+            }
+            SimSolid::Polygon(corners) => {
+                if corners.len() < 3 {
+                    return f32::MAX;
                 }
 
-                let lookup_index = self.get_lookup_index(Vec2::new(row as f32, col as f32));
+                /* Find the distance to the closest edge, and flip the sign each time a ray going
+                right from the point crosses an edge to tell if the point is inside. */
+                let mut distance_squared: f32 = f32::MAX;
+                let mut inside: bool = false;
+                for i in 0..corners.len() {
+                    let (start, end) = (corners[i], corners[(i + 1) % corners.len()]);
+                    let edge: Vec2 = end - start;
+                    let t: f32 = match edge.length_squared() > 0.0 {
+                        true => ((point - start).dot(edge) / edge.length_squared()).clamp(0.0, 1.0),
+                        false => 0.0,
+                    };
+                    distance_squared =
+                        f32::min(distance_squared, point.distance_squared(start + edge * t));
 
-                // Get the particles within the current cell
-                let particles = self.get_particles_in_lookup(lookup_index);
+                    if (start.y > point.y) != (end.y > point.y)
+                        && point.x < start.x + (point.y - start.y) * edge.x / edge.y
+                    {
+                        inside = !inside;
+                    }
+                }
 
-                // Determine if non-solid cell is Air or fluid.
-                if particles.len() == 0 {
-                    cell_types[row][col] = SimGridCellType::Air;
-                } else {
-                    cell_types[row][col] = SimGridCellType::Fluid;
+                match inside {
+                    true => -distance_squared.sqrt(),
+                    false => distance_squared.sqrt(),
                 }
             }
         }
-
-        // Set the label array to new label area
-        self.cell_type = cell_types;
-    }
-
-    /// Generate walls around simulation bounds.
-    pub fn force_edge_solids(&mut self) {
-        // Set rows.
-        for i in 0..(self.dimensions.0 as usize) {
-            let _ = self.set_grid_cell_type(i, 0, SimGridCellType::Solid);
-            let _ = self.set_grid_cell_type(
-                i,
-                (self.dimensions.0 - 1) as usize,
-                SimGridCellType::Solid,
-            );
-        }
-
-        // Set columns.
-        for i in 0..(self.dimensions.1 as usize) {
-            let _ = self.set_grid_cell_type(
-                (self.dimensions.0 - 1) as usize,
-                i,
-                SimGridCellType::Solid,
-            );
-            let _ = self.set_grid_cell_type(0, i, SimGridCellType::Solid);
-        }
     }
 }
 
-#[derive(Component, Default, Reflect)]
+#[derive(Component, Clone, Reflect)]
 #[reflect(Component)]
 pub struct SimParticle {
     pub position: Vec2,      // This particle's [x, y] position.
     pub velocity: Vec2,      // This particle's [x, y] velocity.
     pub lookup_index: usize, // Bucket index into spatial lookup for efficient neighbor search.
+    pub affine: Mat2, // APIC velocity gradient; velocity + (affine * offset) = velocity at offset.
+    pub material: usize, // Index of this particle's fluid in SimConstraints::fluid_materials.
+    pub temperature: f32, // How warm this particle's fluid is.
+}
+
+impl Default for SimParticle {
+    fn default() -> SimParticle {
+        SimParticle {
+            position: Vec2::ZERO,
+            velocity: Vec2::ZERO,
+            lookup_index: 0,
+            affine: Mat2::ZERO, // Mat2::default() is the identity matrix, which is not what we want.
+            material: 0,
+            temperature: ROOM_TEMPERATURE,
+        }
+    }
 }
 
 /// Faucet Object for simulation
@@ -1137,6 +2194,7 @@ pub struct SimFaucet {
     pub direction: Option<SimSurfaceDirection>, // Direction to which the faucet is connected with the wall
     pub diameter: f32,
     pub velocity: Vec2,
+    pub material: usize, // Fluid material of the particles this faucet adds.
 }
 
 impl SimFaucet {
@@ -1145,12 +2203,14 @@ impl SimFaucet {
         direction: Option<SimSurfaceDirection>,
         diameter: f32,
         velocity: Vec2,
+        material: usize,
     ) -> Self {
         Self {
             position,
             direction,
             diameter,
             velocity,
+            material,
         }
     }
 
@@ -1171,6 +2231,7 @@ impl SimFaucet {
             self.diameter,
             position,
             self.velocity,
+            self.material,
         );
 
         Ok(())
@@ -1207,6 +2268,7 @@ impl SimDrain {
     pub fn drain(
         &self,
         commands: &mut Commands,
+        constraints: &mut SimConstraints,
         grid: &mut SimGrid,
         particles: &mut Query<(Entity, &mut SimParticle)>,
     ) -> Result<()> {
@@ -1226,6 +2288,7 @@ impl SimDrain {
 
         delete_particles_in_radius(
             commands,
+            constraints,
             grid,
             particles,
             self.position,
@@ -1235,3 +2298,178 @@ impl SimDrain {
         Ok(())
     }
 }
+
+/// Shape of an obstacle, centered on the obstacle's position.
+#[derive(Clone, Copy, Debug, PartialEq, Reflect)]
+pub enum SimShape {
+    Circle(f32),     // Radius.
+    Rectangle(Vec2), // Half of the width and height, before rotating.
+}
+
+impl Default for SimShape {
+    fn default() -> SimShape {
+        SimShape::Circle(10.0)
+    }
+}
+
+impl SimShape {
+    /// Area covered by the shape.
+    pub fn area(&self) -> f32 {
+        match *self {
+            SimShape::Circle(radius) => std::f32::consts::PI * radius * radius,
+            SimShape::Rectangle(half_size) => 4.0 * half_size.x * half_size.y,
+        }
+    }
+
+    /// Moment of inertia of a solid shape of the given mass, spinning about its center.
+    pub fn moment_of_inertia(&self, mass: f32) -> f32 {
+        match *self {
+            SimShape::Circle(radius) => 0.5 * mass * radius * radius,
+            SimShape::Rectangle(half_size) => mass * half_size.length_squared() / 3.0,
+        }
+    }
+
+    /** Points spread evenly around the outline of the shape, relative to its center and before
+    rotating; used to find where the shape touches walls. */
+    pub fn outline_points(&self) -> Vec<Vec2> {
+        let points_per_side: usize = 8;
+        match *self {
+            SimShape::Circle(radius) => (0..points_per_side * 4)
+                .map(|i| {
+                    let angle: f32 =
+                        i as f32 * std::f32::consts::TAU / (points_per_side * 4) as f32;
+                    Vec2::from_angle(angle) * radius
+                })
+                .collect(),
+            SimShape::Rectangle(half_size) => {
+                let corners: [Vec2; 4] = [
+                    Vec2::new(-half_size.x, -half_size.y),
+                    Vec2::new(half_size.x, -half_size.y),
+                    Vec2::new(half_size.x, half_size.y),
+                    Vec2::new(-half_size.x, half_size.y),
+                ];
+                let mut points: Vec<Vec2> = Vec::new();
+                for side in 0..4 {
+                    let (start, end) = (corners[side], corners[(side + 1) % 4]);
+                    for i in 0..points_per_side {
+                        points.push(start.lerp(end, i as f32 / points_per_side as f32));
+                    }
+                }
+                points
+            }
+        }
+    }
+}
+
+/** A solid that moves on its own (a paddle, a piston, a mixer...), pushing fluid out of its way
+and dragging fluid along with it.  Each step the obstacle moves by its velocity and spins by its
+angular velocity, and every cell whose center is inside of it is made solid. */
+#[derive(Component, Debug, Clone, Default, Reflect)]
+#[reflect(Component)]
+pub struct SimObstacle {
+    pub shape: SimShape,
+    pub position: Vec2,        // Center of the obstacle.
+    pub rotation: f32,         // Counter-clockwise rotation about its center, in radians.
+    pub velocity: Vec2,        // How fast the obstacle moves, in units / second.
+    pub angular_velocity: f32, // How fast the obstacle spins counter-clockwise, in radians / second.
+}
+
+impl SimObstacle {
+    pub fn new(shape: SimShape, position: Vec2, velocity: Vec2, angular_velocity: f32) -> Self {
+        Self {
+            shape,
+            position,
+            rotation: 0.0,
+            velocity,
+            angular_velocity,
+        }
+    }
+
+    /// Distance from a point to the obstacle's surface; negative inside of the obstacle.
+    pub fn signed_distance(&self, point: Vec2) -> f32 {
+        // Work in the obstacle's own frame, where it is centered on zero and not rotated.
+        let local: Vec2 = Vec2::from_angle(-self.rotation).rotate(point - self.position);
+
+        match self.shape {
+            SimShape::Circle(radius) => local.length() - radius,
+            SimShape::Rectangle(half_size) => {
+                let outside: Vec2 = local.abs() - half_size;
+                outside.max(Vec2::ZERO).length() + f32::min(outside.max_element(), 0.0)
+            }
+        }
+    }
+
+    /// Is a point inside of the obstacle?
+    pub fn contains(&self, point: Vec2) -> bool {
+        self.signed_distance(point) < 0.0
+    }
+
+    /// Direction pointing out of the obstacle's surface nearest to a point.
+    pub fn surface_normal(&self, point: Vec2) -> Vec2 {
+        util::surface_normal(point, |point| self.signed_distance(point))
+    }
+
+    /// Velocity of the (solid) obstacle at a point, including the velocity from its spin.
+    pub fn velocity_at(&self, point: Vec2) -> Vec2 {
+        self.velocity + self.angular_velocity * (point - self.position).perp()
+    }
+}
+
+/** A solid that is moved by gravity and the fluid around it: it floats or sinks depending on its
+mass, gets pushed around by the fluid's pressure and dragged along by its flow, pushes fluid out of
+its way, and collides with walls.  Water has a mass of 1 per square unit, so a body lighter than
+the water it displaces floats. */
+#[derive(Component, Debug, Clone, Default, Reflect)]
+#[reflect(Component)]
+pub struct SimRigidBody {
+    pub shape: SimShape,
+    pub mass: f32,
+    pub position: Vec2,        // Center of mass of the body.
+    pub rotation: f32,         // Counter-clockwise rotation about its center, in radians.
+    pub velocity: Vec2,        // Velocity of the body's center, in units / second.
+    pub angular_velocity: f32, // How fast the body spins counter-clockwise, in radians / second.
+}
+
+impl SimRigidBody {
+    pub fn new(shape: SimShape, mass: f32, position: Vec2, velocity: Vec2) -> Self {
+        Self {
+            shape,
+            mass,
+            position,
+            rotation: 0.0,
+            velocity,
+            angular_velocity: 0.0,
+        }
+    }
+
+    /// Mass per square unit of the body; bodies with a density below the fluid's float.
+    pub fn density(&self) -> f32 {
+        self.mass / self.shape.area()
+    }
+
+    /// How hard the body is to spin.
+    pub fn moment_of_inertia(&self) -> f32 {
+        self.shape.moment_of_inertia(self.mass)
+    }
+
+    /// An obstacle in the same place and moving the same way as this body, for this step.
+    pub fn as_obstacle(&self) -> SimObstacle {
+        SimObstacle {
+            shape: self.shape,
+            position: self.position,
+            rotation: self.rotation,
+            velocity: self.velocity,
+            angular_velocity: self.angular_velocity,
+        }
+    }
+
+    /// Push the body at a point with an impulse, changing both how it moves and how it spins.
+    pub fn apply_impulse(&mut self, impulse: Vec2, point: Vec2) {
+        if self.mass <= 0.0 {
+            return;
+        }
+        self.velocity += impulse / self.mass;
+        self.angular_velocity +=
+            (point - self.position).perp_dot(impulse) / self.moment_of_inertia();
+    }
+}
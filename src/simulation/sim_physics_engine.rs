@@ -1,14 +1,73 @@
 use super::util::*;
-use super::{SimConstraints, SimGrid, SimGridCellType, SimParticle};
+use super::{
+    SimBoundary, SimConstraints, SimGrid, SimGridArray, SimGridCellType, SimHeatSource,
+    SimObstacle, SimParticle, SimRigidBody, SimSurfaceDirection, SimTransferScheme,
+    MAX_EFFECTIVE_VISCOSITY,
+};
 use crate::error::Error;
 use bevy::prelude::*;
+use rayon::prelude::*;
 
 pub type Result<T> = core::result::Result<T, Error>;
 
+/** Particles and grid cells are handed out to threads in chunks of (at least) this many, so that
+small loops don't spend more time scheduling work than doing it.  In deterministic mode, sums are
+added up one chunk at a time, so their rounding depends on this and never on the thread count. */
+const PARALLEL_CHUNK_SIZE: usize = 1024;
+
+/** How strongly fluid sliding past a rigid body drags it along, as the drag coefficient in
+`drag = 0.5 * coefficient * density * area * speed^2`; around 1 for blunt shapes like boxes. */
+const RIGID_BODY_DRAG_COEFFICIENT: f32 = 1.0;
+
+/** How many passes of rub_velocities() apply_granular_friction() makes each step.  Each pass only
+carries friction about one face further through the sand, so too few passes let piles slump
+flatter than their angle of repose; a third as many passes visibly flattens a pile, while twice as
+many barely changes its slope. */
+const GRANULAR_FRICTION_PASSES: usize = 30;
+
+/** Add up every item in parallel: `zero()` makes an empty total, `add()` adds one item to a total,
+and `combine()` adds two totals together.  In deterministic mode every chunk of items gets its own
+total and the chunk totals are combined in order, so that the result is bit-identical no matter
+how many threads there are; otherwise rayon is free to split the items however it likes. */
+fn parallel_sum<T: Sync, S: Send>(
+    items: &[T],
+    deterministic: bool,
+    zero: impl Fn() -> S + Sync + Send,
+    add: impl Fn(&mut S, &T) + Sync + Send,
+    combine: impl Fn(S, S) -> S + Sync + Send,
+) -> S {
+    if deterministic {
+        let chunk_totals: Vec<S> = items
+            .par_chunks(PARALLEL_CHUNK_SIZE)
+            .map(|chunk| {
+                let mut total: S = zero();
+                for item in chunk.iter() {
+                    add(&mut total, item);
+                }
+                total
+            })
+            .collect();
+        return chunk_totals
+            .into_iter()
+            .reduce(&combine)
+            .unwrap_or_else(&zero);
+    }
+
+    items
+        .par_iter()
+        .with_min_len(PARALLEL_CHUNK_SIZE)
+        .fold(&zero, |mut total, item| {
+            add(&mut total, item);
+            total
+        })
+        .reduce(&zero, &combine)
+}
+
 /// Applies Particle velocities to grid velocity points
 pub fn particles_to_grid(
     grid: &mut SimGrid,
     particles: &mut Query<(Entity, &mut SimParticle)>,
+    constraints: &SimConstraints,
 ) -> SimGrid {
     // for velocity_u points and velocity_v points,
     // up all particle velocities nearby scaled
@@ -27,9 +86,65 @@ pub fn particles_to_grid(
     let grid_height = rows as f32 * grid.cell_size as f32;
     let grid_width = cols as f32 * grid.cell_size as f32;
 
-    // Create new, blank grids
-    let mut velocity_u = vec![vec![f32::MIN; (cols + 1) as usize]; rows as usize];
-    let mut velocity_v = vec![vec![f32::MIN; cols as usize]; (rows + 1) as usize];
+    /* Scatter every particle's weighted velocity into the velocity points around it in a single
+    pass, split across threads; see parallel_sum() for how the sums are kept deterministic.  APIC
+    particles scatter to their bilinear stencil instead, with their affine matrix adjusting the
+    velocity given to each point. */
+    let use_affine = constraints.transfer_scheme == SimTransferScheme::Apic;
+    let particle_list: Vec<&SimParticle> = particles.iter().map(|(_, particle)| particle).collect();
+    let sums: VelocitySums = parallel_sum(
+        &particle_list,
+        constraints.deterministic,
+        || VelocitySums::new(rows as usize, cols as usize),
+        |sums, particle| {
+            if use_affine {
+                scatter_affine_to_velocity_points(
+                    grid,
+                    particle,
+                    true,
+                    &mut sums.u_velocity,
+                    &mut sums.u_influence,
+                );
+                scatter_affine_to_velocity_points(
+                    grid,
+                    particle,
+                    false,
+                    &mut sums.v_velocity,
+                    &mut sums.v_influence,
+                );
+            } else {
+                scatter_to_velocity_points(
+                    grid,
+                    particle.position,
+                    particle.velocity[0],
+                    true,
+                    &mut sums.u_velocity,
+                    &mut sums.u_influence,
+                );
+                scatter_to_velocity_points(
+                    grid,
+                    particle.position,
+                    particle.velocity[1],
+                    false,
+                    &mut sums.v_velocity,
+                    &mut sums.v_influence,
+                );
+            }
+        },
+        VelocitySums::combine,
+    );
+    let (u_velocity_sum, u_influence_sum) = (sums.u_velocity, sums.u_influence);
+    let (v_velocity_sum, v_influence_sum) = (sums.v_velocity, sums.v_influence);
+
+    let old_grid = grid.clone();
+
+    // Blank out the grid's velocities in place; only points with fluid nearby get a value.
+    grid.velocity_u
+        .resize(rows as usize, cols as usize + 1, f32::MIN);
+    grid.velocity_v
+        .resize(rows as usize + 1, cols as usize, f32::MIN);
+    grid.velocity_u.fill(f32::MIN);
+    grid.velocity_v.fill(f32::MIN);
 
     // Go through each horizontal u velocity point in the MAC grid
     for row_index in 0..rows as usize {
@@ -55,43 +170,36 @@ pub fn particles_to_grid(
             let left_center_coords = grid.get_cell_coordinates_from_position(&left_center);
             let right_center_coords = grid.get_cell_coordinates_from_position(&right_center);
 
-            if grid.cell_type[left_center_coords.x as usize][left_center_coords.y as usize]
+            if grid.cell_type[(left_center_coords.x as usize, left_center_coords.y as usize)]
                 == SimGridCellType::Air
-                && grid.cell_type[right_center_coords.x as usize][right_center_coords.y as usize]
-                    == SimGridCellType::Air
+                && grid.cell_type[(
+                    right_center_coords.x as usize,
+                    right_center_coords.y as usize,
+                )] == SimGridCellType::Air
             {
                 continue;
             }
 
-            if grid.cell_type[left_center_coords.x as usize][left_center_coords.y as usize]
+            if grid.cell_type[(left_center_coords.x as usize, left_center_coords.y as usize)]
                 == SimGridCellType::Solid
-                && grid.cell_type[right_center_coords.x as usize][right_center_coords.y as usize]
-                    == SimGridCellType::Solid
+                && grid.cell_type[(
+                    right_center_coords.x as usize,
+                    right_center_coords.y as usize,
+                )] == SimGridCellType::Solid
             {
                 continue;
             }
 
-            let mut scaled_velocity_sum = 0.0;
-
-            let mut scaled_influence_sum = 0.0;
-
-            particles.for_each(|(_, particle)| {
-                let influence = find_influence(particle.position, pos, grid.cell_size);
-
-                if influence != 0.0 {
-                    scaled_influence_sum += influence;
-                    scaled_velocity_sum += particle.velocity[0] * influence;
-                }
-            });
+            let scaled_influence_sum = u_influence_sum[(row_index, col_index)];
 
             if scaled_influence_sum == 0.0 {
-                velocity_u[row_index][col_index] = 0.0;
+                grid.velocity_u[(row_index, col_index)] = 0.0;
                 continue;
             }
 
-            let new_velocity = scaled_velocity_sum / scaled_influence_sum;
+            let new_velocity = u_velocity_sum[(row_index, col_index)] / scaled_influence_sum;
 
-            velocity_u[row_index][col_index] = new_velocity;
+            grid.velocity_u[(row_index, col_index)] = new_velocity;
         }
     }
 
@@ -114,52 +222,1391 @@ pub fn particles_to_grid(
             let bottom_center_coords = grid.get_cell_coordinates_from_position(&bottom_center);
             let top_center_coords = grid.get_cell_coordinates_from_position(&top_center);
 
-            if grid.cell_type[bottom_center_coords.x as usize][bottom_center_coords.y as usize]
-                == SimGridCellType::Air
-                && grid.cell_type[top_center_coords.x as usize][top_center_coords.y as usize]
+            if grid.cell_type[(
+                bottom_center_coords.x as usize,
+                bottom_center_coords.y as usize,
+            )] == SimGridCellType::Air
+                && grid.cell_type[(top_center_coords.x as usize, top_center_coords.y as usize)]
                     == SimGridCellType::Air
             {
                 continue;
             }
 
-            if grid.cell_type[bottom_center_coords.x as usize][bottom_center_coords.y as usize]
-                == SimGridCellType::Solid
-                && grid.cell_type[top_center_coords.x as usize][top_center_coords.y as usize]
+            if grid.cell_type[(
+                bottom_center_coords.x as usize,
+                bottom_center_coords.y as usize,
+            )] == SimGridCellType::Solid
+                && grid.cell_type[(top_center_coords.x as usize, top_center_coords.y as usize)]
                     == SimGridCellType::Solid
             {
                 continue;
             }
 
-            let mut scaled_velocity_sum = 0.0;
+            let scaled_influence_sum = v_influence_sum[(row_index, col_index)];
 
-            let mut scaled_influence_sum = 0.0;
+            if scaled_influence_sum == 0.0 {
+                grid.velocity_v[(row_index, col_index)] = 0.0;
+                continue;
+            }
 
-            particles.for_each(|(_, particle)| {
-                let influence = find_influence(particle.position, pos, grid.cell_size);
+            let new_velocity = v_velocity_sum[(row_index, col_index)] / scaled_influence_sum;
+
+            grid.velocity_v[(row_index, col_index)] = new_velocity;
+        }
+    }
 
-                if influence != 0.0 {
-                    scaled_influence_sum += influence;
-                    scaled_velocity_sum += particle.velocity[1] * influence;
+    old_grid
+}
+
+/// Weighted particle velocities and weights summed at each u and v velocity point.
+struct VelocitySums {
+    u_velocity: SimGridArray<f32>,
+    u_influence: SimGridArray<f32>,
+    v_velocity: SimGridArray<f32>,
+    v_influence: SimGridArray<f32>,
+}
+
+impl VelocitySums {
+    /// All zero sums for a grid with this many rows and columns.
+    fn new(rows: usize, cols: usize) -> Self {
+        Self {
+            u_velocity: SimGridArray::new(rows, cols + 1, 0.0),
+            u_influence: SimGridArray::new(rows, cols + 1, 0.0),
+            v_velocity: SimGridArray::new(rows + 1, cols, 0.0),
+            v_influence: SimGridArray::new(rows + 1, cols, 0.0),
+        }
+    }
+
+    /// Add the sums from `other` onto these ones.
+    fn combine(mut self, other: Self) -> Self {
+        let pairs = [
+            (&mut self.u_velocity, &other.u_velocity),
+            (&mut self.u_influence, &other.u_influence),
+            (&mut self.v_velocity, &other.v_velocity),
+            (&mut self.v_influence, &other.v_influence),
+        ];
+        for (sums, other_sums) in pairs {
+            for (sum, other_sum) in sums.iter_mut().zip(other_sums.iter()) {
+                *sum += other_sum;
+            }
+        }
+        self
+    }
+}
+
+/**
+    Add one particle's velocity component, weighted by find_influence(),
+    to every u (horizontal = true) or v velocity point within one cell
+    width of the particle.  Points any further away have no influence.
+*/
+fn scatter_to_velocity_points(
+    grid: &SimGrid,
+    particle_pos: Vec2,
+    particle_velocity: f32,
+    horizontal: bool,
+    velocity_sum: &mut SimGridArray<f32>,
+    influence_sum: &mut SimGridArray<f32>,
+) {
+    let cell_size = grid.cell_size as f32;
+    let grid_height = grid.dimensions.0 as f32 * cell_size;
+    let offset = (grid.cell_size / 2) as f32;
+
+    // Fractional (row, col) of the particle in this velocity array's index
+    // space; see SimGrid::get_velocity_point_pos() for the inverse.
+    let (row, col) = if horizontal {
+        (
+            (grid_height - offset - particle_pos.y) / cell_size,
+            particle_pos.x / cell_size,
+        )
+    } else {
+        (
+            (grid_height - particle_pos.y) / cell_size,
+            (particle_pos.x - offset) / cell_size,
+        )
+    };
+
+    // Only the two nearest points along each axis can be in range; check one
+    // extra on each side so rounding never drops a point.
+    let max_row = velocity_sum.row_count() as i32 - 1;
+    let max_col = velocity_sum.col_count() as i32 - 1;
+    let first_row = i32::max(0, row.floor() as i32 - 1);
+    let last_row = i32::min(max_row, row.floor() as i32 + 2);
+    let first_col = i32::max(0, col.floor() as i32 - 1);
+    let last_col = i32::min(max_col, col.floor() as i32 + 2);
+
+    for row_index in first_row..=last_row {
+        for col_index in first_col..=last_col {
+            let (row_index, col_index) = (row_index as usize, col_index as usize);
+            let pos = grid.get_velocity_point_pos(row_index, col_index, horizontal);
+            let influence = find_influence(particle_pos, pos, grid.cell_size);
+
+            if influence != 0.0 {
+                influence_sum[(row_index, col_index)] += influence;
+                velocity_sum[(row_index, col_index)] += particle_velocity * influence;
+            }
+        }
+    }
+}
+
+/**
+    Add one APIC particle's velocity component to the four u (horizontal =
+    true) or v velocity points around it, weighted bilinearly.  The
+    particle's affine matrix adjusts the velocity it gives to each point.
+*/
+fn scatter_affine_to_velocity_points(
+    grid: &SimGrid,
+    particle: &SimParticle,
+    horizontal: bool,
+    velocity_sum: &mut SimGridArray<f32>,
+    influence_sum: &mut SimGridArray<f32>,
+) {
+    let axis = if horizontal { 0 } else { 1 };
+
+    for (row_index, col_index, influence, _) in
+        bilinear_velocity_points(particle.position, grid, horizontal)
+    {
+        if influence != 0.0 {
+            let pos = grid.get_velocity_point_pos(row_index, col_index, horizontal);
+            let particle_velocity = particle.velocity + particle.affine * (pos - particle.position);
+
+            influence_sum[(row_index, col_index)] += influence;
+            velocity_sum[(row_index, col_index)] += particle_velocity[axis] * influence;
+        }
+    }
+}
+
+/** Find the average material density (relative to water), viscosity and temperature of the
+particles in each grid cell.  Cells without any particles are given the density of water, no
+viscosity and the ambient temperature.  Must be called after the spatial lookup is up to date,
+e.g. right after SimGrid::label_cells(). */
+pub fn update_fluid_properties(
+    constraints: &SimConstraints,
+    grid: &mut SimGrid,
+    particles: &Query<(Entity, &mut SimParticle)>,
+) {
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    let cell_count: usize = rows * cols;
+    grid.fluid_density.reset(rows, cols, 1.0);
+    grid.fluid_viscosity.reset(rows, cols, 0.0);
+    grid.fluid_flow_index.reset(rows, cols, 1.0);
+    grid.fluid_yield_stress.reset(rows, cols, 0.0);
+    grid.fluid_friction.reset(rows, cols, 0.0);
+    grid.temperature
+        .reset(rows, cols, constraints.ambient_temperature);
+
+    for index in 0..cell_count {
+        let mut density_sum: f32 = 0.0;
+        let mut viscosity_sum: f32 = 0.0;
+        let mut flow_index_sum: f32 = 0.0;
+        let mut yield_stress_sum: f32 = 0.0;
+        let mut friction_sum: f32 = 0.0;
+        let mut temperature_sum: f32 = 0.0;
+        let mut particle_count: usize = 0;
+        for particle_id in grid.spatial_lookup.cell(index) {
+            if let Ok((_, particle)) = particles.get(*particle_id) {
+                density_sum += constraints.material_density(particle.material);
+                viscosity_sum += constraints.material_viscosity(particle.material);
+                flow_index_sum += constraints.material_flow_index(particle.material);
+                yield_stress_sum += constraints.material_yield_stress(particle.material);
+                friction_sum += constraints.material_friction(particle.material);
+                temperature_sum += particle.temperature;
+                particle_count += 1;
+            }
+        }
+
+        if particle_count > 0 {
+            grid.fluid_density[index] = density_sum / particle_count as f32;
+            grid.fluid_viscosity[index] = viscosity_sum / particle_count as f32;
+            grid.fluid_flow_index[index] = flow_index_sum / particle_count as f32;
+            grid.fluid_yield_stress[index] = yield_stress_sum / particle_count as f32;
+            grid.fluid_friction[index] = friction_sum / particle_count as f32;
+            grid.temperature[index] = temperature_sum / particle_count as f32;
+        }
+    }
+}
+
+/** Diffuse the grid's velocities to account for viscosity.  Every face between two non-solid
+cells, where at least one of them is fluid, is moved towards its neighbors by
+`timestep * viscosity * laplacian(velocity)`, where the viscosity is `constraints.viscosity` plus
+the average material viscosity of the face's fluid cells.  Non-Newtonian fluids find their
+material viscosity from how fast they are being sheared, using effective_viscosity().  This is
+solved explicitly, so the step is split into as many smaller steps as it needs to stay stable. */
+pub fn apply_viscosity(grid: &mut SimGrid, constraints: &SimConstraints, timestep: f32) {
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    let cell_area: f32 = grid.cell_size as f32 * grid.cell_size as f32;
+
+    // Find the viscosity of every fluid cell from the velocities before any diffusion.
+    let cell_viscosities: Vec<Option<f32>> = (0..rows * cols)
+        .map(|index| {
+            let (row, col) = (index / cols, index % cols);
+            if grid.cell_type[(row, col)] != SimGridCellType::Fluid {
+                return None;
+            }
+            let material_value = |values: &SimGridArray<f32>, default: f32| -> f32 {
+                values.get(row, col).copied().unwrap_or(default)
+            };
+            let consistency: f32 = material_value(&grid.fluid_viscosity, 0.0);
+            let flow_index: f32 = material_value(&grid.fluid_flow_index, 1.0);
+            let yield_stress: f32 = material_value(&grid.fluid_yield_stress, 0.0);
+            if flow_index == 1.0 && yield_stress <= 0.0 {
+                return Some(constraints.viscosity + consistency);
+            }
+
+            let center: Vec2 =
+                grid.get_cell_center_position_from_coordinates(&Vec2::new(row as f32, col as f32));
+            let (_, gradient) = interpolate_velocity_and_gradient(center, grid);
+            let density: f32 = material_value(&grid.fluid_density, 1.0);
+            Some(
+                constraints.viscosity
+                    + effective_viscosity(
+                        consistency,
+                        flow_index,
+                        yield_stress,
+                        density,
+                        strain_rate(gradient),
+                    ),
+            )
+        })
+        .collect();
+
+    // Find the diffusion rate, viscosity * timestep / cell_size^2, of each face that has fluid.
+    let cell_viscosity =
+        |row: usize, col: usize| -> Option<f32> { cell_viscosities[row * cols + col] };
+    let face_rate = |first: (usize, usize), second: (usize, usize)| -> f32 {
+        if grid.get_cell_type_value(first.0, first.1) == 0
+            || grid.get_cell_type_value(second.0, second.1) == 0
+        {
+            return 0.0;
+        }
+        let viscosity: f32 = match (
+            cell_viscosity(first.0, first.1),
+            cell_viscosity(second.0, second.1),
+        ) {
+            (Some(first), Some(second)) => (first + second) / 2.0,
+            (Some(viscosity), None) | (None, Some(viscosity)) => viscosity,
+            (None, None) => 0.0,
+        };
+        f32::max(0.0, viscosity) * timestep / cell_area
+    };
+
+    let mut u_rate: SimGridArray<f32> = SimGridArray::new(rows, cols + 1, 0.0);
+    let mut v_rate: SimGridArray<f32> = SimGridArray::new(rows + 1, cols, 0.0);
+    let mut max_rate: f32 = 0.0;
+    for (row, row_rates) in u_rate.iter_rows_mut().enumerate() {
+        for (col, rate) in row_rates.iter_mut().enumerate().take(cols).skip(1) {
+            *rate = face_rate((row, col - 1), (row, col));
+            max_rate = f32::max(max_rate, *rate);
+        }
+    }
+    for (row, row_rates) in v_rate.iter_rows_mut().enumerate().take(rows).skip(1) {
+        for (col, rate) in row_rates.iter_mut().enumerate() {
+            *rate = face_rate((row - 1, col), (row, col));
+            max_rate = f32::max(max_rate, *rate);
+        }
+    }
+    if max_rate <= 0.0 || !max_rate.is_finite() {
+        return;
+    }
+
+    // Explicit diffusion is only stable while rate <= 0.25, so split the step up as needed.
+    let iterations: usize = f32::ceil(max_rate / 0.25) as usize;
+    for _ in 0..iterations {
+        grid.velocity_u = diffuse_velocities(&grid.velocity_u, &u_rate, iterations as f32);
+        grid.velocity_v = diffuse_velocities(&grid.velocity_v, &v_rate, iterations as f32);
+    }
+}
+
+/** Kinematic viscosity of a fluid being sheared at `strain_rate` (per second), following the
+Herschel-Bulkley model: `consistency * strain_rate^(flow_index - 1)`, plus
+`yield_stress / (density * strain_rate)`.  A flow index of 1 and no yield stress is an ordinary
+(Newtonian) fluid, a flow index below 1 thins out and above 1 thickens up the harder it is sheared,
+and a yield stress makes a Bingham plastic that hardly moves until pushed hard enough.  Fluid that
+is barely sheared would be infinitely thick, so this is capped at MAX_EFFECTIVE_VISCOSITY. */
+pub fn effective_viscosity(
+    consistency: f32,
+    flow_index: f32,
+    yield_stress: f32,
+    density: f32,
+    strain_rate: f32,
+) -> f32 {
+    let strain_rate: f32 = f32::max(strain_rate, 0.0001);
+    let mut viscosity: f32 = consistency * strain_rate.powf(flow_index - 1.0);
+    if yield_stress > 0.0 {
+        viscosity += yield_stress / (density * strain_rate);
+    }
+
+    f32::clamp(viscosity, 0.0, MAX_EFFECTIVE_VISCOSITY)
+}
+
+/** How fast a fluid is being sheared (per second), from its velocity gradient: the magnitude
+`sqrt(2 D:D)` of the strain rate tensor `D`, the symmetric part of the gradient. */
+pub fn strain_rate(gradient: Mat2) -> f32 {
+    let (du_dx, dv_dx) = (gradient.x_axis.x, gradient.x_axis.y);
+    let (du_dy, dv_dy) = (gradient.y_axis.x, gradient.y_axis.y);
+    let shear: f32 = du_dy + dv_dx;
+
+    f32::sqrt(2.0 * (du_dx * du_dx + dv_dy * dv_dy) + shear * shear)
+}
+
+/** One explicit diffusion step over a velocity array, where each point moves by `rate / divisor`
+times the 5-point laplacian.  Neighbors that are out of bounds or have no velocity are treated as
+having the same velocity as the point itself. */
+fn diffuse_velocities(
+    velocities: &SimGridArray<f32>,
+    rate: &SimGridArray<f32>,
+    divisor: f32,
+) -> SimGridArray<f32> {
+    let mut diffused: SimGridArray<f32> = velocities.clone();
+    let (rows, cols) = (velocities.row_count(), velocities.col_count());
+
+    for row in 0..rows {
+        for col in 0..cols {
+            let velocity: f32 = velocities[(row, col)];
+            if rate[(row, col)] == 0.0 || velocity == f32::MIN {
+                continue;
+            }
+
+            let mut laplacian: f32 = 0.0;
+            let neighbors: [(usize, usize); 4] = [
+                (row, usize::wrapping_sub(col, 1)),
+                (row, col + 1),
+                (usize::wrapping_sub(row, 1), col),
+                (row + 1, col),
+            ];
+            for (neighbor_row, neighbor_col) in neighbors {
+                if neighbor_row >= rows || neighbor_col >= cols {
+                    continue;
                 }
-            });
+                let neighbor: f32 = velocities[(neighbor_row, neighbor_col)];
+                if neighbor != f32::MIN {
+                    laplacian += neighbor - velocity;
+                }
+            }
 
-            if scaled_influence_sum == 0.0 {
-                velocity_v[row_index][col_index] = 0.0;
+            diffused[(row, col)] = velocity + rate[(row, col)] / divisor * laplacian;
+        }
+    }
+
+    diffused
+}
+
+/** Let granular material (like sand) resist sliding with Coulomb friction.  Neighboring faces of
+sand cells rub against each other, and each step friction can take away up to
+`friction * pressure * timestep / (density * cell_size)` of the velocity they slide past each other
+with; sand that is pressed together hard enough stops sliding entirely, so it piles up at its angle
+of repose while loose sand at the surface still flows.  Faces next to walls rub against the wall,
+so sand grips walls instead of sliding along them.  Must run after
+make_grid_velocities_incompressible(), which finds the pressure; returns whether there was any
+sand to rub, since the velocities then need to be made incompressible again. */
+pub fn apply_granular_friction(
+    grid: &mut SimGrid,
+    constraints: &SimConstraints,
+    timestep: f32,
+) -> bool {
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    if grid.fluid_friction.len() != rows * cols
+        || grid.fluid_friction.iter().all(|friction| *friction <= 0.0)
+    {
+        return false;
+    }
+    let cell_size: f32 = grid.cell_size as f32;
+
+    // The most sliding velocity friction can take away inside each sand cell this step.
+    let mut cell_grip: SimGridArray<f32> = SimGridArray::new(rows, cols, 0.0);
+    for row in 0..rows {
+        for col in 0..cols {
+            let friction: f32 = grid.fluid_friction[(row, col)];
+            if grid.cell_type[(row, col)] != SimGridCellType::Fluid || friction <= 0.0 {
                 continue;
             }
 
-            let new_velocity = scaled_velocity_sum / scaled_influence_sum;
+            // Even at the surface, grains are pressed together by their own weight.
+            let density: f32 = grid.fluid_density.get(row, col).copied().unwrap_or(1.0);
+            let weight: f32 = density * constraints.gravity.length() * cell_size / 2.0;
+            let pressure: f32 = f32::max(grid.cell_center[(row, col)], weight);
+            cell_grip[(row, col)] = friction * pressure * timestep / (density * cell_size);
+        }
+    }
+
+    // Faces between two open cells grip with the average of both cells.
+    let face_grip = |first: (usize, usize), second: (usize, usize)| -> f32 {
+        if grid.get_cell_type_value(first.0, first.1) == 0
+            || grid.get_cell_type_value(second.0, second.1) == 0
+        {
+            return 0.0;
+        }
+        (cell_grip[first] + cell_grip[second]) / 2.0
+    };
+    let mut u_grip: SimGridArray<f32> = SimGridArray::new(rows, cols + 1, 0.0);
+    let mut v_grip: SimGridArray<f32> = SimGridArray::new(rows + 1, cols, 0.0);
+    for (row, row_grips) in u_grip.iter_rows_mut().enumerate() {
+        for (col, grip) in row_grips.iter_mut().enumerate().take(cols).skip(1) {
+            *grip = face_grip((row, col - 1), (row, col));
+        }
+    }
+    for (row, row_grips) in v_grip.iter_rows_mut().enumerate().take(rows).skip(1) {
+        for (col, grip) in row_grips.iter_mut().enumerate() {
+            *grip = face_grip((row - 1, col), (row, col));
+        }
+    }
 
-            velocity_v[row_index][col_index] = new_velocity;
+    /* Faces touching a solid cell are held still, and so are faces on an edge of the grid that is
+    a solid wall; sand flows freely out of open edges. */
+    let is_wall = |row: usize, col: usize, edge: SimSurfaceDirection| -> bool {
+        if row >= rows || col >= cols {
+            return grid.get_boundary(edge) == SimBoundary::Solid;
         }
+        grid.cell_type[(row, col)] == SimGridCellType::Solid
+    };
+    let u_walls: SimGridArray<bool> = SimGridArray::from_rows(
+        (0..rows)
+            .map(|row| {
+                (0..=cols)
+                    .map(|col| {
+                        is_wall(row, usize::wrapping_sub(col, 1), SimSurfaceDirection::West)
+                            || is_wall(row, col, SimSurfaceDirection::East)
+                    })
+                    .collect()
+            })
+            .collect(),
+    );
+    let v_walls: SimGridArray<bool> = SimGridArray::from_rows(
+        (0..=rows)
+            .map(|row| {
+                (0..cols)
+                    .map(|col| {
+                        is_wall(usize::wrapping_sub(row, 1), col, SimSurfaceDirection::North)
+                            || is_wall(row, col, SimSurfaceDirection::South)
+                    })
+                    .collect()
+            })
+            .collect(),
+    );
+
+    for _ in 0..GRANULAR_FRICTION_PASSES {
+        rub_velocities(&mut grid.velocity_u, &u_grip, &u_walls);
+        rub_velocities(&mut grid.velocity_v, &v_grip, &v_walls);
     }
+    true
+}
 
-    let old_grid = grid.clone();
+/** One pass of friction for apply_granular_friction().  Every pair of neighboring velocities slides
+towards each other by up to their average grip, and velocities next to a wall slide towards zero
+by up to their own grip. */
+fn rub_velocities(
+    velocities: &mut SimGridArray<f32>,
+    grip: &SimGridArray<f32>,
+    walls: &SimGridArray<bool>,
+) {
+    let (rows, cols) = (velocities.row_count(), velocities.col_count());
+
+    for row in 0..rows {
+        for col in 0..cols {
+            if grip[(row, col)] <= 0.0 || velocities[(row, col)] == f32::MIN {
+                continue;
+            }
+
+            // Rub against any walls around this face.
+            let neighbors: [(usize, usize); 4] = [
+                (row, usize::wrapping_sub(col, 1)),
+                (row, col + 1),
+                (usize::wrapping_sub(row, 1), col),
+                (row + 1, col),
+            ];
+            for (neighbor_row, neighbor_col) in neighbors {
+                if neighbor_row < rows && neighbor_col < cols && walls[(neighbor_row, neighbor_col)]
+                {
+                    let velocity: f32 = velocities[(row, col)];
+                    velocities[(row, col)] -=
+                        velocity.signum() * velocity.abs().min(grip[(row, col)]);
+                }
+            }
+
+            // Rub against the faces to the right and below, so that each pair is only done once.
+            for (neighbor_row, neighbor_col) in [(row, col + 1), (row + 1, col)] {
+                if neighbor_row >= rows
+                    || neighbor_col >= cols
+                    || walls[(neighbor_row, neighbor_col)]
+                    || velocities[(neighbor_row, neighbor_col)] == f32::MIN
+                {
+                    continue;
+                }
+                let pair_grip: f32 = (grip[(row, col)] + grip[(neighbor_row, neighbor_col)]) / 2.0;
+                let sliding: f32 =
+                    velocities[(row, col)] - velocities[(neighbor_row, neighbor_col)];
+                let change: f32 = sliding.signum() * sliding.abs().min(pair_grip) / 2.0;
+                velocities[(row, col)] -= change;
+                velocities[(neighbor_row, neighbor_col)] += change;
+            }
+        }
+    }
+}
+
+/** Pull the fluid's surface towards a smaller area.  The surface is found from a smoothed fraction
+of fluid in each cell, and every face along it is accelerated by
+`coefficient * curvature * gradient(fraction) / density`, so that convex blobs are squeezed and
+small droplets bead up. */
+pub fn apply_surface_tension(grid: &mut SimGrid, constraints: &SimConstraints, timestep: f32) {
+    if constraints.surface_tension <= 0.0 {
+        return;
+    }
+
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    let cell_size: f32 = grid.cell_size as f32;
+    let fraction: SimGridArray<f32> = find_fluid_fraction(grid);
+    let curvature: SimGridArray<f32> = find_surface_curvature(grid, &fraction);
+
+    // Only faces between two open cells, where at least one of them holds fluid, are pushed.
+    let is_surface_face = |first: (usize, usize), second: (usize, usize)| -> bool {
+        grid.get_cell_type_value(first.0, first.1) != 0
+            && grid.get_cell_type_value(second.0, second.1) != 0
+            && (grid.cell_type[(first.0, first.1)] == SimGridCellType::Fluid
+                || grid.cell_type[(second.0, second.1)] == SimGridCellType::Fluid)
+    };
+    let face_acceleration = |first: (usize, usize), second: (usize, usize)| -> f32 {
+        let gradient: f32 =
+            (fraction[(second.0, second.1)] - fraction[(first.0, first.1)]) / cell_size;
+        let face_curvature: f32 =
+            (curvature[(first.0, first.1)] + curvature[(second.0, second.1)]) / 2.0;
+        constraints.surface_tension * face_curvature * gradient
+            / face_fluid_density(grid, first, second)
+    };
+
+    let mut velocity_u: SimGridArray<f32> = grid.velocity_u.clone();
+    let mut velocity_v: SimGridArray<f32> = grid.velocity_v.clone();
+    for (row, row_velocities) in velocity_u.iter_rows_mut().enumerate() {
+        for (col, velocity) in row_velocities.iter_mut().enumerate().take(cols).skip(1) {
+            let (left, right) = ((row, col - 1), (row, col));
+            if *velocity != f32::MIN && is_surface_face(left, right) {
+                *velocity += timestep * face_acceleration(left, right);
+            }
+        }
+    }
+    for (row, row_velocities) in velocity_v.iter_rows_mut().enumerate().take(rows).skip(1) {
+        for (col, velocity) in row_velocities.iter_mut().enumerate() {
+            // Positive v points up, towards the cell in the row above.
+            let (below, above) = ((row, col), (row - 1, col));
+            if *velocity != f32::MIN && is_surface_face(below, above) {
+                *velocity += timestep * face_acceleration(below, above);
+            }
+        }
+    }
 
     grid.velocity_u = velocity_u;
     grid.velocity_v = velocity_v;
+}
 
-    old_grid
+/** Find how much of each cell is fluid, by smoothing out the cell labels so that the surface
+spreads over a few cells.  Solid cells are left at 0.0 and ignored while smoothing. */
+fn find_fluid_fraction(grid: &SimGrid) -> SimGridArray<f32> {
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    let mut fraction: SimGridArray<f32> = SimGridArray::new(rows, cols, 0.0);
+    for (row, row_fractions) in fraction.iter_rows_mut().enumerate() {
+        for (col, cell_fraction) in row_fractions.iter_mut().enumerate() {
+            if grid.cell_type[(row, col)] == SimGridCellType::Fluid {
+                *cell_fraction = 1.0;
+            }
+        }
+    }
+
+    for _ in 0..2 {
+        let mut smoothed: SimGridArray<f32> = fraction.clone();
+        for (row, row_fractions) in smoothed.iter_rows_mut().enumerate() {
+            for (col, cell_fraction) in row_fractions.iter_mut().enumerate() {
+                if grid.cell_type[(row, col)] == SimGridCellType::Solid {
+                    continue;
+                }
+
+                // Average this cell with all of its open neighbors.
+                let (first_row, last_row) = (row.saturating_sub(1), usize::min(row + 2, rows));
+                let (first_col, last_col) = (col.saturating_sub(1), usize::min(col + 2, cols));
+                let mut fraction_sum: f32 = 0.0;
+                let mut cell_count: f32 = 0.0;
+                for neighbor_row in first_row..last_row {
+                    let neighbor_fractions: &[f32] = fraction.row(neighbor_row);
+                    let neighbor_types: &[SimGridCellType] = grid.cell_type.row(neighbor_row);
+                    for (neighbor_fraction, neighbor_type) in neighbor_fractions
+                        [first_col..last_col]
+                        .iter()
+                        .zip(&neighbor_types[first_col..last_col])
+                    {
+                        if *neighbor_type != SimGridCellType::Solid {
+                            fraction_sum += neighbor_fraction;
+                            cell_count += 1.0;
+                        }
+                    }
+                }
+                *cell_fraction = fraction_sum / cell_count;
+            }
+        }
+        fraction = smoothed;
+    }
+
+    fraction
+}
+
+/** Find the curvature of the fluid's surface at each cell center, in 1 / world units.  Convex
+fluid has a positive curvature, and cells away from the surface have none.  Neighbors that are
+solid or out of bounds are treated like the cell itself, so walls do not bend the surface. */
+fn find_surface_curvature(grid: &SimGrid, fraction: &SimGridArray<f32>) -> SimGridArray<f32> {
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    let cell_size: f32 = grid.cell_size as f32;
+    let neighbor =
+        |row: usize, col: usize, row_offset: isize, col_offset: isize| -> (usize, usize) {
+            let neighbor_row: usize = row.wrapping_add_signed(row_offset);
+            let neighbor_col: usize = col.wrapping_add_signed(col_offset);
+            if neighbor_row >= rows
+                || neighbor_col >= cols
+                || grid.cell_type[(neighbor_row, neighbor_col)] == SimGridCellType::Solid
+            {
+                return (row, col);
+            }
+            (neighbor_row, neighbor_col)
+        };
+
+    // Unit normals pointing into the fluid, with x to the right and y up.
+    let mut normals: SimGridArray<Vec2> = SimGridArray::new(rows, cols, Vec2::ZERO);
+    for (row, row_normals) in normals.iter_rows_mut().enumerate() {
+        for (col, normal) in row_normals.iter_mut().enumerate() {
+            let (left, right) = (neighbor(row, col, 0, -1), neighbor(row, col, 0, 1));
+            let (up, down) = (neighbor(row, col, -1, 0), neighbor(row, col, 1, 0));
+            let gradient: Vec2 = Vec2::new(
+                fraction[(right.0, right.1)] - fraction[(left.0, left.1)],
+                fraction[(up.0, up.1)] - fraction[(down.0, down.1)],
+            ) / (2.0 * cell_size);
+            if gradient.length() > 0.01 / cell_size {
+                *normal = gradient.normalize();
+            }
+        }
+    }
+
+    let mut curvature: SimGridArray<f32> = SimGridArray::new(rows, cols, 0.0);
+    for (row, row_curvatures) in curvature.iter_rows_mut().enumerate() {
+        for (col, cell_curvature) in row_curvatures.iter_mut().enumerate() {
+            if normals[(row, col)] == Vec2::ZERO {
+                continue;
+            }
+            let (left, right) = (neighbor(row, col, 0, -1), neighbor(row, col, 0, 1));
+            let (up, down) = (neighbor(row, col, -1, 0), neighbor(row, col, 1, 0));
+            let divergence: f32 = (normals[(right.0, right.1)].x - normals[(left.0, left.1)].x
+                + normals[(up.0, up.1)].y
+                - normals[(down.0, down.1)].y)
+                / (2.0 * cell_size);
+            *cell_curvature = -divergence;
+        }
+    }
+
+    curvature
+}
+
+/** Put back some of the swirling motion that grid transfers smear out.  The vorticity (curl) of
+the fluid's velocity is found at each cell center, and every fluid cell is pushed around the
+nearest peak in vorticity by `strength * cell_size * (N x vorticity)`, where N points towards that
+peak.  See Fedkiw et al., "Visual Simulation of Smoke" (2001). */
+pub fn apply_vorticity_confinement(
+    grid: &mut SimGrid,
+    constraints: &SimConstraints,
+    timestep: f32,
+) {
+    if constraints.vorticity_confinement <= 0.0 {
+        return;
+    }
+
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    let cell_size: f32 = grid.cell_size as f32;
+    let is_fluid = |row: usize, col: usize| -> bool {
+        row < rows && col < cols && grid.cell_type[(row, col)] == SimGridCellType::Fluid
+    };
+    let has_fluid_neighbors = |row: usize, col: usize| -> bool {
+        is_fluid(row, col)
+            && is_fluid(row.wrapping_sub(1), col)
+            && is_fluid(row + 1, col)
+            && is_fluid(row, col.wrapping_sub(1))
+            && is_fluid(row, col + 1)
+    };
+
+    // Velocity at each cell center, with x to the right and y up.
+    let mut center_velocity: SimGridArray<Vec2> = SimGridArray::new(rows, cols, Vec2::ZERO);
+    for (row, row_velocities) in center_velocity.iter_rows_mut().enumerate() {
+        for (col, velocity) in row_velocities.iter_mut().enumerate() {
+            if is_fluid(row, col) {
+                *velocity = Vec2::new(
+                    (grid.velocity_u[(row, col)] + grid.velocity_u[(row, col + 1)]) / 2.0,
+                    (grid.velocity_v[(row, col)] + grid.velocity_v[(row + 1, col)]) / 2.0,
+                );
+            }
+        }
+    }
+
+    // Vorticity is only found where the central differences stay inside the fluid.
+    let mut vorticity: SimGridArray<f32> = SimGridArray::new(rows, cols, 0.0);
+    for (row, row_vorticities) in vorticity.iter_rows_mut().enumerate() {
+        for (col, cell_vorticity) in row_vorticities.iter_mut().enumerate() {
+            if has_fluid_neighbors(row, col) {
+                let dv_dx: f32 =
+                    center_velocity[(row, col + 1)].y - center_velocity[(row, col - 1)].y;
+                let du_dy: f32 =
+                    center_velocity[(row - 1, col)].x - center_velocity[(row + 1, col)].x;
+                *cell_vorticity = (dv_dx - du_dy) / (2.0 * cell_size);
+            }
+        }
+    }
+
+    // Confinement force at each cell center.
+    let mut force: SimGridArray<Vec2> = SimGridArray::new(rows, cols, Vec2::ZERO);
+    for (row, row_forces) in force.iter_rows_mut().enumerate() {
+        for (col, cell_force) in row_forces.iter_mut().enumerate() {
+            if !has_fluid_neighbors(row, col) {
+                continue;
+            }
+            let towards_peak: Vec2 = Vec2::new(
+                vorticity[(row, col + 1)].abs() - vorticity[(row, col - 1)].abs(),
+                vorticity[(row - 1, col)].abs() - vorticity[(row + 1, col)].abs(),
+            );
+            if towards_peak.length() <= f32::EPSILON {
+                continue;
+            }
+            let towards_peak: Vec2 = towards_peak.normalize();
+            *cell_force = constraints.vorticity_confinement
+                * cell_size
+                * Vec2::new(towards_peak.y, -towards_peak.x)
+                * vorticity[(row, col)];
+        }
+    }
+
+    // Push each face between two fluid cells by the average force of both cells.
+    let mut velocity_u: SimGridArray<f32> = grid.velocity_u.clone();
+    let mut velocity_v: SimGridArray<f32> = grid.velocity_v.clone();
+    for (row, row_velocities) in velocity_u.iter_rows_mut().enumerate() {
+        for (col, velocity) in row_velocities.iter_mut().enumerate().take(cols).skip(1) {
+            if *velocity != f32::MIN && is_fluid(row, col - 1) && is_fluid(row, col) {
+                *velocity += timestep * (force[(row, col - 1)].x + force[(row, col)].x) / 2.0;
+            }
+        }
+    }
+    for (row, row_velocities) in velocity_v.iter_rows_mut().enumerate().take(rows).skip(1) {
+        for (col, velocity) in row_velocities.iter_mut().enumerate() {
+            if *velocity != f32::MIN && is_fluid(row - 1, col) && is_fluid(row, col) {
+                *velocity += timestep * (force[(row - 1, col)].y + force[(row, col)].y) / 2.0;
+            }
+        }
+    }
+
+    grid.velocity_u = velocity_u;
+    grid.velocity_v = velocity_v;
+}
+
+/** Spread heat between neighboring fluid cells by `thermal_diffusivity`.  Heater and cooler cells
+hold the fluid next to them at their own temperature, while air and other solids insulate it.
+This is solved explicitly, so the step is split into as many smaller steps as it needs to stay
+stable. */
+pub fn diffuse_temperature(grid: &mut SimGrid, constraints: &SimConstraints, timestep: f32) {
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    let cell_area: f32 = grid.cell_size as f32 * grid.cell_size as f32;
+    let rate: f32 = f32::max(0.0, constraints.thermal_diffusivity) * timestep / cell_area;
+    if rate <= 0.0 || !rate.is_finite() || grid.temperature.len() < rows * cols {
+        return;
+    }
+
+    // Temperature that flows into a cell from each neighbor, if heat can flow from it at all.
+    let is_fluid = |row: usize, col: usize| -> bool {
+        grid.get_cell_type_value(row, col) != 0
+            && grid.cell_type[(row, col)] == SimGridCellType::Fluid
+    };
+    let source_temperature = |row: usize, col: usize| -> Option<f32> {
+        match grid.get_heat_source(row, col) {
+            SimHeatSource::Heater => Some(constraints.heater_temperature),
+            SimHeatSource::Cooler => Some(constraints.cooler_temperature),
+            SimHeatSource::None => None,
+        }
+    };
+
+    // Explicit diffusion is only stable while rate <= 0.25, so split the step up as needed.
+    let iterations: usize = f32::ceil(rate / 0.25) as usize;
+    let mut temperature: SimGridArray<f32> = grid.temperature.clone();
+    for _ in 0..iterations {
+        let mut diffused: SimGridArray<f32> = temperature.clone();
+        for row in 0..rows {
+            for col in 0..cols {
+                if !is_fluid(row, col) {
+                    continue;
+                }
+
+                let cell_temperature: f32 = temperature[(row, col)];
+                let mut heat_flow: f32 = 0.0;
+                let neighbors: [(usize, usize); 4] = [
+                    (row, usize::wrapping_sub(col, 1)),
+                    (row, col + 1),
+                    (usize::wrapping_sub(row, 1), col),
+                    (row + 1, col),
+                ];
+                for (neighbor_row, neighbor_col) in neighbors {
+                    let neighbor_temperature: Option<f32> = if is_fluid(neighbor_row, neighbor_col)
+                    {
+                        Some(temperature[(neighbor_row, neighbor_col)])
+                    } else {
+                        source_temperature(neighbor_row, neighbor_col)
+                    };
+                    if let Some(neighbor_temperature) = neighbor_temperature {
+                        heat_flow += neighbor_temperature - cell_temperature;
+                    }
+                }
+
+                diffused[(row, col)] = cell_temperature + rate / iterations as f32 * heat_flow;
+            }
+        }
+        temperature = diffused;
+    }
+
+    grid.temperature = temperature;
+}
+
+/** Push fluid that is warmer than the ambient temperature against gravity, and cooler fluid along
+with it, by `thermal_expansion * (ambient_temperature - temperature) * gravity` (the Boussinesq
+approximation). */
+pub fn apply_buoyancy(grid: &mut SimGrid, constraints: &SimConstraints, timestep: f32) {
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    if constraints.thermal_expansion == 0.0 || grid.temperature.len() < rows * cols {
+        return;
+    }
+
+    // Temperature of the fluid at a face between two open cells, if there is any fluid there.
+    let cell_temperature = |(row, col): (usize, usize)| -> Option<f32> {
+        if grid.get_cell_type_value(row, col) == 0
+            || grid.cell_type[(row, col)] != SimGridCellType::Fluid
+        {
+            return None;
+        }
+        Some(grid.temperature[(row, col)])
+    };
+    let face_acceleration = |first: (usize, usize), second: (usize, usize)| -> Option<Vec2> {
+        if grid.get_cell_type_value(first.0, first.1) == 0
+            || grid.get_cell_type_value(second.0, second.1) == 0
+        {
+            return None;
+        }
+        let temperature: f32 = match (cell_temperature(first), cell_temperature(second)) {
+            (Some(first), Some(second)) => (first + second) / 2.0,
+            (Some(temperature), None) | (None, Some(temperature)) => temperature,
+            (None, None) => return None,
+        };
+        Some(
+            constraints.thermal_expansion
+                * (constraints.ambient_temperature - temperature)
+                * constraints.gravity,
+        )
+    };
+
+    let mut velocity_u: SimGridArray<f32> = grid.velocity_u.clone();
+    let mut velocity_v: SimGridArray<f32> = grid.velocity_v.clone();
+    for (row, row_velocities) in velocity_u.iter_rows_mut().enumerate() {
+        for (col, velocity) in row_velocities.iter_mut().enumerate().take(cols).skip(1) {
+            if *velocity == f32::MIN {
+                continue;
+            }
+            if let Some(acceleration) = face_acceleration((row, col - 1), (row, col)) {
+                *velocity += timestep * acceleration.x;
+            }
+        }
+    }
+    for (row, row_velocities) in velocity_v.iter_rows_mut().enumerate().take(rows).skip(1) {
+        for (col, velocity) in row_velocities.iter_mut().enumerate() {
+            if *velocity == f32::MIN {
+                continue;
+            }
+            // Positive v points up, just like the y axis of gravity.
+            if let Some(acceleration) = face_acceleration((row - 1, col), (row, col)) {
+                *velocity += timestep * acceleration.y;
+            }
+        }
+    }
+
+    grid.velocity_u = velocity_u;
+    grid.velocity_v = velocity_v;
+}
+
+/** Carry the grid's velocities, smoke density and temperature along with the flow.  Each sample
+point is traced backwards through the velocity field (with a midpoint step), and takes whatever
+value is found where it started (semi-Lagrangian advection).  Grid velocities must all be set,
+i.e. not f32::MIN. */
+pub fn advect_smoke(grid: &mut SimGrid, timestep: f32) {
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    let grid_size: Vec2 = Vec2::new(cols as f32, rows as f32) * grid.cell_size as f32;
+    let velocity_at =
+        |position: Vec2| -> Vec2 { interpolate_velocity_and_gradient(position, grid).0 };
+    let trace_back = |position: Vec2| -> Vec2 {
+        let midpoint: Vec2 = position - 0.5 * timestep * velocity_at(position);
+        (position - timestep * velocity_at(midpoint)).clamp(Vec2::ZERO, grid_size)
+    };
+
+    let mut velocity_u: SimGridArray<f32> = grid.velocity_u.clone();
+    for (row, row_velocities) in velocity_u.iter_rows_mut().enumerate() {
+        for (col, velocity) in row_velocities.iter_mut().enumerate() {
+            let start: Vec2 = trace_back(grid.get_velocity_point_pos(row, col, true));
+            *velocity = velocity_at(start).x;
+        }
+    }
+    let mut velocity_v: SimGridArray<f32> = grid.velocity_v.clone();
+    for (row, row_velocities) in velocity_v.iter_rows_mut().enumerate() {
+        for (col, velocity) in row_velocities.iter_mut().enumerate() {
+            let start: Vec2 = trace_back(grid.get_velocity_point_pos(row, col, false));
+            *velocity = velocity_at(start).y;
+        }
+    }
+
+    let mut smoke_density: SimGridArray<f32> = SimGridArray::new(rows, cols, 0.0);
+    let mut temperature: SimGridArray<f32> = SimGridArray::new(rows, cols, 0.0);
+    for row in 0..rows {
+        for col in 0..cols {
+            let center: Vec2 =
+                grid.get_cell_center_position_from_coordinates(&Vec2::new(row as f32, col as f32));
+            let start: Vec2 = trace_back(center);
+            smoke_density[(row, col)] = interpolate_cell_value(start, grid, &grid.smoke_density);
+            temperature[(row, col)] = interpolate_cell_value(start, grid, &grid.temperature);
+        }
+    }
+
+    grid.velocity_u = velocity_u;
+    grid.velocity_v = velocity_v;
+    grid.smoke_density = smoke_density;
+    grid.temperature = temperature;
+}
+
+/** Stop any flow into or out of solid cells and the edges of the grid, and give every face that
+has no velocity yet a velocity of zero. */
+pub fn close_solid_faces(grid: &mut SimGrid) {
+    let mut velocity_u: SimGridArray<f32> = grid.velocity_u.clone();
+    for (row, row_velocities) in velocity_u.iter_rows_mut().enumerate() {
+        for (col, velocity) in row_velocities.iter_mut().enumerate() {
+            if *velocity == f32::MIN
+                || col == 0
+                || grid.get_cell_type_value(row, col - 1) == 0
+                || grid.get_cell_type_value(row, col) == 0
+            {
+                *velocity = 0.0;
+            }
+        }
+    }
+    let mut velocity_v: SimGridArray<f32> = grid.velocity_v.clone();
+    for (row, row_velocities) in velocity_v.iter_rows_mut().enumerate() {
+        for (col, velocity) in row_velocities.iter_mut().enumerate() {
+            if *velocity == f32::MIN
+                || row == 0
+                || grid.get_cell_type_value(row - 1, col) == 0
+                || grid.get_cell_type_value(row, col) == 0
+            {
+                *velocity = 0.0;
+            }
+        }
+    }
+
+    grid.velocity_u = velocity_u;
+    grid.velocity_v = velocity_v;
+    apply_boundary_velocities(grid);
+}
+
+/** Set the velocities on the faces along the grid's open edges: inflow edges push fluid in at
+their velocity, outflow edges let it leave at whatever speed it reaches them with, and periodic
+edges share one velocity with the face on the opposite edge.  Solid edges are left alone, just
+like any other face next to a solid. */
+pub fn apply_boundary_velocities(grid: &mut SimGrid) {
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    let boundaries = grid.boundaries;
+
+    // Faces that have no velocity yet shouldn't drag down the one on the other side.
+    let share = |first: f32, second: f32| -> f32 {
+        match (first == f32::MIN, second == f32::MIN) {
+            (false, false) => (first + second) / 2.0,
+            (true, _) => second,
+            (_, true) => first,
+        }
+    };
+
+    for row_velocities in grid.velocity_u.iter_rows_mut() {
+        match boundaries.west {
+            SimBoundary::Inflow(velocity) => row_velocities[0] = velocity.x,
+            SimBoundary::Outflow => row_velocities[0] = row_velocities[1],
+            SimBoundary::Periodic => {
+                let shared: f32 = share(row_velocities[0], row_velocities[cols]);
+                row_velocities[0] = shared;
+                row_velocities[cols] = shared;
+            }
+            SimBoundary::Solid => {}
+        }
+        match boundaries.east {
+            SimBoundary::Inflow(velocity) => row_velocities[cols] = velocity.x,
+            SimBoundary::Outflow => row_velocities[cols] = row_velocities[cols - 1],
+            SimBoundary::Periodic | SimBoundary::Solid => {}
+        }
+    }
+
+    for col in 0..cols {
+        match boundaries.north {
+            SimBoundary::Inflow(velocity) => grid.velocity_v[(0, col)] = velocity.y,
+            SimBoundary::Outflow => grid.velocity_v[(0, col)] = grid.velocity_v[(1, col)],
+            SimBoundary::Periodic => {
+                let shared: f32 = share(grid.velocity_v[(0, col)], grid.velocity_v[(rows, col)]);
+                grid.velocity_v[(0, col)] = shared;
+                grid.velocity_v[(rows, col)] = shared;
+            }
+            SimBoundary::Solid => {}
+        }
+        match boundaries.south {
+            SimBoundary::Inflow(velocity) => grid.velocity_v[(rows, col)] = velocity.y,
+            SimBoundary::Outflow => grid.velocity_v[(rows, col)] = grid.velocity_v[(rows - 1, col)],
+            SimBoundary::Periodic | SimBoundary::Solid => {}
+        }
+    }
+}
+
+/// Move every obstacle along by its velocity, and spin it by its angular velocity.
+pub fn move_obstacles(obstacles: &mut Query<(Entity, &mut SimObstacle)>, timestep: f32) {
+    for (_, mut obstacle) in obstacles.iter_mut() {
+        let velocity: Vec2 = obstacle.velocity;
+        let angular_velocity: f32 = obstacle.angular_velocity;
+        obstacle.position += velocity * timestep;
+        obstacle.rotation += angular_velocity * timestep;
+    }
+}
+
+/** Turn every cell whose center is inside of an obstacle into a solid, and turn the cells that
+obstacles have moved out of back into air.  Walls that an obstacle passes over stay walls. */
+pub fn rasterize_obstacles(grid: &mut SimGrid, obstacles: &[SimObstacle]) {
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    for lookup_index in std::mem::take(&mut grid.obstacle_cells) {
+        if lookup_index < rows * cols {
+            grid.cell_type[(lookup_index / cols, lookup_index % cols)] = SimGridCellType::Air;
+        }
+    }
+
+    if obstacles.is_empty() {
+        return;
+    }
+    for row in 0..rows {
+        for col in 0..cols {
+            if grid.cell_type[(row, col)] == SimGridCellType::Solid {
+                continue;
+            }
+
+            let center: Vec2 =
+                grid.get_cell_center_position_from_coordinates(&Vec2::new(row as f32, col as f32));
+            if obstacles.iter().any(|obstacle| obstacle.contains(center)) {
+                grid.cell_type[(row, col)] = SimGridCellType::Solid;
+                grid.obstacle_cells.push(row * cols + col);
+            }
+        }
+    }
+}
+
+/** Give every face between an obstacle's cell and an open cell the obstacle's own velocity at
+that face.  The pressure solve leaves faces touching solids alone, so this pushes fluid out of the
+way of moving obstacles and drags fluid along with spinning ones. */
+pub fn apply_obstacle_velocities(grid: &mut SimGrid, obstacles: &[SimObstacle]) {
+    if obstacles.is_empty() || grid.obstacle_cells.is_empty() {
+        return;
+    }
+
+    // Find which obstacle made each obstacle cell solid.
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    let mut cell_obstacle: SimGridArray<Option<&SimObstacle>> = SimGridArray::new(rows, cols, None);
+    for &lookup_index in grid.obstacle_cells.iter() {
+        let coordinates: Vec2 =
+            Vec2::new((lookup_index / cols) as f32, (lookup_index % cols) as f32);
+        let center: Vec2 = grid.get_cell_center_position_from_coordinates(&coordinates);
+        if lookup_index < cell_obstacle.len() {
+            cell_obstacle[lookup_index] =
+                obstacles.iter().find(|obstacle| obstacle.contains(center));
+        }
+    }
+
+    /* A face takes the velocity of the obstacle on one side of it, so long as the other side is
+    open; faces inside of obstacles and walls don't matter. */
+    let face_obstacle = |first: (usize, usize), second: (usize, usize)| -> Option<&SimObstacle> {
+        let obstacle_in = |(row, col): (usize, usize)| -> Option<&SimObstacle> {
+            cell_obstacle.get(row, col).copied().flatten()
+        };
+        let is_open = |(row, col): (usize, usize)| grid.get_cell_type_value(row, col) != 0;
+
+        match (obstacle_in(first), obstacle_in(second)) {
+            (Some(obstacle), None) if is_open(second) => Some(obstacle),
+            (None, Some(obstacle)) if is_open(first) => Some(obstacle),
+            _ => None,
+        }
+    };
+
+    let mut velocity_u: SimGridArray<f32> = grid.velocity_u.clone();
+    for (row, row_velocities) in velocity_u.iter_rows_mut().enumerate() {
+        for (col, velocity) in row_velocities.iter_mut().enumerate().skip(1) {
+            if let Some(obstacle) = face_obstacle((row, col - 1), (row, col)) {
+                *velocity = obstacle
+                    .velocity_at(grid.get_velocity_point_pos(row, col, true))
+                    .x;
+            }
+        }
+    }
+    let mut velocity_v: SimGridArray<f32> = grid.velocity_v.clone();
+    for (row, row_velocities) in velocity_v.iter_rows_mut().enumerate().skip(1) {
+        for (col, velocity) in row_velocities.iter_mut().enumerate() {
+            if let Some(obstacle) = face_obstacle((row - 1, col), (row, col)) {
+                *velocity = obstacle
+                    .velocity_at(grid.get_velocity_point_pos(row, col, false))
+                    .y;
+            }
+        }
+    }
+
+    grid.velocity_u = velocity_u;
+    grid.velocity_v = velocity_v;
+}
+
+/** Let gravity pull on every rigid body, move it by its velocity, and push it back out of any walls
+it has moved into. */
+pub fn move_rigid_bodies(
+    constraints: &SimConstraints,
+    grid: &SimGrid,
+    rigid_bodies: &mut Query<(Entity, &mut SimRigidBody)>,
+    timestep: f32,
+) {
+    if rigid_bodies.is_empty() {
+        return;
+    }
+
+    // Mark the cells obstacles and rigid bodies made solid, so they can be told apart from walls.
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    let mut obstacle_mask: SimGridArray<bool> = SimGridArray::new(rows, cols, false);
+    for &lookup_index in grid.obstacle_cells.iter() {
+        if lookup_index < rows * cols {
+            obstacle_mask[lookup_index] = true;
+        }
+    }
+
+    for (_, mut body) in rigid_bodies.iter_mut() {
+        body.velocity += constraints.gravity * timestep;
+        let velocity: Vec2 = body.velocity;
+        let angular_velocity: f32 = body.angular_velocity;
+        body.position += velocity * timestep;
+        body.rotation += angular_velocity * timestep;
+
+        collide_rigid_body_with_walls(grid, &obstacle_mask, body.as_mut());
+    }
+}
+
+/** Push a rigid body out of every wall cell (or edge of the grid) that its outline has moved into,
+and stop it from moving any further into them; bodies slide along walls with a bit of friction.
+Cells made solid by obstacles and rigid bodies (marked in `obstacle_mask`) are not walls. */
+fn collide_rigid_body_with_walls(
+    grid: &SimGrid,
+    obstacle_mask: &SimGridArray<bool>,
+    body: &mut SimRigidBody,
+) {
+    if body.mass <= 0.0 {
+        return;
+    }
+
+    let friction: f32 = 0.3;
+    let tolerance: f32 = 0.1; // Same as particles, so bodies don't get stuck to walls.
+    let cell_size: f32 = grid.cell_size as f32;
+    let (rows, cols) = (grid.dimensions.0 as i64, grid.dimensions.1 as i64);
+    let grid_height: f32 = rows as f32 * cell_size;
+    let is_wall = |row: i64, col: i64| -> bool {
+        if row < 0 || col < 0 || row >= rows || col >= cols {
+            return true;
+        }
+        let (row, col) = (row as usize, col as usize);
+        grid.cell_type[(row, col)] == SimGridCellType::Solid && !obstacle_mask[(row, col)]
+    };
+
+    for outline_point in body.shape.outline_points() {
+        let point: Vec2 = body.position + Vec2::from_angle(body.rotation).rotate(outline_point);
+        let row: i64 = f32::floor((grid_height - point.y) / cell_size) as i64;
+        let col: i64 = f32::floor(point.x / cell_size) as i64;
+        if !is_wall(row, col) {
+            continue;
+        }
+
+        // Find the shortest way out of the wall cell and into an open cell next to it.
+        let cell_left: f32 = col as f32 * cell_size;
+        let cell_top: f32 = grid_height - row as f32 * cell_size;
+        let exits: [(bool, Vec2, f32); 4] = [
+            (!is_wall(row, col - 1), Vec2::NEG_X, point.x - cell_left),
+            (
+                !is_wall(row, col + 1),
+                Vec2::X,
+                cell_left + cell_size - point.x,
+            ),
+            (!is_wall(row - 1, col), Vec2::Y, cell_top - point.y),
+            (
+                !is_wall(row + 1, col),
+                Vec2::NEG_Y,
+                point.y - (cell_top - cell_size),
+            ),
+        ];
+        let Some((_, normal, depth)) = exits
+            .iter()
+            .filter(|(is_open, _, _)| *is_open)
+            .min_by(|a, b| a.2.total_cmp(&b.2))
+            .copied()
+        else {
+            continue;
+        };
+        body.position += normal * (depth + tolerance);
+
+        // Stop the touching point from moving into the wall...
+        let contact: Vec2 = point + normal * (depth + tolerance);
+        let offset: Vec2 = contact - body.position;
+        let inverse_mass: f32 = 1.0 / body.mass;
+        let inverse_inertia: f32 = 1.0 / body.moment_of_inertia();
+        let contact_velocity: Vec2 = body.velocity + body.angular_velocity * offset.perp();
+        let normal_speed: f32 = contact_velocity.dot(normal);
+        if normal_speed >= 0.0 {
+            continue;
+        }
+        let normal_impulse: f32 =
+            -normal_speed / (inverse_mass + offset.perp_dot(normal).powi(2) * inverse_inertia);
+        body.apply_impulse(normal * normal_impulse, contact);
+
+        // ...and let friction slow it down as it slides along the wall.
+        let tangent: Vec2 = normal.perp();
+        let contact_velocity: Vec2 = body.velocity + body.angular_velocity * offset.perp();
+        let tangent_impulse: f32 = (-contact_velocity.dot(tangent)
+            / (inverse_mass + offset.perp_dot(tangent).powi(2) * inverse_inertia))
+            .clamp(-friction * normal_impulse, friction * normal_impulse);
+        body.apply_impulse(tangent * tangent_impulse, contact);
+    }
+}
+
+/** Push every rigid body with the pressure of the fluid around it, using the pressure from the last
+make_grid_velocities_incompressible(), and drag it along with the fluid's flow.  Each face between
+one of a body's cells and a fluid cell pushes on the body with that fluid cell's pressure, which
+makes bodies float, and drags on it by how fast the fluid there slides past the body's surface,
+which carries bodies along with the flow and slows them down as they push through still fluid.
+Drag never does more than bring a body up to the fluid's speed in a single step. */
+pub fn apply_fluid_forces(
+    grid: &SimGrid,
+    rigid_bodies: &mut Query<(Entity, &mut SimRigidBody)>,
+    timestep: f32,
+) {
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    let cell_size: f32 = grid.cell_size as f32;
+
+    for (_, mut body) in rigid_bodies.iter_mut() {
+        if body.mass <= 0.0 {
+            continue;
+        }
+
+        let obstacle: SimObstacle = body.as_obstacle();
+        let mut force: Vec2 = Vec2::ZERO;
+        let mut torque: f32 = 0.0;
+        let mut drag: Vec2 = Vec2::ZERO;
+        let mut drag_torque: f32 = 0.0;
+        let mut relative_velocity_sum: Vec2 = Vec2::ZERO;
+        let mut drag_faces: usize = 0;
+        for &lookup_index in grid.obstacle_cells.iter() {
+            let (row, col) = (lookup_index / cols, lookup_index % cols);
+            let center: Vec2 =
+                grid.get_cell_center_position_from_coordinates(&Vec2::new(row as f32, col as f32));
+            if !obstacle.contains(center) {
+                continue;
+            }
+
+            // Faces to the left, right, top, and bottom of the cell, and which way they face.
+            let neighbors: [(usize, usize, Vec2); 4] = [
+                (row, usize::wrapping_sub(col, 1), Vec2::NEG_X),
+                (row, col + 1, Vec2::X),
+                (usize::wrapping_sub(row, 1), col, Vec2::Y),
+                (row + 1, col, Vec2::NEG_Y),
+            ];
+            for (neighbor_row, neighbor_col, normal) in neighbors {
+                if neighbor_row >= rows
+                    || neighbor_col >= cols
+                    || grid.cell_type[(neighbor_row, neighbor_col)] != SimGridCellType::Fluid
+                {
+                    continue;
+                }
+
+                let face_force: Vec2 =
+                    -grid.cell_center[(neighbor_row, neighbor_col)] * cell_size * normal;
+                let face_center: Vec2 = center + normal * (cell_size / 2.0);
+                force += face_force;
+                torque += (face_center - body.position).perp_dot(face_force);
+
+                // Quadratic drag from the fluid sliding past this face.
+                let Some(fluid_velocity) = fluid_cell_velocity(grid, neighbor_row, neighbor_col)
+                else {
+                    continue;
+                };
+                let relative_velocity: Vec2 = fluid_velocity - obstacle.velocity_at(face_center);
+                let density: f32 = grid
+                    .fluid_density
+                    .get(neighbor_row, neighbor_col)
+                    .copied()
+                    .unwrap_or(1.0);
+                let face_drag: Vec2 = 0.5
+                    * RIGID_BODY_DRAG_COEFFICIENT
+                    * density
+                    * cell_size
+                    * relative_velocity.length()
+                    * relative_velocity;
+                drag += face_drag;
+                drag_torque += (face_center - body.position).perp_dot(face_drag);
+                relative_velocity_sum += relative_velocity;
+                drag_faces += 1;
+            }
+        }
+
+        /* Quadratic drag grows so quickly that a light body in a fast flow could be flung right
+        past the fluid's speed, so scale it back to at most what matches their speeds. */
+        let mass: f32 = body.mass;
+        if drag_faces > 0 {
+            let drag_change: f32 = drag.length() / mass * timestep;
+            let most_change: f32 = (relative_velocity_sum / drag_faces as f32).length();
+            if drag_change > most_change {
+                let scale: f32 = most_change / drag_change;
+                drag *= scale;
+                drag_torque *= scale;
+            }
+        }
+
+        let moment_of_inertia: f32 = body.moment_of_inertia();
+        body.velocity += (force + drag) / mass * timestep;
+        body.angular_velocity += (torque + drag_torque) / moment_of_inertia * timestep;
+    }
+}
+
+/** Velocity of the fluid at the center of a cell, from the velocities on its four faces; None if
+any of them has no velocity. */
+fn fluid_cell_velocity(grid: &SimGrid, row: usize, col: usize) -> Option<Vec2> {
+    let faces: [f32; 4] = [
+        grid.velocity_u[(row, col)],
+        grid.velocity_u[(row, col + 1)],
+        grid.velocity_v[(row, col)],
+        grid.velocity_v[(row + 1, col)],
+    ];
+    if faces.contains(&f32::MIN) {
+        return None;
+    }
+
+    Some(Vec2::new(
+        (faces[0] + faces[1]) / 2.0,
+        (faces[2] + faces[3]) / 2.0,
+    ))
+}
+
+/// Find the largest speed of any particle, or of any grid velocity point that has a value.
+pub fn find_max_velocity(grid: &SimGrid, particles: &Query<(Entity, &mut SimParticle)>) -> f32 {
+    let mut max_velocity: f32 = 0.0;
+
+    for (_, particle) in particles.iter() {
+        max_velocity = f32::max(max_velocity, particle.velocity.length());
+    }
+
+    for velocity in grid.velocity_u.iter().chain(grid.velocity_v.iter()) {
+        if *velocity != f32::MIN {
+            max_velocity = f32::max(max_velocity, velocity.abs());
+        }
+    }
+
+    max_velocity
 }
 
 /**
@@ -175,24 +1622,24 @@ pub fn create_change_grid(old_grid: &SimGrid, new_grid: &SimGrid) -> SimGrid {
     let (rows, cols) = old_grid.dimensions;
 
     let mut change_grid = old_grid.clone();
-    let mut change_u = vec![vec![f32::MIN; (cols + 1) as usize]; rows as usize];
-    let mut change_v = vec![vec![f32::MIN; cols as usize]; (rows + 1) as usize];
+    let mut change_u = SimGridArray::new(rows as usize, (cols + 1) as usize, f32::MIN);
+    let mut change_v = SimGridArray::new((rows + 1) as usize, cols as usize, f32::MIN);
 
     for row_index in 0..rows as usize {
         for col_index in 0..(cols as usize + 1) {
-            let change_in_u = new_grid.velocity_u[row_index][col_index]
-                - old_grid.velocity_u[row_index][col_index];
+            let change_in_u = new_grid.velocity_u[(row_index, col_index)]
+                - old_grid.velocity_u[(row_index, col_index)];
 
-            change_u[row_index][col_index] = change_in_u;
+            change_u[(row_index, col_index)] = change_in_u;
         }
     }
 
     for row_index in 0..(rows as usize + 1) {
         for col_index in 0..cols as usize {
-            let change_in_v = new_grid.velocity_v[row_index][col_index]
-                - old_grid.velocity_v[row_index][col_index];
+            let change_in_v = new_grid.velocity_v[(row_index, col_index)]
+                - old_grid.velocity_v[(row_index, col_index)];
 
-            change_v[row_index][col_index] = change_in_v;
+            change_v[(row_index, col_index)] = change_in_v;
         }
     }
 
@@ -206,36 +1653,35 @@ pub fn create_change_grid(old_grid: &SimGrid, new_grid: &SimGrid) -> SimGrid {
     Extrapolates values in velocity_u and velocity_v up to the stated depth
     using the Fast Sweeping algorithm
 */
-
 pub fn extrapolate_values(grid: &mut SimGrid, depth: i32) {
     let (rows, cols) = grid.dimensions;
 
-    let mut d_u = vec![vec![0; (cols + 1) as usize]; rows as usize];
-    let mut d_v = vec![vec![0; cols as usize]; (rows + 1) as usize];
+    let mut d_u = SimGridArray::new(rows as usize, (cols + 1) as usize, 0);
+    let mut d_v = SimGridArray::new((rows + 1) as usize, cols as usize, 0);
 
     // Initialize caches for u and v components
     for row in 0..rows as usize {
         for col in 0..cols as usize + 1 {
-            if grid.velocity_u[row][col] != f32::MIN {
-                d_u[row][col] = 0;
+            if grid.velocity_u[(row, col)] != f32::MIN {
+                d_u[(row, col)] = 0;
             } else {
-                d_u[row][col] = i32::MAX;
+                d_u[(row, col)] = i32::MAX;
             }
         }
     }
 
     for row in 0..rows as usize + 1 {
         for col in 0..cols as usize {
-            if grid.velocity_v[row][col] != f32::MIN {
-                d_v[row][col] = 0;
+            if grid.velocity_v[(row, col)] != f32::MIN {
+                d_v[(row, col)] = 0;
             } else {
-                d_v[row][col] = i32::MAX;
+                d_v[(row, col)] = i32::MAX;
             }
         }
     }
 
-    let mut wave_u: Vec<Vec2> = Vec::new();
-    let mut wave_v: Vec<Vec2> = Vec::new();
+    let mut wave_u: Vec<(usize, usize)> = Vec::new();
+    let mut wave_v: Vec<(usize, usize)> = Vec::new();
 
     // Set up surrounding index offsets
     let surrounding = [
@@ -252,119 +1698,96 @@ pub fn extrapolate_values(grid: &mut SimGrid, depth: i32) {
     // Create first waves for u and v components
     for row in 0..rows as usize {
         for col in 0..cols as usize + 1 {
-            if d_u[row][col] != 0 {
-                if check_surrounding(&d_u, surrounding, (row, col), 0).len() != 0 {
-                    d_u[row][col] = 1;
-                    wave_u.push(Vec2::new(row as f32, col as f32));
-                }
+            if d_u[(row, col)] != 0
+                && !check_surrounding(&d_u, surrounding, (row, col), 0).is_empty()
+            {
+                d_u[(row, col)] = 1;
+                wave_u.push((row, col));
             }
         }
     }
 
     for row in 0..rows as usize + 1 {
         for col in 0..cols as usize {
-            if d_v[row][col] != 0 {
-                if check_surrounding(&d_v, surrounding, (row, col), 0).len() != 0 {
-                    d_v[row][col] = 1;
-                    wave_v.push(Vec2::new(row as f32, col as f32));
-                }
+            if d_v[(row, col)] != 0
+                && !check_surrounding(&d_v, surrounding, (row, col), 0).is_empty()
+            {
+                d_v[(row, col)] = 1;
+                wave_v.push((row, col));
             }
         }
     }
 
     // For both u and v components, extend their
     // velocities to empty neighbor velocity points
-    let mut wavefronts_u: Vec<Vec<Vec2>> = Vec::new();
-    wavefronts_u.push(wave_u);
-    let mut wavefronts_v: Vec<Vec<Vec2>> = Vec::new();
-    wavefronts_v.push(wave_v);
-
-    let mut curr_wave_index = 0;
-
-    while curr_wave_index < depth {
-        let cur_wave = wavefronts_u.iter().nth(curr_wave_index as usize).unwrap();
-
-        let mut next_wave = Vec::new();
+    extrapolate_wavefronts(&mut grid.velocity_u, &mut d_u, surrounding, wave_u, depth);
+    extrapolate_wavefronts(&mut grid.velocity_v, &mut d_v, surrounding, wave_v, depth);
+}
 
-        for i in 0..cur_wave.len() {
-            let index = cur_wave.iter().nth(i).unwrap();
+/**
+    Helper function for extrapolate_values(); sets every velocity point in
+    the wave to the average of its neighbors from earlier waves, then moves
+    on to the unset points around the wave, up to `depth` waves.  Points in
+    a wave only read points from earlier waves, so each wave is averaged in
+    parallel.
+*/
+fn extrapolate_wavefronts(
+    velocities: &mut SimGridArray<f32>,
+    d: &mut SimGridArray<i32>,
+    surrounding: [[i32; 2]; 8],
+    first_wave: Vec<(usize, usize)>,
+    depth: i32,
+) {
+    let height = velocities.row_count() as i32;
+    let width = velocities.col_count() as i32;
+    let neighbors = move |(row, col): (usize, usize)| {
+        surrounding
+            .into_iter()
+            .filter_map(move |[offset_x, offset_y]| {
+                let neighbor_x = col as i32 + offset_x;
+                let neighbor_y = row as i32 + offset_y;
+                if neighbor_x >= 0 && neighbor_x < width && neighbor_y >= 0 && neighbor_y < height {
+                    Some((neighbor_y as usize, neighbor_x as usize))
+                } else {
+                    None
+                }
+            })
+    };
 
-            let mut average = 0.0;
-            let mut num_used = 0;
+    let mut cur_wave = first_wave;
 
-            for k in 0..8 {
-                let offset_x = surrounding[k][0];
-                let offset_y = surrounding[k][1];
-                let neighbor_x = index.y as i32 + offset_x;
-                let neighbor_y = index.x as i32 + offset_y;
+    for _ in 0..depth {
+        let averages: Vec<f32> = cur_wave
+            .par_iter()
+            .with_min_len(PARALLEL_CHUNK_SIZE)
+            .map(|&(row, col)| {
+                let mut average = 0.0;
+                let mut num_used = 0;
 
-                if neighbor_x >= 0
-                    && neighbor_x < grid.velocity_u[0].len() as i32
-                    && neighbor_y >= 0
-                    && neighbor_y < grid.velocity_u.len() as i32
-                {
-                    if d_u[neighbor_y as usize][neighbor_x as usize]
-                        < d_u[index.x as usize][index.y as usize]
-                    {
-                        average += grid.velocity_u[neighbor_y as usize][neighbor_x as usize];
+                for (neighbor_row, neighbor_col) in neighbors((row, col)) {
+                    if d[(neighbor_row, neighbor_col)] < d[(row, col)] {
+                        average += velocities[(neighbor_row, neighbor_col)];
                         num_used += 1;
-                    } else if d_u[neighbor_y as usize][neighbor_x as usize] == i32::MAX {
-                        d_u[neighbor_y as usize][neighbor_x as usize] =
-                            d_u[index.x as usize][index.y as usize] + 1;
-                        next_wave.push(Vec2::new(neighbor_y as f32, neighbor_x as f32));
                     }
                 }
-            }
-            average /= num_used as f32;
-            grid.velocity_u[index.x as usize][index.y as usize] = average;
-        }
-
-        wavefronts_u.push(next_wave);
-        curr_wave_index += 1;
-    }
-
-    curr_wave_index = 0;
-
-    while curr_wave_index < depth {
-        let cur_wave = wavefronts_v.iter().nth(curr_wave_index as usize).unwrap();
+                average / num_used as f32
+            })
+            .collect();
 
         let mut next_wave = Vec::new();
 
-        for i in 0..cur_wave.len() {
-            let index = cur_wave.iter().nth(i).unwrap();
-
-            let mut average = 0.0;
-            let mut num_used = 0;
-
-            for k in 0..8 {
-                let offset_x = surrounding[k][0];
-                let offset_y = surrounding[k][1];
-                let neighbor_x = index.y as i32 + offset_x;
-                let neighbor_y = index.x as i32 + offset_y;
+        for (&(row, col), average) in cur_wave.iter().zip(averages) {
+            velocities[(row, col)] = average;
 
-                if neighbor_x >= 0
-                    && neighbor_x < grid.velocity_v[0].len() as i32
-                    && neighbor_y >= 0
-                    && neighbor_y < grid.velocity_v.len() as i32
-                {
-                    if d_v[neighbor_y as usize][neighbor_x as usize]
-                        < d_v[index.x as usize][index.y as usize]
-                    {
-                        average += grid.velocity_v[neighbor_y as usize][neighbor_x as usize];
-                        num_used += 1;
-                    } else if d_v[neighbor_y as usize][neighbor_x as usize] == i32::MAX {
-                        d_v[neighbor_y as usize][neighbor_x as usize] =
-                            d_v[index.x as usize][index.y as usize] + 1;
-                        next_wave.push(Vec2::new(neighbor_y as f32, neighbor_x as f32));
-                    }
+            for (neighbor_row, neighbor_col) in neighbors((row, col)) {
+                if d[(neighbor_row, neighbor_col)] == i32::MAX {
+                    d[(neighbor_row, neighbor_col)] = d[(row, col)] + 1;
+                    next_wave.push((neighbor_row, neighbor_col));
                 }
             }
-            average /= num_used as f32;
-            grid.velocity_v[index.x as usize][index.y as usize] = average;
         }
 
-        wavefronts_v.push(next_wave);
-        curr_wave_index += 1;
+        cur_wave = next_wave;
     }
 }
 
@@ -372,26 +1795,26 @@ pub fn extrapolate_values(grid: &mut SimGrid, depth: i32) {
     Helper function to check surrounding velocity points
 */
 fn check_surrounding(
-    grid: &Vec<Vec<i32>>,
+    grid: &SimGridArray<i32>,
     surroundings: [[i32; 2]; 8],
     index: (usize, usize),
     value: i32,
 ) -> Vec<i32> {
     let mut valid_neighbors: Vec<i32> = Vec::new();
-    let grid_width = grid[0].len() as i32;
-    let grid_height = grid.len() as i32;
+    let grid_width = grid.col_count() as i32;
+    let grid_height = grid.row_count() as i32;
 
-    for i in 0..8 {
-        let offset_x = surroundings[i][0];
-        let offset_y = surroundings[i][1];
+    for (i, [offset_x, offset_y]) in surroundings.into_iter().enumerate() {
         let neighbor_x = index.1 as i32 + offset_x;
         let neighbor_y = index.0 as i32 + offset_y;
 
-        if neighbor_x >= 0 && neighbor_x < grid_width && neighbor_y >= 0 && neighbor_y < grid_height
+        if neighbor_x >= 0
+            && neighbor_x < grid_width
+            && neighbor_y >= 0
+            && neighbor_y < grid_height
+            && grid[(neighbor_y as usize, neighbor_x as usize)] == value
         {
-            if grid[neighbor_y as usize][neighbor_x as usize] == value {
-                valid_neighbors.push(i as i32);
-            }
+            valid_neighbors.push(i as i32);
         }
     }
 
@@ -399,112 +1822,120 @@ fn check_surrounding(
 }
 
 /**
-    Collects all the particles within a cell and returns
-    a vector of particles with their ID and data
-*/
-fn collect_particles<'a>(
-    grid: &SimGrid,
-    center: Vec2,
-    particles: &'a mut Query<(Entity, &mut SimParticle)>,
-) -> Vec<(Entity, Mut<'a, SimParticle>)> {
-    let mut particle_bag = Vec::new();
-
-    let index = grid.get_lookup_index(center);
-
-    let particle_ids = grid.get_particles_in_lookup(index);
-
-    if particle_ids.len() == 0 {
-        return Vec::new();
-    }
-
-    // Goes through all the particles and selects only
-    // particles within the cell and adds them
-    // to the bag
-    particles.for_each_mut(|particle| {
-        if particle_ids.contains(&particle.0) {
-            particle_bag.push(particle);
-        }
-    });
-
-    particle_bag
-}
-
-/**
-    Interpolates new particle velocities from grid points for a given
-    set of particles.
+    Interpolates a new particle velocity from grid points for a
+    single particle.  With APIC, the particle takes the grid velocity
+    and its gradient; otherwise PIC and FLIP velocities are blended.
 */
-fn apply_grid<'a>(
-    particles: Vec<(Entity, Mut<'a, SimParticle>)>,
+fn apply_grid(
+    particle: &mut SimParticle,
     grid: &SimGrid,
     change_grid: &SimGrid,
     constraints: &SimConstraints,
+    timestep: f32,
 ) {
     // New velocity value using equation from section 7.6
     // in Fluid Simulation for Computer Graphics, Second Edition
     // (Bridson, Robert)
 
-    let pic_coef = constraints.grid_particle_ratio;
+    if constraints.transfer_scheme == SimTransferScheme::Apic {
+        let (velocity, affine) = interpolate_velocity_and_gradient(particle.position, grid);
+        particle.velocity = velocity + (constraints.gravity * timestep);
+        particle.affine = affine;
+        return;
+    }
+
+    // Grains lose their energy to friction instead of keeping it like a liquid, so use pure PIC.
+    let pic_coef = match constraints.material_friction(particle.material) > 0.0 {
+        true => 1.0,
+        false => constraints.grid_particle_ratio,
+    };
 
-    for (_, mut particle) in particles {
-        let interp_vel = interpolate_velocity(particle.position, &grid);
-        let change_vel = interpolate_velocity(particle.position, &change_grid);
+    let interp_vel = interpolate_velocity(particle.position, grid);
+    let change_vel = interpolate_velocity(particle.position, change_grid);
 
-        let pic_velocity = interp_vel;
-        let flip_velocity = particle.velocity + change_vel;
-        let new_velocity = (pic_coef * pic_velocity) + ((1.0 - pic_coef) * flip_velocity);
-        particle.velocity = new_velocity + (constraints.gravity * constraints.timestep);
-    }
+    let pic_velocity = interp_vel;
+    let flip_velocity = particle.velocity + change_vel;
+    let new_velocity = (pic_coef * pic_velocity) + ((1.0 - pic_coef) * flip_velocity);
+    particle.velocity = new_velocity + (constraints.gravity * timestep);
+    particle.affine = Mat2::ZERO;
 }
 
-/// Apply grid velocities to particle velocities
+/** Apply grid velocities to particle velocities, along with gravity over a (sub)step of
+`timestep`. */
 pub fn grid_to_particles(
     grid: &mut SimGrid,
     change_grid: &SimGrid,
     particles: &mut Query<(Entity, &mut SimParticle)>,
     constraints: &SimConstraints,
+    timestep: f32,
 ) {
-    // Basic idea right now is to go through each cell,
-    // figure out which particles are 'within' that cell,
-    // then apply the grid transformation
-
-    for row_index in 0..grid.dimensions.0 as usize {
-        for col_index in 0..grid.dimensions.1 as usize {
-            // Skip over looking for particles where
-            // they are not located
-            match grid.cell_type[row_index][col_index] {
-                SimGridCellType::Air => {
-                    continue;
-                }
-                SimGridCellType::Solid => {
-                    continue;
-                }
-                SimGridCellType::Fluid => {
-                    // Grab the center postition of the cell
-                    let coords = Vec2::new(row_index as f32, col_index as f32);
+    // Basic idea right now is to find the particles sitting in
+    // a fluid cell, then apply the grid transformation to all
+    // of them in parallel
+
+    let grid: &SimGrid = grid;
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    let is_in_fluid_cell = |lookup_index: usize| -> bool {
+        // Skip over particles outside of fluid cells
+        lookup_index < rows * cols
+            && grid.cell_type[(lookup_index / cols, lookup_index % cols)] == SimGridCellType::Fluid
+    };
 
-                    // Grab all the particles within this specific cell
-                    let particles_in_cell = collect_particles(grid, coords, particles);
+    let mut particle_list: Vec<Mut<SimParticle>> = particles
+        .iter_mut()
+        .filter(|(_, particle)| is_in_fluid_cell(particle.lookup_index))
+        .map(|(_, particle)| particle)
+        .collect();
+
+    // Solve for the new velocities of the particles; each one only reads the grids
+    particle_list
+        .par_iter_mut()
+        .with_min_len(PARALLEL_CHUNK_SIZE)
+        .for_each(|particle| {
+            apply_grid(particle.as_mut(), grid, change_grid, constraints, timestep)
+        });
+}
 
-                    // Solve for the new velocities of the particles
-                    apply_grid(particles_in_cell, grid, change_grid, constraints);
-                }
-            }
+/** Carry the change in each cell's temperature over this step back to the particles inside of it,
+the same way FLIP carries velocity changes, so that particles keep their own temperature as they
+move between cells. */
+pub fn temperatures_to_particles(
+    old_grid: &SimGrid,
+    grid: &SimGrid,
+    particles: &mut Query<(Entity, &mut SimParticle)>,
+) {
+    for (_, mut particle) in particles.iter_mut() {
+        /* Particles have moved since their lookup_index was last set, so find the cell they're in
+        now from their position. */
+        let cell_coordinates: Vec2 = grid.get_cell_coordinates_from_position(&particle.position);
+        let (row, col) = (cell_coordinates.x as usize, cell_coordinates.y as usize);
+        if let (Some(old_temperature), Some(temperature)) = (
+            old_grid.temperature.get(row, col),
+            grid.temperature.get(row, col),
+        ) {
+            particle.temperature += temperature - old_temperature;
         }
     }
 }
 
-/// Update the particle's lookup_index based on position, then update the grid's lookup table.
-pub fn update_particle_lookup(particle_id: Entity, particle: &mut SimParticle, grid: &mut SimGrid) {
-    // Find the cell that this particle belongs to and update our spatial lookup accordingly.
-    let cell_coordinates: Vec2 = grid.get_cell_coordinates_from_position(&particle.position);
-    let lookup_index: usize = grid.get_lookup_index(cell_coordinates);
-
-    // Remove the particle from its old lookup cell and place it here in its new one.
-    if !grid.spatial_lookup[lookup_index].contains(&particle_id) {
-        grid.remove_particle_from_lookup(particle_id, particle.lookup_index);
-        grid.spatial_lookup[lookup_index].push(particle_id);
-        particle.lookup_index = lookup_index;
+/** Update every particle's lookup_index based on its position, then rebuild the grid's lookup
+table from scratch, so that it holds exactly the particles that are in the query. */
+pub fn update_particle_lookup(
+    grid: &mut SimGrid,
+    particles: &mut Query<(Entity, &mut SimParticle)>,
+) {
+    let mut particle_cells: Vec<(Entity, usize)> = Vec::with_capacity(particles.iter().len());
+    for (particle_id, mut particle) in particles.iter_mut() {
+        // Find the cell that this particle belongs to.
+        let cell_coordinates: Vec2 = grid.get_cell_coordinates_from_position(&particle.position);
+        let lookup_index: usize = grid.get_lookup_index(cell_coordinates);
+        if particle.lookup_index != lookup_index {
+            particle.lookup_index = lookup_index;
+        }
+        particle_cells.push((particle_id, lookup_index));
     }
+
+    grid.rebuild_spatial_lookup(&particle_cells);
 }
 
 /** For each particle: integrate velocity into position, update cell type, update spatial lookup,
@@ -513,39 +1944,64 @@ pub fn update_particles(
     constraints: &SimConstraints,
     particles: &mut Query<(Entity, &mut SimParticle)>,
     grid: &mut SimGrid,
+    obstacles: &[SimObstacle],
     delta_time: f32,
 ) {
     grid.clear_density_values();
 
-    for (id, mut particle) in particles.iter_mut() {
-        // Integrate the particles while handling collisions.
-        let target_velocity: Vec2 = particle.velocity + constraints.gravity * delta_time;
-        let target_position: Vec2 = particle.position + target_velocity * delta_time;
-        integrate_particle_with_collisions(
-            grid,
-            particle.as_mut(),
-            &target_position,
-            &target_velocity,
-        );
-
-        // Update the grid's spatial lookup based on this particle's position!
-        update_particle_lookup(id, particle.as_mut(), grid);
-
-        // Update the grid's density value for this current cell.
+    // Integrate the particles in parallel while handling collisions; they only read the grid.
+    let mut particle_list: Vec<(Entity, Mut<SimParticle>)> = particles.iter_mut().collect();
+    let read_grid: &SimGrid = grid;
+    particle_list
+        .par_iter_mut()
+        .with_min_len(PARALLEL_CHUNK_SIZE)
+        .for_each(|(_, particle)| {
+            let target_velocity: Vec2 = particle.velocity + constraints.gravity * delta_time;
+            let target_position: Vec2 = particle.position + target_velocity * delta_time;
+            integrate_particle_with_collisions(
+                read_grid,
+                obstacles,
+                particle.as_mut(),
+                &target_position,
+                &target_velocity,
+            );
+        });
+
+    // The densities are shared between particles, so update them in query order.
+    for (_, particle) in particle_list {
         grid.update_grid_density(particle.position);
     }
+
+    // Sort the particles into the cells they have moved to.
+    update_particle_lookup(grid, particles);
 }
 
 /// Find the maximum distance a particle can move before hitting a solid!
 fn integrate_particle_with_collisions(
     grid: &SimGrid,
+    obstacles: &[SimObstacle],
     particle: &mut SimParticle,
     target_position: &Vec2,
     target_velocity: &Vec2,
 ) {
+    /* Particles leaving through a periodic edge come back in through the opposite one; move the
+    particle over along with its target so that it still collides with whatever is there. */
+    let wrapped_target: Vec2 = grid.wrap_position(*target_position);
+    particle.position += wrapped_target - *target_position;
+    let target_position: &Vec2 = &wrapped_target;
+
+    // Particles are free to leave through outflow edges; they get deleted once they are out.
+    if let Some(edge) = grid.get_crossed_edge(*target_position) {
+        if grid.get_boundary(edge) == SimBoundary::Outflow {
+            particle.position = *target_position;
+            particle.velocity = *target_velocity;
+            return;
+        }
+    }
+
     // Calculate the cell coords. (even if they are OOB) the particle will be in next frame if unimpeded.
     let target_coordinates: Vec2 =
-        grid.get_hypothetical_cell_coordinates_from_position(&target_position);
+        grid.get_hypothetical_cell_coordinates_from_position(target_position);
     if grid.is_position_within_grid(target_position) {
         // Figure out the type of the valid grid cell that the particle is heading towards.
         let target_cell_type: u8 =
@@ -553,8 +2009,11 @@ fn integrate_particle_with_collisions(
 
         // If the target position is not inside of a solid cell, move as normal.
         if target_cell_type != 0 {
+            let start_position: Vec2 = particle.position;
             particle.position = *target_position;
             particle.velocity = *target_velocity;
+            push_particle_out_of_solids(grid, particle, start_position);
+            push_particle_out_of_obstacles(particle, obstacles);
             return;
         }
     }
@@ -571,6 +2030,7 @@ fn integrate_particle_with_collisions(
 
     // Set a small collision tolerance so our particles don't get stuck to walls.
     let tolerance: f32 = 0.1;
+    let start_position: Vec2 = particle.position;
 
     if particle.position.x <= cell_left && target_position.x >= cell_left {
         particle.position.x = cell_left - tolerance;
@@ -593,33 +2053,108 @@ fn integrate_particle_with_collisions(
         particle.velocity.y = target_velocity.y;
         particle.position.y = target_position.y;
     }
+
+    push_particle_out_of_solids(grid, particle, start_position);
+    push_particle_out_of_obstacles(particle, obstacles);
+}
+
+/** Stop a particle that has moved from `start_position` into one of the grid's sub-cell solids at
+the solid's surface, and take away only the part of its velocity going into the surface so that it
+slides along slanted walls instead of sticking to them. */
+fn push_particle_out_of_solids(grid: &SimGrid, particle: &mut SimParticle, start_position: Vec2) {
+    if grid.solids.is_empty() {
+        return;
+    }
+
+    // Same small tolerance as wall collisions, so particles don't get stuck to solids.
+    let tolerance: f32 = 0.1;
+
+    /* Walk along the particle's path in steps of half a cell, so that it can't skip over a thin
+    solid in a single step. */
+    let step_count: usize = ((particle.position - start_position).length() * 2.0
+        / grid.cell_size as f32)
+        .ceil() as usize;
+    for step in 1..=step_count {
+        let position: Vec2 =
+            start_position.lerp(particle.position, step as f32 / step_count as f32);
+        if grid.get_solid_distance(position) < 0.0 {
+            particle.position = position;
+            break;
+        }
+    }
+
+    let distance: f32 = grid.get_solid_distance(particle.position);
+    if distance >= tolerance {
+        return;
+    }
+
+    let normal: Vec2 = grid.get_solid_normal(particle.position);
+    particle.position += normal * (tolerance - distance);
+
+    let approach_speed: f32 = particle.velocity.dot(normal);
+    if approach_speed < 0.0 {
+        particle.velocity -= approach_speed * normal;
+    }
 }
 
-/// Handle particle collisions with the grid.
+/** Move a particle that has ended up inside of an obstacle back out past its surface, and stop it
+from moving into the obstacle any faster than the obstacle's surface is moving. */
+fn push_particle_out_of_obstacles(particle: &mut SimParticle, obstacles: &[SimObstacle]) {
+    // Same small tolerance as wall collisions, so particles don't get stuck to obstacles.
+    let tolerance: f32 = 0.1;
+
+    for obstacle in obstacles.iter() {
+        let distance: f32 = obstacle.signed_distance(particle.position);
+        if distance >= 0.0 {
+            continue;
+        }
+
+        let normal: Vec2 = obstacle.surface_normal(particle.position);
+        particle.position += normal * (tolerance - distance);
+
+        let relative_velocity: Vec2 = particle.velocity - obstacle.velocity_at(particle.position);
+        let approach_speed: f32 = relative_velocity.dot(normal);
+        if approach_speed < 0.0 {
+            particle.velocity -= approach_speed * normal;
+        }
+    }
+}
+
+/** Handle particle collisions with the grid's edges.  Solid and inflow edges stop particles,
+periodic edges wrap them around to the opposite edge, and outflow edges let them leave. */
 pub fn handle_particle_grid_collisions(
     constraints: &SimConstraints,
     grid: &SimGrid,
     particles: &mut Query<(Entity, &mut SimParticle)>,
 ) {
+    let is_wall = |boundary: SimBoundary| -> bool {
+        matches!(boundary, SimBoundary::Solid | SimBoundary::Inflow(_))
+    };
+
     for (_, mut particle) in particles.iter_mut() {
         // Don't let particles escape the grid!
         let grid_width: f32 = (grid.cell_size * grid.dimensions.1) as f32;
         let grid_height: f32 = (grid.cell_size * grid.dimensions.0) as f32;
+        particle.position = grid.wrap_position(particle.position);
 
         // Left/right collision checks.
-        if particle.position.x < constraints.particle_radius {
+        if particle.position.x < constraints.particle_radius && is_wall(grid.boundaries.west) {
             particle.position.x = constraints.particle_radius;
             particle.velocity.x = 0.0;
-        } else if particle.position.x > grid_width - constraints.particle_radius {
+        } else if particle.position.x > grid_width - constraints.particle_radius
+            && is_wall(grid.boundaries.east)
+        {
             particle.position.x = grid_width - constraints.particle_radius;
             particle.velocity.x = 0.0;
         }
 
         // Up/down collision checks.
-        if particle.position.y < constraints.particle_radius {
+        if particle.position.y < constraints.particle_radius && is_wall(grid.boundaries.south) {
             particle.position.y = constraints.particle_radius;
             particle.velocity.y = 0.0;
-        } else if particle.position.y > grid_height - constraints.particle_radius {
+        } else if particle.position.y > grid_height - constraints.particle_radius
+            && is_wall(grid.boundaries.north)
+        {
             particle.position.y = grid_height - constraints.particle_radius;
             particle.velocity.y = 0.0;
         }
@@ -627,42 +2162,133 @@ pub fn handle_particle_grid_collisions(
 }
 
 /** Push particles apart so that we account for drift and grid cells with incorrect densities.
-TODO: Improve collision solving speed between particles within cells.  Lots of particles in
-one cell leads to a large slowdown. */
+Every pair of particles in the same or neighbouring lookup cells is pushed apart exactly once per
+collision iteration: each cell handles the pairs inside of itself, plus the pairs it makes with
+the cell to its right and the three cells below it.  Copies of the particles are sorted by cell,
+so each row of cells is one contiguous run; a row only touches itself and the row below it, so the
+even rows are solved in parallel, then the odd rows.  This gives the same result no matter how
+many threads there are. */
 pub fn push_particles_apart(
     constraints: &SimConstraints,
     grid: &SimGrid,
     particles: &mut Query<(Entity, &mut SimParticle)>,
+    obstacles: &[SimObstacle],
 ) {
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+
+    /* Copy the particles in lookup order; the particles of the cell at `lookup_index` are
+    `sorted_particles[cell_offsets[lookup_index]..cell_offsets[lookup_index + 1]]`. */
+    let particle_count: usize = particles.iter().len();
+    let mut sorted_ids: Vec<Entity> = Vec::with_capacity(particle_count);
+    let mut sorted_particles: Vec<SimParticle> = Vec::with_capacity(particle_count);
+    let mut cell_offsets: Vec<usize> = Vec::with_capacity(rows * cols + 1);
+    for lookup_index in 0..rows * cols {
+        cell_offsets.push(sorted_particles.len());
+        for &particle_id in grid.spatial_lookup.cell(lookup_index) {
+            if let Ok((_, particle)) = particles.get(particle_id) {
+                sorted_ids.push(particle_id);
+                sorted_particles.push(particle.clone());
+            }
+        }
+    }
+    cell_offsets.push(sorted_particles.len());
+
     for _i in 0..constraints.collision_iters_per_frame {
-        // For each grid cell.
-        for lookup_index in 0..grid.spatial_lookup.len() {
-            // Create a vector of all particles in all of the surrounding cells.
-            let nearby_particles: Vec<Entity> = grid.get_nearby_particles(lookup_index);
-            let possible_collisions: Vec<Entity> = nearby_particles.clone();
-
-            // For each particle within neighboring grid cell.
-            for particle0_id in nearby_particles.iter() {
-                // For each OTHER particle within this grid cell.
-                for particle1_id in possible_collisions.iter() {
-                    // Don't process a collision between ourself!
-                    if particle0_id == particle1_id {
-                        continue;
-                    }
+        for first_row in 0..2 {
+            // Hand each row of cells the particles of itself and the row below it.
+            let mut row_particles: Vec<(usize, &mut [SimParticle])> = Vec::new();
+            let mut remaining: &mut [SimParticle] = &mut sorted_particles;
+            let mut remaining_offset: usize = 0;
+            for row in (first_row..rows).step_by(2) {
+                let start: usize = cell_offsets[row * cols];
+                let end: usize = cell_offsets[usize::min(row + 2, rows) * cols];
+                let (_, rest) =
+                    std::mem::take(&mut remaining).split_at_mut(start - remaining_offset);
+                let (band, rest) = rest.split_at_mut(end - start);
+                row_particles.push((row, band));
+                remaining = rest;
+                remaining_offset = end;
+            }
 
-                    // Get both particles involved in the collision.
-                    let particle_combo_result =
-                        particles.get_many_mut([*particle0_id, *particle1_id]);
-                    let particle_combo = match particle_combo_result {
-                        Ok(particle_combo_result) => particle_combo_result,
-                        Err(_error) => {
-                            // eprintln!("Invalid particle combo; skipping!");
-                            continue;
-                        }
-                    };
+            row_particles.into_par_iter().for_each(|(row, band)| {
+                separate_row(constraints, grid, obstacles, &cell_offsets, row, band);
+            });
+        }
+    }
+
+    /* Pushing particles apart only ever moves them.  Only write back the ones that did move, so
+    particles left alone aren't marked as changed. */
+    for (particle_id, copy) in sorted_ids.into_iter().zip(sorted_particles) {
+        let Ok((_, mut particle)) = particles.get_mut(particle_id) else {
+            continue;
+        };
+        if particle.position != copy.position || particle.velocity != copy.velocity {
+            particle.position = copy.position;
+            particle.velocity = copy.velocity;
+        }
+    }
+}
+
+/** Helper function for push_particles_apart().  Push apart every pair of particles that has at
+least one particle in `row`, and the other in the same cell, the cell to its right, or one of the
+three cells below it.  `band` holds the particles of `row` and the row below it, in lookup order. */
+fn separate_row(
+    constraints: &SimConstraints,
+    grid: &SimGrid,
+    obstacles: &[SimObstacle],
+    cell_offsets: &[usize],
+    row: usize,
+    band: &mut [SimParticle],
+) {
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    let band_start: usize = cell_offsets[row * cols];
+    let cell_range = |cell_row: usize, cell_col: usize| -> std::ops::Range<usize> {
+        let lookup_index: usize = cell_row * cols + cell_col;
+        (cell_offsets[lookup_index] - band_start)..(cell_offsets[lookup_index + 1] - band_start)
+    };
+
+    for col in 0..cols {
+        let cell: std::ops::Range<usize> = cell_range(row, col);
+        if cell.is_empty() {
+            continue;
+        }
 
-                    // Push both particles apart.
-                    separate_particle_pair(constraints, grid, particle_combo);
+        // Pairs within this cell...
+        for particle0 in cell.clone() {
+            for particle1 in particle0 + 1..cell.end {
+                let (low, high) = band.split_at_mut(particle1);
+                separate_particle_pair(
+                    constraints,
+                    grid,
+                    obstacles,
+                    [&mut low[particle0], &mut high[0]],
+                );
+            }
+        }
+
+        /* ...and pairs with the neighbouring cells that come after this one in lookup order, so
+        that no pair is found from both of its cells.  Cells on the left and right borders don't
+        pick up particles from the other side of the grid. */
+        let mut neighbours: Vec<(usize, usize)> = Vec::with_capacity(4);
+        if col + 1 < cols {
+            neighbours.push((row, col + 1));
+        }
+        if row + 1 < rows {
+            for neighbour_col in col.saturating_sub(1)..usize::min(col + 2, cols) {
+                neighbours.push((row + 1, neighbour_col));
+            }
+        }
+        for (neighbour_row, neighbour_col) in neighbours {
+            let neighbour: std::ops::Range<usize> = cell_range(neighbour_row, neighbour_col);
+            for particle0 in cell.clone() {
+                for particle1 in neighbour.clone() {
+                    let (low, high) = band.split_at_mut(particle1);
+                    separate_particle_pair(
+                        constraints,
+                        grid,
+                        obstacles,
+                        [&mut low[particle0], &mut high[0]],
+                    );
                 }
             }
         }
@@ -673,16 +2299,19 @@ pub fn push_particles_apart(
 fn separate_particle_pair(
     constraints: &SimConstraints,
     grid: &SimGrid,
-    mut particle_combo: [(Entity, Mut<'_, SimParticle>); 2],
+    obstacles: &[SimObstacle],
+    particle_pair: [&mut SimParticle; 2],
 ) {
+    let [particle0, particle1] = particle_pair;
+
     // Collision radii used to find the particle pair's push force on each other.
     let collision_radius: f32 = constraints.particle_radius * 2.0;
     let collision_radius_squared: f32 = collision_radius * collision_radius;
 
     // Figure out if we even need to push the particles apart in the first place!
     let mut delta_position: Vec2 = Vec2 {
-        x: particle_combo[0].1.position[0] - particle_combo[1].1.position[0],
-        y: particle_combo[0].1.position[1] - particle_combo[1].1.position[1],
+        x: particle0.position[0] - particle1.position[0],
+        y: particle0.position[1] - particle1.position[1],
     };
     let distance_squared: f32 =
         (delta_position.x * delta_position.x) + (delta_position.y * delta_position.y);
@@ -696,99 +2325,639 @@ fn separate_particle_pair(
     delta_position *= separation_scale;
 
     // Move the particles apart!
-    let target_velocity0: Vec2 = particle_combo[0].1.velocity;
-    let target_velocity1: Vec2 = particle_combo[1].1.velocity;
+    let target_velocity0: Vec2 = particle0.velocity;
+    let target_velocity1: Vec2 = particle1.velocity;
 
-    let target_position0: Vec2 = particle_combo[0].1.position + delta_position;
-    let target_position1: Vec2 = particle_combo[1].1.position - delta_position;
+    let target_position0: Vec2 = particle0.position + delta_position;
+    let target_position1: Vec2 = particle1.position - delta_position;
 
     integrate_particle_with_collisions(
         grid,
-        particle_combo[0].1.as_mut(),
+        obstacles,
+        particle0,
         &target_position0,
         &target_velocity0,
     );
     integrate_particle_with_collisions(
         grid,
-        particle_combo[1].1.as_mut(),
+        obstacles,
+        particle1,
         &target_position1,
         &target_velocity1,
     );
 }
 
-/** Force velocity incompressibility for each grid cell within the simulation.  Uses the
-Gauss-Seidel method. */
-pub fn make_grid_velocities_incompressible(grid: &mut SimGrid, constraints: &mut SimConstraints) {
-    // Get the "particle rest density" for the simulation domain.
+/** Force velocity incompressibility for each grid cell within the simulation.  Solves the
+pressure Poisson equation over all fluid cells with a conjugate gradient method (preconditioned
+with red-black IC(0)), subtracts the pressure gradient from the face velocities, and stores the
+resulting pressure in `grid.cell_center`.  At most `incomp_iters_per_frame` iterations are run;
+the residual reached is stored in `constraints.pressure_residual`. */
+pub fn make_grid_velocities_incompressible(
+    grid: &mut SimGrid,
+    constraints: &mut SimConstraints,
+    timestep: f32,
+) {
+    // Get the "particle rest density", the average density of the fluid cells.
     let mut fluid_cell_count: f32 = 0.0;
     let mut density_sum: f32 = 0.0;
-    for i in 0..grid.density.len() {
-        density_sum += grid.density[i];
-        fluid_cell_count += 1.0;
+    for (density, cell_type) in grid.density.iter().zip(grid.cell_type.iter()) {
+        if *cell_type == SimGridCellType::Fluid {
+            density_sum += density;
+            fluid_cell_count += 1.0;
+        }
     }
     if fluid_cell_count > 0.0 {
         constraints.particle_rest_density = density_sum / fluid_cell_count;
     }
 
-    // Allows the user to make the simulation go BRRRRRRR or brrr.
-    for _ in 0..constraints.incomp_iters_per_frame {
-        /* For each grid cell, calculate the inflow/outflow (divergence).  Then, find out how many
-        surrounding cells are solid and adjust grid velocities accordingly. */
-        for row in 0..grid.dimensions.0 {
-            for col in 0..grid.dimensions.1 {
-                // Don't process this cell if we are not inside of a fluid cell.
-                if grid.cell_type[row as usize][col as usize] != SimGridCellType::Fluid {
-                    continue;
+    let system: PressureSystem = PressureSystem::new(grid, constraints);
+
+    // Solve for each fluid cell's pressure; this is in units of velocity (p * timestep / cell_size).
+    let (pressure, residual, iterations) = system.solve(
+        constraints.pressure_tolerance as f64,
+        constraints.incomp_iters_per_frame as usize,
+        constraints.deterministic,
+    );
+    constraints.pressure_residual = residual as f32;
+    constraints.pressure_iterations = iterations;
+
+    /* Subtract the pressure gradient, divided by the fluid density at the face, from every face
+    between two non-solid cells where at least one of them is fluid.  Air cells have zero
+    pressure, and faces touching a solid are left alone since the fluid cannot flow through them
+    anyways.  Faces that a sub-cell solid covers completely are stopped instead. */
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    let cell_pressure = |row: usize, col: usize| -> f32 {
+        match system.cell_index[row * cols + col] {
+            Some(index) => pressure[index] as f32,
+            None => 0.0,
+        }
+    };
+    let is_fluid =
+        |row: usize, col: usize| -> bool { grid.cell_type[(row, col)] == SimGridCellType::Fluid };
+
+    let mut velocity_u: SimGridArray<f32> = grid.velocity_u.clone();
+    let mut velocity_v: SimGridArray<f32> = grid.velocity_v.clone();
+    for row in 0..rows {
+        for col in 0..cols {
+            if grid.get_cell_type_value(row, col) == 0 {
+                continue;
+            }
+
+            // Face between this cell and the cell to its right.
+            if col + 1 < cols && grid.get_cell_type_value(row, col + 1) != 0 {
+                if grid.get_face_open_fraction(row, col + 1, true) == 0.0 {
+                    velocity_u[(row, col + 1)] = 0.0;
+                } else if is_fluid(row, col) || is_fluid(row, col + 1) {
+                    velocity_u[(row, col + 1)] -= (cell_pressure(row, col + 1)
+                        - cell_pressure(row, col))
+                        / face_fluid_density(grid, (row, col), (row, col + 1));
+                }
+            }
+
+            // Face between this cell and the cell below it; v points up, so below -> here.
+            if row + 1 < rows && grid.get_cell_type_value(row + 1, col) != 0 {
+                if grid.get_face_open_fraction(row + 1, col, false) == 0.0 {
+                    velocity_v[(row + 1, col)] = 0.0;
+                } else if is_fluid(row, col) || is_fluid(row + 1, col) {
+                    velocity_v[(row + 1, col)] -= (cell_pressure(row, col)
+                        - cell_pressure(row + 1, col))
+                        / face_fluid_density(grid, (row, col), (row + 1, col));
                 }
+            }
+        }
+    }
+
+    /* Faces on the edges of the grid: outflow edges have air (zero pressure) past them, and a face
+    on a periodic edge is the same face as the one on the opposite edge. */
+    let is_open = |row: usize, col: usize| -> bool { grid.get_cell_type_value(row, col) != 0 };
+    let outside: (usize, usize) = (usize::MAX, usize::MAX);
+    for (row, row_velocities) in velocity_u.iter_rows_mut().enumerate() {
+        match grid.boundaries.west {
+            SimBoundary::Outflow if is_fluid(row, 0) => {
+                row_velocities[0] -=
+                    cell_pressure(row, 0) / face_fluid_density(grid, outside, (row, 0));
+            }
+            SimBoundary::Periodic
+                if is_open(row, 0)
+                    && is_open(row, cols - 1)
+                    && (is_fluid(row, 0) || is_fluid(row, cols - 1)) =>
+            {
+                let change: f32 = (cell_pressure(row, 0) - cell_pressure(row, cols - 1))
+                    / face_fluid_density(grid, (row, cols - 1), (row, 0));
+                row_velocities[0] -= change;
+                row_velocities[cols] -= change;
+            }
+            _ => {}
+        }
+        if grid.boundaries.east == SimBoundary::Outflow && is_fluid(row, cols - 1) {
+            row_velocities[cols] +=
+                cell_pressure(row, cols - 1) / face_fluid_density(grid, (row, cols - 1), outside);
+        }
+    }
+    let (top_velocities, bottom_velocities) = velocity_v.as_mut_slice().split_at_mut(rows * cols);
+    let edge_velocities = top_velocities[..cols]
+        .iter_mut()
+        .zip(bottom_velocities.iter_mut());
+    for (col, (top_velocity, bottom_velocity)) in edge_velocities.enumerate() {
+        match grid.boundaries.north {
+            SimBoundary::Outflow if is_fluid(0, col) => {
+                *top_velocity +=
+                    cell_pressure(0, col) / face_fluid_density(grid, outside, (0, col));
+            }
+            SimBoundary::Periodic
+                if is_open(0, col)
+                    && is_open(rows - 1, col)
+                    && (is_fluid(0, col) || is_fluid(rows - 1, col)) =>
+            {
+                let change: f32 = (cell_pressure(rows - 1, col) - cell_pressure(0, col))
+                    / face_fluid_density(grid, (rows - 1, col), (0, col));
+                *top_velocity -= change;
+                *bottom_velocity -= change;
+            }
+            _ => {}
+        }
+        if grid.boundaries.south == SimBoundary::Outflow && is_fluid(rows - 1, col) {
+            *bottom_velocity -=
+                cell_pressure(rows - 1, col) / face_fluid_density(grid, (rows - 1, col), outside);
+        }
+    }
+
+    // Store the pressure in physical units (taking water to have a density of 1).
+    let pressure_scale: f32 = grid.cell_size as f32 / timestep;
+    grid.cell_center.resize(rows, cols, 0.0);
+    for (lookup_index, cell_pressure_value) in grid.cell_center.iter_mut().enumerate() {
+        *cell_pressure_value =
+            cell_pressure(lookup_index / cols, lookup_index % cols) * pressure_scale;
+    }
+
+    grid.velocity_u = velocity_u;
+    grid.velocity_v = velocity_v;
+}
+
+/** The discrete pressure Poisson equation `A * p = b` for every fluid cell that has at least one
+non-solid neighbor.  Each face between two non-solid cells is weighted by 1 / (fluid density at
+the face), so heavier fluids are harder to push around.  Row i of A holds the sum of cell i's
+non-solid face weights on the diagonal and -weight for each fluid neighbor; air neighbors have
+zero pressure so they only add to the diagonal (as do outflow edges of the grid).  b is the
+negative divergence of each cell.  Cells are numbered in row-major order.  With only water, every
+weight is 1.  Neighbors across periodic edges are kept apart in `wraps`, and the preconditioner
+leaves them out.  Cells are colored red and black like a checkerboard, so that the neighbors of a
+red cell are all black and vice versa. */
+struct PressureSystem {
+    cell_index: Vec<Option<usize>>, // System index for each grid cell, by lookup index.
+    diagonal: Vec<f64>,             // A(i, i).
+    left: Vec<Option<usize>>,       // Fluid neighbor to the left of each cell.
+    right: Vec<Option<usize>>,      // Fluid neighbor to the right of each cell.
+    right_weight: Vec<f64>,         // -A(i, right).
+    up: Vec<Option<usize>>,         // Fluid neighbor above each cell.
+    down: Vec<Option<usize>>,       // Fluid neighbor below each cell.
+    down_weight: Vec<f64>,          // -A(i, down).
+    wraps: Vec<(usize, usize, f64)>, // Neighbors across periodic edges of the grid, and -A(i, j).
+    red: Vec<bool>,                 // Is each cell red (row + col is even) or black?
+    rhs: Vec<f64>,                  // b.
+}
+
+/// One cell's row of the pressure equation; see PressureSystem.
+struct PressureEquation {
+    diagonal: f64,
+    right: Option<usize>,
+    right_weight: f64,
+    down: Option<usize>,
+    down_weight: f64,
+    wraps: [Option<(usize, f64)>; 2], // Neighbors across the east and north edges, if periodic.
+    rhs: f64,
+}
+
+impl PressureEquation {
+    /// Build the equation for the fluid cell at (row, col).
+    fn new(
+        grid: &SimGrid,
+        constraints: &SimConstraints,
+        cell_index: &[Option<usize>],
+        row: usize,
+        col: usize,
+    ) -> Self {
+        let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+        let mut equation: Self = Self {
+            diagonal: 0.0,
+            right: None,
+            right_weight: 0.0,
+            down: None,
+            down_weight: 0.0,
+            wraps: [None; 2],
+            rhs: 0.0,
+        };
+
+        let solids: [f32; 5] = calculate_cell_solids(grid, row, col);
+        let neighbors: [(usize, usize); 4] = find_cell_neighbors(grid, row, col);
+        for (neighbor, open) in neighbors.into_iter().zip(solids[1..].iter()) {
+            if *open > 0.0 {
+                equation.diagonal += (open / face_fluid_density(grid, (row, col), neighbor)) as f64;
+            }
+        }
+        if col + 1 < cols {
+            equation.right = cell_index[row * cols + col + 1];
+            equation.right_weight =
+                (solids[2] / face_fluid_density(grid, (row, col), (row, col + 1))) as f64;
+        } else if grid.boundaries.east == SimBoundary::Periodic {
+            if let Some(other) = cell_index[row * cols + neighbors[1].1] {
+                let weight: f32 = solids[2] / face_fluid_density(grid, (row, col), neighbors[1]);
+                equation.wraps[0] = Some((other, weight as f64));
+            }
+        }
+        if row + 1 < rows {
+            equation.down = cell_index[(row + 1) * cols + col];
+            equation.down_weight =
+                (solids[4] / face_fluid_density(grid, (row, col), (row + 1, col))) as f64;
+        } else if grid.boundaries.north == SimBoundary::Periodic {
+            if let Some(other) = cell_index[neighbors[3].0 * cols + col] {
+                let weight: f32 = solids[4] / face_fluid_density(grid, (row, col), neighbors[3]);
+                equation.wraps[1] = Some((other, weight as f64));
+            }
+        }
+
+        let mut divergence: f32 = calculate_cell_divergence(grid, row, col);
+
+        /* Density calculations; will reduce jittering in high-density areas by negatively
+        increasing divergence, indicating there is too much inflow. */
+        if constraints.particle_rest_density > 0.0 {
+            let stiffness: f32 = 1.0;
+            let density: f32 = grid.density[(row, col)];
+            let compression: f32 = density - constraints.particle_rest_density;
+            if compression > 0.0 {
+                divergence -= stiffness * compression;
+            }
+        }
 
-                // Calculate and sum the solid modifiers for each surrounding cell.
-                let solids: [u8; 5] = calculate_cell_solids(&grid, row as usize, col as usize);
-                let left_solid: u8 = solids[1];
-                let right_solid: u8 = solids[2];
-                let up_solid: u8 = solids[3];
-                let down_solid: u8 = solids[4];
-                let solids_sum: u8 = left_solid + right_solid + up_solid + down_solid;
+        equation.rhs = -divergence as f64;
+        equation
+    }
+}
 
-                if solids_sum == 0 {
+impl PressureSystem {
+    /// Build the pressure equation from the grid's current velocities and cell types.
+    fn new(grid: &SimGrid, constraints: &SimConstraints) -> Self {
+        let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+
+        // Number each fluid cell; fluid cells boxed in by solids on every side are left out.
+        let mut cell_index: Vec<Option<usize>> = vec![None; rows * cols];
+        let mut cells: Vec<(usize, usize)> = Vec::new();
+        for row in 0..rows {
+            for col in 0..cols {
+                if grid.cell_type[(row, col)] != SimGridCellType::Fluid {
                     continue;
-                } // else if solids_sum != 4 {
-                  // println!("Solids: {:?}, Position: {}, State: {:?}", solids, grid.get_cell_center_position_from_coordinates(&Vec2::new(row as f32, col as f32)), grid.cell_type[row as usize][col as usize]);
-                  // }
-
-                // Determine the inflow/outflow of the current cell.
-                let mut divergence: f32 =
-                    calculate_cell_divergence(&grid, row as usize, col as usize);
-
-                /* Density calculations; will reduce jittering in high-density areas by negatively
-                increasing divergence, indicating there is too much inflow. */
-                if constraints.particle_rest_density > 0.0 {
-                    let stiffness: f32 = 1.0;
-                    let cell_coordinates: Vec2 = Vec2 {
-                        x: row as f32,
-                        y: col as f32,
-                    };
-                    let density: f32 = grid.density[grid.get_lookup_index(cell_coordinates)];
-                    let compression: f32 = density - constraints.particle_rest_density;
-                    if compression > 0.0 {
-                        divergence -= stiffness * compression;
+                }
+                let solids: [f32; 5] = calculate_cell_solids(grid, row, col);
+                if solids[1] + solids[2] + solids[3] + solids[4] == 0.0 {
+                    continue;
+                }
+
+                cell_index[row * cols + col] = Some(cells.len());
+                cells.push((row, col));
+            }
+        }
+
+        /* Each cell's row of A and divergence only depend on the grid, so find them in parallel,
+        then gather them up in order. */
+        let equations: Vec<PressureEquation> = cells
+            .par_iter()
+            .with_min_len(PARALLEL_CHUNK_SIZE)
+            .map(|&(row, col)| PressureEquation::new(grid, constraints, &cell_index, row, col))
+            .collect();
+
+        let count: usize = cells.len();
+        let mut diagonal: Vec<f64> = Vec::with_capacity(count);
+        let mut right: Vec<Option<usize>> = Vec::with_capacity(count);
+        let mut right_weight: Vec<f64> = Vec::with_capacity(count);
+        let mut down: Vec<Option<usize>> = Vec::with_capacity(count);
+        let mut down_weight: Vec<f64> = Vec::with_capacity(count);
+        let mut rhs: Vec<f64> = Vec::with_capacity(count);
+        let mut wraps: Vec<(usize, usize, f64)> = Vec::new();
+        for (index, equation) in equations.into_iter().enumerate() {
+            diagonal.push(equation.diagonal);
+            right.push(equation.right);
+            right_weight.push(equation.right_weight);
+            down.push(equation.down);
+            down_weight.push(equation.down_weight);
+            rhs.push(equation.rhs);
+            for (other, weight) in equation.wraps.into_iter().flatten() {
+                wraps.push((index, other, weight));
+            }
+        }
+
+        // The left and up neighbors of each cell, and its color on a checkerboard.
+        let mut left: Vec<Option<usize>> = vec![None; count];
+        let mut up: Vec<Option<usize>> = vec![None; count];
+        for i in 0..count {
+            if let Some(right) = right[i] {
+                left[right] = Some(i);
+            }
+            if let Some(down) = down[i] {
+                up[down] = Some(i);
+            }
+        }
+        let red: Vec<bool> = cells
+            .iter()
+            .map(|(row, col)| (row + col) % 2 == 0)
+            .collect();
+
+        /* A fluid region that touches no air has no pressure to anchor it, so A is singular there;
+        remove the average of b over each such region to keep the system solvable. */
+        let mut visited: Vec<bool> = vec![false; cells.len()];
+        for start in 0..cells.len() {
+            if visited[start] {
+                continue;
+            }
+            visited[start] = true;
+
+            let mut region: Vec<usize> = vec![start];
+            let mut touches_air: bool = false;
+            let mut next: usize = 0;
+            while next < region.len() {
+                let (row, col) = cells[region[next]];
+                next += 1;
+
+                let solids: [f32; 5] = calculate_cell_solids(grid, row, col);
+                let neighbors: [(usize, usize); 4] = find_cell_neighbors(grid, row, col);
+                for ((neighbor_row, neighbor_col), open) in
+                    neighbors.into_iter().zip(solids[1..].iter())
+                {
+                    if *open == 0.0 {
+                        continue;
+                    }
+                    if neighbor_row >= rows || neighbor_col >= cols {
+                        // Fluid flowing out through an open edge of the grid is next to air.
+                        touches_air = true;
+                        continue;
                     }
+                    if grid.cell_type[(neighbor_row, neighbor_col)] == SimGridCellType::Air {
+                        touches_air = true;
+                    }
+                    if let Some(neighbor) = cell_index[neighbor_row * cols + neighbor_col] {
+                        if !visited[neighbor] {
+                            visited[neighbor] = true;
+                            region.push(neighbor);
+                        }
+                    }
+                }
+            }
+
+            if !touches_air {
+                let mean: f64 = region.iter().map(|&i| rhs[i]).sum::<f64>() / region.len() as f64;
+                for &i in region.iter() {
+                    rhs[i] -= mean;
                 }
+            }
+        }
 
-                // Force incompressibility on this cell.
-                let overrelaxation: f32 = 1.99;
-                let momentum: f32 = overrelaxation * ((0.0 - divergence) / solids_sum as f32);
+        Self {
+            cell_index,
+            diagonal,
+            left,
+            right,
+            right_weight,
+            up,
+            down,
+            down_weight,
+            wraps,
+            red,
+            rhs,
+        }
+    }
+
+    /** Solve the system with the preconditioned conjugate gradient method.  Stops once the
+    largest residual is at most `tolerance` times the largest right hand side value, or after
+    `max_iterations`.  Returns the pressure, the largest residual reached, and the number of
+    iterations run.  Everything is done in f64 since CG loses accuracy quickly in f32.  Every
+    vector operation runs in parallel; see dot() for what `deterministic` does. */
+    fn solve(
+        &self,
+        tolerance: f64,
+        max_iterations: usize,
+        deterministic: bool,
+    ) -> (Vec<f64>, f64, usize) {
+        let mut pressure: Vec<f64> = vec![0.0; self.rhs.len()];
+        let mut residual: Vec<f64> = self.rhs.clone();
+        let target: f64 = tolerance * max_abs(&self.rhs);
+        if max_abs(&residual) <= target {
+            return (pressure, max_abs(&residual), 0);
+        }
 
-                grid.velocity_u[row as usize][col as usize] -= momentum * left_solid as f32;
-                grid.velocity_u[row as usize][(col + 1) as usize] += momentum * right_solid as f32;
-                grid.velocity_v[row as usize][col as usize] += momentum * up_solid as f32;
-                grid.velocity_v[(row + 1) as usize][col as usize] -= momentum * down_solid as f32;
+        let precon: Vec<f64> = self.build_preconditioner();
+        let mut aux: Vec<f64> = self.apply_preconditioner(&precon, &residual);
+        let mut search: Vec<f64> = aux.clone();
+        let mut sigma: f64 = dot(&aux, &residual, deterministic);
 
-                // grid.velocity_u[row as usize][col as usize]			*= left_solid as f32;
-                // grid.velocity_u[row as usize][(col + 1) as usize]	*= right_solid as f32;
-                // grid.velocity_v[row as usize][col as usize]			*= up_solid as f32;
-                // grid.velocity_v[(row + 1) as usize][col as usize]	*= down_solid as f32;
+        for iteration in 1..=max_iterations {
+            aux = self.multiply(&search);
+            let search_dot: f64 = dot(&aux, &search, deterministic);
+            if search_dot == 0.0 {
+                return (pressure, max_abs(&residual), iteration);
             }
+            let alpha: f64 = sigma / search_dot;
+            pressure
+                .par_iter_mut()
+                .zip(residual.par_iter_mut())
+                .zip(search.par_iter().zip(aux.par_iter()))
+                .with_min_len(PARALLEL_CHUNK_SIZE)
+                .for_each(|((pressure, residual), (search, aux))| {
+                    *pressure += alpha * search;
+                    *residual -= alpha * aux;
+                });
+
+            if max_abs(&residual) <= target {
+                return (pressure, max_abs(&residual), iteration);
+            }
+
+            aux = self.apply_preconditioner(&precon, &residual);
+            let sigma_new: f64 = dot(&aux, &residual, deterministic);
+            let beta: f64 = sigma_new / sigma;
+            search
+                .par_iter_mut()
+                .zip(aux.par_iter())
+                .with_min_len(PARALLEL_CHUNK_SIZE)
+                .for_each(|(search, aux)| *search = aux + beta * *search);
+            sigma = sigma_new;
+        }
+
+        (pressure, max_abs(&residual), max_iterations)
+    }
+
+    /// The fluid neighbors of a cell (not counting periodic ones), and -A(i, neighbor) for each.
+    fn neighbors(&self, i: usize) -> impl Iterator<Item = (usize, f64)> + '_ {
+        [
+            self.left[i].map(|left| (left, self.right_weight[left])),
+            self.right[i].map(|right| (right, self.right_weight[i])),
+            self.up[i].map(|up| (up, self.down_weight[up])),
+            self.down[i].map(|down| (down, self.down_weight[i])),
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    /// Compute A * vector.
+    fn multiply(&self, vector: &[f64]) -> Vec<f64> {
+        let mut result: Vec<f64> = (0..vector.len())
+            .into_par_iter()
+            .with_min_len(PARALLEL_CHUNK_SIZE)
+            .map(|i| {
+                let mut value: f64 = self.diagonal[i] * vector[i];
+                for (neighbor, weight) in self.neighbors(i) {
+                    value -= weight * vector[neighbor];
+                }
+                value
+            })
+            .collect();
+        for &(i, j, weight) in self.wraps.iter() {
+            result[i] -= weight * vector[j];
+            result[j] -= weight * vector[i];
+        }
+
+        result
+    }
+
+    /** Build the incomplete Cholesky (IC(0)) preconditioner with the red cells ordered before the
+    black ones; returns 1/sqrt(E) for each cell, where E is the diagonal of the incomplete factor.
+    Red cells only have black neighbors, so their part of the factor is just A's diagonal, and each
+    black cell only depends on the red cells around it; both colors are found in parallel.  Unlike
+    with row-major ordering, moving the dropped fill-in onto the diagonal (MIC(0)) only makes CG
+    take more iterations here, so it is left out. */
+    fn build_preconditioner(&self) -> Vec<f64> {
+        let safety: f64 = 0.25; // Fall back to A(i, i) below this fraction of it.
+        let count: usize = self.diagonal.len();
+
+        let red_precon: Vec<f64> = (0..count)
+            .into_par_iter()
+            .with_min_len(PARALLEL_CHUNK_SIZE)
+            .map(|i| match self.red[i] {
+                true => 1.0 / self.diagonal[i].sqrt(),
+                false => 0.0,
+            })
+            .collect();
+
+        (0..count)
+            .into_par_iter()
+            .with_min_len(PARALLEL_CHUNK_SIZE)
+            .map(|i| {
+                if self.red[i] {
+                    return red_precon[i];
+                }
+
+                /* Eliminating each red neighbor takes from this cell's diagonal, and couples it to
+                the red cell's other black neighbors; IC(0) drops those couplings. */
+                let mut e: f64 = self.diagonal[i];
+                for (red, coupling) in self.neighbors(i) {
+                    e -= (coupling * red_precon[red]) * (coupling * red_precon[red]);
+                }
+                if e < safety * self.diagonal[i] {
+                    e = self.diagonal[i];
+                }
+                1.0 / e.sqrt()
+            })
+            .collect()
+    }
+
+    /** Solve L * L^T * result = vector using the IC(0) factor L.  Each substitution solves all of
+    one color in parallel, then all of the other color in parallel. */
+    fn apply_preconditioner(&self, precon: &[f64], vector: &[f64]) -> Vec<f64> {
+        let count: usize = vector.len();
+
+        // Forward substitution (L * q = vector); red cells first, then black cells pull from them.
+        let red_q: Vec<f64> = (0..count)
+            .into_par_iter()
+            .with_min_len(PARALLEL_CHUNK_SIZE)
+            .map(|i| match self.red[i] {
+                true => vector[i] * precon[i],
+                false => 0.0,
+            })
+            .collect();
+        let q: Vec<f64> = (0..count)
+            .into_par_iter()
+            .with_min_len(PARALLEL_CHUNK_SIZE)
+            .map(|i| {
+                if self.red[i] {
+                    return red_q[i];
+                }
+                let mut t: f64 = vector[i];
+                for (red, weight) in self.neighbors(i) {
+                    t += weight * precon[red] * red_q[red];
+                }
+                t * precon[i]
+            })
+            .collect();
+
+        // Backward substitution (L^T * result = q); black cells first, then red cells pull from them.
+        let black_result: Vec<f64> = (0..count)
+            .into_par_iter()
+            .with_min_len(PARALLEL_CHUNK_SIZE)
+            .map(|i| match self.red[i] {
+                true => 0.0,
+                false => q[i] * precon[i],
+            })
+            .collect();
+        (0..count)
+            .into_par_iter()
+            .with_min_len(PARALLEL_CHUNK_SIZE)
+            .map(|i| {
+                if !self.red[i] {
+                    return black_result[i];
+                }
+                let mut t: f64 = q[i];
+                for (black, weight) in self.neighbors(i) {
+                    t += weight * precon[i] * black_result[black];
+                }
+                t * precon[i]
+            })
+            .collect()
+    }
+}
+
+/** Dot product of two vectors, summed in parallel.  In deterministic mode each chunk is summed on
+its own and the chunk sums are added in order, so the result doesn't depend on the thread count. */
+fn dot(a: &[f64], b: &[f64], deterministic: bool) -> f64 {
+    if deterministic {
+        let chunk_sums: Vec<f64> = a
+            .par_chunks(PARALLEL_CHUNK_SIZE)
+            .zip(b.par_chunks(PARALLEL_CHUNK_SIZE))
+            .map(|(a, b)| a.iter().zip(b.iter()).map(|(a, b)| a * b).sum())
+            .collect();
+        return chunk_sums.iter().sum();
+    }
+
+    a.par_iter()
+        .zip(b.par_iter())
+        .with_min_len(PARALLEL_CHUNK_SIZE)
+        .map(|(a, b)| a * b)
+        .sum()
+}
+
+/// Largest absolute value in a vector, or 0 for an empty vector.
+fn max_abs(vector: &[f64]) -> f64 {
+    vector
+        .par_iter()
+        .with_min_len(PARALLEL_CHUNK_SIZE)
+        .map(|value| value.abs())
+        .reduce(|| 0.0, f64::max)
+}
+
+/** Fluid density at the face between two neighboring cells: the average of both cells if they
+are both fluid, or the density of whichever one is fluid otherwise. */
+fn face_fluid_density(grid: &SimGrid, first: (usize, usize), second: (usize, usize)) -> f32 {
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    let cell_density = |(row, col): (usize, usize)| -> Option<f32> {
+        if row >= rows || col >= cols || grid.cell_type[(row, col)] != SimGridCellType::Fluid {
+            return None;
         }
+        match grid.fluid_density.get(row, col) {
+            Some(density) if *density > 0.0 => Some(*density),
+            _ => Some(1.0),
+        }
+    };
+
+    match (cell_density(first), cell_density(second)) {
+        (Some(first), Some(second)) => (first + second) / 2.0,
+        (Some(density), None) | (None, Some(density)) => density,
+        (None, None) => 1.0,
     }
 }
 
@@ -799,10 +2968,19 @@ fn calculate_cell_divergence(grid: &SimGrid, cell_row: usize, cell_col: usize) -
     /* Retrieve velocities for each face of the current cell.  Note: this will not go out of
     bounds of the velocity arrays; each array is guaranteed to have sufficient space allocated
     to index like this. */
-    let left_velocity: f32 = grid.velocity_u[cell_row][cell_col];
-    let right_velocity: f32 = grid.velocity_u[cell_row][cell_col + 1];
-    let up_velocity: f32 = grid.velocity_v[cell_row][cell_col];
-    let down_velocity: f32 = grid.velocity_v[cell_row + 1][cell_col];
+    let left_velocity: f32 = grid.velocity_u[(cell_row, cell_col)];
+    let right_velocity: f32 = grid.velocity_u[(cell_row, cell_col + 1)];
+    let up_velocity: f32 = grid.velocity_v[(cell_row, cell_col)];
+    let down_velocity: f32 = grid.velocity_v[(cell_row + 1, cell_col)];
+
+    /* Only the part of each face that isn't covered by a sub-cell solid carries any flow; the
+    solids themselves don't move. */
+    let left_velocity: f32 = left_velocity * grid.get_face_open_fraction(cell_row, cell_col, true);
+    let right_velocity: f32 =
+        right_velocity * grid.get_face_open_fraction(cell_row, cell_col + 1, true);
+    let up_velocity: f32 = up_velocity * grid.get_face_open_fraction(cell_row, cell_col, false);
+    let down_velocity: f32 =
+        down_velocity * grid.get_face_open_fraction(cell_row + 1, cell_col, false);
 
     // BUG: The up and down flows may need to be reversed.
     let x_divergence: f32 = right_velocity - left_velocity;
@@ -812,22 +2990,72 @@ fn calculate_cell_divergence(grid: &SimGrid, cell_row: usize, cell_col: usize) -
     divergence
 }
 
-/** Returns the cell solid modifiers (0 for solid, 1 otherwise) for cells in the order of: center,
-left, right, up, down. **/
-fn calculate_cell_solids(grid: &SimGrid, cell_row: usize, cell_col: usize) -> [u8; 5] {
-    /* Calculate collision modifiers for each cell face.  Note that we must perform a wrapping
-    subtraction to prevent an underflow for our usize types. */
+/** Returns the cells next to a cell in the order of: left, right, up, down.  Neighbors across a
+periodic edge are the cells on the opposite edge of the grid; neighbors across any other edge are
+out of bounds.  Note that we must perform a wrapping subtraction to prevent an underflow for our
+usize types. */
+fn find_cell_neighbors(grid: &SimGrid, cell_row: usize, cell_col: usize) -> [(usize, usize); 4] {
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    let mut neighbors: [(usize, usize); 4] = [
+        (cell_row, usize::wrapping_sub(cell_col, 1)),
+        (cell_row, cell_col + 1),
+        (usize::wrapping_sub(cell_row, 1), cell_col),
+        (cell_row + 1, cell_col),
+    ];
+
+    if grid.boundaries.east == SimBoundary::Periodic {
+        neighbors[0].1 = (cell_col + cols - 1) % cols;
+        neighbors[1].1 = (cell_col + 1) % cols;
+    }
+    if grid.boundaries.north == SimBoundary::Periodic {
+        neighbors[2].0 = (cell_row + rows - 1) % rows;
+        neighbors[3].0 = (cell_row + 1) % rows;
+    }
+
+    neighbors
+}
+
+/** Get the collision value of the cell across a face from another cell, like
+SimGrid::get_cell_type_value(), except that fluid can flow through outflow edges of the grid as if
+there were air past them. */
+fn get_neighbor_type_value(grid: &SimGrid, (row, col): (usize, usize)) -> u8 {
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    let edge: Option<SimSurfaceDirection> = if col == usize::MAX {
+        Some(SimSurfaceDirection::West)
+    } else if col >= cols {
+        Some(SimSurfaceDirection::East)
+    } else if row == usize::MAX {
+        Some(SimSurfaceDirection::North)
+    } else if row >= rows {
+        Some(SimSurfaceDirection::South)
+    } else {
+        None
+    };
+
+    match edge.map(|edge| grid.get_boundary(edge)) {
+        Some(SimBoundary::Outflow) => 1,
+        _ => grid.get_cell_type_value(row, col),
+    }
+}
+
+/** Returns the cell solid modifiers for a cell and its faces in the order of: center, left, right,
+up, down.  The center is 0 for a solid cell and 1 otherwise; each face is the fraction of it that
+fluid can flow through, which is 0 next to a solid cell (or a closed edge of the grid) and partly
+open where one of the grid's sub-cell solids cuts across it. **/
+fn calculate_cell_solids(grid: &SimGrid, cell_row: usize, cell_col: usize) -> [f32; 5] {
+    // Calculate collision modifiers for each cell face.
+    let neighbors: [(usize, usize); 4] = find_cell_neighbors(grid, cell_row, cell_col);
     let collision_center: u8 = grid.get_cell_type_value(cell_row, cell_col);
-    let collision_left: u8 = grid.get_cell_type_value(cell_row, usize::wrapping_sub(cell_col, 1));
-    let collision_right: u8 = grid.get_cell_type_value(cell_row, cell_col + 1);
-    let collision_up: u8 = grid.get_cell_type_value(usize::wrapping_sub(cell_row, 1), cell_col);
-    let collision_down: u8 = grid.get_cell_type_value(cell_row + 1, cell_col);
+    let collision_left: u8 = get_neighbor_type_value(grid, neighbors[0]);
+    let collision_right: u8 = get_neighbor_type_value(grid, neighbors[1]);
+    let collision_up: u8 = get_neighbor_type_value(grid, neighbors[2]);
+    let collision_down: u8 = get_neighbor_type_value(grid, neighbors[3]);
 
     [
-        collision_center,
-        collision_left,
-        collision_right,
-        collision_up,
-        collision_down,
+        collision_center as f32,
+        collision_left as f32 * grid.get_face_open_fraction(cell_row, cell_col, true),
+        collision_right as f32 * grid.get_face_open_fraction(cell_row, cell_col + 1, true),
+        collision_up as f32 * grid.get_face_open_fraction(cell_row, cell_col, false),
+        collision_down as f32 * grid.get_face_open_fraction(cell_row + 1, cell_col, false),
     ]
 }
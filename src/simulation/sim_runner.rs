@@ -0,0 +1,336 @@
+use bevy::ecs::system::SystemState;
+use bevy::prelude::*;
+use bevy::tasks::{ComputeTaskPool, TaskPool};
+
+use super::sim_state_manager::{
+    add_drain, add_faucet, add_obstacle, add_particle, add_particles_in_radius, add_rigid_body,
+    add_smoke_in_radius,
+};
+use super::{
+    reset_simulation_to_default, step_simulation_frame, SimConstraints, SimDrain, SimFaucet,
+    SimGrid, SimObstacle, SimParticle, SimRigidBody, SimShape, SimSurfaceDirection,
+};
+use crate::error::Error;
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Everything step_simulation_once() needs out of the world, fetched all at once.
+type SimStepParams = (
+    Commands<'static, 'static>,
+    ResMut<'static, SimConstraints>,
+    ResMut<'static, SimGrid>,
+    Query<'static, 'static, (Entity, &'static mut SimParticle)>,
+    Query<'static, 'static, (Entity, &'static mut SimFaucet)>,
+    Query<'static, 'static, (Entity, &'static mut SimDrain)>,
+    Query<'static, 'static, (Entity, &'static mut SimObstacle)>,
+    Query<'static, 'static, (Entity, &'static mut SimRigidBody)>,
+);
+
+/** Headless simulation runner; owns the Bevy world that the simulation lives in so that the
+PIC/FLIP solver can be driven from tools, tests, and servers without an App, a window, or a UI.
+Every call applies its queued commands before returning, so spawned and despawned particles are
+visible right away. */
+pub struct SimRunner {
+    world: World,
+    system_state: SystemState<SimStepParams>,
+}
+
+impl Default for SimRunner {
+    fn default() -> SimRunner {
+        SimRunner::new(SimConstraints::default(), SimGrid::default())
+    }
+}
+
+impl SimRunner {
+    /// Create a new runner with no particles, faucets, drains, obstacles, or rigid bodies.
+    pub fn new(constraints: SimConstraints, grid: SimGrid) -> Self {
+        // Drains use par_iter_mut(), which needs a compute task pool; normally Bevy makes this.
+        ComputeTaskPool::get_or_init(TaskPool::default);
+
+        let mut world: World = World::new();
+        world.insert_resource(constraints);
+        world.insert_resource(grid);
+        let system_state: SystemState<SimStepParams> = SystemState::new(&mut world);
+
+        Self {
+            world,
+            system_state,
+        }
+    }
+
+    /** Run `f` with the same arguments the simulation systems receive, then apply any commands
+    it queued.  This is the escape hatch for anything the runner does not wrap itself. */
+    pub fn run<R>(
+        &mut self,
+        f: impl FnOnce(
+            &mut Commands,
+            &mut SimConstraints,
+            &mut SimGrid,
+            &mut Query<(Entity, &mut SimParticle)>,
+            &Query<(Entity, &mut SimFaucet)>,
+            &Query<(Entity, &mut SimDrain)>,
+            &mut Query<(Entity, &mut SimObstacle)>,
+            &mut Query<(Entity, &mut SimRigidBody)>,
+        ) -> R,
+    ) -> R {
+        let (
+            mut commands,
+            mut constraints,
+            mut grid,
+            mut particles,
+            faucets,
+            drains,
+            mut obstacles,
+            mut rigid_bodies,
+        ) = self.system_state.get_mut(&mut self.world);
+
+        let result: R = f(
+            &mut commands,
+            constraints.as_mut(),
+            grid.as_mut(),
+            &mut particles,
+            &faucets,
+            &drains,
+            &mut obstacles,
+            &mut rigid_bodies,
+        );
+
+        self.system_state.apply(&mut self.world);
+        result
+    }
+
+    /** Step the simulation one frame using the constraints' fixed timestep, split into substeps
+    if the constraints ask for an adaptive timestep. */
+    pub fn step(&mut self) {
+        let timestep: f32 = self.constraints().timestep;
+        self.run(
+            |commands, constraints, grid, particles, faucets, drains, obstacles, rigid_bodies| {
+                step_simulation_frame(
+                    commands,
+                    constraints,
+                    grid,
+                    particles,
+                    faucets,
+                    drains,
+                    obstacles,
+                    rigid_bodies,
+                    timestep,
+                );
+            },
+        );
+    }
+
+    /** Step the simulation one frame of a custom timestep, split into substeps if the constraints
+    ask for an adaptive timestep. */
+    pub fn step_with_timestep(&mut self, timestep: f32) {
+        self.run(
+            |commands, constraints, grid, particles, faucets, drains, obstacles, rigid_bodies| {
+                step_simulation_frame(
+                    commands,
+                    constraints,
+                    grid,
+                    particles,
+                    faucets,
+                    drains,
+                    obstacles,
+                    rigid_bodies,
+                    timestep,
+                );
+            },
+        );
+    }
+
+    /// Step the simulation `steps` frames using the constraints' fixed timestep.
+    pub fn step_many(&mut self, steps: usize) {
+        for _ in 0..steps {
+            self.step();
+        }
+    }
+
+    /// Delete all particles, faucets, drains, obstacles, and rigid bodies, and reset the grid and
+    /// constraints.
+    pub fn reset(&mut self) {
+        self.run(
+            |commands, constraints, grid, particles, faucets, drains, obstacles, rigid_bodies| {
+                reset_simulation_to_default(
+                    commands,
+                    constraints,
+                    grid,
+                    particles,
+                    faucets,
+                    drains,
+                    obstacles,
+                    rigid_bodies,
+                );
+            },
+        );
+    }
+
+    /// Add a single particle of a fluid material to the simulation.
+    pub fn add_particle(&mut self, position: Vec2, velocity: Vec2, material: usize) -> Result<()> {
+        self.run(|commands, constraints, grid, _, _, _, _, _| {
+            add_particle(commands, constraints, grid, position, velocity, material)
+        })
+    }
+
+    /** Add many particles of a fluid material into the simulation within a radius.  Note that
+    particle_density is the number of particles per unit radius. */
+    pub fn add_particles_in_radius(
+        &mut self,
+        particle_density: f32,
+        radius: f32,
+        center_position: Vec2,
+        velocity: Vec2,
+        material: usize,
+    ) {
+        self.run(|commands, constraints, grid, _, _, _, _, _| {
+            add_particles_in_radius(
+                commands,
+                constraints,
+                grid,
+                particle_density,
+                radius,
+                center_position,
+                velocity,
+                material,
+            );
+        });
+    }
+
+    /// Add hot smoke to every open cell within a radius; only smoke scenes simulate it.
+    pub fn add_smoke_in_radius(
+        &mut self,
+        density: f32,
+        radius: f32,
+        center_position: Vec2,
+    ) -> Result<()> {
+        self.run(|_, constraints, grid, _, _, _, _, _| {
+            add_smoke_in_radius(constraints, grid, density, radius, center_position)
+        })
+    }
+
+    /// Add a faucet of a fluid material to the simulation.
+    pub fn add_faucet(
+        &mut self,
+        position: Vec2,
+        surface_direction: Option<SimSurfaceDirection>,
+        diameter: f32,
+        flow: Vec2,
+        material: usize,
+    ) -> Result<()> {
+        self.run(|commands, _, grid, _, _, _, _, _| {
+            add_faucet(
+                commands,
+                grid,
+                position,
+                surface_direction,
+                diameter,
+                flow,
+                material,
+            )
+        })
+    }
+
+    /// Add a drain to the simulation.
+    pub fn add_drain(
+        &mut self,
+        position: Vec2,
+        surface_direction: Option<SimSurfaceDirection>,
+        radius: f32,
+        pressure: f32,
+    ) -> Result<()> {
+        self.run(|commands, _, grid, _, _, _, _, _| {
+            add_drain(
+                commands,
+                grid,
+                position,
+                surface_direction,
+                radius,
+                pressure,
+            )
+        })
+    }
+
+    /// Add an obstacle that moves and spins on its own through the simulation.
+    pub fn add_obstacle(
+        &mut self,
+        shape: SimShape,
+        position: Vec2,
+        velocity: Vec2,
+        angular_velocity: f32,
+    ) -> Result<()> {
+        self.run(|commands, _, grid, _, _, _, _, _| {
+            add_obstacle(commands, grid, shape, position, velocity, angular_velocity)
+        })
+    }
+
+    /// Get a copy of every obstacle in the simulation along with its entity ID.
+    pub fn obstacles(&mut self) -> Vec<(Entity, SimObstacle)> {
+        self.world
+            .query::<(Entity, &SimObstacle)>()
+            .iter(&self.world)
+            .map(|(id, obstacle)| (id, obstacle.clone()))
+            .collect()
+    }
+
+    /// Add a rigid body that gravity and the fluid will move around.
+    pub fn add_rigid_body(
+        &mut self,
+        shape: SimShape,
+        mass: f32,
+        position: Vec2,
+        velocity: Vec2,
+    ) -> Result<()> {
+        self.run(|commands, _, grid, _, _, _, _, _| {
+            add_rigid_body(commands, grid, shape, mass, position, velocity)
+        })
+    }
+
+    /// Get a copy of every rigid body in the simulation along with its entity ID.
+    pub fn rigid_bodies(&mut self) -> Vec<(Entity, SimRigidBody)> {
+        self.world
+            .query::<(Entity, &SimRigidBody)>()
+            .iter(&self.world)
+            .map(|(id, rigid_body)| (id, rigid_body.clone()))
+            .collect()
+    }
+
+    /// Get a copy of every particle in the simulation along with its entity ID.
+    pub fn particles(&mut self) -> Vec<(Entity, SimParticle)> {
+        self.world
+            .query::<(Entity, &SimParticle)>()
+            .iter(&self.world)
+            .map(|(id, particle)| (id, particle.clone()))
+            .collect()
+    }
+
+    /// Get the simulation's constraints.
+    pub fn constraints(&self) -> &SimConstraints {
+        self.world.resource::<SimConstraints>()
+    }
+
+    /// Get the simulation's constraints for modification.
+    pub fn constraints_mut(&mut self) -> Mut<'_, SimConstraints> {
+        self.world.resource_mut::<SimConstraints>()
+    }
+
+    /// Get the simulation's grid.
+    pub fn grid(&self) -> &SimGrid {
+        self.world.resource::<SimGrid>()
+    }
+
+    /// Get the simulation's grid for modification.
+    pub fn grid_mut(&mut self) -> Mut<'_, SimGrid> {
+        self.world.resource_mut::<SimGrid>()
+    }
+
+    /// Get the world the simulation lives in.
+    pub fn world(&self) -> &World {
+        &self.world
+    }
+
+    /// Get the world the simulation lives in for modification (e.g. loading or saving scenes).
+    pub fn world_mut(&mut self) -> &mut World {
+        &mut self.world
+    }
+}
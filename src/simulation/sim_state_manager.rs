@@ -8,8 +8,9 @@ use super::*;
 
 pub type Result<T> = core::result::Result<T, Error>;
 
-/** Add many particles into the simulation within a radius.  Note that particle_density is
-the number of particles per unit radius. */
+/** Add many particles of one fluid material into the simulation within a radius.  Note that
+particle_density is the number of particles per unit radius. */
+#[allow(clippy::too_many_arguments)]
 pub fn add_particles_in_radius(
     commands: &mut Commands,
     constraints: &mut SimConstraints,
@@ -18,9 +19,17 @@ pub fn add_particles_in_radius(
     radius: f32,
     center_position: Vec2,
     velocity: Vec2,
+    material: usize,
 ) {
     // Create center particle.
-    let _center_particle = add_particle(commands, constraints, grid, center_position, velocity);
+    let _center_particle = add_particle(
+        commands,
+        constraints,
+        grid,
+        center_position,
+        velocity,
+        material,
+    );
 
     // Density for the rings inside the circle.
     let ring_density: f32 = particle_density * 2.0;
@@ -30,8 +39,8 @@ pub fn add_particles_in_radius(
     for ring_index in 1..ring_count {
         /* Create each particle around the current ring. */
         let ring_radius: f32 = ring_index as f32 / ring_density * 10.0;
-        let particle_count: usize = (ring_radius as f32 * particle_density) as usize;
-        for particle_index in 0..particle_count as usize {
+        let particle_count: usize = (ring_radius * particle_density) as usize;
+        for particle_index in 0..particle_count {
             // Find the angle around the circle so we can correctly position this particle.
             let angle: f32 = particle_index as f32 * ((2.0 * PI) / particle_count as f32);
 
@@ -42,13 +51,23 @@ pub fn add_particles_in_radius(
             };
 
             // If particle_position is outside the grid bounds, this will not create a particle:
-            let _particle = add_particle(commands, constraints, grid, particle_position, velocity);
+            let _particle = add_particle(
+                commands,
+                constraints,
+                grid,
+                particle_position,
+                velocity,
+                material,
+            );
         }
     }
 }
 
+/** Remove every particle within `radius` of `position`.  Particles already taken out of the
+lookup (say, by an overlapping drain earlier this frame) are skipped so they aren't counted twice. */
 pub fn delete_particles_in_radius(
     commands: &mut Commands,
+    constraints: &mut SimConstraints,
     grid: &mut SimGrid,
     particles: &Query<(Entity, &mut SimParticle)>,
     position: Vec2,
@@ -56,20 +75,85 @@ pub fn delete_particles_in_radius(
 ) {
     // Can't be par_iter() because &mut commands doesn't have Clone
     particles.iter().for_each(|(id, particle)| {
-        if position.distance(particle.position) <= radius {
+        if position.distance(particle.position) <= radius
+            && grid.remove_particle_from_lookup(id, particle.lookup_index)
+        {
             commands.entity(id).despawn();
-            grid.remove_particle_from_lookup(id, particle.lookup_index);
+            constraints.particle_count = constraints.particle_count.saturating_sub(1);
         }
     });
 }
 
-/// Add particles into the simulation.
+/** Add smoke to every open cell whose center is within a radius, up to a density of 1.0.  New
+smoke is as hot as a heater, so it rises on its own. */
+pub fn add_smoke_in_radius(
+    constraints: &SimConstraints,
+    grid: &mut SimGrid,
+    density: f32,
+    radius: f32,
+    center_position: Vec2,
+) -> Result<()> {
+    if !grid.is_position_within_grid(&center_position) {
+        return Err(Error::OutOfGridBounds(
+            "Position for smoke creation is out of grid bounds!",
+        ));
+    }
+
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    grid.smoke_density.resize(rows, cols, 0.0);
+    grid.temperature
+        .resize(rows, cols, constraints.ambient_temperature);
+    for (row, col) in cells_in_radius(grid, center_position, radius) {
+        grid.smoke_density[(row, col)] = f32::min(1.0, grid.smoke_density[(row, col)] + density);
+        grid.temperature[(row, col)] = constraints.heater_temperature;
+    }
+
+    Ok(())
+}
+
+/// Clear all smoke out of every cell whose center is within a radius, leaving it at room temperature.
+pub fn delete_smoke_in_radius(
+    constraints: &SimConstraints,
+    grid: &mut SimGrid,
+    center_position: Vec2,
+    radius: f32,
+) {
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    grid.smoke_density.resize(rows, cols, 0.0);
+    grid.temperature
+        .resize(rows, cols, constraints.ambient_temperature);
+    for (row, col) in cells_in_radius(grid, center_position, radius) {
+        grid.smoke_density[(row, col)] = 0.0;
+        grid.temperature[(row, col)] = constraints.ambient_temperature;
+    }
+}
+
+/// Find the (row, column) of every non-solid cell whose center is within a radius of a position.
+fn cells_in_radius(grid: &SimGrid, center_position: Vec2, radius: f32) -> Vec<(usize, usize)> {
+    let mut cells: Vec<(usize, usize)> = Vec::new();
+    for row in 0..grid.dimensions.0 as usize {
+        for col in 0..grid.dimensions.1 as usize {
+            let cell_center: Vec2 =
+                grid.get_cell_center_position_from_coordinates(&Vec2::new(row as f32, col as f32));
+            if grid.get_cell_type_value(row, col) != 0
+                && cell_center.distance(center_position) <= radius
+            {
+                cells.push((row, col));
+            }
+        }
+    }
+
+    cells
+}
+
+/// Add a particle of the given fluid material into the simulation.
 pub fn add_particle(
     commands: &mut Commands,
     constraints: &mut SimConstraints,
     grid: &mut SimGrid,
     position: Vec2,
     velocity: Vec2,
+    material: usize,
 ) -> Result<()> {
     // Don't allow the user to create particles out of the simulation grid's bounds!
     if position[0] < 0.0 || position[0] > (grid.dimensions.1 * grid.cell_size) as f32 {
@@ -85,22 +169,27 @@ pub fn add_particle(
     // If the cell we are inside of is a solid, don't create the particle!
     let cell_coordinates: Vec2 = grid.get_cell_coordinates_from_position(&position);
     if matches!(
-        grid.cell_type[cell_coordinates[0] as usize][cell_coordinates[1] as usize],
+        grid.cell_type[(cell_coordinates[0] as usize, cell_coordinates[1] as usize)],
         SimGridCellType::Solid
     ) {
         return Err(Error::InvalidCellParticleCreation("Chosen cell is solid!"));
     }
+    // Only allow particles made of a fluid the simulation knows about!
+    if material >= constraints.fluid_materials.len() {
+        return Err(Error::InvalidMaterial("No fluid material with this index!"));
+    }
 
-    // Add every particle to the 0-cell's lookup at first; we will sort this next frame.
-    let lookup_index: usize = 0;
-    let particle: Entity = commands
-        .spawn(SimParticle {
-            position: position,
-            velocity: velocity,
-            lookup_index: lookup_index,
-        })
-        .id();
-    grid.add_particle_to_lookup(particle, lookup_index);
+    /* The particle joins the spatial lookup the next time it is rebuilt, at the start of the next
+    step, along with every other particle. */
+    let lookup_index: usize = grid.get_lookup_index(cell_coordinates);
+    commands.spawn(SimParticle {
+        position,
+        velocity,
+        lookup_index,
+        affine: Mat2::ZERO,
+        material,
+        temperature: constraints.ambient_temperature,
+    });
 
     constraints.particle_count += 1;
 
@@ -151,7 +240,7 @@ pub fn delete_all_particles(
 
 /** Returns a vector of entity ID's of each particle within a circle centered at `position` with
 radius `radius`; returns an empty vector if no particles are found. */
-pub fn select_particles<'a>(
+pub fn select_particles(
     particles: &Query<(Entity, &mut SimParticle)>,
     grid: &SimGrid,
     position: Vec2,
@@ -162,8 +251,8 @@ pub fn select_particles<'a>(
     // TODO: Maybe use map() here?  Idk.  Garrett I need u to explain map() to me I don't get it :(
     let selected_cell_coordinates: Vec<Vec2> = grid.select_grid_cells(position, radius);
 
-    for i in 0..selected_cell_coordinates.len() {
-        let cell_lookup_index: usize = grid.get_lookup_index(selected_cell_coordinates[i]);
+    for cell_coordinates in selected_cell_coordinates {
+        let cell_lookup_index: usize = grid.get_lookup_index(cell_coordinates);
         for particle_id in grid.get_particles_in_lookup(cell_lookup_index) {
             // Skip particles we can't find
             let Ok(particle_entity) = particles.get(particle_id) else {
@@ -192,6 +281,7 @@ pub fn add_faucet(
     surface_direction: Option<SimSurfaceDirection>,
     faucet_diameter: f32,
     faucet_flow: Vec2,
+    faucet_material: usize,
 ) -> Result<()> {
     if faucet_pos[0] < 0.0 || faucet_pos[0] > (grid.dimensions.1 * grid.cell_size) as f32 {
         return Err(Error::OutOfGridBounds(
@@ -210,6 +300,7 @@ pub fn add_faucet(
             surface_direction,
             faucet_diameter,
             faucet_flow,
+            faucet_material,
         ))
         .id();
     // link_faucet_sprite(commands, &asset_server, faucet, faucet_pos);
@@ -224,13 +315,13 @@ pub fn delete_faucet(
     faucet_id: Entity,
 ) -> Result<()> {
     // Look for the faucet
-    if let Err(_) = faucets.get(faucet_id) {
+    if faucets.get(faucet_id).is_err() {
         return Err(Error::InvalidEntityID("Invalid faucet entity ID!"));
     }
 
     commands.entity(faucet_id).despawn();
 
-    return Ok(());
+    Ok(())
 }
 
 /// Remove all faucets from the simulation.
@@ -280,13 +371,13 @@ pub fn delete_drain(
     drain_id: Entity,
 ) -> Result<()> {
     // Look for the drain
-    if let Err(_) = drains.get(drain_id) {
+    if drains.get(drain_id).is_err() {
         return Err(Error::InvalidEntityID("Invalid drain entity ID!"));
     }
 
     commands.entity(drain_id).despawn();
 
-    return Ok(());
+    Ok(())
 }
 
 /// Remove all drains from the simulation.
@@ -297,6 +388,102 @@ pub fn delete_all_drains(commands: &mut Commands, drains: &Query<(Entity, &mut S
     }
 }
 
+/** Add an obstacle to the simulation; it moves on its own from then on, carrying along any
+fluid in its way. */
+pub fn add_obstacle(
+    commands: &mut Commands,
+    grid: &SimGrid,
+    shape: SimShape,
+    position: Vec2,
+    velocity: Vec2,
+    angular_velocity: f32,
+) -> Result<()> {
+    if !grid.is_position_within_grid(&position) {
+        return Err(Error::OutOfGridBounds(
+            "Position for obstacle creation is out of grid bounds!",
+        ));
+    }
+
+    commands.spawn(SimObstacle::new(
+        shape,
+        position,
+        velocity,
+        angular_velocity,
+    ));
+
+    Ok(())
+}
+
+/// Remove an obstacle from the simulation.
+pub fn delete_obstacle(
+    commands: &mut Commands,
+    obstacles: &Query<(Entity, &mut SimObstacle)>,
+    obstacle_id: Entity,
+) -> Result<()> {
+    if obstacles.get(obstacle_id).is_err() {
+        return Err(Error::InvalidEntityID("Invalid obstacle entity ID!"));
+    }
+
+    commands.entity(obstacle_id).despawn();
+
+    Ok(())
+}
+
+/// Remove all obstacles from the simulation.
+pub fn delete_all_obstacles(
+    commands: &mut Commands,
+    obstacles: &Query<(Entity, &mut SimObstacle)>,
+) {
+    for (obstacle_id, _) in obstacles.iter() {
+        let _ = delete_obstacle(commands, obstacles, obstacle_id);
+    }
+}
+
+/// Add a rigid body to the simulation; from then on, gravity and the fluid move it around.
+pub fn add_rigid_body(
+    commands: &mut Commands,
+    grid: &SimGrid,
+    shape: SimShape,
+    mass: f32,
+    position: Vec2,
+    velocity: Vec2,
+) -> Result<()> {
+    if !grid.is_position_within_grid(&position) {
+        return Err(Error::OutOfGridBounds(
+            "Position for rigid body creation is out of grid bounds!",
+        ));
+    }
+
+    commands.spawn(SimRigidBody::new(shape, mass, position, velocity));
+
+    Ok(())
+}
+
+/// Remove a rigid body from the simulation.
+pub fn delete_rigid_body(
+    commands: &mut Commands,
+    rigid_bodies: &Query<(Entity, &mut SimRigidBody)>,
+    rigid_body_id: Entity,
+) -> Result<()> {
+    if rigid_bodies.get(rigid_body_id).is_err() {
+        return Err(Error::InvalidEntityID("Invalid rigid body entity ID!"));
+    }
+
+    commands.entity(rigid_body_id).despawn();
+
+    Ok(())
+}
+
+/// Remove all rigid bodies from the simulation.
+pub fn delete_all_rigid_bodies(
+    commands: &mut Commands,
+    rigid_bodies: &Query<(Entity, &mut SimRigidBody)>,
+) {
+    for (rigid_body_id, _) in rigid_bodies.iter() {
+        let _ = delete_rigid_body(commands, rigid_bodies, rigid_body_id);
+    }
+}
+
 pub fn activate_components(
     commands: &mut Commands,
     constraints: &mut SimConstraints,
@@ -310,8 +497,244 @@ pub fn activate_components(
     });
 
     drains.for_each(|(_, drain)| {
-        drain.drain(commands, grid, particles).unwrap();
+        drain.drain(commands, constraints, grid, particles).unwrap();
     });
 
     Ok(())
 }
+
+/** Keep fluid coming in through each inflow edge of the grid: every open cell along the edge that
+has run out of particles gets four new ones, moving at the edge's inflow velocity. */
+pub fn feed_inflow_edges(
+    commands: &mut Commands,
+    constraints: &mut SimConstraints,
+    grid: &mut SimGrid,
+) {
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    let edges: [(SimSurfaceDirection, Vec<(usize, usize)>); 4] = [
+        (
+            SimSurfaceDirection::North,
+            (0..cols).map(|col| (0, col)).collect(),
+        ),
+        (
+            SimSurfaceDirection::South,
+            (0..cols).map(|col| (rows - 1, col)).collect(),
+        ),
+        (
+            SimSurfaceDirection::East,
+            (0..rows).map(|row| (row, cols - 1)).collect(),
+        ),
+        (
+            SimSurfaceDirection::West,
+            (0..rows).map(|row| (row, 0)).collect(),
+        ),
+    ];
+
+    let quarter_cell: f32 = grid.cell_size as f32 / 4.0;
+    for (edge, cells) in edges {
+        let SimBoundary::Inflow(velocity) = grid.get_boundary(edge) else {
+            continue;
+        };
+
+        for (row, col) in cells {
+            let coordinates: Vec2 = Vec2::new(row as f32, col as f32);
+            if grid.get_cell_type_value(row, col) == 0
+                || !grid
+                    .get_particles_in_lookup(grid.get_lookup_index(coordinates))
+                    .is_empty()
+            {
+                continue;
+            }
+
+            let center: Vec2 = grid.get_cell_center_position_from_coordinates(&coordinates);
+            for offset in [
+                Vec2::new(-1.0, -1.0),
+                Vec2::new(1.0, -1.0),
+                Vec2::new(-1.0, 1.0),
+                Vec2::new(1.0, 1.0),
+            ] {
+                let _ = add_particle(
+                    commands,
+                    constraints,
+                    grid,
+                    center + offset * quarter_cell,
+                    velocity,
+                    0,
+                );
+            }
+        }
+    }
+}
+
+/** Keep fluid cells evenly populated as particles drift over long runs.  Cells with more than
+`max_particles_per_cell` particles are thinned out, keeping their average velocity.  Empty cells
+inside the fluid get `min_particles_per_cell` (at most five) new particles, with the grid's
+velocity and the closest neighbor's material.  Runs after grid_to_particles(), while the lookup
+and cell labels still match the particles. */
+pub fn reseed_particles(
+    commands: &mut Commands,
+    constraints: &mut SimConstraints,
+    grid: &mut SimGrid,
+    particles: &mut Query<(Entity, &mut SimParticle)>,
+) {
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    let counts: Vec<usize> = (0..rows * cols)
+        .map(|index| grid.get_particles_in_lookup(index).len())
+        .collect();
+
+    /* Thin out crowded cells, starting with the particles closest to the cell's average velocity,
+    since losing them changes the flow the least. */
+    let max_particles: usize = constraints.max_particles_per_cell;
+    for (index, count) in counts.iter().enumerate() {
+        if max_particles == 0 || *count <= max_particles {
+            continue;
+        }
+        let mut crowd: Vec<(Entity, Vec2)> = grid
+            .get_particles_in_lookup(index)
+            .into_iter()
+            .filter_map(|particle_id| particles.get(particle_id).ok())
+            .map(|(particle_id, particle)| (particle_id, particle.velocity))
+            .collect();
+        if crowd.len() <= max_particles {
+            continue;
+        }
+        let velocity: Vec2 =
+            crowd.iter().map(|(_, velocity)| *velocity).sum::<Vec2>() / crowd.len() as f32;
+        crowd.sort_by(|(_, first), (_, second)| {
+            let first: f32 = first.distance_squared(velocity);
+            let second: f32 = second.distance_squared(velocity);
+            first.total_cmp(&second)
+        });
+
+        // Share whatever the removed particles were off from the average among the rest.
+        let kept: Vec<(Entity, Vec2)> = crowd.split_off(crowd.len() - max_particles);
+        let kept_velocity: Vec2 =
+            kept.iter().map(|(_, velocity)| *velocity).sum::<Vec2>() / kept.len() as f32;
+        for (particle_id, _) in kept {
+            if let Ok((_, mut particle)) = particles.get_mut(particle_id) {
+                particle.velocity += velocity - kept_velocity;
+            }
+        }
+        for (particle_id, _) in crowd {
+            let _ = delete_particle(commands, constraints, particles, grid, particle_id);
+        }
+    }
+
+    // Fill holes inside the fluid.
+    let quarter_cell: f32 = grid.cell_size as f32 / 4.0;
+    let offsets: [Vec2; 5] = [
+        Vec2::new(-1.0, -1.0),
+        Vec2::new(1.0, 1.0),
+        Vec2::new(1.0, -1.0),
+        Vec2::new(-1.0, 1.0),
+        Vec2::ZERO,
+    ];
+    for row in 0..rows {
+        for col in 0..cols {
+            let index: usize = row * cols + col;
+            if grid.cell_type[(row, col)] == SimGridCellType::Solid || counts[index] > 0 {
+                continue;
+            }
+
+            // Only cells surrounded by fluid and walls are holes; the rest is the fluid's surface.
+            let neighbors: [(usize, usize); 4] = [
+                (usize::wrapping_sub(row, 1), col),
+                (row + 1, col),
+                (row, usize::wrapping_sub(col, 1)),
+                (row, col + 1),
+            ];
+            let mut neighbor_particles: Vec<Entity> = Vec::new();
+            let mut fluid_neighbors: usize = 0;
+            let mut is_hole: bool = true;
+            for (neighbor_row, neighbor_col) in neighbors {
+                // Past an open edge of the grid is outside air.
+                if neighbor_row >= rows || neighbor_col >= cols {
+                    is_hole = false;
+                    continue;
+                }
+                match grid.cell_type[(neighbor_row, neighbor_col)] {
+                    SimGridCellType::Fluid => {
+                        fluid_neighbors += 1;
+                        neighbor_particles.extend(
+                            grid.get_particles_in_lookup(neighbor_row * cols + neighbor_col),
+                        );
+                    }
+                    SimGridCellType::Air => is_hole = false,
+                    SimGridCellType::Solid => {}
+                }
+            }
+            if !is_hole || fluid_neighbors < 2 {
+                continue;
+            }
+
+            // Past one particle per seeding spot, new particles would sit on top of each other.
+            let target: usize = usize::min(constraints.min_particles_per_cell, offsets.len());
+            if target == 0 || neighbor_particles.len() < target * fluid_neighbors {
+                continue;
+            }
+
+            let coordinates: Vec2 = Vec2::new(row as f32, col as f32);
+            let center: Vec2 = grid.get_cell_center_position_from_coordinates(&coordinates);
+            for offset in offsets.iter().take(target) {
+                let position: Vec2 = center + *offset * quarter_cell;
+                let closest = neighbor_particles
+                    .iter()
+                    .filter_map(|particle_id| particles.get(*particle_id).ok())
+                    .min_by(|(_, first), (_, second)| {
+                        let first: f32 = first.position.distance_squared(position);
+                        let second: f32 = second.position.distance_squared(position);
+                        first.total_cmp(&second)
+                    });
+                let Some((_, closest)) = closest else {
+                    continue;
+                };
+
+                commands.spawn(SimParticle {
+                    position,
+                    velocity: util::interpolate_velocity(position, grid),
+                    lookup_index: index,
+                    affine: Mat2::ZERO,
+                    material: closest.material,
+                    temperature: closest.temperature,
+                });
+                grid.cell_type[(row, col)] = SimGridCellType::Fluid;
+                constraints.particle_count += 1;
+            }
+        }
+    }
+}
+
+/// Construct the new simulation file.
+pub fn construct_new_simulation(
+    constraints: &mut SimConstraints,
+    grid: &mut SimGrid,
+    commands: &mut Commands,
+) {
+    // Generate walls around simulation bounds.
+    grid.force_edge_solids();
+
+    // Spawn a small group of particles at the center of the screen.
+    let grid_center: Vec2 = Vec2 {
+        x: (grid.dimensions.1 * grid.cell_size) as f32 * 0.5,
+        y: (grid.dimensions.0 * grid.cell_size) as f32 * 0.5,
+    };
+
+    add_particles_in_radius(
+        commands,
+        constraints,
+        grid,
+        1.35,
+        50.0,
+        Vec2 {
+            x: grid_center[0],
+            y: grid_center[1],
+        },
+        Vec2::ZERO,
+        0,
+    );
+
+    println!(
+        "Constructing the new simulation file with {} particles...",
+        constraints.particle_count
+    );
+}
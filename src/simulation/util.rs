@@ -1,7 +1,7 @@
 use crate::error::Error;
-use bevy::math::Vec2;
+use bevy::math::{Mat2, Vec2};
 
-use super::SimGrid;
+use super::{SimGrid, SimGridArray};
 
 pub type Result<T> = core::result::Result<T, Error>;
 
@@ -12,18 +12,18 @@ pub type Result<T> = core::result::Result<T, Error>;
 pub fn find_influence(particle_pos: Vec2, grid_point: Vec2, grid_scale: u16) -> f32 {
     let diff = grid_point.distance(particle_pos);
 
-    let scaled_diff = (diff as f32) / (grid_scale as f32);
+    let scaled_diff = diff / (grid_scale as f32);
 
     if scaled_diff.abs() > 1.0 {
         return 0.0;
     }
 
     if scaled_diff > 0.0 {
-        return 1.0 - scaled_diff;
+        1.0 - scaled_diff
     } else if scaled_diff < 0.0 {
-        return 1.0 + scaled_diff;
+        1.0 + scaled_diff
     } else {
-        return 0.0;
+        0.0
     }
 }
 
@@ -49,10 +49,10 @@ pub fn interpolate_velocity(particle_pos: Vec2, grid: &SimGrid) -> Vec2 {
     let top_v_pos = cell_center + Vec2::new(0.0, half_cell);
     let bottom_v_pos = cell_center - Vec2::new(0.0, half_cell);
 
-    let left_u_velocity = grid.velocity_u[row][col];
-    let top_v_velocity = grid.velocity_v[row][col];
-    let right_u_velocity = grid.velocity_u[row][col + 1];
-    let bottom_v_velocity = grid.velocity_v[row + 1][col];
+    let left_u_velocity = grid.velocity_u[(row, col)];
+    let top_v_velocity = grid.velocity_v[(row, col)];
+    let right_u_velocity = grid.velocity_u[(row, col + 1)];
+    let bottom_v_velocity = grid.velocity_v[(row + 1, col)];
 
     let interp_velocity_u = (((right_u_pos.x - particle_pos.x) / (right_u_pos.x - left_u_pos.x))
         * left_u_velocity)
@@ -61,7 +61,161 @@ pub fn interpolate_velocity(particle_pos: Vec2, grid: &SimGrid) -> Vec2 {
         * bottom_v_velocity)
         + (((particle_pos.y - bottom_v_pos.y) / (top_v_pos.y - bottom_v_pos.y)) * top_v_velocity);
 
-    let interp_velocity = Vec2::new(interp_velocity_u, interp_velocity_v);
+    Vec2::new(interp_velocity_u, interp_velocity_v)
+}
+
+/**
+    The four u (horizontal = true) or v velocity points around a particle,
+    each with its bilinear weight and the gradient of that weight in
+    world space.  APIC transfers use these for both particles_to_grid()
+    and grid_to_particles() so the two directions agree.
+*/
+pub fn bilinear_velocity_points(
+    particle_pos: Vec2,
+    grid: &SimGrid,
+    horizontal: bool,
+) -> [(usize, usize, f32, Vec2); 4] {
+    let cell_size = grid.cell_size as f32;
+    let grid_height = grid.dimensions.0 as f32 * cell_size;
+    let offset = (grid.cell_size / 2) as f32;
+
+    // Fractional (row, col) of the particle in this velocity array's index
+    // space; see SimGrid::get_velocity_point_pos() for the inverse.
+    let (row, col, max_row, max_col) = if horizontal {
+        (
+            (grid_height - offset - particle_pos.y) / cell_size,
+            particle_pos.x / cell_size,
+            grid.velocity_u.row_count() - 1,
+            grid.velocity_u.col_count() - 1,
+        )
+    } else {
+        (
+            (grid_height - particle_pos.y) / cell_size,
+            (particle_pos.x - offset) / cell_size,
+            grid.velocity_v.row_count() - 1,
+            grid.velocity_v.col_count() - 1,
+        )
+    };
+
+    let row = row.clamp(0.0, max_row as f32);
+    let col = col.clamp(0.0, max_col as f32);
+    let row0 = usize::min(row as usize, max_row - 1);
+    let col0 = usize::min(col as usize, max_col - 1);
+    let row_weight = row - row0 as f32;
+    let col_weight = col - col0 as f32;
+
+    // Rows run downwards, so the change along y is the negated change
+    // along the rows.
+    let gradient = |col_change: f32, row_change: f32| {
+        Vec2::new(col_change / cell_size, -row_change / cell_size)
+    };
+
+    [
+        (
+            row0,
+            col0,
+            (1.0 - row_weight) * (1.0 - col_weight),
+            gradient(row_weight - 1.0, col_weight - 1.0),
+        ),
+        (
+            row0,
+            col0 + 1,
+            (1.0 - row_weight) * col_weight,
+            gradient(1.0 - row_weight, -col_weight),
+        ),
+        (
+            row0 + 1,
+            col0,
+            row_weight * (1.0 - col_weight),
+            gradient(-row_weight, 1.0 - col_weight),
+        ),
+        (
+            row0 + 1,
+            col0 + 1,
+            row_weight * col_weight,
+            gradient(row_weight, col_weight),
+        ),
+    ]
+}
+
+/**
+    Finds the velocity and the velocity gradient at a particle's position
+    by bilinearly interpolating the u and v velocity points around it.
+    The gradient is the affine matrix APIC stores on each particle: the
+    first column is the change in velocity along x, and the second is the
+    change along y.  Falls back to interpolate_velocity() and no gradient
+    when any of the points has no velocity.
+*/
+pub fn interpolate_velocity_and_gradient(particle_pos: Vec2, grid: &SimGrid) -> (Vec2, Mat2) {
+    let mut velocity = Vec2::ZERO;
+    let mut gradient = Mat2::ZERO;
+
+    for (axis, horizontal) in [(0, true), (1, false)] {
+        let velocities = if horizontal {
+            &grid.velocity_u
+        } else {
+            &grid.velocity_v
+        };
+
+        for (row, col, weight, weight_gradient) in
+            bilinear_velocity_points(particle_pos, grid, horizontal)
+        {
+            let point_velocity = velocities[(row, col)];
+            if point_velocity == f32::MIN {
+                return (interpolate_velocity(particle_pos, grid), Mat2::ZERO);
+            }
+
+            velocity[axis] += weight * point_velocity;
+            gradient.x_axis[axis] += weight_gradient.x * point_velocity;
+            gradient.y_axis[axis] += weight_gradient.y * point_velocity;
+        }
+    }
+
+    (velocity, gradient)
+}
+
+/**
+    Bilinearly interpolates a value stored at each cell center, such as
+    SimGrid::smoke_density, at any position in the grid.  Positions
+    outside of the grid use the value at the nearest edge.
+*/
+pub fn interpolate_cell_value(position: Vec2, grid: &SimGrid, values: &SimGridArray<f32>) -> f32 {
+    let cell_size = grid.cell_size as f32;
+    let rows = grid.dimensions.0 as usize;
+    let cols = grid.dimensions.1 as usize;
+    if values.row_count() < rows || values.col_count() < cols {
+        return 0.0;
+    }
+
+    // Fractional (row, col) of the position, measured between cell centers.
+    let row =
+        ((rows as f32 * cell_size - position.y) / cell_size - 0.5).clamp(0.0, (rows - 1) as f32);
+    let col = (position.x / cell_size - 0.5).clamp(0.0, (cols - 1) as f32);
+    let row0 = usize::min(row as usize, rows.saturating_sub(2));
+    let col0 = usize::min(col as usize, cols.saturating_sub(2));
+    let row1 = usize::min(row0 + 1, rows - 1);
+    let col1 = usize::min(col0 + 1, cols - 1);
+    let row_weight = row - row0 as f32;
+    let col_weight = col - col0 as f32;
+
+    let top = values[(row0, col0)] * (1.0 - col_weight) + values[(row0, col1)] * col_weight;
+    let bottom = values[(row1, col0)] * (1.0 - col_weight) + values[(row1, col1)] * col_weight;
+
+    top * (1.0 - row_weight) + bottom * row_weight
+}
+
+/**
+    Direction pointing out of a surface at a position, found from the
+    central-difference gradient of the surface's signed distance.  Where
+    every direction is equally good, like the center of a circle, this
+    points up.
+*/
+pub fn surface_normal(position: Vec2, signed_distance: impl Fn(Vec2) -> f32) -> Vec2 {
+    let step: f32 = 0.01;
+    let gradient: Vec2 = Vec2 {
+        x: signed_distance(position + Vec2::X * step) - signed_distance(position - Vec2::X * step),
+        y: signed_distance(position + Vec2::Y * step) - signed_distance(position - Vec2::Y * step),
+    };
 
-    interp_velocity
+    gradient.try_normalize().unwrap_or(Vec2::Y)
 }
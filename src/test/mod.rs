@@ -1,4 +1,6 @@
 pub mod test_physics;
+#[cfg(feature = "gui")]
 pub mod test_renderer;
+pub mod test_runner;
 pub mod test_state_manager;
 pub mod test_ui;
@@ -1,7 +1,24 @@
 #[cfg(test)]
-use crate::simulation::util::interpolate_velocity;
+use crate::simulation::sim_physics_engine::{
+    advect_smoke, apply_buoyancy, apply_surface_tension, apply_viscosity,
+    apply_vorticity_confinement, diffuse_temperature, effective_viscosity,
+    make_grid_velocities_incompressible, particles_to_grid, push_particles_apart,
+    update_particle_lookup,
+};
 #[cfg(test)]
-use crate::simulation::{SimConstraints, SimGrid, SimParticle};
+use crate::simulation::sim_runner::SimRunner;
+#[cfg(test)]
+use crate::simulation::sim_state_manager::reseed_particles;
+#[cfg(test)]
+use crate::simulation::util::{
+    find_influence, interpolate_velocity, interpolate_velocity_and_gradient,
+};
+#[cfg(test)]
+use crate::simulation::{
+    SimBoundary, SimConstraints, SimFluidMode, SimGrid, SimGridArray, SimGridCellType,
+    SimHeatSource, SimObstacle, SimParticle, SimRigidBody, SimShape, SimSolid, SimSurfaceDirection,
+    MAX_EFFECTIVE_VISCOSITY,
+};
 #[cfg(test)]
 use crate::test::test_state_manager::{test_setup, test_update};
 #[cfg(test)]
@@ -17,7 +34,7 @@ fn interpolation_test() {
 
     for row in 0..(grid.dimensions.1 + 1) as usize {
         for col in 0..grid.dimensions.0 as usize {
-            grid.velocity_v[row][col] = -9.8;
+            grid.velocity_v[(row, col)] = -9.8;
         }
     }
 
@@ -64,7 +81,7 @@ fn velocity_transfer_test() {
     // have been transfered
     for row in 0..rows as usize {
         for col in 0..cols as usize + 1 {
-            if vel_u[row][col] != f32::MIN {
+            if vel_u[(row, col)] != f32::MIN {
                 transfer_u = true;
             }
         }
@@ -72,7 +89,7 @@ fn velocity_transfer_test() {
 
     for row in 0..rows as usize + 1 {
         for col in 0..cols as usize {
-            if vel_v[row][col] != f32::MIN {
+            if vel_v[(row, col)] != f32::MIN {
                 transfer_v = true;
             }
         }
@@ -142,3 +159,1359 @@ fn extrapolate_test() {
 
     assert_eq!(true, success);
 }
+
+/// Largest absolute divergence of any fluid cell in the grid.
+#[cfg(test)]
+fn max_fluid_divergence(grid: &SimGrid) -> f32 {
+    let mut max_divergence: f32 = 0.0;
+    for row in 0..grid.dimensions.0 as usize {
+        for col in 0..grid.dimensions.1 as usize {
+            if grid.cell_type[(row, col)] != SimGridCellType::Fluid {
+                continue;
+            }
+
+            let divergence: f32 = (grid.velocity_u[(row, col + 1)] - grid.velocity_u[(row, col)])
+                + (grid.velocity_v[(row, col)] - grid.velocity_v[(row + 1, col)]);
+            max_divergence = f32::max(max_divergence, divergence.abs());
+        }
+    }
+
+    max_divergence
+}
+
+#[test]
+fn pressure_solve_test() {
+    let mut grid = SimGrid::default();
+    let mut constraints = SimConstraints::default();
+    grid.force_edge_solids();
+
+    // A pool of fluid in the bottom half of the grid, open to the air above it.
+    for row in 25..49 {
+        for col in 1..49 {
+            grid.cell_type[(row, col)] = SimGridCellType::Fluid;
+        }
+    }
+
+    // Give the fluid a messy, strongly divergent velocity field.
+    for row in 0..grid.dimensions.0 as usize {
+        for col in 0..(grid.dimensions.1 + 1) as usize {
+            grid.velocity_u[(row, col)] = 40.0 * f32::sin(row as f32 * 0.7 + col as f32 * 1.3);
+        }
+    }
+    for row in 0..(grid.dimensions.0 + 1) as usize {
+        for col in 0..grid.dimensions.1 as usize {
+            grid.velocity_v[(row, col)] = -60.0 + 25.0 * f32::cos(row as f32 * 1.1 - col as f32);
+        }
+    }
+    let start_divergence: f32 = max_fluid_divergence(&grid);
+
+    let timestep: f32 = constraints.timestep;
+    make_grid_velocities_incompressible(&mut grid, &mut constraints, timestep);
+
+    // The solve should converge well before running out of iterations...
+    assert_eq!(
+        true,
+        constraints.pressure_iterations < constraints.incomp_iters_per_frame as usize
+    );
+    assert_eq!(
+        true,
+        constraints.pressure_residual <= constraints.pressure_tolerance * start_divergence
+    );
+
+    // ...leave (almost) no divergence behind, and store a pressure for every fluid cell.
+    assert_eq!(true, max_fluid_divergence(&grid) < 0.001 * start_divergence);
+    assert_ne!(0.0, grid.cell_center[(40, 25)]);
+    assert_eq!(0.0, grid.cell_center[(10, 25)]);
+}
+
+#[test]
+fn viscosity_shear_decay_test() {
+    let mut grid = SimGrid::default();
+    let mut constraints = SimConstraints::default();
+    grid.force_edge_solids();
+    for row in 1..49 {
+        for col in 1..49 {
+            grid.cell_type[(row, col)] = SimGridCellType::Fluid;
+        }
+    }
+
+    // A horizontal shear flow whose speed varies sinusoidally from top to bottom.
+    let wave_number: f32 = 8.0 * std::f32::consts::PI / 49.0;
+    for row in 0..grid.dimensions.0 as usize {
+        for col in 0..(grid.dimensions.1 + 1) as usize {
+            grid.velocity_u[(row, col)] = 100.0 * f32::sin(wave_number * row as f32);
+        }
+    }
+    let start_grid: SimGrid = grid.clone();
+
+    // Without any viscosity, nothing should change.
+    let timestep: f32 = constraints.timestep;
+    apply_viscosity(&mut grid, &constraints, timestep);
+    assert_eq!(start_grid.velocity_u, grid.velocity_u);
+
+    /* With viscosity, the shear should decay like exp(-viscosity * k^2 * time), with k being the
+    wave number in world units.  Half of the viscosity comes from the fluid's material. */
+    constraints.viscosity = 25.0;
+    grid.fluid_viscosity = SimGridArray::new(50, 50, 25.0);
+    let steps: usize = 60;
+    for _ in 0..steps {
+        apply_viscosity(&mut grid, &constraints, timestep);
+    }
+    let world_wave_number: f32 = wave_number / grid.cell_size as f32;
+    let expected_decay: f32 =
+        f32::exp(-50.0 * world_wave_number * world_wave_number * timestep * steps as f32);
+    let row: usize = 3;
+    let decay: f32 = grid.velocity_u[(row, 25)] / start_grid.velocity_u[(row, 25)];
+    assert_eq!(true, expected_decay < 0.9);
+    assert_eq!(true, (decay - expected_decay).abs() < 0.01);
+
+    // Faces touching solid cells are left alone.
+    assert_eq!(start_grid.velocity_u[(0, 25)], grid.velocity_u[(0, 25)]);
+    assert_eq!(start_grid.velocity_u[(10, 1)], grid.velocity_u[(10, 1)]);
+}
+
+#[test]
+fn surface_tension_test() {
+    let mut grid = SimGrid::default();
+    let mut constraints = SimConstraints::default();
+    grid.force_edge_solids();
+
+    // A square blob of still fluid floating in the middle of the grid.
+    for row in 15..35 {
+        for col in 15..35 {
+            grid.cell_type[(row, col)] = SimGridCellType::Fluid;
+        }
+    }
+    grid.velocity_u.fill(0.0);
+    grid.velocity_v.fill(0.0);
+    let start_grid: SimGrid = grid.clone();
+
+    // Without a coefficient, nothing should change.
+    let timestep: f32 = constraints.timestep;
+    apply_surface_tension(&mut grid, &constraints, timestep);
+    assert_eq!(start_grid.velocity_u, grid.velocity_u);
+    assert_eq!(start_grid.velocity_v, grid.velocity_v);
+
+    constraints.surface_tension = 10000.0;
+    apply_surface_tension(&mut grid, &constraints, timestep);
+
+    // The blob's corners should be squeezed inwards, the same way on every side...
+    assert_eq!(true, grid.velocity_u[(33, 15)] > 0.0);
+    assert_eq!(
+        true,
+        (grid.velocity_u[(33, 15)] + grid.velocity_u[(33, 35)]).abs() < 0.001
+    );
+    assert_eq!(true, grid.velocity_v[(15, 15)] < 0.0);
+    assert_eq!(
+        true,
+        (grid.velocity_v[(15, 15)] + grid.velocity_v[(35, 15)]).abs() < 0.001
+    );
+
+    // ...while its flat sides and its inside are barely pushed at all.
+    assert_eq!(
+        true,
+        grid.velocity_u[(25, 15)].abs() < 0.5 * grid.velocity_u[(33, 15)]
+    );
+    assert_eq!(0.0, grid.velocity_u[(25, 25)]);
+    assert_eq!(0.0, grid.velocity_v[(25, 25)]);
+}
+
+#[test]
+fn vorticity_confinement_test() {
+    let mut grid = SimGrid::default();
+    let mut constraints = SimConstraints::default();
+    grid.force_edge_solids();
+    for row in 1..49 {
+        for col in 1..49 {
+            grid.cell_type[(row, col)] = SimGridCellType::Fluid;
+        }
+    }
+
+    // A counter-clockwise swirl in the middle of the grid that fades out away from its center.
+    let swirl = |x: f32, y: f32| -> Vec2 {
+        let offset: Vec2 = Vec2::new(x - 25.0, 25.0 - y);
+        Vec2::new(-offset.y, offset.x) * 10.0 * f32::exp(-offset.length_squared() / 50.0)
+    };
+    for row in 0..grid.dimensions.0 as usize {
+        for col in 0..(grid.dimensions.1 + 1) as usize {
+            grid.velocity_u[(row, col)] = swirl(col as f32, row as f32 + 0.5).x;
+        }
+    }
+    for row in 0..(grid.dimensions.0 + 1) as usize {
+        for col in 0..grid.dimensions.1 as usize {
+            grid.velocity_v[(row, col)] = swirl(col as f32 + 0.5, row as f32).y;
+        }
+    }
+    let start_grid: SimGrid = grid.clone();
+    // Without any strength, nothing should change.
+    let timestep: f32 = constraints.timestep;
+    apply_vorticity_confinement(&mut grid, &constraints, timestep);
+    assert_eq!(start_grid.velocity_u, grid.velocity_u);
+    assert_eq!(start_grid.velocity_v, grid.velocity_v);
+
+    // Confinement should spin the core of the swirl up, both above and to the right of it.
+    constraints.vorticity_confinement = 1.0;
+    apply_vorticity_confinement(&mut grid, &constraints, timestep);
+    assert_eq!(true, start_grid.velocity_u[(22, 25)] < 0.0);
+    assert_eq!(
+        true,
+        grid.velocity_u[(22, 25)] < start_grid.velocity_u[(22, 25)]
+    );
+    assert_eq!(true, start_grid.velocity_v[(25, 28)] > 0.0);
+    assert_eq!(
+        true,
+        grid.velocity_v[(25, 28)] > start_grid.velocity_v[(25, 28)]
+    );
+}
+
+#[test]
+fn heat_diffusion_test() {
+    let mut grid = SimGrid::default();
+    let constraints = SimConstraints::default();
+    grid.force_edge_solids();
+    for row in 1..49 {
+        for col in 1..49 {
+            grid.cell_type[(row, col)] = SimGridCellType::Fluid;
+        }
+    }
+
+    // Heat the bottom wall and cool the top wall.
+    for col in 0..50 {
+        grid.set_heat_source(49, col, SimHeatSource::Heater)
+            .unwrap();
+        grid.set_heat_source(0, col, SimHeatSource::Cooler).unwrap();
+    }
+    let timestep: f32 = constraints.timestep;
+    for _ in 0..600 {
+        diffuse_temperature(&mut grid, &constraints, timestep);
+    }
+
+    // Fluid near the heater warms up, fluid near the cooler cools down, and the middle is untouched.
+    let temperature = |row: usize| -> f32 { grid.temperature[(row, 25)] };
+    assert_eq!(true, temperature(48) > temperature(45));
+    assert_eq!(
+        true,
+        temperature(45) > constraints.ambient_temperature + 1.0
+    );
+    assert_eq!(true, temperature(48) < constraints.heater_temperature);
+    assert_eq!(true, temperature(1) < temperature(4));
+    assert_eq!(true, temperature(4) < constraints.ambient_temperature - 1.0);
+    assert_eq!(true, temperature(1) > constraints.cooler_temperature);
+    assert_eq!(
+        true,
+        (temperature(25) - constraints.ambient_temperature).abs() < 0.01
+    );
+}
+
+#[test]
+fn buoyancy_test() {
+    let mut grid = SimGrid::default();
+    let mut constraints = SimConstraints::default();
+    grid.force_edge_solids();
+    for row in 1..49 {
+        for col in 1..49 {
+            grid.cell_type[(row, col)] = SimGridCellType::Fluid;
+        }
+    }
+    grid.velocity_v.fill(0.0);
+
+    // A hot patch of fluid on the left, and a cold one on the right.
+    for row in 20..30 {
+        for col in 5..15 {
+            grid.temperature[(row, col)] = 80.0;
+            grid.temperature[(row, col + 30)] = 5.0;
+        }
+    }
+    let timestep: f32 = constraints.timestep;
+    apply_buoyancy(&mut grid, &constraints, timestep);
+
+    // Hot fluid should rise, cold fluid should sink, and fluid at the ambient temperature stays.
+    assert_eq!(true, grid.velocity_v[(25, 10)] > 0.0);
+    assert_eq!(true, grid.velocity_v[(25, 40)] < 0.0);
+    assert_eq!(0.0, grid.velocity_v[(10, 25)]);
+
+    // Buoyancy always pushes against gravity, whichever way it points.
+    let upwards: f32 = grid.velocity_v[(25, 10)];
+    constraints.gravity = Vec2::new(385.0, 0.0);
+    apply_buoyancy(&mut grid, &constraints, timestep);
+    assert_eq!(upwards, grid.velocity_v[(25, 10)]);
+    assert_eq!(true, grid.velocity_u[(25, 10)] < 0.0);
+}
+
+#[test]
+fn particles_to_grid_scatter_test() {
+    // Get some particles moving around in a box.
+    let mut runner = SimRunner::default();
+    runner.grid_mut().force_edge_solids();
+    runner.add_particles_in_radius(1.0, 30.0, Vec2::new(125.0, 150.0), Vec2::new(13.0, -7.0), 0);
+    runner.step_many(10);
+
+    let particles: Vec<SimParticle> = runner
+        .particles()
+        .into_iter()
+        .map(|(_, particle)| particle)
+        .collect();
+    let grid = runner.run(|_, constraints, grid, particles, _, _, _, _| {
+        grid.label_cells();
+        particles_to_grid(grid, particles, constraints);
+        grid.clone()
+    });
+
+    /* Every velocity point that was transferred to should hold exactly the influence-weighted
+    average of all particles, as if it had checked every particle itself. */
+    let mut transferred_points: usize = 0;
+    for row in 0..grid.dimensions.0 as usize {
+        for col in 0..(grid.dimensions.1 + 1) as usize {
+            let velocity = grid.velocity_u[(row, col)];
+            if velocity == f32::MIN {
+                continue;
+            }
+
+            let position = grid.get_velocity_point_pos(row, col, true);
+            let mut velocity_sum: f32 = 0.0;
+            let mut influence_sum: f32 = 0.0;
+            for particle in particles.iter() {
+                let influence = find_influence(particle.position, position, grid.cell_size);
+                influence_sum += influence;
+                velocity_sum += particle.velocity.x * influence;
+            }
+
+            if influence_sum == 0.0 {
+                assert_eq!(0.0, velocity);
+            } else {
+                assert_eq!(velocity_sum / influence_sum, velocity);
+                transferred_points += 1;
+            }
+        }
+    }
+
+    assert_ne!(0, transferred_points);
+}
+
+#[test]
+fn velocity_gradient_test() {
+    let mut grid = SimGrid::default();
+
+    // Shear flow: horizontal velocity grows by 2.0 for every unit we move up.
+    for row in 0..grid.dimensions.0 as usize {
+        for col in 0..(grid.dimensions.1 + 1) as usize {
+            grid.velocity_u[(row, col)] = 2.0 * grid.get_velocity_point_pos(row, col, true).y;
+        }
+    }
+    for row in 0..(grid.dimensions.0 + 1) as usize {
+        for col in 0..grid.dimensions.1 as usize {
+            grid.velocity_v[(row, col)] = 0.0;
+        }
+    }
+
+    let (velocity, gradient): (Vec2, Mat2) =
+        interpolate_velocity_and_gradient(Vec2::new(61.0, 87.3), &grid);
+
+    // Bilinear interpolation is exact for a linear field, and only du/dy should be non-zero.
+    assert_eq!(true, (velocity.x - 2.0 * 87.3).abs() < 0.001);
+    assert_eq!(true, velocity.y.abs() < 0.001);
+    assert_eq!(true, (gradient.y_axis.x - 2.0).abs() < 0.001);
+    assert_eq!(true, gradient.x_axis.x.abs() < 0.001);
+    assert_eq!(true, gradient.x_axis.y.abs() < 0.001);
+    assert_eq!(true, gradient.y_axis.y.abs() < 0.001);
+}
+
+/// Average height of every particle made of a given fluid material.
+#[cfg(test)]
+fn average_material_height(runner: &mut SimRunner, material: usize) -> f32 {
+    let heights: Vec<f32> = runner
+        .particles()
+        .iter()
+        .filter(|(_, particle)| particle.material == material)
+        .map(|(_, particle)| particle.position.y)
+        .collect();
+
+    heights.iter().sum::<f32>() / heights.len() as f32
+}
+
+#[test]
+fn multi_fluid_buoyancy_test() {
+    // Fill the bottom of a tank with oil and pour a layer of water on top of it.
+    let mut runner = SimRunner::default();
+    runner.grid_mut().force_edge_solids();
+    let (water, oil): (usize, usize) = (0, 1);
+    for row in 0..16 {
+        for col in 0..65 {
+            let position = Vec2::new(7.5 + col as f32 * 3.5, 7.5 + row as f32 * 3.5);
+            let material: usize = if row < 8 { oil } else { water };
+            runner.add_particle(position, Vec2::ZERO, material).unwrap();
+        }
+    }
+
+    // Particles can only be made of fluids the simulation knows about.
+    let unknown_material: usize = runner.constraints().fluid_materials.len();
+    assert_eq!(
+        true,
+        runner
+            .add_particle(Vec2::new(125.0, 200.0), Vec2::ZERO, unknown_material)
+            .is_err()
+    );
+
+    // After a while, the lighter oil should have risen above the water.
+    let oil_height: f32 = average_material_height(&mut runner, oil);
+    let water_height: f32 = average_material_height(&mut runner, water);
+    assert_eq!(true, oil_height < water_height);
+    runner.step_many(1000);
+    let oil_height: f32 = average_material_height(&mut runner, oil);
+    let water_height: f32 = average_material_height(&mut runner, water);
+    assert_eq!(true, oil_height > water_height);
+}
+
+/// Total smoke in the grid, and the average height of it in cells from the bottom of the grid.
+#[cfg(test)]
+fn smoke_amount_and_height(grid: &SimGrid) -> (f32, f32) {
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    let mut total_density: f32 = 0.0;
+    let mut height_sum: f32 = 0.0;
+    for row in 0..rows {
+        for col in 0..cols {
+            let density: f32 = grid.smoke_density[(row, col)];
+            total_density += density;
+            height_sum += density * (rows - row) as f32;
+        }
+    }
+
+    (total_density, height_sum / total_density)
+}
+
+#[test]
+fn smoke_advection_test() {
+    let mut grid = SimGrid::default();
+
+    // A puff of smoke in a steady flow up and to the right.
+    for row in 20..30 {
+        for col in 10..20 {
+            grid.smoke_density[(row, col)] = 1.0;
+        }
+    }
+    grid.velocity_u.fill(50.0);
+    grid.velocity_v.fill(25.0);
+
+    // After 0.2 seconds, the puff should have moved 2 cells right and 1 cell up, smoke and all.
+    let center_of_smoke = |grid: &SimGrid| -> Vec2 {
+        let mut center: Vec2 = Vec2::ZERO;
+        for row in 0..50 {
+            for col in 0..50 {
+                center += grid.smoke_density[(row, col)] * Vec2::new(col as f32, row as f32);
+            }
+        }
+        center / grid.smoke_density.iter().sum::<f32>()
+    };
+    let start_center: Vec2 = center_of_smoke(&grid);
+    for _ in 0..10 {
+        advect_smoke(&mut grid, 0.02);
+    }
+    let moved: Vec2 = center_of_smoke(&grid) - start_center;
+    assert_eq!(true, (moved.x - 2.0).abs() < 0.1);
+    assert_eq!(true, (moved.y + 1.0).abs() < 0.1);
+    assert_eq!(
+        true,
+        (grid.smoke_density.iter().sum::<f32>() - 100.0).abs() < 1.0
+    );
+
+    // A steady flow should carry itself along unchanged.
+    assert_eq!(true, (grid.velocity_u[(25, 25)] - 50.0).abs() < 0.001);
+    assert_eq!(true, (grid.velocity_v[(25, 25)] - 25.0).abs() < 0.001);
+}
+
+#[test]
+fn smoke_rises_test() {
+    let mut runner = SimRunner::default();
+    runner.grid_mut().force_edge_solids();
+    runner.constraints_mut().fluid_mode = SimFluidMode::Smoke;
+    runner
+        .add_smoke_in_radius(1.0, 15.0, Vec2::new(125.0, 40.0))
+        .unwrap();
+    assert_eq!(
+        true,
+        runner
+            .add_smoke_in_radius(1.0, 15.0, Vec2::new(-10.0, 40.0))
+            .is_err()
+    );
+    let (start_amount, start_height) = smoke_amount_and_height(runner.grid());
+
+    // Hot smoke should rise, without leaking much of itself away or needing any particles.
+    runner.step_many(120);
+    let (amount, height) = smoke_amount_and_height(runner.grid());
+    assert_eq!(true, height > start_height + 2.0);
+    assert_eq!(true, amount > 0.8 * start_amount);
+    assert_eq!(true, runner.particles().is_empty());
+    assert_eq!(
+        true,
+        runner.constraints().pressure_iterations
+            < runner.constraints().incomp_iters_per_frame as usize
+    );
+}
+
+#[test]
+fn obstacle_shape_test() {
+    // A 20x4 paddle turned a quarter turn stands upright.
+    let mut paddle = SimObstacle::new(
+        SimShape::Rectangle(Vec2::new(10.0, 2.0)),
+        Vec2::new(50.0, 50.0),
+        Vec2::ZERO,
+        2.0,
+    );
+    paddle.rotation = std::f32::consts::FRAC_PI_2;
+    assert_eq!(true, paddle.contains(Vec2::new(50.0, 58.0)));
+    assert_eq!(false, paddle.contains(Vec2::new(58.0, 50.0)));
+    assert_eq!(
+        true,
+        (paddle.signed_distance(Vec2::new(55.0, 50.0)) - 3.0).abs() < 0.001
+    );
+    assert_eq!(
+        true,
+        paddle
+            .surface_normal(Vec2::new(55.0, 50.0))
+            .abs_diff_eq(Vec2::X, 0.001)
+    );
+
+    // Spinning counter-clockwise, the top of the paddle moves left.
+    assert_eq!(
+        true,
+        paddle
+            .velocity_at(Vec2::new(50.0, 60.0))
+            .abs_diff_eq(Vec2::new(-20.0, 0.0), 0.001)
+    );
+
+    let ball = SimObstacle::new(
+        SimShape::Circle(5.0),
+        Vec2::new(20.0, 20.0),
+        Vec2::new(3.0, 0.0),
+        0.0,
+    );
+    assert_eq!(
+        true,
+        (ball.signed_distance(Vec2::new(20.0, 30.0)) - 5.0).abs() < 0.001
+    );
+    assert_eq!(Vec2::new(3.0, 0.0), ball.velocity_at(Vec2::new(25.0, 20.0)));
+}
+
+/** Let a pool of water settle, put an obstacle into it, and run for a bit longer; returns the
+runner so that the fluid can be compared against a run with a still obstacle. */
+#[cfg(test)]
+fn run_with_obstacle(
+    gravity: Vec2,
+    pool_center: Vec2,
+    pool_radius: f32,
+    obstacle: SimObstacle,
+) -> SimRunner {
+    let mut runner = SimRunner::default();
+    runner.grid_mut().force_edge_solids();
+    runner.constraints_mut().gravity = gravity;
+    runner.add_particles_in_radius(1.0, pool_radius, pool_center, Vec2::ZERO, 0);
+    runner.step_many(240);
+
+    runner
+        .add_obstacle(
+            obstacle.shape,
+            obstacle.position,
+            obstacle.velocity,
+            obstacle.angular_velocity,
+        )
+        .unwrap();
+    runner.step_many(90);
+    runner
+}
+
+#[test]
+fn moving_obstacle_test() {
+    // Sweep a piston through a pool from left to right, and compare to a piston that stays put.
+    let gravity: Vec2 = SimConstraints::default().gravity;
+    let piston = |speed: f32| {
+        SimObstacle::new(
+            SimShape::Rectangle(Vec2::new(4.0, 25.0)),
+            Vec2::new(30.0, 30.0),
+            Vec2::new(speed, 0.0),
+            0.0,
+        )
+    };
+    let mut moved = run_with_obstacle(gravity, Vec2::new(125.0, 80.0), 70.0, piston(80.0));
+    let mut still = run_with_obstacle(gravity, Vec2::new(125.0, 80.0), 70.0, piston(0.0));
+
+    // The piston should have pushed most of the fluid out of the left side of the tank...
+    let piston: SimObstacle = moved.obstacles()[0].1.clone();
+    assert_eq!(true, (piston.position.x - 90.0).abs() < 0.01);
+    let left_side_count = |runner: &mut SimRunner| {
+        runner
+            .particles()
+            .iter()
+            .filter(|(_, particle)| particle.position.x < 100.0)
+            .count() as f32
+    };
+    assert_eq!(
+        true,
+        left_side_count(&mut moved) < 0.75 * left_side_count(&mut still)
+    );
+
+    // ...without leaving any of it inside of the piston.
+    for (_, particle) in moved.particles() {
+        assert_eq!(false, piston.contains(particle.position));
+    }
+
+    // Only the cells the piston is in now should be solid, not the ones it passed through.
+    let grid: &SimGrid = moved.grid();
+    let now: Vec2 = grid.get_cell_coordinates_from_position(&piston.position);
+    let before: Vec2 = grid.get_cell_coordinates_from_position(&Vec2::new(30.0, 30.0));
+    assert_eq!(
+        SimGridCellType::Solid,
+        grid.cell_type[(now.x as usize, now.y as usize)]
+    );
+    assert_eq!(
+        false,
+        grid.cell_type[(before.x as usize, before.y as usize)] == SimGridCellType::Solid
+    );
+}
+
+#[test]
+fn rotating_obstacle_test() {
+    // A mixer spinning counter-clockwise in the middle of a ball of fluid, without gravity...
+    let center: Vec2 = Vec2::new(125.0, 125.0);
+    let mixer = |angular_velocity: f32| {
+        SimObstacle::new(
+            SimShape::Rectangle(Vec2::new(20.0, 3.0)),
+            center,
+            Vec2::ZERO,
+            angular_velocity,
+        )
+    };
+    let mut spun = run_with_obstacle(Vec2::ZERO, center, 60.0, mixer(4.0));
+    let mut still = run_with_obstacle(Vec2::ZERO, center, 60.0, mixer(0.0));
+    assert_eq!(true, (spun.obstacles()[0].1.rotation - 3.0).abs() < 0.01);
+
+    /* ...should drag the fluid it sweeps through around with it counter-clockwise.  Fluid around
+    the still mixer swirls a little on its own, and which way it goes changes with how exactly the
+    pressure is solved, so compare the two by how fast the fluid within the mixer's reach turns on
+    average; the spinning mixer should add at least a tenth of its own 4 radians per second. */
+    let angular_velocity = |runner: &mut SimRunner| -> f32 {
+        let (mut momentum, mut inertia): (f32, f32) = (0.0, 0.0);
+        for (_, particle) in runner.particles() {
+            let offset: Vec2 = particle.position - center;
+            if offset.length() <= 25.0 {
+                momentum += offset.perp_dot(particle.velocity);
+                inertia += offset.length_squared();
+            }
+        }
+        momentum / inertia
+    };
+    let spun_turning: f32 = angular_velocity(&mut spun);
+    let still_turning: f32 = angular_velocity(&mut still);
+    assert_eq!(true, spun_turning > still_turning + 0.4);
+}
+
+/** Drop a rigid body of the given density into a shallow tank of water, and record where it is every
+ten steps for the next 300 steps. */
+#[cfg(test)]
+fn drop_rigid_body_into_tank(shape: SimShape, density: f32) -> Vec<SimRigidBody> {
+    let mut runner = SimRunner::default();
+    runner.grid_mut().force_edge_solids();
+    for x in [45.0, 125.0, 205.0] {
+        runner.add_particles_in_radius(1.0, 40.0, Vec2::new(x, 45.0), Vec2::ZERO, 0);
+    }
+    runner.step_many(240);
+
+    runner
+        .add_rigid_body(
+            shape,
+            density * shape.area(),
+            Vec2::new(125.0, 160.0),
+            Vec2::ZERO,
+        )
+        .unwrap();
+    let mut history: Vec<SimRigidBody> = Vec::new();
+    for _ in 0..30 {
+        runner.step_many(10);
+        history.push(runner.rigid_bodies()[0].1.clone());
+    }
+    history
+}
+
+#[test]
+fn rigid_body_buoyancy_test() {
+    let floor: f32 = SimGrid::default().cell_size as f32;
+    let raft = drop_rigid_body_into_tank(SimShape::Rectangle(Vec2::new(12.0, 8.0)), 0.4);
+    let anchor = drop_rigid_body_into_tank(SimShape::Circle(8.0), 3.0);
+
+    // Neither body should ever end up inside of the tank's walls.
+    for body in raft.iter().chain(anchor.iter()) {
+        assert_eq!(true, body.position.y > floor);
+        assert_eq!(
+            true,
+            body.position.x > floor && body.position.x < 250.0 - floor
+        );
+    }
+
+    // The light raft should be held up by the water once it lands...
+    let raft_height: f32 =
+        raft[15..].iter().map(|body| body.position.y).sum::<f32>() / raft[15..].len() as f32;
+    assert_eq!(true, raft_height > floor + 30.0);
+
+    // ...while the heavy anchor sinks to the bottom and stays there.
+    for body in anchor[15..].iter() {
+        assert_eq!(true, body.position.y < floor + 8.0 + 1.0);
+    }
+}
+
+#[test]
+fn rigid_body_drag_test() {
+    /* Put a thin plate that weighs as much as water in the middle of a blob of water flowing along
+    a channel that wraps around, with no gravity to float or sink it.  The plate lies along the
+    flow, so the fluid's pressure only pushes on its narrow ends and drag has to do the rest. */
+    let mut runner = SimRunner::default();
+    runner.constraints_mut().gravity = Vec2::ZERO;
+    runner
+        .grid_mut()
+        .set_boundary(SimSurfaceDirection::West, SimBoundary::Periodic);
+    runner.grid_mut().force_edge_solids();
+    runner.add_particles_in_radius(1.0, 60.0, Vec2::new(125.0, 125.0), Vec2::new(60.0, 0.0), 0);
+    let shape = SimShape::Rectangle(Vec2::new(20.0, 3.0));
+    runner
+        .add_rigid_body(shape, shape.area(), Vec2::new(125.0, 125.0), Vec2::ZERO)
+        .unwrap();
+    runner.step_many(30);
+
+    // The flow should have carried the body along with it, without flinging it past the flow.
+    let body = runner.rigid_bodies()[0].1.clone();
+    assert_eq!(true, body.velocity.x > 30.0 && body.velocity.x < 60.0);
+}
+
+#[test]
+fn sub_cell_solid_test() {
+    // Each kind of solid should know how far away its surface is.
+    let circle = SimSolid::Circle(Vec2::new(50.0, 50.0), 10.0);
+    assert_eq!(
+        true,
+        (circle.signed_distance(Vec2::new(65.0, 50.0)) - 5.0).abs() < 0.001
+    );
+    assert_eq!(
+        true,
+        (circle.signed_distance(Vec2::new(50.0, 50.0)) + 10.0).abs() < 0.001
+    );
+    let segment = SimSolid::Segment(Vec2::new(0.0, 0.0), Vec2::new(100.0, 0.0), 2.0);
+    assert_eq!(
+        true,
+        (segment.signed_distance(Vec2::new(50.0, 5.0)) - 3.0).abs() < 0.001
+    );
+    assert_eq!(
+        true,
+        (segment.signed_distance(Vec2::new(105.0, 0.0)) - 3.0).abs() < 0.001
+    );
+    let triangle = SimSolid::Polygon(vec![
+        Vec2::new(0.0, 0.0),
+        Vec2::new(100.0, 0.0),
+        Vec2::new(0.0, 100.0),
+    ]);
+    assert_eq!(
+        true,
+        (triangle.signed_distance(Vec2::new(10.0, 50.0)) + 10.0).abs() < 0.001
+    );
+    assert_eq!(
+        true,
+        (triangle.signed_distance(Vec2::new(50.0, -4.0)) - 4.0).abs() < 0.001
+    );
+    assert_eq!(true, triangle.signed_distance(Vec2::new(60.0, 60.0)) > 0.0);
+
+    // A floor that ends halfway up a row of cells should cover half of those cells' side faces.
+    let mut grid = SimGrid::default();
+    grid.add_solid(SimSolid::Polygon(vec![
+        Vec2::new(-10.0, -10.0),
+        Vec2::new(260.0, -10.0),
+        Vec2::new(260.0, 12.5),
+        Vec2::new(-10.0, 12.5),
+    ]));
+    let row: usize = 47; // Cells from y = 10 to y = 15.
+    assert_eq!(
+        true,
+        (grid.get_face_open_fraction(row, 25, true) - 0.5).abs() < 0.001
+    );
+    assert_eq!(0.0, grid.get_face_open_fraction(row + 1, 25, false));
+    assert_eq!(1.0, grid.get_face_open_fraction(row, 25, false));
+    assert_eq!(1.0, grid.get_face_open_fraction(20, 25, true));
+
+    // Resizing the grid should keep the solid where it is.
+    grid.set_grid_cell_size(10).unwrap();
+    assert_eq!(
+        true,
+        (grid.get_face_open_fraction(48, 12, true) - 0.75).abs() < 0.001
+    );
+    grid.clear_solids();
+    assert_eq!(1.0, grid.get_face_open_fraction(48, 12, true));
+}
+
+#[test]
+fn slanted_wall_test() {
+    /* Pour some fluid onto a ramp, once as smooth sub-cell geometry and once as the staircase of
+    whole solid cells that it would otherwise be drawn as. */
+    let ramp = SimSolid::Polygon(vec![
+        Vec2::new(0.0, 0.0),
+        Vec2::new(250.0, 0.0),
+        Vec2::new(0.0, 130.0),
+    ]);
+    let slide_down_ramp = |smooth: bool| {
+        let mut runner = SimRunner::default();
+        runner.grid_mut().force_edge_solids();
+        if smooth {
+            runner.grid_mut().add_solid(ramp.clone());
+        } else {
+            let mut grid = runner.grid_mut();
+            for row in 0..50 {
+                for col in 0..50 {
+                    let center: Vec2 = grid.get_cell_center_position_from_coordinates(&Vec2::new(
+                        row as f32, col as f32,
+                    ));
+                    if ramp.signed_distance(center) < 0.0 {
+                        grid.cell_type[(row, col)] = SimGridCellType::Solid;
+                    }
+                }
+            }
+        }
+        runner.add_particles_in_radius(1.0, 15.0, Vec2::new(35.0, 150.0), Vec2::ZERO, 0);
+        runner.step_many(80);
+        runner.particles()
+    };
+    let smooth = slide_down_ramp(true);
+    let staircase = slide_down_ramp(false);
+
+    // Fluid should slide down the smooth ramp much further than it tumbles down the stairs...
+    let average_x = |particles: &Vec<(Entity, SimParticle)>| {
+        particles
+            .iter()
+            .map(|(_, particle)| particle.position.x)
+            .sum::<f32>()
+            / particles.len() as f32
+    };
+    assert_eq!(true, average_x(&smooth) > 1.5 * average_x(&staircase));
+
+    // ...without any of it sinking into the ramp.
+    for (_, particle) in smooth.iter() {
+        assert_eq!(true, ramp.signed_distance(particle.position) > -0.5);
+    }
+}
+
+#[test]
+fn outflow_boundary_test() {
+    // Pour fluid next to an open east edge, and next to a closed one.
+    let run = |boundary: SimBoundary| {
+        let mut runner = SimRunner::default();
+        runner
+            .grid_mut()
+            .set_boundary(SimSurfaceDirection::East, boundary);
+        runner.grid_mut().force_edge_solids();
+        runner.add_particles_in_radius(1.0, 40.0, Vec2::new(190.0, 60.0), Vec2::ZERO, 0);
+        let start_count: usize = runner.particles().len();
+        runner.step_many(120);
+        (start_count, runner)
+    };
+    let (start_count, mut open) = run(SimBoundary::Outflow);
+    let (_, mut closed) = run(SimBoundary::Solid);
+
+    // Much of the fluid should pour out of the open edge and be deleted...
+    let open_count: usize = open.particles().len();
+    assert_eq!(true, (open_count as f32) < 0.7 * start_count as f32);
+    assert_eq!(open_count, open.constraints().particle_count);
+    for (_, particle) in open.particles() {
+        assert_eq!(
+            true,
+            open.grid().is_position_within_grid(&particle.position)
+        );
+    }
+
+    // ...while the closed tank keeps all of its fluid.
+    assert_eq!(start_count, closed.particles().len());
+}
+
+#[test]
+fn periodic_boundary_test() {
+    // Making one edge periodic should make its opposite periodic too, and undo it again.
+    let mut grid = SimGrid::default();
+    grid.set_boundary(SimSurfaceDirection::West, SimBoundary::Periodic);
+    assert_eq!(
+        SimBoundary::Periodic,
+        grid.get_boundary(SimSurfaceDirection::East)
+    );
+    assert_eq!(
+        Vec2::new(10.0, 100.0),
+        grid.wrap_position(Vec2::new(260.0, 100.0))
+    );
+    grid.set_boundary(SimSurfaceDirection::East, SimBoundary::Outflow);
+    assert_eq!(
+        SimBoundary::Solid,
+        grid.get_boundary(SimSurfaceDirection::West)
+    );
+
+    // Throw a blob of fluid at the east edge of a channel that wraps around.
+    let mut runner = SimRunner::default();
+    runner.constraints_mut().gravity = Vec2::ZERO;
+    runner
+        .grid_mut()
+        .set_boundary(SimSurfaceDirection::West, SimBoundary::Periodic);
+    runner.grid_mut().force_edge_solids();
+    runner.add_particles_in_radius(1.0, 30.0, Vec2::new(200.0, 125.0), Vec2::new(100.0, 0.0), 0);
+    let start_count: usize = runner.particles().len();
+    runner.step_many(60);
+
+    // It should come back in through the west edge without losing any fluid or speed.
+    let particles = runner.particles();
+    assert_eq!(start_count, particles.len());
+    let count: f32 = particles.len() as f32;
+    let average_x: f32 = particles.iter().map(|(_, p)| p.position.x).sum::<f32>() / count;
+    let average_speed: f32 = particles.iter().map(|(_, p)| p.velocity.x).sum::<f32>() / count;
+    assert_eq!(true, average_x < 150.0);
+    assert_eq!(true, average_speed > 90.0);
+    for (_, particle) in particles.iter() {
+        assert_eq!(
+            true,
+            runner.grid().is_position_within_grid(&particle.position)
+        );
+    }
+}
+
+#[test]
+fn reopened_edge_test() {
+    // Opening up a walled edge should take its wall away, but leave the corners it shares alone.
+    let mut grid = SimGrid::default();
+    grid.force_edge_solids();
+    grid.set_boundary(SimSurfaceDirection::East, SimBoundary::Outflow);
+    grid.force_edge_solids();
+
+    let last_col: usize = grid.dimensions.1 as usize - 1;
+    let last_row: usize = grid.dimensions.0 as usize - 1;
+    assert_eq!(SimGridCellType::Air, grid.cell_type[(10, last_col)]);
+    assert_eq!(SimGridCellType::Solid, grid.cell_type[(10, 0)]);
+    assert_eq!(SimGridCellType::Solid, grid.cell_type[(0, last_col)]);
+    assert_eq!(SimGridCellType::Solid, grid.cell_type[(last_row, last_col)]);
+}
+
+#[test]
+fn inflow_boundary_test() {
+    // Blow fluid into an empty wind tunnel from the west, and let it out to the east.
+    let mut runner = SimRunner::default();
+    runner.constraints_mut().gravity = Vec2::ZERO;
+    runner.grid_mut().set_boundary(
+        SimSurfaceDirection::West,
+        SimBoundary::Inflow(Vec2::new(60.0, 0.0)),
+    );
+    runner
+        .grid_mut()
+        .set_boundary(SimSurfaceDirection::East, SimBoundary::Outflow);
+    runner.grid_mut().force_edge_solids();
+    runner.step_many(240);
+
+    // The tunnel should be filling up with fluid moving at about the inflow velocity.
+    let particles = runner.particles();
+    let count: f32 = particles.len() as f32;
+    let average_speed: f32 = particles.iter().map(|(_, p)| p.velocity.x).sum::<f32>() / count;
+    let furthest: f32 = particles
+        .iter()
+        .map(|(_, p)| p.position.x)
+        .fold(0.0, f32::max);
+    assert_eq!(true, count > 1000.0);
+    assert_eq!(true, (average_speed - 60.0).abs() < 20.0);
+    assert_eq!(true, furthest > 150.0);
+}
+
+/// Pour a tall, thin column of a fluid material into the middle of an empty tank.
+#[cfg(test)]
+fn pour_column(material: usize) -> SimRunner {
+    let mut runner = SimRunner::default();
+    runner.grid_mut().force_edge_solids();
+    for row in 0..20 {
+        for col in 0..10 {
+            let position = Vec2::new(108.0 + col as f32 * 3.5, 7.5 + row as f32 * 3.5);
+            runner.add_particle(position, Vec2::ZERO, material).unwrap();
+        }
+    }
+
+    runner
+}
+
+/// Height of the highest particle, and the leftmost and rightmost particle positions.
+#[cfg(test)]
+fn pile_extent(runner: &mut SimRunner) -> (f32, f32, f32) {
+    let particles = runner.particles();
+    let top: f32 = particles
+        .iter()
+        .map(|(_, p)| p.position.y)
+        .fold(0.0, f32::max);
+    let left: f32 = particles
+        .iter()
+        .map(|(_, p)| p.position.x)
+        .fold(f32::MAX, f32::min);
+    let right: f32 = particles
+        .iter()
+        .map(|(_, p)| p.position.x)
+        .fold(0.0, f32::max);
+
+    (top, left, right)
+}
+
+#[test]
+fn granular_pile_test() {
+    // A column of water collapses and runs all the way out to both walls of the tank.
+    let (water, sand): (usize, usize) = (0, 4);
+    assert_eq!(
+        true,
+        SimRunner::default().constraints().fluid_materials[sand].is_granular()
+    );
+    let mut runner = pour_column(water);
+    runner.step_many(600);
+    let (_, left, right) = pile_extent(&mut runner);
+    assert_eq!(true, left < 25.0 && right > 225.0);
+
+    // A column of sand slumps into a pile with sloped sides instead, and then stays put.
+    let mut runner = pour_column(sand);
+    runner.step_many(1500);
+    let (top, left, right) = pile_extent(&mut runner);
+    assert_eq!(true, top > 20.0);
+    assert_eq!(true, left > 30.0 && right < 220.0);
+    runner.step_many(600);
+    let (settled_top, _, _) = pile_extent(&mut runner);
+    assert_eq!(true, (settled_top - top).abs() < 4.0);
+
+    /* Its sides should slope at somewhere around sand's angle of repose, atan(0.7) = ~35 degrees,
+    measured from the middle of the pile's top down to its edges on the floor. */
+    let floor: f32 = runner.grid().cell_size as f32;
+    let slope: f32 = f32::atan2(settled_top - floor, (right - left) / 2.0).to_degrees();
+    assert_eq!(true, slope > 10.0 && slope < 60.0);
+}
+
+#[test]
+fn granular_fluid_mix_test() {
+    // Pour a column of water on top of a column of sand.
+    let (water, sand): (usize, usize) = (0, 4);
+    let mut runner = pour_column(sand);
+    for row in 0..20 {
+        for col in 0..10 {
+            let position = Vec2::new(108.0 + col as f32 * 3.5, 85.0 + row as f32 * 3.5);
+            runner.add_particle(position, Vec2::ZERO, water).unwrap();
+        }
+    }
+    runner.step_many(1500);
+
+    // Nothing should blow up or escape the tank...
+    let particles = runner.particles();
+    assert_eq!(400, particles.len());
+    let material_extent = |material: usize| -> (f32, f32, f32) {
+        let positions: Vec<Vec2> = particles
+            .iter()
+            .filter(|(_, particle)| particle.material == material)
+            .map(|(_, particle)| particle.position)
+            .collect();
+        let height: f32 = positions.iter().map(|position| position.y).sum::<f32>();
+        let left: f32 = positions
+            .iter()
+            .map(|position| position.x)
+            .fold(f32::MAX, f32::min);
+        let right: f32 = positions
+            .iter()
+            .map(|position| position.x)
+            .fold(0.0, f32::max);
+        (height / positions.len() as f32, left, right)
+    };
+    let (sand_height, sand_left, sand_right) = material_extent(sand);
+    let (water_height, water_left, water_right) = material_extent(water);
+    for (_, particle) in particles.iter() {
+        assert_eq!(
+            true,
+            runner.grid().is_position_within_grid(&particle.position)
+        );
+    }
+
+    /* ...the heavier sand should stay piled up underneath, poking out above the water that runs
+    out around it.  A pile's average height is only a third of its peak, so compare the peak. */
+    let sand_top: f32 = particles
+        .iter()
+        .filter(|(_, particle)| particle.material == sand)
+        .map(|(_, particle)| particle.position.y)
+        .fold(0.0, f32::max);
+    let middle: f32 = (sand_left + sand_right) / 2.0;
+    let (_, bottom_of_middle) = particles
+        .iter()
+        .filter(|(_, particle)| (particle.position.x - middle).abs() < 10.0)
+        .min_by(|(_, a), (_, b)| a.position.y.total_cmp(&b.position.y))
+        .unwrap();
+    assert_eq!(sand, bottom_of_middle.material);
+    assert_eq!(true, sand_top > water_height);
+    assert_eq!(true, sand_height > runner.grid().cell_size as f32);
+    assert_eq!(
+        true,
+        sand_left > water_left + 10.0 && sand_right < water_right - 10.0
+    );
+}
+
+/** Run a sinusoidal shear flow like viscosity_shear_decay_test()'s, with `amplitude` as its fastest
+speed, through 30 steps of viscosity in a non-Newtonian fluid.  Returns how much of the flow is
+left. */
+#[cfg(test)]
+fn non_newtonian_shear_decay(
+    consistency: f32,
+    flow_index: f32,
+    yield_stress: f32,
+    amplitude: f32,
+) -> f32 {
+    let mut grid = SimGrid::default();
+    let constraints = SimConstraints::default();
+    grid.force_edge_solids();
+    for row in 1..49 {
+        for col in 1..49 {
+            grid.cell_type[(row, col)] = SimGridCellType::Fluid;
+        }
+    }
+    let wave_number: f32 = 8.0 * std::f32::consts::PI / 49.0;
+    for row in 0..grid.dimensions.0 as usize {
+        for col in 0..(grid.dimensions.1 + 1) as usize {
+            grid.velocity_u[(row, col)] = amplitude * f32::sin(wave_number * row as f32);
+        }
+    }
+    grid.fluid_viscosity = SimGridArray::new(50, 50, consistency);
+    grid.fluid_flow_index = SimGridArray::new(50, 50, flow_index);
+    grid.fluid_yield_stress = SimGridArray::new(50, 50, yield_stress);
+
+    let start_velocity: f32 = grid.velocity_u[(3, 25)];
+    for _ in 0..30 {
+        apply_viscosity(&mut grid, &constraints, constraints.timestep);
+    }
+    grid.velocity_u[(3, 25)] / start_velocity
+}
+
+#[test]
+fn non_newtonian_viscosity_test() {
+    // Newtonian fluids are just as thick no matter how fast they're sheared.
+    assert_eq!(40.0, effective_viscosity(40.0, 1.0, 0.0, 1.0, 0.5));
+    assert_eq!(40.0, effective_viscosity(40.0, 1.0, 0.0, 1.0, 50.0));
+
+    // Shear-thinning fluids get thinner, and shear-thickening fluids get thicker.
+    assert_eq!(
+        true,
+        effective_viscosity(100.0, 0.5, 0.0, 1.0, 50.0)
+            < effective_viscosity(100.0, 0.5, 0.0, 1.0, 0.5)
+    );
+    assert_eq!(
+        true,
+        effective_viscosity(10.0, 2.0, 0.0, 1.0, 50.0)
+            > effective_viscosity(10.0, 2.0, 0.0, 1.0, 0.5)
+    );
+
+    // Bingham plastics are as thick as they can be at rest, and flow normally once they yield.
+    assert_eq!(
+        MAX_EFFECTIVE_VISCOSITY,
+        effective_viscosity(20.0, 1.0, 1500.0, 1.0, 0.0)
+    );
+    assert_eq!(
+        true,
+        (effective_viscosity(20.0, 1.0, 1500.0, 1.0, 1.0e5) - 20.0).abs() < 0.1
+    );
+
+    // Fast shearing smooths out relatively quicker in oobleck, and slower in a thinning fluid.
+    let thickening_slow: f32 = non_newtonian_shear_decay(4.0, 2.0, 0.0, 10.0);
+    let thickening_fast: f32 = non_newtonian_shear_decay(4.0, 2.0, 0.0, 100.0);
+    assert_eq!(true, thickening_fast < thickening_slow);
+    let thinning_slow: f32 = non_newtonian_shear_decay(400.0, 0.4, 0.0, 10.0);
+    let thinning_fast: f32 = non_newtonian_shear_decay(400.0, 0.4, 0.0, 100.0);
+    assert_eq!(true, thinning_fast > thinning_slow);
+
+    // A gentle flow can't push past ketchup's yield stress, but barely slows plain syrup down.
+    assert_eq!(
+        true,
+        non_newtonian_shear_decay(20.0, 1.0, 1500.0, 10.0) < 0.01
+    );
+    assert_eq!(true, non_newtonian_shear_decay(20.0, 1.0, 0.0, 10.0) > 0.9);
+
+    // Both kinds of fluid can be added from the start.
+    let materials = SimConstraints::default().fluid_materials;
+    for name in ["Ketchup", "Oobleck"] {
+        let material = materials.iter().find(|material| material.name == name);
+        assert_eq!(
+            true,
+            material.is_some_and(|material| material.is_non_newtonian())
+        );
+    }
+}
+
+#[test]
+fn particle_reseeding_test() {
+    /* A block of oil with three particles per cell, one empty cell inside it, and one crowded cell
+    whose extra particles are all moving at different speeds. */
+    let constraints: SimConstraints = SimConstraints {
+        min_particles_per_cell: 3,
+        max_particles_per_cell: 12,
+        ..SimConstraints::default()
+    };
+    let mut runner = SimRunner::new(constraints, SimGrid::default());
+    runner.grid_mut().force_edge_solids();
+    let oil: usize = 1;
+    let (hole, crowded): ((usize, usize), (usize, usize)) = ((43, 20), (40, 15));
+    for row in 38..49 {
+        for col in 10..30 {
+            if (row, col) == hole {
+                continue;
+            }
+            let coordinates: Vec2 = Vec2::new(row as f32, col as f32);
+            let center: Vec2 = runner
+                .grid()
+                .get_cell_center_position_from_coordinates(&coordinates);
+            for offset in [-1.5, 0.0, 1.5] {
+                let position: Vec2 = center + Vec2::new(offset, 0.0);
+                runner.add_particle(position, Vec2::ZERO, oil).unwrap();
+            }
+            if (row, col) == crowded {
+                for extra in 0..30 {
+                    let offset: Vec2 = Vec2::new((extra % 6) as f32, (extra / 6) as f32) * 0.7;
+                    let position: Vec2 = center + offset - Vec2::splat(1.75);
+                    let velocity: Vec2 = Vec2::new(extra as f32, -(extra as f32) / 2.0);
+                    runner.add_particle(position, velocity, oil).unwrap();
+                }
+            }
+        }
+    }
+
+    // Sort the particles into their cells, then reseed.
+    runner.run(|commands, constraints, grid, particles, _, _, _, _| {
+        update_particle_lookup(grid, particles);
+        grid.label_cells();
+        reseed_particles(commands, constraints, grid, particles);
+    });
+
+    // New particles join the lookup the next time it is rebuilt.
+    runner.run(|_, _, grid, particles, _, _, _, _| update_particle_lookup(grid, particles));
+
+    // The hole is filled with oil like its neighbors, and the crowded cell is thinned out.
+    let particles = runner.particles();
+    let grid: &SimGrid = runner.grid();
+    let cell_particles = |(row, col): (usize, usize)| -> Vec<Entity> {
+        grid.get_particles_in_lookup(grid.get_lookup_index(Vec2::new(row as f32, col as f32)))
+    };
+    assert_eq!(SimGridCellType::Fluid, grid.cell_type[(hole.0, hole.1)]);
+    assert_eq!(
+        runner.constraints().min_particles_per_cell,
+        cell_particles(hole).len()
+    );
+    assert_eq!(
+        runner.constraints().max_particles_per_cell,
+        cell_particles(crowded).len()
+    );
+
+    // The crowded cell still moves at the same average velocity, (0 + 1 + ... + 29) / 33 across.
+    let crowded_velocity: Vec2 = cell_particles(crowded)
+        .iter()
+        .map(|particle_id| particles.iter().find(|(id, _)| id == particle_id).unwrap())
+        .map(|(_, particle)| particle.velocity)
+        .sum::<Vec2>()
+        / cell_particles(crowded).len() as f32;
+    let expected_velocity: Vec2 = Vec2::new(435.0, -217.5) / 33.0;
+    assert_eq!(true, crowded_velocity.distance(expected_velocity) < 0.001);
+
+    for particle_id in cell_particles(hole) {
+        let (_, particle) = particles.iter().find(|(id, _)| *id == particle_id).unwrap();
+        assert_eq!(oil, particle.material);
+    }
+
+    // Every particle is still counted, and sits in the lookup cell it says it does.
+    assert_eq!(particles.len(), runner.constraints().particle_count);
+    for (particle_id, particle) in particles.iter() {
+        assert_eq!(
+            true,
+            grid.spatial_lookup
+                .cell(particle.lookup_index)
+                .contains(particle_id)
+        );
+    }
+
+    // Cells at the surface of the fluid are left alone.
+    assert_eq!(true, cell_particles((37, 20)).is_empty());
+}
+
+#[test]
+fn deterministic_thread_count_test() {
+    /* Slosh a tank of water big enough that every stage is split up between threads, with two
+    particles in each cell of the tank... */
+    let run_on_threads = |thread_count: usize| -> Vec<[u32; 4]> {
+        let thread_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(thread_count)
+            .build()
+            .unwrap();
+        thread_pool.install(|| {
+            let mut runner = SimRunner::default();
+            runner.grid_mut().force_edge_solids();
+            runner.constraints_mut().deterministic = true;
+            for row in 6..49 {
+                for col in 1..49 {
+                    let coordinates: Vec2 = Vec2::new(row as f32, col as f32);
+                    let center: Vec2 = runner
+                        .grid()
+                        .get_cell_center_position_from_coordinates(&coordinates);
+                    let velocity: Vec2 = Vec2::new(60.0 - 2.5 * col as f32, 0.0);
+                    for offset in [-1.25, 1.25] {
+                        let position: Vec2 = center + Vec2::new(offset, offset);
+                        runner.add_particle(position, velocity, 0).unwrap();
+                    }
+                }
+            }
+            runner.step_many(30);
+            runner
+                .particles()
+                .iter()
+                .map(|(_, particle)| {
+                    let [x, y] = particle.position.to_array();
+                    let [u, v] = particle.velocity.to_array();
+                    [x.to_bits(), y.to_bits(), u.to_bits(), v.to_bits()]
+                })
+                .collect()
+        })
+    };
+
+    // ...and it should end up exactly the same no matter how many threads step it.
+    assert_eq!(run_on_threads(1), run_on_threads(4));
+}
+
+#[test]
+fn push_particles_apart_test() {
+    /* Two overlapping particles in the same cell, and two overlapping particles on either side of
+    the corner between two diagonal cells... */
+    let mut runner = SimRunner::default();
+    runner.constraints_mut().collision_iters_per_frame = 1;
+    let center = |row: f32, col: f32| -> Vec2 {
+        runner
+            .grid()
+            .get_cell_center_position_from_coordinates(&Vec2::new(row, col))
+    };
+    let (same_cell, corner): (Vec2, Vec2) = (center(10.0, 10.0), center(20.5, 20.5));
+    let offsets: [Vec2; 2] = [Vec2::new(0.75, 0.0), Vec2::new(0.5, 0.5)];
+    let positions: [Vec2; 4] = [
+        same_cell - offsets[0],
+        same_cell + offsets[0],
+        corner - offsets[1],
+        corner + offsets[1],
+    ];
+    for position in positions {
+        runner.add_particle(position, Vec2::ZERO, 0).unwrap();
+    }
+
+    // ...are each pushed to exactly one collision diameter apart in a single iteration.
+    runner.run(|_, constraints, grid, particles, _, _, _, _| {
+        update_particle_lookup(grid, particles);
+        push_particles_apart(constraints, grid, particles, &[]);
+    });
+    let collision_diameter: f32 = runner.constraints().particle_radius * 2.0;
+    let particles = runner.particles();
+    let position_near = |start: Vec2| -> Vec2 {
+        particles
+            .iter()
+            .map(|(_, particle)| particle.position)
+            .min_by(|a, b| a.distance(start).total_cmp(&b.distance(start)))
+            .unwrap()
+    };
+    for pair in positions.chunks(2) {
+        let (position0, position1) = (position_near(pair[0]), position_near(pair[1]));
+        assert!((position0.distance(position1) - collision_diameter).abs() < 0.001);
+
+        // Both particles are pushed the same distance, straight away from each other.
+        let midpoint: Vec2 = (pair[0] + pair[1]) / 2.0;
+        assert!(((position0 + position1) / 2.0).distance(midpoint) < 0.001);
+    }
+}
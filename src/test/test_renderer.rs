@@ -26,6 +26,9 @@ fn test_sprite_particle_linking() {
             position: Vec2 { x: 66.098, y: 19.5 },
             velocity: Vec2::ZERO,
             lookup_index: 0,
+            affine: Mat2::ZERO,
+            material: 0,
+            temperature: crate::simulation::ROOM_TEMPERATURE,
         })
         .id();
     commands.entity(particle).insert(SpriteBundle::default());
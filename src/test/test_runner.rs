@@ -0,0 +1,613 @@
+#[cfg(test)]
+use crate::cli::{parse_run_args, run_scene, RunArgs};
+#[cfg(test)]
+use crate::file_system::{init_world_for_files, load_scene, save_scene};
+#[cfg(test)]
+use crate::simulation::sim_runner::SimRunner;
+#[cfg(test)]
+use crate::simulation::sim_state_manager::delete_particle;
+#[cfg(test)]
+use crate::simulation::{
+    SimBoundary, SimColor, SimFluidMaterial, SimFluidMode, SimGridCellType, SimHeatSource,
+    SimParticle, SimShape, SimSolid, SimSurfaceDirection, SimTransferScheme,
+};
+#[cfg(test)]
+use bevy::ecs::entity::Entity;
+#[cfg(test)]
+use bevy::math::{Mat2, Vec2};
+
+/// Average height of every particle in a runner's simulation.
+#[cfg(test)]
+fn average_particle_height(runner: &mut SimRunner) -> f32 {
+    let particles = runner.particles();
+    let height_sum: f32 = particles
+        .iter()
+        .map(|(_, particle)| particle.position.y)
+        .sum();
+
+    height_sum / particles.len() as f32
+}
+
+/// A scene file in the temp directory that is deleted when dropped, even if the test fails.
+#[cfg(test)]
+struct TempSave {
+    key: String, // Path of the file, without the .juice extension.
+}
+
+#[cfg(test)]
+impl TempSave {
+    fn new(name: &str) -> TempSave {
+        TempSave {
+            key: std::env::temp_dir()
+                .join(format!("juice_box_{}", name))
+                .to_string_lossy()
+                .into_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempSave {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(format!("{}.juice", self.key));
+    }
+}
+
+/// Save a runner's scene to a temporary file named after `name`, and load it into a fresh runner.
+#[cfg(test)]
+fn save_and_reload(runner: &mut SimRunner, name: &str) -> SimRunner {
+    let save: TempSave = TempSave::new(name);
+    init_world_for_files(runner.world_mut());
+    save_scene(save.key.clone(), runner.world_mut()).unwrap();
+
+    let mut loaded = SimRunner::default();
+    init_world_for_files(loaded.world_mut());
+    load_scene(save.key.clone(), loaded.world_mut()).unwrap();
+    loaded
+}
+
+#[test]
+fn headless_runner_test() {
+    // Build a simulation without an App, a window, or any systems.
+    let mut runner = SimRunner::default();
+    runner.grid_mut().force_edge_solids();
+    runner.add_particles_in_radius(1.0, 20.0, Vec2::new(125.0, 150.0), Vec2::ZERO, 0);
+
+    // Every particle we asked for should exist as soon as the call returns.
+    let particle_count = runner.constraints().particle_count;
+    assert_ne!(0, particle_count);
+    assert_eq!(particle_count, runner.particles().len());
+
+    // Step a handful of times and make sure gravity pulled the fluid downwards.
+    let start_height = average_particle_height(&mut runner);
+    runner.step_many(10);
+    let end_height = average_particle_height(&mut runner);
+
+    assert_eq!(true, end_height < start_height);
+}
+
+#[test]
+fn parse_run_args_test() {
+    let args: Vec<String> = ["scenes/dam.juice", "--steps", "600", "--out", "final.juice"]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+    let expected: RunArgs = RunArgs {
+        scene: String::from("scenes/dam"),
+        steps: 600,
+        out: String::from("final"),
+    };
+    assert_eq!(expected, parse_run_args(&args).unwrap());
+
+    // Missing --out and a non-numeric step count should both be rejected.
+    let missing_out: Vec<String> = ["dam.juice", "--steps", "5"]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+    assert_eq!(true, parse_run_args(&missing_out).is_err());
+    let bad_steps: Vec<String> = ["dam.juice", "--steps", "lots", "--out", "final.juice"]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+    assert_eq!(true, parse_run_args(&bad_steps).is_err());
+}
+
+#[test]
+fn headless_run_scene_test() {
+    let save: TempSave = TempSave::new("headless_run_scene_test");
+    let args: RunArgs = RunArgs {
+        scene: String::from("metadata/default-file"),
+        steps: 5,
+        out: save.key.clone(),
+    };
+    run_scene(&args).unwrap();
+
+    // The saved scene should load back into a fresh headless runner.
+    let mut runner = SimRunner::default();
+    init_world_for_files(runner.world_mut());
+    assert_eq!(
+        true,
+        load_scene(save.key.clone(), runner.world_mut()).is_ok()
+    );
+    let particle_count = runner.constraints().particle_count;
+    assert_eq!(particle_count, runner.particles().len());
+}
+
+#[test]
+fn apic_save_load_test() {
+    let mut runner = SimRunner::default();
+    runner.constraints_mut().transfer_scheme = SimTransferScheme::Apic;
+    runner.grid_mut().force_edge_solids();
+    runner.add_particles_in_radius(1.0, 20.0, Vec2::new(125.0, 150.0), Vec2::new(30.0, 0.0), 0);
+    runner.step_many(10);
+
+    // Particles should have picked up velocity gradients from the grid.
+    let has_affine = |runner: &mut SimRunner| {
+        runner
+            .particles()
+            .iter()
+            .any(|(_, particle)| particle.affine != Mat2::ZERO)
+    };
+    assert_eq!(true, has_affine(&mut runner));
+
+    // Both the transfer scheme and the affine matrices should survive a save and load.
+    let mut loaded: SimRunner = save_and_reload(&mut runner, "apic_save_load_test");
+    assert_eq!(
+        SimTransferScheme::Apic,
+        loaded.constraints().transfer_scheme
+    );
+    assert_eq!(true, has_affine(&mut loaded));
+}
+
+#[test]
+fn adaptive_timestep_test() {
+    // Particles moving 2000 units/s cross 2000 / 120 = ~16.7 units, or ~3.3 cells, every frame.
+    let mut runner = SimRunner::default();
+    runner.grid_mut().force_edge_solids();
+    runner.add_particles_in_radius(
+        1.0,
+        10.0,
+        Vec2::new(125.0, 125.0),
+        Vec2::new(2000.0, 0.0),
+        0,
+    );
+
+    // A fixed timestep always takes a single step per frame.
+    runner.step();
+    assert_eq!(1, runner.constraints().substeps);
+
+    // The adaptive timestep should split the frame so nothing moves more than a cell per substep.
+    let mut adaptive = SimRunner::default();
+    adaptive.constraints_mut().adaptive_timestep = true;
+    adaptive.grid_mut().force_edge_solids();
+    adaptive.add_particles_in_radius(
+        1.0,
+        10.0,
+        Vec2::new(125.0, 125.0),
+        Vec2::new(2000.0, 0.0),
+        0,
+    );
+    adaptive.step();
+    assert_eq!(4, adaptive.constraints().substeps);
+
+    // However fast things get, the count never goes past the limit.
+    adaptive.constraints_mut().max_substeps = 2;
+    adaptive.run(|_, _, _, particles, _, _, _, _| {
+        for (_, mut particle) in particles.iter_mut() {
+            particle.velocity = Vec2::new(1.0e9, 0.0);
+        }
+    });
+    adaptive.step();
+    assert_eq!(2, adaptive.constraints().substeps);
+}
+
+#[test]
+fn capillary_substep_test() {
+    // Still fluid never needs more than one substep to keep its particles within a cell.
+    let mut runner = SimRunner::default();
+    runner.constraints_mut().adaptive_timestep = true;
+    runner.grid_mut().force_edge_solids();
+    runner.add_particles_in_radius(1.0, 10.0, Vec2::new(125.0, 125.0), Vec2::ZERO, 0);
+    runner.step();
+    assert_eq!(1, runner.constraints().substeps);
+
+    /* Strong surface tension has to be split up anyway: with oil's density of 0.8 and 5-unit cells,
+    each substep can be at most sqrt(0.8 * 125 / (2 * PI * 1.5e6)) = ~0.0033s of the 1/120s frame. */
+    let mut runner = SimRunner::default();
+    runner.constraints_mut().adaptive_timestep = true;
+    runner.constraints_mut().surface_tension = 1.5e6;
+    runner.grid_mut().force_edge_solids();
+    runner.add_particles_in_radius(1.0, 10.0, Vec2::new(125.0, 125.0), Vec2::ZERO, 0);
+    runner.step();
+    assert_eq!(3, runner.constraints().substeps);
+}
+
+#[test]
+fn adaptive_timestep_free_fall_test() {
+    /* A blob of fluid flying sideways fast enough to need three substeps per frame, and a faucet
+    well away from it.  A lone particle would pick up the empty cells next to it whenever it
+    wasn't sitting right on a cell center, so use enough fluid to fill a few cells... */
+    let run_frame = |adaptive_timestep: bool| -> (SimRunner, Vec<Entity>) {
+        let mut runner = SimRunner::default();
+        runner.constraints_mut().adaptive_timestep = adaptive_timestep;
+        runner.grid_mut().force_edge_solids();
+        runner.add_particles_in_radius(
+            1.0,
+            10.0,
+            Vec2::new(60.0, 200.0),
+            Vec2::new(1500.0, 0.0),
+            0,
+        );
+        let blob: Vec<Entity> = runner.particles().iter().map(|(id, _)| *id).collect();
+        runner
+            .add_faucet(Vec2::new(200.0, 100.0), None, 5.0, Vec2::ZERO, 0)
+            .unwrap();
+        runner.step();
+        (runner, blob)
+    };
+    let (mut fixed, fixed_blob) = run_frame(false);
+    let (mut adaptive, adaptive_blob) = run_frame(true);
+    assert_eq!(1, fixed.constraints().substeps);
+    assert_eq!(3, adaptive.constraints().substeps);
+
+    // ...falls just as far and just as fast over the frame either way...
+    let average = |runner: &mut SimRunner, blob: &[Entity]| -> (f32, f32) {
+        let particles = runner.particles();
+        let blob_particles: Vec<&SimParticle> = particles
+            .iter()
+            .filter(|(id, _)| blob.contains(id))
+            .map(|(_, particle)| particle)
+            .collect();
+        let count: f32 = blob_particles.len() as f32;
+        let height: f32 = blob_particles.iter().map(|p| p.position.y).sum::<f32>() / count;
+        let velocity: f32 = blob_particles.iter().map(|p| p.velocity.y).sum::<f32>() / count;
+        (height, velocity)
+    };
+    let (fixed_height, fixed_velocity) = average(&mut fixed, &fixed_blob);
+    let (adaptive_height, adaptive_velocity) = average(&mut adaptive, &adaptive_blob);
+    eprintln!(
+        "DBG {} {} {} {}",
+        fixed_height, fixed_velocity, adaptive_height, adaptive_velocity
+    );
+    let fixed_drop: f32 = 200.0 - fixed_height;
+    let adaptive_drop: f32 = 200.0 - adaptive_height;
+    assert!(fixed_drop > 0.0);
+    assert!((adaptive_drop - fixed_drop).abs() < 0.1 * fixed_drop);
+    assert!((adaptive_velocity - fixed_velocity).abs() < 0.1 * fixed_velocity.abs());
+
+    // ...and the faucet pours the same amount of fluid no matter how many substeps there were.
+    assert!(fixed.constraints().particle_count > fixed_blob.len());
+    assert_eq!(
+        fixed.constraints().particle_count,
+        adaptive.constraints().particle_count
+    );
+}
+
+#[test]
+fn fluid_material_save_load_test() {
+    let mut runner = SimRunner::default();
+    runner.grid_mut().force_edge_solids();
+    runner
+        .constraints_mut()
+        .fluid_materials
+        .push(SimFluidMaterial::new(
+            "Syrup",
+            1.3,
+            80.0,
+            SimColor::rgb(0.5, 0.0, 0.0),
+        ));
+    runner.add_particles_in_radius(1.0, 10.0, Vec2::new(125.0, 150.0), Vec2::ZERO, 1);
+
+    // Both the material table and each particle's material should survive a save and load.
+    let mut loaded: SimRunner = save_and_reload(&mut runner, "fluid_material_save_load_test");
+    assert_eq!(
+        runner.constraints().fluid_materials,
+        loaded.constraints().fluid_materials
+    );
+    assert_eq!(true, !loaded.particles().is_empty());
+    assert_eq!(
+        true,
+        loaded
+            .particles()
+            .iter()
+            .all(|(_, particle)| particle.material == 1)
+    );
+}
+
+#[test]
+fn heater_save_load_test() {
+    let mut runner = SimRunner::default();
+    runner.grid_mut().force_edge_solids();
+    for col in 0..50 {
+        runner
+            .grid_mut()
+            .set_heat_source(49, col, SimHeatSource::Heater)
+            .unwrap();
+    }
+    runner.add_particles_in_radius(1.0, 20.0, Vec2::new(125.0, 25.0), Vec2::ZERO, 0);
+    runner.step_many(120);
+
+    // Fluid sitting on the heater should have warmed up.
+    let average_temperature = |runner: &mut SimRunner| {
+        let particles = runner.particles();
+        particles
+            .iter()
+            .map(|(_, particle)| particle.temperature)
+            .sum::<f32>()
+            / particles.len() as f32
+    };
+    let temperature: f32 = average_temperature(&mut runner);
+    assert_eq!(true, temperature > runner.constraints().ambient_temperature);
+
+    // Both the heater and the fluid's temperature should survive a save and load.
+    let mut loaded: SimRunner = save_and_reload(&mut runner, "heater_save_load_test");
+    assert_eq!(SimHeatSource::Heater, loaded.grid().get_heat_source(49, 25));
+    assert_eq!(SimHeatSource::None, loaded.grid().get_heat_source(0, 25));
+    assert_eq!(
+        true,
+        (average_temperature(&mut loaded) - temperature).abs() < 0.001
+    );
+}
+
+#[test]
+fn smoke_save_load_test() {
+    let mut runner = SimRunner::default();
+    runner.grid_mut().force_edge_solids();
+    runner.constraints_mut().fluid_mode = SimFluidMode::Smoke;
+    runner
+        .add_smoke_in_radius(1.0, 15.0, Vec2::new(125.0, 40.0))
+        .unwrap();
+    runner.step_many(30);
+
+    // Smoke mode and the smoke itself should survive a save and load.
+    let loaded: SimRunner = save_and_reload(&mut runner, "smoke_save_load_test");
+    assert_eq!(SimFluidMode::Smoke, loaded.constraints().fluid_mode);
+    let total_smoke = |runner: &SimRunner| runner.grid().smoke_density.iter().sum::<f32>();
+    assert_eq!(true, total_smoke(&runner) > 0.0);
+    assert_eq!(
+        true,
+        (total_smoke(&loaded) - total_smoke(&runner)).abs() < 0.001
+    );
+}
+
+#[test]
+fn obstacle_save_load_test() {
+    let mut runner = SimRunner::default();
+    runner.grid_mut().force_edge_solids();
+    runner
+        .add_obstacle(
+            SimShape::Circle(10.0),
+            Vec2::new(125.0, 125.0),
+            Vec2::new(20.0, 0.0),
+            1.0,
+        )
+        .unwrap();
+    assert_eq!(
+        true,
+        runner
+            .add_obstacle(
+                SimShape::Circle(10.0),
+                Vec2::new(-5.0, 0.0),
+                Vec2::ZERO,
+                0.0
+            )
+            .is_err()
+    );
+    runner.step_many(30);
+    let obstacle = runner.obstacles()[0].1.clone();
+
+    // The obstacle should survive a save and load, and carry on where it left off.
+    let mut loaded: SimRunner = save_and_reload(&mut runner, "obstacle_save_load_test");
+    let loaded_obstacles = loaded.obstacles();
+    assert_eq!(1, loaded_obstacles.len());
+    assert_eq!(SimShape::Circle(10.0), loaded_obstacles[0].1.shape);
+    assert_eq!(obstacle.position, loaded_obstacles[0].1.position);
+    assert_eq!(obstacle.rotation, loaded_obstacles[0].1.rotation);
+    assert_eq!(runner.grid().obstacle_cells, loaded.grid().obstacle_cells);
+
+    // Once it moves on, the cells it was in when saved should open back up.
+    loaded.step_many(30);
+    let grid = loaded.grid();
+    let old_cell = grid.get_cell_coordinates_from_position(&(obstacle.position - Vec2::X * 9.0));
+    assert_eq!(
+        SimGridCellType::Air,
+        grid.cell_type[(old_cell.x as usize, old_cell.y as usize)]
+    );
+}
+
+#[test]
+fn rigid_body_save_load_test() {
+    let mut runner = SimRunner::default();
+    runner.grid_mut().force_edge_solids();
+    runner
+        .add_rigid_body(
+            SimShape::Rectangle(Vec2::new(10.0, 5.0)),
+            50.0,
+            Vec2::new(125.0, 125.0),
+            Vec2::new(15.0, 0.0),
+        )
+        .unwrap();
+    assert_eq!(
+        true,
+        runner
+            .add_rigid_body(
+                SimShape::Circle(5.0),
+                10.0,
+                Vec2::new(0.0, 300.0),
+                Vec2::ZERO
+            )
+            .is_err()
+    );
+    runner.step_many(10);
+    let body = runner.rigid_bodies()[0].1.clone();
+
+    // The body should come back exactly as it was, momentum and all.
+    let mut loaded: SimRunner = save_and_reload(&mut runner, "rigid_body_save_load_test");
+    let loaded_bodies = loaded.rigid_bodies();
+    assert_eq!(1, loaded_bodies.len());
+    assert_eq!(body.shape, loaded_bodies[0].1.shape);
+    assert_eq!(body.mass, loaded_bodies[0].1.mass);
+    assert_eq!(body.position, loaded_bodies[0].1.position);
+    assert_eq!(body.velocity, loaded_bodies[0].1.velocity);
+
+    // And it should keep falling from where it left off.
+    loaded.step_many(10);
+    assert_eq!(
+        true,
+        loaded.rigid_bodies()[0].1.position.y < body.position.y
+    );
+}
+
+#[test]
+fn sub_cell_solid_save_load_test() {
+    let mut runner = SimRunner::default();
+    runner.grid_mut().add_solid(SimSolid::Segment(
+        Vec2::new(20.0, 100.0),
+        Vec2::new(200.0, 40.0),
+        2.0,
+    ));
+    runner.grid_mut().add_solid(SimSolid::Polygon(vec![
+        Vec2::new(150.0, 150.0),
+        Vec2::new(200.0, 150.0),
+        Vec2::new(175.0, 200.0),
+    ]));
+
+    // Solids should come back from a save, along with the face fractions they cover.
+    let loaded: SimRunner = save_and_reload(&mut runner, "sub_cell_solid_save_load_test");
+    assert_eq!(runner.grid().solids, loaded.grid().solids);
+    assert_eq!(runner.grid().solid_distance, loaded.grid().solid_distance);
+}
+
+#[test]
+fn boundary_save_load_test() {
+    let mut runner = SimRunner::default();
+    runner.grid_mut().set_boundary(
+        SimSurfaceDirection::West,
+        SimBoundary::Inflow(Vec2::new(40.0, 5.0)),
+    );
+    runner
+        .grid_mut()
+        .set_boundary(SimSurfaceDirection::East, SimBoundary::Outflow);
+    runner
+        .grid_mut()
+        .set_boundary(SimSurfaceDirection::North, SimBoundary::Periodic);
+
+    // Every edge's boundary should come back from a save.
+    let loaded: SimRunner = save_and_reload(&mut runner, "boundary_save_load_test");
+    assert_eq!(runner.grid().boundaries, loaded.grid().boundaries);
+    assert_eq!(
+        SimBoundary::Periodic,
+        loaded.grid().get_boundary(SimSurfaceDirection::South)
+    );
+}
+
+#[test]
+fn legacy_grid_save_load_test() {
+    // metadata/default-file.juice was saved when grids were still lists of rows.
+    let legacy: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string("metadata/default-file.juice").unwrap())
+            .unwrap();
+    let legacy_grid: &serde_json::Value = &legacy["resources"]["juice_box::simulation::SimGrid"];
+    let legacy_solids: usize = legacy_grid["cell_type"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .flat_map(|row| row.as_array().unwrap().iter())
+        .filter(|cell_type| *cell_type == "Solid")
+        .count();
+
+    let mut runner = SimRunner::default();
+    init_world_for_files(runner.world_mut());
+    load_scene(String::from("metadata/default-file"), runner.world_mut()).unwrap();
+    let grid = runner.grid();
+    let (rows, cols) = (grid.dimensions.0 as usize, grid.dimensions.1 as usize);
+    assert_eq!(
+        (rows, cols),
+        (grid.cell_type.row_count(), grid.cell_type.col_count())
+    );
+    assert_eq!(
+        (rows, cols + 1),
+        (grid.velocity_u.row_count(), grid.velocity_u.col_count())
+    );
+    assert_eq!(
+        (rows + 1, cols),
+        (grid.velocity_v.row_count(), grid.velocity_v.col_count())
+    );
+    assert_ne!(0, legacy_solids);
+    assert_eq!(
+        legacy_solids,
+        grid.cell_type
+            .iter()
+            .filter(|cell_type| **cell_type == SimGridCellType::Solid)
+            .count()
+    );
+    assert_eq!(
+        legacy_grid["velocity_u"][3][7].as_f64().unwrap() as f32,
+        grid.velocity_u[(3, 7)]
+    );
+
+    // Saving it again should write flat grids, and leave the spatial lookup out.
+    let save: TempSave = TempSave::new("legacy_grid_save_load_test");
+    save_scene(save.key.clone(), runner.world_mut()).unwrap();
+    let saved: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(format!("{}.juice", save.key)).unwrap())
+            .unwrap();
+    let saved_grid: &serde_json::Value = &saved["resources"]["juice_box::simulation::SimGrid"];
+    assert_eq!(rows + 1, saved_grid["velocity_v"]["rows"]);
+    assert_eq!(true, saved_grid.get("spatial_lookup").is_none());
+
+    let mut loaded = SimRunner::default();
+    init_world_for_files(loaded.world_mut());
+    load_scene(save.key.clone(), loaded.world_mut()).unwrap();
+    assert_eq!(runner.grid().cell_type, loaded.grid().cell_type);
+    assert_eq!(runner.grid().velocity_u, loaded.grid().velocity_u);
+    assert_eq!(runner.grid().velocity_v, loaded.grid().velocity_v);
+}
+
+#[test]
+fn spatial_lookup_load_test() {
+    let mut runner = SimRunner::default();
+    runner.grid_mut().force_edge_solids();
+    runner.add_particles_in_radius(1.0, 20.0, Vec2::new(125.0, 150.0), Vec2::ZERO, 0);
+    runner.step();
+
+    // After a load and a step, every particle is in the cell it says, and nothing else is.
+    let mut loaded: SimRunner = save_and_reload(&mut runner, "spatial_lookup_load_test");
+    loaded.step();
+    let particles = loaded.particles();
+    let grid = loaded.grid();
+    let cell_count: usize = grid.dimensions.0 as usize * grid.dimensions.1 as usize;
+    let indexed: usize = (0..cell_count)
+        .map(|index| grid.spatial_lookup.cell(index).len())
+        .sum();
+    assert_ne!(0, particles.len());
+    assert_eq!(particles.len(), indexed);
+    for (particle_id, particle) in particles.iter() {
+        assert_eq!(
+            true,
+            grid.spatial_lookup
+                .cell(particle.lookup_index)
+                .contains(particle_id)
+        );
+    }
+
+    // Only cells with particles in them are labeled as fluid.
+    for index in 0..cell_count {
+        let is_fluid: bool = grid.cell_type[index] == SimGridCellType::Fluid;
+        assert_eq!(is_fluid, !grid.spatial_lookup.cell(index).is_empty());
+    }
+
+    // A deleted particle is taken out of the lookup right away.
+    let (particle_id, particle) = particles[0].clone();
+    loaded.run(|commands, constraints, grid, particles, _, _, _, _| {
+        delete_particle(commands, constraints, particles, grid, particle_id).unwrap();
+    });
+    assert_eq!(
+        false,
+        loaded
+            .grid()
+            .spatial_lookup
+            .cell(particle.lookup_index)
+            .contains(&particle_id)
+    );
+}
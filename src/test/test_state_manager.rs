@@ -1,54 +1,22 @@
+#[cfg(feature = "gui")]
 use crate::juice_renderer::draw_selection_circle;
+#[cfg(feature = "gui")]
 use crate::simulation::sim_state_manager::{delete_particle, select_particles};
-use crate::simulation::step_simulation_once;
+use crate::simulation::step_simulation_frame;
+#[cfg(feature = "gui")]
+use crate::simulation::SimGridCellType;
 #[cfg(test)]
 use crate::simulation::{self, SimSurfaceDirection};
 use crate::simulation::{
     sim_state_manager::{add_particle, add_particles_in_radius},
-    SimConstraints, SimDrain, SimFaucet, SimGrid, SimGridCellType, SimParticle,
+    SimConstraints, SimDrain, SimFaucet, SimGrid, SimObstacle, SimParticle, SimRigidBody,
 };
+#[cfg(feature = "gui")]
 use crate::util::{cartesian_to_polar, get_cursor_position, polar_to_cartesian};
+#[cfg(feature = "gui")]
 use bevy::input::mouse::MouseMotion;
 use bevy::prelude::*;
 
-/// Construct the new simulation file.
-pub fn construct_new_simulation(
-    constraints: &mut SimConstraints,
-    grid: &mut SimGrid,
-    commands: &mut Commands,
-) {
-    // Generate walls around simulation bounds.
-    for i in 0..50 {
-        let _ = grid.set_grid_cell_type(49, i, SimGridCellType::Solid);
-        let _ = grid.set_grid_cell_type(0, i, SimGridCellType::Solid);
-        let _ = grid.set_grid_cell_type(i, 0, SimGridCellType::Solid);
-        let _ = grid.set_grid_cell_type(i, 49, SimGridCellType::Solid);
-    }
-
-    // Spawn a small group of particles at the center of the screen.
-    let grid_center: Vec2 = Vec2 {
-        x: (grid.dimensions.1 * grid.cell_size) as f32 * 0.5,
-        y: (grid.dimensions.0 * grid.cell_size) as f32 * 0.5,
-    };
-
-    let _party_boys = add_particles_in_radius(
-        commands,
-        constraints,
-        grid,
-        1.35,
-        50.0,
-        Vec2 {
-            x: grid_center[0],
-            y: grid_center[1],
-        },
-        Vec2::ZERO,
-    );
-
-    println!(
-        "Constructing the new simulation file with {} particles...",
-        constraints.particle_count
-    );
-}
 /// Create a simulation layout for testing.
 pub fn construct_test_simulation_layout(
     constraints: &mut SimConstraints,
@@ -116,7 +84,7 @@ pub fn construct_test_simulation_layout(
     // 	Vec2 { x: 0.0, y: 0.0 }
     // );
 
-    let _moar_test_particles = add_particles_in_radius(
+    add_particles_in_radius(
         commands,
         constraints,
         grid,
@@ -127,6 +95,7 @@ pub fn construct_test_simulation_layout(
             y: grid_center[1] * 0.85,
         },
         Vec2::ZERO,
+        0,
     );
 
     println!(
@@ -136,6 +105,7 @@ pub fn construct_test_simulation_layout(
 }
 
 /// Create a simulation layout for testing.
+#[allow(dead_code)] // Debugging tool; wire it into the app by hand when needed.
 pub fn construct_simulation_bias_test(
     constraints: &mut SimConstraints,
     grid: &mut SimGrid,
@@ -147,7 +117,7 @@ pub fn construct_simulation_bias_test(
         y: (grid.dimensions.0 * grid.cell_size) as f32 * 0.5,
     };
 
-    let _test_particle_sphere = add_particles_in_radius(
+    add_particles_in_radius(
         commands,
         constraints,
         grid,
@@ -158,17 +128,18 @@ pub fn construct_simulation_bias_test(
             y: grid_center[1] * 0.85,
         },
         Vec2::ZERO,
+        0,
     );
 
     for x in 0..(grid.dimensions.1 * grid.cell_size) as usize {
         for y in 0..50 {
-            if x % 5 as usize == 0 && y % 5 as usize == 0 {
+            if x % 5_usize == 0 && y % 5_usize == 0 {
                 let grid_top: f32 = (grid.dimensions.0 * grid.cell_size) as f32;
                 let pos: Vec2 = Vec2 {
                     x: x as f32,
                     y: grid_top - y as f32,
                 };
-                let _ = add_particle(commands, constraints, grid, pos, Vec2::ZERO);
+                let _ = add_particle(commands, constraints, grid, pos, Vec2::ZERO, 0);
             }
         }
     }
@@ -180,6 +151,8 @@ pub fn construct_simulation_bias_test(
 }
 
 /// Debugging state controller.
+#[cfg(feature = "gui")]
+#[allow(dead_code)] // Debugging tool; wire it into the app by hand when needed.
 pub fn debug_state_controller(
     mut commands: Commands,
     keys: Res<Input<KeyCode>>,
@@ -192,6 +165,8 @@ pub fn debug_state_controller(
     mut particles: Query<(Entity, &mut SimParticle)>,
     mut faucets: Query<(Entity, &mut SimFaucet)>,
     mut drains: Query<(Entity, &mut SimDrain)>,
+    obstacles: Query<(Entity, &mut SimObstacle)>,
+    rigid_bodies: Query<(Entity, &mut SimRigidBody)>,
 ) {
     // Reset simulation when we press R.
     if keys.just_pressed(KeyCode::R) {
@@ -202,6 +177,8 @@ pub fn debug_state_controller(
             &mut particles,
             &mut faucets,
             &mut drains,
+            &obstacles,
+            &rigid_bodies,
         );
         construct_test_simulation_layout(constraints.as_mut(), grid.as_mut(), &mut commands);
         return;
@@ -267,29 +244,36 @@ pub fn test_setup(
     construct_test_simulation_layout(constraints.as_mut(), grid.as_mut(), &mut commands);
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn test_update(
     mut constraints: ResMut<SimConstraints>,
     mut grid: ResMut<SimGrid>,
     mut particles: Query<(Entity, &mut SimParticle)>,
     faucets: Query<(Entity, &mut SimFaucet)>,
     drains: Query<(Entity, &mut SimDrain)>,
+    mut obstacles: Query<(Entity, &mut SimObstacle)>,
+    mut rigid_bodies: Query<(Entity, &mut SimRigidBody)>,
     mut commands: Commands,
 ) {
     // let delta_time: f32 = time.delta().as_millis() as f32 * 0.001;
     let fixed_timestep: f32 = constraints.timestep;
 
-    step_simulation_once(
+    step_simulation_frame(
         &mut commands,
         constraints.as_mut(),
         grid.as_mut(),
         &mut particles,
         &faucets,
         &drains,
+        &mut obstacles,
+        &mut rigid_bodies,
         fixed_timestep,
     );
 }
 
 /// Test particle selection.
+#[allow(dead_code)] // Debugging tool; wire it into the app by hand when needed.
+#[cfg(feature = "gui")]
 pub fn test_select_particles(
     commands: &mut Commands,
     constraints: &mut SimConstraints,
@@ -314,6 +298,8 @@ pub fn test_select_particles(
 }
 
 /// Test grid cell selection.
+#[cfg(feature = "gui")]
+#[allow(dead_code)] // Debugging tool; wire it into the app by hand when needed.
 pub fn test_select_grid_cells(
     grid: ResMut<SimGrid>,
     windows: Query<&Window>,
@@ -357,6 +343,7 @@ fn test_add_faucet_update(mut commands: Commands, mut grid: ResMut<SimGrid>) {
         surface_direction,
         1.0,
         Vec2::ZERO,
+        0,
     ) else {
         return;
     };
@@ -494,13 +481,10 @@ fn drain_drain_test() {
         .resource::<SimConstraints>()
         .particle_count;
 
-    // Run a couple times to let the fluid fall
-    juicebox_test.update();
-    juicebox_test.update();
-    juicebox_test.update();
-    juicebox_test.update();
-    juicebox_test.update();
-    juicebox_test.update();
+    // Run for a second so the fluid can fall the ~55 units down to the drain
+    for _ in 0..120 {
+        juicebox_test.update();
+    }
 
     // Get particle count after drain has drained
     let after_count = juicebox_test
@@ -55,6 +55,39 @@ pub fn handle_input(
     }
     ui_state.is_paused = constraints.is_paused;
 
+    /* Switch the timestep mode if the UI asks to, and otherwise show the scene's own mode, along
+    with how many substeps the last frame was split into. */
+    if ui_state.adaptive_timestep_changed {
+        ui_state.adaptive_timestep_changed = false;
+        constraints.adaptive_timestep = ui_state.adaptive_timestep;
+    }
+    ui_state.adaptive_timestep = constraints.adaptive_timestep;
+    ui_state.substeps = constraints.substeps;
+
+    // Change the surface tension if the UI asks to, and otherwise show the scene's own value.
+    if ui_state.surface_tension_changed {
+        ui_state.surface_tension_changed = false;
+        constraints.surface_tension = ui_state.surface_tension;
+    }
+    ui_state.surface_tension = constraints.surface_tension;
+
+    // Switch between liquid and smoke if the UI asks to, and otherwise show the scene's own mode.
+    if ui_state.fluid_mode_changed {
+        ui_state.fluid_mode_changed = false;
+        constraints.fluid_mode = ui_state.fluid_mode;
+    }
+    ui_state.fluid_mode = constraints.fluid_mode;
+
+    // Show every fluid the simulation knows about, and keep the selected one valid.
+    ui_state.fluid_material_names = constraints
+        .fluid_materials
+        .iter()
+        .map(|material| material.name.clone())
+        .collect();
+    if ui_state.fluid_material >= ui_state.fluid_material_names.len() {
+        ui_state.fluid_material = 0;
+    }
+
     // Handle tool usage for both mouse buttons.
     if left_mouse_pressed || right_mouse_pressed {
         let mouse_button: MouseButton;
@@ -20,6 +20,7 @@ use egui::TextStyle::*;
 use crate::{
     events::{ModifyVisualizationEvent, PlayPauseStepEvent},
     file_system::JuiceStates,
+    simulation::{SimFluidMode, SimHeatSource},
 };
 
 pub fn init_user_interface(
@@ -76,6 +77,9 @@ pub fn draw_user_interface(
     if ui_state.show_visualization {
         show_visualization_menu(&mut ui_state, &mut contexts, ev_viz);
     }
+    if ui_state.show_physics {
+        show_physics_menu(&mut ui_state, &mut contexts);
+    }
     if ui_state.show_informational {
         show_informational_menu(&mut ui_state, &mut contexts);
     }
@@ -206,7 +210,7 @@ fn show_file_manager_panel(ui_state: &mut UIStateManager, ui: &mut Ui) {
         }
 
         // "View" scene dropdown.
-        let view_options = ["View", "Tool", "Visuals", "Controls"];
+        let view_options = ["View", "Tool", "Visuals", "Physics", "Controls"];
         let mut view_selection = 0;
         egui::ComboBox::from_id_source(2).show_index(
             ui,
@@ -218,7 +222,8 @@ fn show_file_manager_panel(ui_state: &mut UIStateManager, ui: &mut Ui) {
         match view_selection {
             1 => ui_state.show_selected_tool = !ui_state.show_selected_tool,
             2 => ui_state.show_visualization = !ui_state.show_visualization,
-            3 => ui_state.show_informational = !ui_state.show_informational,
+            3 => ui_state.show_physics = !ui_state.show_physics,
+            4 => ui_state.show_informational = !ui_state.show_informational,
             _ => {}
         }
 
@@ -341,6 +346,7 @@ fn show_current_tool_menu(ui_state: &mut UIStateManager, contexts: &mut EguiCont
                             egui::Slider::new(&mut ui_state.add_fluid_density, 0.01..=1.0)
                                 .text("Fluid Density"),
                         );
+                        show_fluid_material_picker(ui_state, ui);
                     }
 
                     // For the Remove Fluid tool, show a radius slider.
@@ -351,9 +357,27 @@ fn show_current_tool_menu(ui_state: &mut UIStateManager, contexts: &mut EguiCont
                         );
                     }
 
-                    // For the Add Wall tool, show some text as there are no options for Add Wall.
+                    // For the Add Wall tool, show some text and whether the wall heats or cools.
                     SimTool::AddWall => {
                         ui.label("Click anywhere in the simulation to add a wall!");
+                        ui.horizontal_wrapped(|ui| {
+                            ui.label("Wall:");
+                            ui.selectable_value(
+                                &mut ui_state.wall_heat_source,
+                                SimHeatSource::None,
+                                "Plain",
+                            );
+                            ui.selectable_value(
+                                &mut ui_state.wall_heat_source,
+                                SimHeatSource::Heater,
+                                "Heater",
+                            );
+                            ui.selectable_value(
+                                &mut ui_state.wall_heat_source,
+                                SimHeatSource::Cooler,
+                                "Cooler",
+                            );
+                        });
                     }
 
                     // For the Remove Wall tool, show some text as there are no options for Remove Wall.
@@ -377,6 +401,7 @@ fn show_current_tool_menu(ui_state: &mut UIStateManager, contexts: &mut EguiCont
                             egui::Slider::new(&mut ui_state.faucet_pressure, 0.0..=100.0)
                                 .text("Faucet Pressure"),
                         );
+                        show_fluid_material_picker(ui_state, ui);
                     }
 
                     // For the Remove Faucet tool, show some text as there are no options for Remove Faucet.
@@ -409,6 +434,20 @@ fn show_current_tool_menu(ui_state: &mut UIStateManager, contexts: &mut EguiCont
         });
 }
 
+/// Dropdown for picking which fluid material the Add Fluid and Add Faucet tools use.
+fn show_fluid_material_picker(ui_state: &mut UIStateManager, ui: &mut Ui) {
+    ui.horizontal_wrapped(|ui| {
+        ui.label("Fluid:");
+        let material_names: &Vec<String> = &ui_state.fluid_material_names;
+        egui::ComboBox::from_id_source("Fluid Material").show_index(
+            ui,
+            &mut ui_state.fluid_material,
+            material_names.len(),
+            |i| material_names[i].to_owned(),
+        );
+    });
+}
+
 /// Grid/fluid visualization settings menu.
 fn show_visualization_menu(
     ui_state: &mut UIStateManager,
@@ -452,7 +491,7 @@ fn show_visualization_menu(
                 ui.horizontal_wrapped(|ui| {
                     // Labels for each button.
                     ui.label("Color by:");
-                    let color_options = ["Velocity", "Density", "None"];
+                    let color_options = ["Velocity", "Density", "Material", "Temperature", "None"];
 
                     // Combobox setup and event polling:
                     if egui::ComboBox::from_id_source(0)
@@ -508,6 +547,23 @@ fn show_visualization_menu(
                 {
                     viz_mod = true;
                 }
+
+                ui.separator();
+
+                // Whether this scene simulates a liquid made of particles, or smoke on the grid.
+                ui.horizontal_wrapped(|ui| {
+                    ui.label("Simulate:");
+                    let liquid = ui.selectable_value(
+                        &mut ui_state.fluid_mode,
+                        SimFluidMode::Liquid,
+                        "Liquid",
+                    );
+                    let smoke =
+                        ui.selectable_value(&mut ui_state.fluid_mode, SimFluidMode::Smoke, "Smoke");
+                    if liquid.clicked() || smoke.clicked() {
+                        ui_state.fluid_mode_changed = true;
+                    }
+                });
             });
         });
 
@@ -516,6 +572,43 @@ fn show_visualization_menu(
     }
 }
 
+/// Timestep and surface tension settings menu.
+fn show_physics_menu(ui_state: &mut UIStateManager, contexts: &mut EguiContexts) {
+    egui::Window::new("Physics Options")
+        .frame(ui_state.window_frame)
+        .pivot(Align2::RIGHT_BOTTOM)
+        .default_pos(Pos2 {
+            x: ui_state.window_size.x,
+            y: ui_state.window_size.y,
+        })
+        .default_width(0.0)
+        .resizable(false)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.with_layout(egui::Layout::top_down(egui::Align::TOP), |ui| {
+                // Adaptive timestep toggle, along with the substeps it chose for the last frame.
+                if ui
+                    .checkbox(&mut ui_state.adaptive_timestep, "Adaptive Timestep")
+                    .clicked()
+                {
+                    ui_state.adaptive_timestep_changed = true;
+                }
+                ui.label(format!("Substeps per frame: {}", ui_state.substeps));
+
+                /* How strongly the fluid's surface pulls itself together.  Strong surface tension
+                is only stable with the adaptive timestep on, which splits the frame up for it. */
+                if ui
+                    .add(
+                        egui::Slider::new(&mut ui_state.surface_tension, 0.0..=100000.0)
+                            .text("Surface Tension"),
+                    )
+                    .changed()
+                {
+                    ui_state.surface_tension_changed = true;
+                }
+            });
+        });
+}
+
 /// Play/pause menu.
 fn show_play_pause_menu(
     ui_state: &mut UIStateManager,
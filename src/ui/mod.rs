@@ -16,6 +16,7 @@ use bevy_egui::{
 use self::interaction::{change_cursor_icon, handle_camera_input, handle_input};
 use crate::events::{ResetEvent, ClearEvent, UseToolEvent};
 use crate::file_system::JuiceStates;
+use crate::simulation::{SimFluidMode, SimHeatSource};
 use crate::{
     events::{ModifyVisualizationEvent, PlayPauseStepEvent},
     util,
@@ -113,6 +114,9 @@ pub struct UIStateManager {
     pub faucet_pressure: f32,
     pub drain_radius: f32,
     pub drain_pressure: f32,
+    pub fluid_material: usize,
+    pub fluid_material_names: Vec<String>,
+    pub wall_heat_source: SimHeatSource,
 
     pub show_visualization: bool,
     pub show_grid: bool,
@@ -123,6 +127,15 @@ pub struct UIStateManager {
     pub gravity_magnitude: f32,
     pub fluid_color_variable: usize,
     pub fluid_colors: [[f32; 3]; 4],
+    pub fluid_mode: SimFluidMode,
+    pub fluid_mode_changed: bool,
+
+    pub show_physics: bool,
+    pub adaptive_timestep: bool,
+    pub adaptive_timestep_changed: bool,
+    pub substeps: usize,
+    pub surface_tension: f32,
+    pub surface_tension_changed: bool,
 
     pub is_paused: bool,
     pub play_pause_icon_handles: Vec<Handle<Image>>,
@@ -154,6 +167,9 @@ impl Default for UIStateManager {
             faucet_pressure: 35.0,
             drain_radius: 10.5,
             drain_pressure: 30.0,
+            fluid_material: 0,
+            fluid_material_names: Vec::new(),
+            wall_heat_source: SimHeatSource::None,
 
             // Visualization menu.
             show_visualization: true,
@@ -186,6 +202,16 @@ impl Default for UIStateManager {
                     util::JUICE_RED.b(),
                 ],
             ],
+            fluid_mode: SimFluidMode::Liquid,
+            fluid_mode_changed: false,
+
+            // Physics.
+            show_physics: true,
+            adaptive_timestep: false,
+            adaptive_timestep_changed: false,
+            substeps: 0,
+            surface_tension: 0.0,
+            surface_tension_changed: false,
 
             // Play/pause.
             is_paused: false,
@@ -1,6 +1,8 @@
+use bevy::math::Vec2;
+#[cfg(feature = "gui")]
 use bevy::{
     ecs::system::{NonSend, Query},
-    math::{Quat, Vec2, Vec4},
+    math::{Quat, Vec4},
     prelude::Color,
     render::camera::{Camera, OrthographicProjection},
     time::Time,
@@ -9,23 +11,30 @@ use bevy::{
     window::{MonitorSelection, Window, WindowPlugin, WindowPosition},
     winit::WinitWindows,
 };
+#[cfg(feature = "gui")]
 use image::RgbaImage;
-use std::{
-    f32::consts::{FRAC_PI_2, PI},
-    time::SystemTime,
-};
+#[cfg(feature = "gui")]
+use std::f32::consts::FRAC_PI_2;
+use std::{f32::consts::PI, time::SystemTime};
+#[cfg(feature = "gui")]
 use winit::window::Icon;
 
+#[cfg(feature = "gui")]
 use crate::simulation::{SimConstraints, SimGrid};
 
 pub const WINDOW_WIDTH: f32 = 1440.0;
 pub const WINDOW_HEIGHT: f32 = 1080.0;
 
 /// Color definitions!
+#[cfg(feature = "gui")]
 pub const JUICE_RED: Color = Color::rgb(0.93, 0.16, 0.07);
+#[cfg(feature = "gui")]
 pub const JUICE_YELLOW: Color = Color::rgb(1.0, 0.73, 0.17);
+#[cfg(feature = "gui")]
 pub const JUICE_GREEN: Color = Color::rgb(0.48, 1.0, 0.18);
+#[cfg(feature = "gui")]
 pub const JUICE_BLUE: Color = Color::rgb(0.0, 0.25, 1.0);
+#[cfg(feature = "gui")]
 pub const JUICE_SKY_BLUE: Color = Color::rgb(0.66, 0.91, 1.0);
 
 /// Get the magnitude of a vector.
@@ -37,6 +46,7 @@ pub fn vector_magnitude(vector: Vec2) -> f32 {
 }
 
 /// Basic camera controller.
+#[cfg(feature = "gui")]
 pub fn control_camera(
     time: &Time,
     grid: &SimGrid,
@@ -106,6 +116,7 @@ pub fn control_camera(
 }
 
 /// Get the mouse cursor's position on the screen!  Returns (0.0, 0.0) if cursor position not found.
+#[cfg(feature = "gui")]
 pub fn get_cursor_position(
     windows: &Query<&Window>,
     cameras: &Query<(&Camera, &GlobalTransform)>,
@@ -206,6 +217,7 @@ pub fn cartesian_to_polar(cartesian_vector: Vec2) -> Vec2 {
 
 /** Generate a color value from a gradient between n colors based on a value between 0.0 and 1.0.
 	**Color values should be provided in lowest value -> highest value order.** */
+#[cfg(feature = "gui")]
 pub fn generate_color_from_gradient(colors: &Vec<Color>, mut value: f32) -> Color {
     // Clamp value and get the total number of color zones we can interpolate between.
     value = value.clamp(0.0, 1.0);
@@ -226,6 +238,7 @@ pub fn generate_color_from_gradient(colors: &Vec<Color>, mut value: f32) -> Colo
 }
 
 /// Create a window plugin to add into Bevy's default plugins suite.
+#[cfg(feature = "gui")]
 pub fn create_window_plugin() -> WindowPlugin {
     // First, create a nice window handle.
     let window_handle: Window = Window {
@@ -277,6 +290,7 @@ pub fn create_window_title(title: &str) -> String {
 }
 
 /// Sets the window icon for the app window(s).
+#[cfg(feature = "gui")]
 pub fn set_window_icon(windows: NonSend<WinitWindows>) {
     let (icon_rgba, icon_width, icon_height) = {
         // Load the JuiceBox logo icon, generating a black 16x16 image if it is not found.